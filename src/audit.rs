@@ -0,0 +1,316 @@
+//! Append-only audit log of applied changes.
+//!
+//! Every change set tust applies is appended as one JSON line to `audit.log`
+//! in the user's data directory, with the hashes of the touched files before
+//! and after. Each line carries the BLAKE3 hash of the line before it, so
+//! editing or removing an entry breaks the chain at the next one, which
+//! `tust audit verify` reports. Truncating the end of the log can only be
+//! detected by comparing against a head hash recorded elsewhere. An entry
+//! whose write was cut short is moved to `audit.log.partial` before the next
+//! one is appended, so the chain stays intact.
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use colored::Colorize;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{Change, hash_file};
+
+/// `previous` of the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    /// BLAKE3 hash of the previous line of the log
+    pub previous: String,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub user: String,
+    pub directory: PathBuf,
+    /// The tust invocation that applied the changes; a list of arguments in
+    /// older entries
+    #[serde(deserialize_with = "command_line")]
+    pub command: String,
+    pub changes: Vec<EntryChange>,
+    /// Set when applying stopped partway through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryChange {
+    pub kind: String,
+    pub path: PathBuf,
+    /// Destination of a rename
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+}
+
+/// Read a command line, joining it when stored as a list of arguments
+pub fn command_line<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum CommandLine {
+        Line(String),
+        Args(Vec<String>),
+    }
+    
+    Ok(match CommandLine::deserialize(deserializer)? {
+        CommandLine::Line(line) => line,
+        CommandLine::Args(args) => args.join(" "),
+    })
+}
+
+fn log_path() -> std::io::Result<PathBuf> {
+    Ok(crate::data_dir()?.join("audit.log"))
+}
+
+/// Describe `changes` with the current hashes of the original files and the
+/// hashes of the content they will be replaced with
+pub fn describe(original: &Path, modified: &Path, changes: &[Change]) -> std::io::Result<Vec<EntryChange>> {
+    let hash = |path: &Path| -> std::io::Result<Option<String>> { Ok(Some(hash_file(path)?.to_hex().to_string())) };
+    
+    changes
+        .iter()
+        .map(|change| {
+            Ok(match change {
+                Change::Create(path) => EntryChange {
+                    kind: "create".to_string(),
+                    path: path.clone(),
+                    to: None,
+                    before: None,
+                    after: hash(&modified.join(path))?,
+                },
+                Change::Modify(path) => EntryChange {
+                    kind: "modify".to_string(),
+                    path: path.clone(),
+                    to: None,
+                    before: hash(&original.join(path))?,
+                    after: hash(&modified.join(path))?,
+                },
+                Change::Chmod(path) => EntryChange {
+                    kind: "chmod".to_string(),
+                    path: path.clone(),
+                    to: None,
+                    before: hash(&original.join(path))?,
+                    after: hash(&modified.join(path))?,
+                },
+                Change::Xattr(path) => EntryChange {
+                    kind: "xattr".to_string(),
+                    path: path.clone(),
+                    to: None,
+                    before: hash(&original.join(path))?,
+                    after: hash(&modified.join(path))?,
+                },
+                Change::Delete(path) => EntryChange {
+                    kind: "delete".to_string(),
+                    path: path.clone(),
+                    to: None,
+                    before: hash(&original.join(path))?,
+                    after: None,
+                },
+                Change::Rename(from, to) => EntryChange {
+                    kind: "rename".to_string(),
+                    path: from.clone(),
+                    to: Some(to.clone()),
+                    before: None,
+                    after: None,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Append an entry for changes applied to `directory`
+pub fn record(directory: &Path, changes: Vec<EntryChange>, error: Option<String>) -> std::io::Result<()> {
+    append(&log_path()?, directory, changes, error)
+}
+
+fn append(path: &Path, directory: &Path, changes: Vec<EntryChange>, error: Option<String>) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    
+    let mut file = fs::OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    // Keep concurrent tust processes from chaining onto the same entry
+    file.lock()?;
+    
+    let mut log = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut log)?;
+    // A write that was cut short left part of an entry; it is moved aside so
+    // the chain continues from the last complete entry
+    if !log.is_empty() && !log.ends_with('\n') {
+        let end = log.rfind('\n').map_or(0, |index| index + 1);
+        quarantine(path, &log[end..])?;
+        file.set_len(end as u64)?;
+        log.truncate(end);
+    }
+    let previous = match log.lines().next_back() {
+        Some(line) => blake3::hash(line.as_bytes()).to_hex().to_string(),
+        None => GENESIS.to_string(),
+    };
+    
+    let entry = Entry {
+        previous,
+        time: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+        user: std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default(),
+        directory: directory.to_path_buf(),
+        command: crate::redact::command_line(&std::env::args().collect::<Vec<_>>()),
+        changes,
+        error,
+    };
+    let mut line = serde_json::to_string(&entry).map_err(std::io::Error::other)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    
+    debug!("Appended audit entry for {} changes to {}", entry.changes.len(), path.display());
+    Ok(())
+}
+
+/// Append the incomplete last line of the log at `path` to `audit.log.partial`
+fn quarantine(path: &Path, partial: &str) -> std::io::Result<()> {
+    let quarantine = partial_path(path);
+    warn!("Moving an incomplete audit entry to {}", quarantine.display());
+    eprintln!(
+        "{}",
+        format!("Warning: the audit log ended with an incomplete entry, moved to {}", quarantine.display()).yellow()
+    );
+    let mut file = fs::OpenOptions::new().append(true).create(true).open(&quarantine)?;
+    file.write_all(format!("{}\n", partial).as_bytes())?;
+    file.sync_data()
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    path.with_extension("log.partial")
+}
+
+/// Check that every entry links to the one before it and print the head hash
+pub fn verify() -> std::io::Result<bool> {
+    verify_log(&log_path()?)
+}
+
+fn verify_log(path: &Path) -> std::io::Result<bool> {
+    let log = match fs::read_to_string(path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("{}", "Audit log is empty".green());
+            return Ok(true);
+        }
+        Err(e) => return Err(e),
+    };
+    
+    let mut previous = GENESIS.to_string();
+    let mut count = 0;
+    let complete = &log[..log.rfind('\n').map_or(0, |index| index + 1)];
+    for (index, line) in complete.lines().enumerate() {
+        let problem = match serde_json::from_str::<Entry>(line) {
+            Ok(entry) if entry.previous == previous => None,
+            Ok(_) => Some("does not match the hash of the entry before it"),
+            Err(_) => Some("is not a valid entry"),
+        };
+        if let Some(problem) = problem {
+            println!("{}", format!("Audit log broken at line {}: entry {}", index + 1, problem).red().bold());
+            return Ok(false);
+        }
+        previous = blake3::hash(line.as_bytes()).to_hex().to_string();
+        count += 1;
+    }
+    
+    if complete.len() < log.len() {
+        println!(
+            "{}",
+            format!(
+                "Audit log ends with an incomplete entry at line {}, left by a write that was cut short; \
+                 the next apply moves it to {}",
+                count + 1,
+                partial_path(path).display()
+            )
+            .red()
+            .bold()
+        );
+        return Ok(false);
+    }
+    if partial_path(path).is_file() {
+        println!(
+            "{}",
+            format!("Incomplete entries from writes that were cut short were moved to {}", partial_path(path).display())
+                .yellow()
+        );
+    }
+    
+    info!("Verified {} audit entries in {}", count, path.display());
+    println!("{}", format!("Audit log intact: {} entries", count).green());
+    println!("Head: {}", previous);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn entry_change(path: &str) -> Vec<EntryChange> {
+        vec![EntryChange { kind: "modify".to_string(), path: PathBuf::from(path), to: None, before: None, after: None }]
+    }
+    
+    /// A log with three chained entries
+    fn chained_log(dir: &Path) -> PathBuf {
+        let path = dir.join("audit.log");
+        for file in ["a", "b", "c"] {
+            append(&path, dir, entry_change(file), None).unwrap();
+        }
+        path
+    }
+    
+    #[test]
+    fn appended_entries_form_an_intact_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = chained_log(dir.path());
+        assert!(verify_log(&path).unwrap());
+        
+        let log = fs::read_to_string(&path).unwrap();
+        let entries: Vec<Entry> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries[0].previous, GENESIS);
+        assert_eq!(entries[2].previous, blake3::hash(log.lines().nth(1).unwrap().as_bytes()).to_hex().to_string());
+    }
+    
+    #[test]
+    fn an_edited_entry_breaks_the_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = chained_log(dir.path());
+        let log = fs::read_to_string(&path).unwrap();
+        fs::write(&path, log.replacen("\"path\":\"b\"", "\"path\":\"x\"", 1)).unwrap();
+        assert!(!verify_log(&path).unwrap());
+    }
+    
+    #[test]
+    fn a_removed_entry_breaks_the_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = chained_log(dir.path());
+        let log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(!verify_log(&path).unwrap());
+    }
+    
+    #[test]
+    fn an_incomplete_entry_is_moved_aside_by_the_next_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = chained_log(dir.path());
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"previous\":").unwrap();
+        assert!(!verify_log(&path).unwrap());
+        
+        append(&path, dir.path(), entry_change("d"), None).unwrap();
+        assert!(verify_log(&path).unwrap());
+        assert_eq!(fs::read_to_string(partial_path(&path)).unwrap(), "{\"previous\":\n");
+    }
+}
@@ -0,0 +1,306 @@
+//! Filesystem snapshot backends for creating the sandbox (`--backend`).
+//!
+//! By default the sandbox is filled by copying the project file by file.
+//! Where the project lives on a filesystem with snapshots, the whole tree
+//! can instead be snapshotted in one step, whatever its size:
+//!
+//! - btrfs: a snapshot of the project's subvolume (the project directory must
+//!   be the root of a subvolume; nested subvolumes show up empty)
+//! - ZFS: a snapshot of the project's dataset (the project directory must be
+//!   its mountpoint), cloned and mounted at the sandbox path
+//! - APFS: a `clonefile` of the whole directory
+//!
+//! A snapshot holds the entire tree, including paths excluded from copying;
+//! they are still left out of the comparison. `--backend auto` picks the
+//! backend matching the project's filesystem and copies when there is none
+//! or the snapshot fails.
+//!
+//! `docker[:image]` and `podman[:image]` copy the files and run the command
+//! in a container instead, `bwrap` runs it under bubblewrap and
+//! `sandbox-exec` under a Seatbelt profile; see the `container`, `bwrap` and
+//! `seatbelt` modules.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use colored::Colorize;
+use log::{debug, info, warn};
+
+use crate::container::Container;
+
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Copy the files into the sandbox
+    #[default]
+    Copy,
+    /// Snapshot where the filesystem supports it, copy otherwise
+    Auto,
+    Btrfs,
+    Zfs,
+    Apfs,
+    /// Copy the files and run the command in a container
+    Container(Container),
+    /// Copy the files and run the command under bubblewrap
+    Bwrap,
+    /// Copy the files and run the command under `sandbox-exec`
+    SandboxExec,
+}
+
+impl Backend {
+    /// The container to run the command in, if any
+    pub fn container(&self) -> Option<&Container> {
+        match self {
+            Backend::Container(container) => Some(container),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the value of `--backend`
+pub fn parse(value: &str) -> Result<Backend, String> {
+    match value {
+        "copy" => Ok(Backend::Copy),
+        "auto" => Ok(Backend::Auto),
+        "btrfs" => Ok(Backend::Btrfs),
+        "zfs" => Ok(Backend::Zfs),
+        "apfs" => Ok(Backend::Apfs),
+        "bwrap" => Ok(Backend::Bwrap),
+        "sandbox-exec" => Ok(Backend::SandboxExec),
+        _ => Container::parse(value).map(Backend::Container).ok_or_else(|| {
+            format!("unknown backend {:?}; use copy, auto, btrfs, zfs, apfs, docker[:image], podman[:image], bwrap or sandbox-exec", value)
+        }),
+    }
+}
+
+/// A sandbox created as a snapshot, removed again on drop unless kept
+pub struct Snapshot {
+    backend: Backend,
+    path: PathBuf,
+    /// `dataset@name` of a ZFS snapshot; destroying it also destroys the clone
+    zfs_snapshot: Option<String>,
+    keep: bool,
+}
+
+impl Snapshot {
+    /// Leave the snapshot in place on drop (`--keep`)
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        let removed = match (&self.backend, &self.zfs_snapshot) {
+            (Backend::Btrfs, _) => run("btrfs", &["subvolume".as_ref(), "delete".as_ref(), self.path.as_os_str()]),
+            (Backend::Zfs, Some(snapshot)) => run("zfs", &["destroy".as_ref(), "-R".as_ref(), snapshot.as_ref()]),
+            // APFS clones are plain directories, removed with the sandbox
+            _ => Ok(()),
+        };
+        if let Err(e) = removed {
+            warn!("Failed to remove snapshot {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Fill the empty sandbox directory `dest` with a snapshot of `src`.
+/// `Ok(None)` means `backend` is `auto` and no snapshot could be taken, so
+/// the caller copies the files instead.
+pub fn create(backend: &Backend, src: &Path, dest: &Path) -> std::io::Result<Option<Snapshot>> {
+    let chosen = match backend {
+        Backend::Copy | Backend::Container(_) | Backend::Bwrap | Backend::SandboxExec => return Ok(None),
+        Backend::Auto => match detect(src) {
+            Some(detected) => detected,
+            None => {
+                debug!("No snapshot backend for {}, copying", src.display());
+                return Ok(None);
+            }
+        },
+        explicit => explicit.clone(),
+    };
+    
+    match snapshot(chosen.clone(), src, dest) {
+        Ok(snapshot) => {
+            info!("Created sandbox {} as a {:?} snapshot of {}", dest.display(), chosen, src.display());
+            Ok(Some(snapshot))
+        }
+        Err(e) if *backend == Backend::Auto => {
+            info!("Could not snapshot {} with {:?}, copying instead: {}", src.display(), chosen, e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn snapshot(backend: Backend, src: &Path, dest: &Path) -> std::io::Result<Snapshot> {
+    let mut snapshot = Snapshot { backend: backend.clone(), path: dest.to_path_buf(), zfs_snapshot: None, keep: false };
+    match backend {
+        Backend::Btrfs => {
+            if !is_subvolume(src) {
+                return Err(unsupported(format!("{} is not the root of a btrfs subvolume", src.display())));
+            }
+            // The snapshot is created at the sandbox path itself
+            std::fs::remove_dir(dest)?;
+            let created = run("btrfs", &["subvolume".as_ref(), "snapshot".as_ref(), src.as_os_str(), dest.as_os_str()]);
+            if created.is_err() {
+                std::fs::create_dir(dest)?;
+            }
+            created?;
+        }
+        Backend::Zfs => {
+            let dataset = zfs_dataset(src)?
+                .ok_or_else(|| unsupported(format!("{} is not the mountpoint of a ZFS dataset", src.display())))?;
+            let name = dest.file_name().and_then(|name| name.to_str()).unwrap_or("tust");
+            let pool = dataset.split('/').next().unwrap_or(&dataset);
+            let snapshot_name = format!("{}@{}", dataset, name);
+            let clone_name = format!("{}/{}", pool, name);
+            run("zfs", &["snapshot".as_ref(), snapshot_name.as_ref()])?;
+            // From here on, dropping the snapshot cleans up after a failed clone
+            snapshot.zfs_snapshot = Some(snapshot_name.clone());
+            let mountpoint = format!("mountpoint={}", dest.display());
+            run("zfs", &["clone".as_ref(), "-o".as_ref(), mountpoint.as_ref(), snapshot_name.as_ref(), clone_name.as_ref()])?;
+        }
+        Backend::Apfs => {
+            std::fs::remove_dir(dest)?;
+            let cloned = clone_directory(src, dest);
+            if cloned.is_err() {
+                std::fs::create_dir(dest)?;
+            }
+            cloned?;
+        }
+        _ => unreachable!("not a snapshot backend"),
+    }
+    Ok(snapshot)
+}
+
+/// Destroy ZFS snapshots (and their clones) left behind by kept or
+/// interrupted runs, printing each one; returns how many there were
+pub fn clean_zfs() -> std::io::Result<usize> {
+    let output = match Command::new("zfs").args(["list", "-H", "-o", "name", "-t", "snapshot"]).output() {
+        Ok(output) if output.status.success() => output,
+        // No ZFS on this machine
+        _ => return Ok(0),
+    };
+    
+    let mut count = 0;
+    for snapshot in String::from_utf8_lossy(&output.stdout).lines().filter(|name| name.contains("@tust-")) {
+        debug!("Found tust ZFS snapshot: {}", snapshot);
+        run("zfs", &["destroy".as_ref(), "-R".as_ref(), snapshot.as_ref()])?;
+        info!("Destroyed ZFS snapshot: {}", snapshot);
+        println!("  {}{}", "-".red(), snapshot);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The snapshot backend matching the filesystem `path` is on
+#[cfg(target_os = "linux")]
+fn detect(path: &Path) -> Option<Backend> {
+    const BTRFS_SUPER_MAGIC: u32 = 0x9123_683e;
+    const ZFS_SUPER_MAGIC: u32 = 0x2fc1_2fc1;
+    
+    let stat = statfs(path)?;
+    match stat.f_type as u32 {
+        BTRFS_SUPER_MAGIC => Some(Backend::Btrfs),
+        ZFS_SUPER_MAGIC => Some(Backend::Zfs),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn detect(path: &Path) -> Option<Backend> {
+    let stat = statfs(path)?;
+    // SAFETY: statfs NUL-terminates the filesystem type name
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    match name.to_bytes() {
+        b"apfs" => Some(Backend::Apfs),
+        b"zfs" => Some(Backend::Zfs),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn detect(_path: &Path) -> Option<Backend> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn statfs(path: &Path) -> Option<libc::statfs> {
+    use std::os::unix::ffi::OsStrExt;
+    
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read after statfs
+    // filled it in
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        debug!("statfs {} failed: {}", path.to_string_lossy(), std::io::Error::last_os_error());
+        return None;
+    }
+    Some(unsafe { stat.assume_init() })
+}
+
+/// Whether `path` is the root of a btrfs subvolume, which always has the
+/// same inode number
+fn is_subvolume(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(path).is_ok_and(|metadata| metadata.ino() == 256)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Name of the ZFS dataset mounted at `path`
+fn zfs_dataset(path: &Path) -> std::io::Result<Option<String>> {
+    let path = path.canonicalize()?;
+    let output = Command::new("zfs").args(["list", "-H", "-o", "name,mountpoint", "-t", "filesystem"]).output()?;
+    if !output.status.success() {
+        return Err(command_failed("zfs list", &output.stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().find_map(|line| {
+        let (name, mountpoint) = line.split_once('\t')?;
+        (Path::new(mountpoint) == path).then(|| name.to_string())
+    }))
+}
+
+#[cfg(target_os = "macos")]
+fn clone_directory(src: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dest = CString::new(dest.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL-terminated strings
+    if unsafe { libc::clonefile(src.as_ptr(), dest.as_ptr(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn clone_directory(_src: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(unsupported("APFS clones are only available on macOS".to_string()))
+}
+
+fn run(program: &str, args: &[&std::ffi::OsStr]) -> std::io::Result<()> {
+    debug!("Running {} {:?}", program, args);
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        return Err(command_failed(&format!("{} {}", program, args[0].to_string_lossy()), &output.stderr));
+    }
+    Ok(())
+}
+
+fn command_failed(command: &str, stderr: &[u8]) -> std::io::Error {
+    std::io::Error::other(format!("{} failed: {}", command, String::from_utf8_lossy(stderr).trim()))
+}
+
+fn unsupported(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, message)
+}
@@ -0,0 +1,83 @@
+//! Shared read-only baselines.
+//!
+//! `tust baseline` copies the project once into a `tust-baseline-<id>`
+//! directory under the system temp dir (or `TUST_TMPDIR`). Runs started with
+//! `--from-baseline <id>` seed their sandbox from that copy and compute
+//! their changes against it, so several sandboxes (or users on one
+//! machine) start from the exact same tree without re-reading the project.
+//! Their sandboxes are always copies: hard links (`--link`) would let one
+//! command's in-place writes reach the baseline and every later sandbox.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+const PREFIX: &str = "tust-baseline-";
+
+/// A baseline stored on disk
+pub struct Baseline {
+    pub id: String,
+    root: PathBuf,
+}
+
+impl Baseline {
+    /// Directory holding the copied project tree
+    pub fn tree(&self) -> PathBuf {
+        self.root.join("tree")
+    }
+    
+    /// Directory the baseline was taken from
+    pub fn source(&self) -> std::io::Result<PathBuf> {
+        Ok(PathBuf::from(fs::read_to_string(self.root.join("source"))?))
+    }
+    
+    /// Delete the baseline once nothing needs it anymore
+    pub fn remove(self) {
+        if let Err(e) = fs::remove_dir_all(&self.root) {
+            log::warn!("Failed to remove baseline {}: {}", self.id, e);
+        }
+    }
+}
+
+/// Copy `source` into a new baseline directory
+pub fn create(source: &Path) -> std::io::Result<Baseline> {
+    let root = tempfile::Builder::new().prefix(PREFIX).tempdir_in(crate::sandbox_parent(None))?.keep();
+    let id = root
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(PREFIX))
+        .unwrap_or_default()
+        .to_string();
+    let baseline = Baseline { id, root };
+    
+    info!("Creating baseline {} in {}", baseline.id, baseline.root.display());
+    if let Err(e) = crate::copy_directory(source, &baseline.tree())
+        .and_then(|()| fs::write(baseline.root.join("source"), source.to_string_lossy().as_bytes()))
+    {
+        let _ = fs::remove_dir_all(&baseline.root);
+        return Err(e);
+    }
+    
+    Ok(baseline)
+}
+
+/// Look up an existing baseline by id
+pub fn open(id: &str) -> std::io::Result<Baseline> {
+    // Ids are the random part of the directory name, never a path
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a baseline id", id),
+        ));
+    }
+    let root = crate::sandbox_parent(None).join(format!("{}{}", PREFIX, id));
+    if !root.join("tree").is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no baseline with id {}", id),
+        ));
+    }
+    
+    Ok(Baseline { id: id.to_string(), root })
+}
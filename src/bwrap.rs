@@ -0,0 +1,34 @@
+//! Running the command under bubblewrap (`--backend bwrap`, Linux).
+//!
+//! The sandbox is filled by copying as usual; the command then sees the
+//! whole system read-only, with the sandbox as the only writable path, a
+//! private `/tmp` and its own process namespace. Unlike a container this
+//! needs no daemon or image, and the command keeps the host's toolchain.
+
+use std::path::Path;
+use std::process::Command;
+
+use log::debug;
+
+/// A command running `program` under bubblewrap with `sandbox` as its
+/// working directory; its arguments are added by the caller
+pub fn command(sandbox: &Path, program: &str, network: bool) -> Command {
+    let mut command = Command::new("bwrap");
+    command
+        .args(["--ro-bind", "/", "/"])
+        .args(["--dev", "/dev"])
+        .args(["--proc", "/proc"])
+        // Mounted before the sandbox, which usually lives below /tmp
+        .args(["--tmpfs", "/tmp"])
+        .arg("--bind")
+        .args([sandbox, sandbox])
+        .arg("--chdir")
+        .arg(sandbox)
+        .args(["--unshare-pid", "--die-with-parent"]);
+    if !network {
+        command.arg("--unshare-net");
+    }
+    command.arg("--").arg(program);
+    debug!("Running the command with {:?}", command);
+    command
+}
@@ -0,0 +1,221 @@
+//! Incremental sandbox cache (`--cache`).
+//!
+//! Instead of a fresh temporary directory, the sandbox is kept in
+//! `.tust/cache` inside the project and reused by the next run. Before each
+//! run it is brought back in line with the project, rsync-style: a file is
+//! only copied again when its size or modification time in the project
+//! differs from when it was last copied, or when the previous command
+//! touched the cached copy. Whatever the previous command created is
+//! deleted. `.tust/cache.json` records what was copied, and a lock on
+//! `.tust/cache.lock` keeps concurrent runs from sharing the cache; they
+//! fall back to a temporary sandbox.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use log::{debug, info};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{filter, hardlink, reflink, special, symlink, xattrs};
+
+/// Directory in the project holding the cache, never copied or compared
+pub const DIR: &str = ".tust";
+
+/// Size and modification times of a file, as last copied into the cache
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+struct Stamp {
+    size: u64,
+    /// Nanoseconds since the Unix epoch of the file in the project
+    source_modified: u64,
+    /// The same for the cached copy, to notice when a command changed it
+    copy_modified: u64,
+}
+
+/// The project's sandbox cache, locked for this run
+pub struct Cache {
+    root: PathBuf,
+    /// Held until the run ends, including when tust exits without unwinding
+    _lock: fs::File,
+}
+
+impl Cache {
+    /// Directory the sandbox lives in
+    pub fn path(&self) -> PathBuf {
+        self.root.join("cache")
+    }
+    
+    fn index_path(&self) -> PathBuf {
+        self.root.join("cache.json")
+    }
+    
+    /// Make the cached tree match `src`, calling `on_file` with the size of
+    /// every file in it, copied or not
+    pub fn sync(&self, src: &Path, on_file: &mut (dyn FnMut(u64) + Send)) -> std::io::Result<()> {
+        let old = self.read_index();
+        // Written again once the cache is in a known state
+        let _ = fs::remove_file(self.index_path());
+        
+        let tree = self.path();
+        let mut index = HashMap::new();
+        let mut files = Vec::new();
+        sync_tree(src, &tree, Path::new(""), &old, &mut index, &mut files)?;
+        info!("Cache: {} files up to date, copying {}", index.len(), files.len());
+        
+        let pairs = files.iter().map(|relative| (src.join(relative), tree.join(relative))).collect();
+        let (files, links) = hardlink::split(pairs);
+        let stamp = |from: &Path, to: &Path, bytes| -> std::io::Result<_> {
+            // Symlinks are cheap to recreate and aren't recorded
+            if symlink::target(from).is_some() {
+                return Ok(None);
+            }
+            let relative = to.strip_prefix(&tree).map_err(std::io::Error::other)?.to_path_buf();
+            Ok(Some((relative, Stamp { size: bytes, source_modified: modified(from)?, copy_modified: modified(to)? })))
+        };
+        
+        let on_file = std::sync::Mutex::new(on_file);
+        let mut copied = files
+            .par_iter()
+            .map(|(from, to)| {
+                let bytes = reflink::copy(from, to)?;
+                (on_file.lock().unwrap_or_else(|e| e.into_inner()))(bytes);
+                stamp(from, to, bytes)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let on_file = on_file.into_inner().unwrap_or_else(|e| e.into_inner());
+        for (existing, to) in links {
+            hardlink::create(&existing, &to)?;
+            on_file(0);
+            let from = src.join(to.strip_prefix(&tree).map_err(std::io::Error::other)?);
+            let bytes = fs::metadata(&to)?.len();
+            copied.push(stamp(&from, &to, bytes)?);
+        }
+        for size in index.values().map(|stamp: &Stamp| stamp.size) {
+            on_file(size);
+        }
+        index.extend(copied.into_iter().flatten());
+        
+        let data = serde_json::to_vec(&index).map_err(std::io::Error::other)?;
+        fs::write(self.index_path(), data)
+    }
+    
+    fn read_index(&self) -> HashMap<PathBuf, Stamp> {
+        match fs::read(self.index_path()) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                debug!("Ignoring unreadable cache index: {}", e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        }
+    }
+}
+
+/// Lock the cache of `project`, creating it if needed; `None` when another
+/// run is using it
+pub fn open(project: &Path) -> std::io::Result<Option<Cache>> {
+    let root = project.join(DIR);
+    fs::create_dir_all(&root)?;
+    // Keep the cache out of git without touching the project's .gitignore
+    let gitignore = root.join(".gitignore");
+    if !gitignore.exists() {
+        fs::write(&gitignore, "*\n")?;
+    }
+    
+    let lock = fs::File::create(root.join("cache.lock"))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(Some(Cache { root, _lock: lock })),
+        Err(fs::TryLockError::WouldBlock) => {
+            info!("The cache in {} is in use", root.display());
+            Ok(None)
+        }
+        Err(fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Bring `dest` in line with `src` at `relative`: directories are created,
+/// files and directories `src` doesn't have are removed, up-to-date files
+/// are added to `index` and the rest to `files` to be copied
+fn sync_tree(
+    src: &Path,
+    dest: &Path,
+    relative: &Path,
+    old: &HashMap<PathBuf, Stamp>,
+    index: &mut HashMap<PathBuf, Stamp>,
+    files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    // Never follow a symlink the previous command left in place of a directory
+    if !fs::symlink_metadata(dest).is_ok_and(|metadata| metadata.is_dir()) {
+        remove(dest)?;
+        fs::create_dir(dest)?;
+    }
+    
+    let mut wanted = HashSet::new();
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        let dest_path = dest.join(entry.file_name());
+        let relative_path = relative.join(entry.file_name());
+        let is_dir = symlink::is_dir(&entry_path);
+        if filter::is_excluded(&relative_path, is_dir) || filter::is_untracked(&relative_path, is_dir) {
+            continue;
+        }
+        wanted.insert(entry.file_name());
+        
+        if is_dir {
+            sync_tree(&entry_path, &dest_path, &relative_path, old, index, files)?;
+        } else if special::is_special(&entry_path) {
+            remove(&dest_path)?;
+            special::copy(&entry_path, &dest_path, &relative_path)?;
+        } else if let Some(stamp) = up_to_date(old.get(&relative_path), &entry_path, &dest_path) {
+            index.insert(relative_path, stamp);
+        } else {
+            remove(&dest_path)?;
+            files.push(relative_path);
+        }
+    }
+    
+    for entry in fs::read_dir(dest)? {
+        let entry = entry?;
+        if !wanted.contains(&entry.file_name()) {
+            debug!("Removing from the cache: {}", relative.join(entry.file_name()).display());
+            remove(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// The recorded stamp if the cached copy of `source` is still current
+fn up_to_date(stamp: Option<&Stamp>, source: &Path, copy: &Path) -> Option<Stamp> {
+    let stamp = *stamp?;
+    let (source_metadata, copy_metadata) = (fs::metadata(source).ok()?, fs::symlink_metadata(copy).ok()?);
+    let current = copy_metadata.is_file()
+        && source_metadata.len() == stamp.size
+        && copy_metadata.len() == stamp.size
+        && nanos(&source_metadata) == Some(stamp.source_modified)
+        && nanos(&copy_metadata) == Some(stamp.copy_modified)
+        && source_metadata.permissions() == copy_metadata.permissions()
+        // Setting attributes doesn't touch the modification time
+        && xattrs::read(source).ok()? == xattrs::read(copy).ok()?;
+    current.then_some(stamp)
+}
+
+fn modified(path: &Path) -> std::io::Result<u64> {
+    nanos(&fs::metadata(path)?).ok_or_else(|| std::io::Error::other("file has no modification time"))
+}
+
+fn nanos(metadata: &fs::Metadata) -> Option<u64> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+/// Remove whatever is at `path`, if anything
+fn remove(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,482 @@
+//! Process settings applied to the sandboxed command when it is spawned, and
+//! the resources it used by the time it exited.
+
+use std::process::{Child, Command, ExitStatus};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Resources used by the command and the processes it waited for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ResourceUsage {
+    pub peak_memory_bytes: u64,
+    #[serde(rename = "user_cpu_seconds", with = "seconds")]
+    pub user_time: Duration,
+    #[serde(rename = "system_cpu_seconds", with = "seconds")]
+    pub system_time: Duration,
+    /// Filesystem blocks read and written, as counted by the kernel
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "peak memory {}, CPU {:.2}s user + {:.2}s system, {} blocks read / {} written",
+            crate::format_size(self.peak_memory_bytes),
+            self.user_time.as_secs_f64(),
+            self.system_time.as_secs_f64(),
+            self.blocks_read,
+            self.blocks_written
+        )
+    }
+}
+
+/// How long the command ran and what it used, as stored in session records
+/// and printed by `--format json`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Usage {
+    #[serde(rename = "duration_seconds", with = "seconds")]
+    pub duration: Duration,
+    /// Missing where the platform doesn't report it
+    #[serde(flatten)]
+    pub resources: Option<ResourceUsage>,
+}
+
+/// Durations as fractional seconds
+mod seconds {
+    use std::time::Duration;
+    
+    use serde::{Deserialize, Deserializer, Serializer};
+    
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Duration::try_from_secs_f64(f64::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Wait for `child` to exit and collect its resource usage where supported
+#[cfg(unix)]
+pub fn wait(child: Child) -> std::io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    use std::os::unix::process::ExitStatusExt;
+    
+    let pid = child.id() as libc::pid_t;
+    let mut status = 0;
+    // SAFETY: rusage is plain data, and wait4 only writes into the provided structs
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: pid belongs to our own child, which has not been reaped yet
+        if unsafe { libc::wait4(pid, &mut status, 0, &mut usage) } != -1 {
+            break;
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    
+    // Linux reports the peak resident set size in kilobytes, macOS in bytes
+    let rss_unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    let timeval = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    let usage = ResourceUsage {
+        peak_memory_bytes: usage.ru_maxrss as u64 * rss_unit,
+        user_time: timeval(usage.ru_utime),
+        system_time: timeval(usage.ru_stime),
+        blocks_read: usage.ru_inblock as u64,
+        blocks_written: usage.ru_oublock as u64,
+    };
+    
+    Ok((ExitStatus::from_raw(status), Some(usage)))
+}
+
+#[cfg(windows)]
+pub fn wait(mut child: Child) -> std::io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    Ok((child.wait()?, None))
+}
+
+/// Job object holding the command and everything it starts
+#[cfg(windows)]
+static JOB: std::sync::OnceLock<Job> = std::sync::OnceLock::new();
+
+#[cfg(windows)]
+struct Job(windows_sys::Win32::Foundation::HANDLE);
+
+// SAFETY: a job handle may be used from any thread
+#[cfg(windows)]
+unsafe impl Send for Job {}
+#[cfg(windows)]
+unsafe impl Sync for Job {}
+
+/// Put the just spawned `process` into a job object, so every process it
+/// starts is killed with it by `kill_tree`, and when tust exits however it
+/// exits. Only Windows has job objects; elsewhere this does nothing.
+#[cfg(windows)]
+pub fn contain(process: &Child) {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JobObjectExtendedLimitInformation, SetInformationJobObject,
+    };
+    
+    // SAFETY: null attributes and name create an anonymous job with default
+    // security
+    let job = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+    if job.is_null() {
+        log::debug!("Failed to create a job object: {}", std::io::Error::last_os_error());
+        return;
+    }
+    let mut limits = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+    // The job is closed, and so killed, when tust exits
+    limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+    // SAFETY: `limits` is the struct the information class expects, and the
+    // process handle is valid as long as `process` is
+    let contained = unsafe {
+        SetInformationJobObject(
+            job,
+            JobObjectExtendedLimitInformation,
+            (&raw const limits).cast(),
+            std::mem::size_of_val(&limits) as u32,
+        ) != 0
+            && AssignProcessToJobObject(job, process.as_raw_handle()) != 0
+    };
+    if !contained {
+        log::debug!("Failed to put the command into a job object: {}", std::io::Error::last_os_error());
+        // SAFETY: the job was created above and isn't used anywhere else
+        unsafe { CloseHandle(job) };
+        return;
+    }
+    let _ = JOB.set(Job(job));
+}
+
+#[cfg(unix)]
+pub fn contain(_process: &Child) {}
+
+/// Lower (or raise) the scheduling priority of the command and everything it
+/// starts. `nice` uses Unix niceness semantics (-20 highest, 19 lowest); on
+/// Windows it is mapped to the closest priority class.
+#[cfg(unix)]
+pub fn set_priority(command: &mut Command, nice: i32) {
+    use std::os::unix::process::CommandExt;
+    
+    // SAFETY: setpriority is async-signal-safe, which is all that is
+    // allowed between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(windows)]
+pub fn set_priority(command: &mut Command, nice: i32) {
+    use std::os::windows::process::CommandExt;
+    
+    const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x0000_0020;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x0000_8000;
+    const HIGH_PRIORITY_CLASS: u32 = 0x0000_0080;
+    
+    let class = match nice {
+        15.. => IDLE_PRIORITY_CLASS,
+        1..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        -9..=-1 => ABOVE_NORMAL_PRIORITY_CLASS,
+        _ => HIGH_PRIORITY_CLASS,
+    };
+    command.creation_flags(class);
+}
+
+/// Resource limits for the command and everything it starts
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    /// Address space of each process, in bytes
+    pub memory: Option<u64>,
+    /// CPU time of each process, in seconds
+    pub cpu: Option<u64>,
+    /// Processes the user may have running at once
+    pub processes: Option<u64>,
+}
+
+/// Apply `limits` as rlimits, which the command's children inherit. A
+/// process over the memory limit fails to allocate, one over the CPU limit
+/// is killed with SIGXCPU, and forks beyond the process limit fail.
+#[cfg(unix)]
+pub fn set_limits(command: &mut Command, limits: Limits) -> std::io::Result<()> {
+    use std::os::unix::process::CommandExt;
+    
+    let limits = [
+        (libc::RLIMIT_AS, limits.memory),
+        (libc::RLIMIT_CPU, limits.cpu),
+        (libc::RLIMIT_NPROC, limits.processes),
+    ];
+    // SAFETY: setrlimit is async-signal-safe, and the limits are copied into
+    // the closure before the fork
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, value) in limits {
+                let Some(value) = value else {
+                    continue;
+                };
+                let limit = libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: value as libc::rlim_t };
+                if libc::setrlimit(resource, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn set_limits(_command: &mut Command, _limits: Limits) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "only supported on Unix"))
+}
+
+/// A command running `program` without network access. On Linux it gets a
+/// network namespace of its own with only a loopback interface, inside a
+/// user namespace when tust isn't root; on macOS it runs under
+/// `sandbox-exec` with a profile that denies the network.
+#[cfg(target_os = "linux")]
+pub fn without_network(program: &str) -> std::io::Result<Command> {
+    use std::os::unix::process::CommandExt;
+    
+    // Everything is prepared here, since nothing may allocate between fork
+    // and exec
+    // SAFETY: these calls have no preconditions
+    let (uid, gid, root) = unsafe { (libc::getuid(), libc::getgid(), libc::geteuid() == 0) };
+    let maps = [
+        (c"/proc/self/setgroups", b"deny".to_vec()),
+        (c"/proc/self/uid_map", format!("{} {} 1", uid, uid).into_bytes()),
+        (c"/proc/self/gid_map", format!("{} {} 1", gid, gid).into_bytes()),
+    ];
+    let mut command = Command::new(program);
+    // SAFETY: unshare, open, write, close, socket and ioctl are
+    // async-signal-safe, and the closure only uses data prepared before
+    unsafe {
+        command.pre_exec(move || {
+            let flags = if root { libc::CLONE_NEWNET } else { libc::CLONE_NEWNET | libc::CLONE_NEWUSER };
+            if libc::unshare(flags) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if !root {
+                // Keep the user's own IDs inside the namespace, so files
+                // the command creates belong to them as before
+                for (path, content) in &maps {
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+                    if fd < 0 || libc::write(fd, content.as_ptr().cast(), content.len()) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    libc::close(fd);
+                }
+            }
+            loopback_up();
+            Ok(())
+        });
+    }
+    Ok(command)
+}
+
+#[cfg(target_os = "macos")]
+pub fn without_network(program: &str) -> std::io::Result<Command> {
+    let mut command = Command::new("sandbox-exec");
+    command.args(["-p", "(version 1) (allow default) (deny network*) (allow network* (local ip \"localhost:*\"))", program]);
+    Ok(command)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn without_network(_program: &str) -> std::io::Result<Command> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "only supported on Linux and macOS"))
+}
+
+/// Bring up the loopback interface of a new network namespace, which starts
+/// out down, so the command can still talk to servers it starts itself
+#[cfg(target_os = "linux")]
+fn loopback_up() {
+    // SAFETY: ifreq is plain data, and the ioctls only access the struct
+    // they are given
+    unsafe {
+        let socket = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if socket < 0 {
+            return;
+        }
+        let mut request: libc::ifreq = std::mem::zeroed();
+        for (to, from) in request.ifr_name.iter_mut().zip(b"lo") {
+            *to = *from as libc::c_char;
+        }
+        if libc::ioctl(socket, libc::SIOCGIFFLAGS as _, &mut request) == 0 {
+            request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            libc::ioctl(socket, libc::SIOCSIFFLAGS as _, &request);
+        }
+        libc::close(socket);
+    }
+}
+
+/// Kills the command and everything it started once a timeout passes
+pub struct Deadline {
+    done: std::sync::mpsc::Sender<()>,
+    thread: std::thread::JoinHandle<bool>,
+}
+
+impl Deadline {
+    /// Start the timeout for the command with process ID `pid`
+    pub fn start(pid: u32, timeout: Duration) -> Self {
+        let (done, finished) = std::sync::mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            // Dropping the sender disconnects the channel before the timeout
+            if finished.recv_timeout(timeout) == Err(std::sync::mpsc::RecvTimeoutError::Timeout) {
+                log::warn!("Command is still running after {:?}, killing it", timeout);
+                kill_tree(pid);
+                return true;
+            }
+            false
+        });
+        Deadline { done, thread }
+    }
+    
+    /// Cancel the timeout once the command exited; returns whether the
+    /// command was killed for running too long
+    pub fn stop(self) -> bool {
+        drop(self.done);
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+/// Have processes the command starts and then leaves behind, like daemons
+/// that double-fork, become children of tust instead of init, so
+/// `kill_tree` still finds them. Only Linux supports this.
+#[cfg(target_os = "linux")]
+pub fn adopt_orphans() {
+    // SAFETY: PR_SET_CHILD_SUBREAPER takes a plain integer flag
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) } != 0 {
+        log::debug!("Failed to become a subreaper: {}", std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn adopt_orphans() {}
+
+/// Kill the process `pid`, every process it started, and the orphans tust
+/// adopted. Each one is stopped before its children are looked up, so
+/// nothing can fork away meanwhile.
+#[cfg(unix)]
+pub fn kill_tree(pid: u32) {
+    let pid = pid as libc::pid_t;
+    // SAFETY: getpid has no preconditions
+    let adopted = children(unsafe { libc::getpid() }).into_iter().filter(|child| *child != pid);
+    kill_all(std::iter::once(pid).chain(adopted).collect());
+}
+
+/// Kill what the command left running after it exited: the orphans tust
+/// adopted, and everything they started
+#[cfg(unix)]
+pub fn kill_leftovers() {
+    // SAFETY: getpid has no preconditions
+    kill_all(children(unsafe { libc::getpid() }));
+}
+
+/// Kill `pids` and all their descendants
+#[cfg(unix)]
+fn kill_all(mut stopped: Vec<libc::pid_t>) {
+    let mut next = 0;
+    while next < stopped.len() {
+        let pid = stopped[next];
+        // SAFETY: kill only sends a signal; a pid that is gone fails harmlessly
+        unsafe { libc::kill(pid, libc::SIGSTOP) };
+        stopped.extend(children(pid));
+        next += 1;
+    }
+    for pid in stopped {
+        // SAFETY: as above
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+    }
+}
+
+#[cfg(windows)]
+pub fn kill_tree(pid: u32) {
+    if JOB.get().is_some() {
+        kill_leftovers();
+    } else {
+        let _ = Command::new("taskkill").args(["/T", "/F", "/PID", &pid.to_string()]).output();
+    }
+}
+
+/// Kill what the command left running after it exited, all of it still in
+/// its job
+#[cfg(windows)]
+pub fn kill_leftovers() {
+    use windows_sys::Win32::System::JobObjects::{
+        JOBOBJECT_BASIC_ACCOUNTING_INFORMATION, JobObjectBasicAccountingInformation, QueryInformationJobObject,
+        TerminateJobObject,
+    };
+    
+    let Some(job) = JOB.get() else {
+        return;
+    };
+    // SAFETY: the job handle stays open until tust exits
+    unsafe { TerminateJobObject(job.0, 1) };
+    // Termination is asynchronous; the sandbox can only be removed once no
+    // process holds files in it open
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while std::time::Instant::now() < deadline {
+        let mut accounting = JOBOBJECT_BASIC_ACCOUNTING_INFORMATION::default();
+        // SAFETY: `accounting` is the struct the information class expects
+        let queried = unsafe {
+            QueryInformationJobObject(
+                job.0,
+                JobObjectBasicAccountingInformation,
+                (&raw mut accounting).cast(),
+                std::mem::size_of_val(&accounting) as u32,
+                std::ptr::null_mut(),
+            )
+        };
+        if queried == 0 || accounting.ActiveProcesses == 0 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// The processes whose parent is `pid`
+#[cfg(target_os = "linux")]
+fn children(pid: libc::pid_t) -> Vec<libc::pid_t> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<libc::pid_t>().ok())
+        .filter(|child| {
+            // The parent is the second field after the command name, which
+            // is in parentheses and may contain anything
+            std::fs::read_to_string(format!("/proc/{}/stat", child)).ok().is_some_and(|stat| {
+                stat.rsplit_once(')').and_then(|(_, fields)| fields.split_whitespace().nth(1)?.parse().ok()) == Some(pid)
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+fn children(pid: libc::pid_t) -> Vec<libc::pid_t> {
+    let mut pids = vec![0 as libc::pid_t; 4096];
+    let size = (pids.len() * std::mem::size_of::<libc::pid_t>()) as libc::c_int;
+    // SAFETY: the buffer holds `size` bytes, and the call returns how many
+    // pids it wrote into it
+    let count = unsafe { libc::proc_listchildpids(pid, pids.as_mut_ptr().cast(), size) };
+    pids.truncate(count.max(0) as usize);
+    pids
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn children(_pid: libc::pid_t) -> Vec<libc::pid_t> {
+    Vec::new()
+}
@@ -0,0 +1,280 @@
+//! Global and project configuration.
+//!
+//! `~/.config/tust/config.toml` (the platform's config directory) applies to
+//! every run, and a `.tust.toml` in the directory tust runs in applies to that
+//! project; where both set a value, the project wins. `[defaults]` supplies
+//! options that would otherwise have to be passed on every run; flags given
+//! on the command line are added to them or take precedence. `ignore` and
+//! `protected` take gitignore-style patterns: changes to ignored paths are
+//! not reviewed or applied at all, and changes to protected paths are shown
+//! but never applied. `[hooks]` runs shell commands in the project at points
+//! of a run (see `hooks`). A project's `yes`, `[hooks]` and keys that run
+//! commands or send data only count once the project is trusted (see
+//! `trust`).
+//!
+//! Either file can define named profiles (`[profiles.ci]` with the same
+//! keys) that `--profile ci` layers on top of the file's own settings.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::info;
+use serde::Deserialize;
+
+use crate::Change;
+
+pub const FILE_NAME: &str = ".tust.toml";
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub defaults: Defaults,
+    pub ignore: Vec<String>,
+    pub protected: Vec<String>,
+    pub hooks: Hooks,
+    profiles: HashMap<String, Config>,
+    /// Settings of an untrusted project that were left out (see `trust`)
+    #[serde(skip)]
+    pub untrusted: Vec<&'static str>,
+}
+
+/// Defaults for command-line options, named like the flags
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Defaults {
+    pub heartbeat: Option<bool>,
+    pub diff: Option<bool>,
+    pub keep: Option<bool>,
+    pub nice_io: Option<bool>,
+    pub notify: Option<bool>,
+    pub notify_webhook: Option<String>,
+    /// Apply without asking, unless the command line asks for a review
+    pub yes: Option<bool>,
+    /// Run before the setup commands given on the command line
+    pub setup: Vec<String>,
+    pub validate: Option<String>,
+    pub merge_tool: Option<String>,
+    pub difftool: Option<String>,
+    /// Key `--sign` signs exported patches with
+    pub sign_key: Option<String>,
+    pub encrypt: Option<bool>,
+    /// Age recipients to `--encrypt` to, added to those on the command line
+    pub encrypt_to: Vec<String>,
+    /// Bundled syntect theme or `.tmTheme` file to highlight diffs in
+    pub diff_theme: Option<String>,
+    pub lock_timeout: Option<u64>,
+    pub no_undo: Option<bool>,
+    /// Size like `500M`
+    pub max_undo_size: Option<String>,
+    pub redact: Vec<String>,
+    pub diff_handler: Vec<String>,
+    /// `KEY=V1,V2` variables to run every command under each value of
+    pub matrix: Vec<String>,
+    /// Names of the `tust-NAME` inspectors to run on every change set
+    pub inspector: Vec<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Hooks {
+    /// Runs before the sandbox is filled; failing aborts the run
+    pub pre_copy: Option<String>,
+    /// Runs once the command exited, whether it succeeded or not
+    pub post_run: Option<String>,
+    /// Runs in the project before changes are applied; failing aborts the apply
+    pub pre_apply: Option<String>,
+    /// Runs in the project after changes were applied
+    pub post_apply: Option<String>,
+}
+
+impl Config {
+    /// Layer `other` on top: its values win and its lists are appended
+    fn merge(&mut self, other: Config) {
+        let (defaults, other_defaults) = (&mut self.defaults, other.defaults);
+        defaults.heartbeat = other_defaults.heartbeat.or(defaults.heartbeat);
+        defaults.diff = other_defaults.diff.or(defaults.diff);
+        defaults.keep = other_defaults.keep.or(defaults.keep);
+        defaults.nice_io = other_defaults.nice_io.or(defaults.nice_io);
+        defaults.notify = other_defaults.notify.or(defaults.notify);
+        defaults.notify_webhook = other_defaults.notify_webhook.or(defaults.notify_webhook.take());
+        defaults.yes = other_defaults.yes.or(defaults.yes);
+        defaults.setup.extend(other_defaults.setup);
+        defaults.validate = other_defaults.validate.or(defaults.validate.take());
+        defaults.merge_tool = other_defaults.merge_tool.or(defaults.merge_tool.take());
+        defaults.difftool = other_defaults.difftool.or(defaults.difftool.take());
+        defaults.sign_key = other_defaults.sign_key.or(defaults.sign_key.take());
+        defaults.encrypt = other_defaults.encrypt.or(defaults.encrypt);
+        defaults.encrypt_to.extend(other_defaults.encrypt_to);
+        defaults.diff_theme = other_defaults.diff_theme.or(defaults.diff_theme.take());
+        defaults.lock_timeout = other_defaults.lock_timeout.or(defaults.lock_timeout);
+        defaults.no_undo = other_defaults.no_undo.or(defaults.no_undo);
+        defaults.max_undo_size = other_defaults.max_undo_size.or(defaults.max_undo_size.take());
+        defaults.redact.extend(other_defaults.redact);
+        defaults.diff_handler.extend(other_defaults.diff_handler);
+        defaults.matrix.extend(other_defaults.matrix);
+        defaults.inspector.extend(other_defaults.inspector);
+        
+        self.ignore.extend(other.ignore);
+        self.protected.extend(other.protected);
+        self.hooks.pre_copy = other.hooks.pre_copy.or(self.hooks.pre_copy.take());
+        self.hooks.post_run = other.hooks.post_run.or(self.hooks.post_run.take());
+        self.hooks.pre_apply = other.hooks.pre_apply.or(self.hooks.pre_apply.take());
+        self.hooks.post_apply = other.hooks.post_apply.or(self.hooks.post_apply.take());
+    }
+    
+    /// Keys set here that run commands outside the sandbox, apply without
+    /// asking or send data somewhere, which a project file only gets to set
+    /// once trusted
+    fn needing_trust(&self) -> Vec<&'static str> {
+        let (defaults, hooks) = (&self.defaults, &self.hooks);
+        [
+            ("yes", defaults.yes.is_some()),
+            ("setup", !defaults.setup.is_empty()),
+            ("validate", defaults.validate.is_some()),
+            ("merge-tool", defaults.merge_tool.is_some()),
+            ("difftool", defaults.difftool.is_some()),
+            ("diff-handler", !defaults.diff_handler.is_empty()),
+            ("inspector", !defaults.inspector.is_empty()),
+            ("notify-webhook", defaults.notify_webhook.is_some()),
+            ("encrypt-to", !defaults.encrypt_to.is_empty()),
+            ("pre-copy hook", hooks.pre_copy.is_some()),
+            ("post-run hook", hooks.post_run.is_some()),
+            ("pre-apply hook", hooks.pre_apply.is_some()),
+            ("post-apply hook", hooks.post_apply.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect()
+    }
+    
+    /// Take out everything `needing_trust` looks for
+    fn withhold(&mut self) {
+        let defaults = &mut self.defaults;
+        defaults.yes = None;
+        defaults.setup.clear();
+        defaults.validate = None;
+        defaults.merge_tool = None;
+        defaults.difftool = None;
+        defaults.diff_handler.clear();
+        defaults.inspector.clear();
+        defaults.notify_webhook = None;
+        defaults.encrypt_to.clear();
+        self.hooks = Hooks::default();
+    }
+}
+
+/// Path of the per-user configuration file
+fn global_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("tust").join("config.toml"))
+}
+
+/// Combined configuration for a run in `dir`: the global file, then the
+/// project's `.tust.toml`, each followed by its `profile` section if given.
+/// What the project's file may only set once trusted is left out otherwise.
+pub fn load(dir: &Path, profile: Option<&str>) -> std::io::Result<Config> {
+    let mut config = Config::default();
+    let mut found_profile = false;
+    let project_path = dir.join(FILE_NAME);
+    for path in global_path().into_iter().chain([project_path.clone()]) {
+        let Some(mut file) = read(&path)? else {
+            continue;
+        };
+        let mut section = profile.and_then(|name| file.profiles.remove(name));
+        if path == project_path {
+            let mut untrusted = file.needing_trust();
+            for name in section.as_ref().map(Config::needing_trust).unwrap_or_default() {
+                if !untrusted.contains(&name) {
+                    untrusted.push(name);
+                }
+            }
+            if !untrusted.is_empty() && !crate::trust::is_trusted(dir)? {
+                info!("Leaving out {} of the untrusted {}", untrusted.join(", "), path.display());
+                file.withhold();
+                if let Some(section) = &mut section {
+                    section.withhold();
+                }
+                config.untrusted = untrusted;
+            }
+        }
+        config.merge(file);
+        if let Some(section) = section {
+            if !section.profiles.is_empty() {
+                return Err(invalid(&path, "profiles can't contain other profiles"));
+            }
+            info!("Using profile {} from {}", profile.unwrap_or_default(), path.display());
+            config.merge(section);
+            found_profile = true;
+        }
+    }
+    
+    match profile {
+        Some(name) if !found_profile => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no profile named {} in the global or project configuration", name),
+        )),
+        _ => Ok(config),
+    }
+}
+
+/// Names of the profiles in the global configuration, sorted
+pub fn global_profiles() -> std::io::Result<Vec<String>> {
+    let Some(file) = global_path().map(|path| read(&path)).transpose()?.flatten() else {
+        return Ok(Vec::new());
+    };
+    let mut names: Vec<String> = file.profiles.into_keys().collect();
+    names.sort();
+    Ok(names)
+}
+
+fn read(path: &Path) -> std::io::Result<Option<Config>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+    };
+    info!("Loading configuration from {}", path.display());
+    toml::from_str(&text).map(Some).map_err(|e| invalid(path, e))
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+/// Gitignore-style patterns matched against paths relative to the project
+pub struct Patterns(Gitignore);
+
+impl Patterns {
+    pub fn new(patterns: &[String]) -> std::io::Result<Self> {
+        let mut builder = GitignoreBuilder::new(".");
+        for pattern in patterns {
+            builder
+                .add_line(None, pattern)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+        }
+        let matcher = builder.build().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Patterns(matcher))
+    }
+    
+    /// Whether the file `path` or a directory containing it matches
+    pub fn matches(&self, path: &Path) -> bool {
+        self.matches_entry(path, false)
+    }
+    
+    /// Like `matches`, for a path that may be a directory itself
+    pub fn matches_entry(&self, path: &Path, is_dir: bool) -> bool {
+        self.0.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+    
+    /// Whether a change touches a matching path (either side of a rename)
+    pub fn matches_change(&self, change: &Change) -> bool {
+        match change {
+            Change::Create(path) | Change::Modify(path) | Change::Delete(path) | Change::Chmod(path) | Change::Xattr(path) => {
+                self.matches(path)
+            }
+            // Renames are of whole directories
+            Change::Rename(from, to) => self.matches_entry(from, true) || self.matches_entry(to, true),
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! Three-way safety check for the apply step.
+//!
+//! Before the sandbox is filled, every original file is fingerprinted: its
+//! size and modification time, and with `--paranoid` its hash. Where the
+//! filesystem can clone files (see `reflink`), the originals are cloned
+//! aside too, which costs no space until they are edited. Right before the
+//! user is asked to confirm, the state of every original path a change will
+//! touch is recorded, and modified files that still match their fingerprint
+//! are copied aside as the merge base. A path that no longer matches its
+//! fingerprint was edited while the command ran and is a conflict from the
+//! start; one whose recorded state changed by the time the changes are
+//! applied is one too. Either way the user picks a resolution per file
+//! instead of having their edits silently overwritten. The merge base of a
+//! file edited while the command ran is its clone, so without one only the
+//! files edited during the review can be merged. A merge that leaves
+//! conflict markers is shown before it is taken. A kept session stores its
+//! fingerprints, so `tust exec` checks against the originals as they were
+//! when its sandbox was filled; their clones are gone by then.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use colored::Colorize;
+use log::{debug, info};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{Change, hash_file, merge, read_confirmation, review, shell_command, shell_quote, symlink};
+
+/// Fingerprints of the original files from before the command ran
+#[derive(Serialize, Deserialize)]
+pub struct Seed {
+    files: HashMap<PathBuf, Fingerprint>,
+    /// Whether the fingerprints include the files' hashes
+    hashed: bool,
+    /// Clones of the original files, when the filesystem supports them
+    #[serde(skip)]
+    clones: Option<tempfile::TempDir>,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+struct Fingerprint {
+    size: u64,
+    modified: Option<SystemTime>,
+    /// Hex BLAKE3 hash
+    hash: Option<String>,
+}
+
+/// State of the original paths when the changes were shown to the user
+pub struct OriginalState {
+    base_dir: tempfile::TempDir,
+    /// For each change: the path it touches and what is expected there
+    expected: Vec<(PathBuf, Expected)>,
+}
+
+enum Expected {
+    /// Nothing exists at the path
+    Absent,
+    /// A file with this hash
+    Hash(blake3::Hash),
+    /// The path was edited while the command ran
+    Changed,
+}
+
+pub struct Conflict {
+    index: usize,
+    path: PathBuf,
+}
+
+impl Seed {
+    /// Fingerprint the `files` (relative paths) of `original`, hashing them
+    /// when `hashed` is set, and clone them into `temp_dir` where possible
+    pub fn record(
+        original: &Path,
+        files: impl Iterator<Item = std::io::Result<PathBuf>>,
+        hashed: bool,
+        temp_dir: Option<&Path>,
+    ) -> std::io::Result<Self> {
+        let paths = files.collect::<std::io::Result<Vec<PathBuf>>>()?;
+        debug!("Fingerprinting {} original files", paths.len());
+        let files = paths
+            .into_par_iter()
+            .filter_map(|path| match fingerprint(&original.join(&path), hashed) {
+                Ok(fingerprint) => Some(Ok((path, fingerprint))),
+                // Deleted since the walk; it is missing from the sandbox too
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<std::io::Result<HashMap<PathBuf, Fingerprint>>>()?;
+        let clones = clone_all(original, files.keys(), temp_dir)?;
+        Ok(Seed { files, hashed, clones })
+    }
+    
+    /// The clone of the original `path` from before the command ran
+    fn clone_of(&self, path: &Path) -> Option<PathBuf> {
+        let clone = self.clones.as_ref()?.path().join(path);
+        clone.is_file().then_some(clone)
+    }
+    
+    /// Whether the original `path` is no longer what it was when the sandbox
+    /// was filled
+    fn changed(&self, original: &Path, path: &Path) -> std::io::Result<bool> {
+        let full_path = original.join(path);
+        match self.files.get(path) {
+            None => Ok(symlink::exists(&full_path)),
+            Some(recorded) => Ok(!symlink::exists(&full_path)
+                || symlink::is_dir(&full_path)
+                || fingerprint(&full_path, self.hashed)? != *recorded),
+        }
+    }
+}
+
+/// Clone the `paths` of `original` into a new directory in `temp_dir`, or
+/// return `None` if the filesystem can't clone them
+fn clone_all<'a>(
+    original: &Path,
+    mut paths: impl Iterator<Item = &'a PathBuf>,
+    temp_dir: Option<&Path>,
+) -> std::io::Result<Option<tempfile::TempDir>> {
+    let clones = tempfile::Builder::new().prefix("tust-").tempdir_in(crate::sandbox_parent(temp_dir))?;
+    let clone = |path: &PathBuf| -> std::io::Result<bool> {
+        let to = clones.path().join(path);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        crate::reflink::clone(&original.join(path), &to)
+    };
+    
+    // The first file tells whether cloning works here at all
+    match paths.next() {
+        Some(first) if clone(first)? => {}
+        _ => return Ok(None),
+    }
+    let paths: Vec<&PathBuf> = paths.collect();
+    let cloned = paths.into_par_iter().map(clone).collect::<std::io::Result<Vec<bool>>>()?;
+    debug!("Cloned {} original files as merge bases", cloned.iter().filter(|cloned| **cloned).count() + 1);
+    Ok(Some(clones))
+}
+
+fn fingerprint(path: &Path, hashed: bool) -> std::io::Result<Fingerprint> {
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(Fingerprint {
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+        hash: if hashed { Some(hash_file(path)?.to_hex().to_string()) } else { None },
+    })
+}
+
+/// Record the state of every original path touched by `changes`, with the
+/// merge bases in `temp_dir`; paths that changed since `seed` was recorded
+/// are conflicts already
+pub fn capture(
+    original: &Path,
+    changes: &[Change],
+    seed: Option<&Seed>,
+    temp_dir: Option<&Path>,
+) -> std::io::Result<OriginalState> {
+    let base_dir = tempfile::Builder::new().prefix("tust-").tempdir_in(crate::sandbox_parent(temp_dir))?;
+    let mut expected = Vec::with_capacity(changes.len());
+    let changed = |path: &Path| seed.map_or(Ok(false), |seed| seed.changed(original, path));
+    
+    for change in changes {
+        match change {
+            Change::Create(path) | Change::Modify(path) | Change::Delete(path) | Change::Chmod(path) | Change::Xattr(path)
+                if changed(path)? =>
+            {
+                debug!("Original changed while the command ran: {}", path.display());
+                // The sandbox started from the clone, which makes it the base
+                if let (Change::Modify(_), Some(clone)) = (change, seed.and_then(|seed| seed.clone_of(path))) {
+                    let base_path = base_dir.path().join(path);
+                    if let Some(parent) = base_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    crate::reflink::copy(&clone, &base_path)?;
+                }
+                expected.push((path.clone(), Expected::Changed));
+            }
+            Change::Create(path) => expected.push((path.clone(), Expected::Absent)),
+            Change::Modify(path) => {
+                let original_path = original.join(path);
+                let base_path = base_dir.path().join(path);
+                if let Some(parent) = base_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                crate::reflink::copy(&original_path, &base_path)?;
+                expected.push((path.clone(), Expected::Hash(hash_file(&original_path)?)));
+            }
+            Change::Delete(path) | Change::Chmod(path) | Change::Xattr(path) => {
+                expected.push((path.clone(), Expected::Hash(hash_file(&original.join(path))?)))
+            }
+            Change::Rename(_, to) => expected.push((to.clone(), Expected::Absent)),
+        }
+    }
+    
+    Ok(OriginalState { base_dir, expected })
+}
+
+impl OriginalState {
+    /// Forget the changes not marked in `keep`, matching a `retain` on the change list
+    pub fn retain(&mut self, keep: &[bool]) {
+        let mut index = 0;
+        self.expected.retain(|_| {
+            index += 1;
+            keep[index - 1]
+        });
+    }
+    
+    /// Changes whose original path no longer matches the recorded state
+    pub fn find_conflicts(&self, original: &Path) -> std::io::Result<Vec<Conflict>> {
+        let mut conflicts = Vec::new();
+        for (index, (path, expected)) in self.expected.iter().enumerate() {
+            let full_path = original.join(path);
+            let changed = match expected {
+                Expected::Absent => symlink::exists(&full_path),
+                Expected::Hash(hash) => {
+                    !symlink::exists(&full_path) || symlink::is_dir(&full_path) || hash_file(&full_path)? != *hash
+                }
+                Expected::Changed => true,
+            };
+            
+            if changed {
+                debug!("Original changed since review: {}", path.display());
+                conflicts.push(Conflict { index, path: path.clone() });
+            }
+        }
+        Ok(conflicts)
+    }
+}
+
+/// Let the user resolve each conflict. Changes the user wants to keep their
+/// version for are removed from `changes`; merged results are written into
+/// `modified` so they are picked up by the regular apply.
+///
+/// Returns `false` if the user aborted.
+pub fn resolve(
+    state: &OriginalState,
+    conflicts: &[Conflict],
+    changes: &mut Vec<Change>,
+    original: &Path,
+    modified: &Path,
+    allow_stdin: bool,
+    merge_tool: Option<&str>,
+) -> std::io::Result<bool> {
+    println!(
+        "\n{}",
+        format!("{} file(s) changed in the original directory in the meantime:", conflicts.len())
+            .red()
+            .bold()
+    );
+    
+    let mut skipped = Vec::new();
+    for conflict in conflicts {
+        // A modified file edited while the command ran has a merge base
+        // only if the seed could clone it
+        let mergeable = match changes[conflict.index] {
+            Change::Create(_) => true,
+            Change::Modify(_) => state.base_dir.path().join(&conflict.path).is_file(),
+            _ => false,
+        } && original.join(&conflict.path).is_file();
+        let mut options = vec!["[k]eep original", "[t]ake sandbox version"];
+        if mergeable {
+            options.push("[m]erge");
+            if merge_tool.is_some() {
+                options.push("[o]pen merge tool");
+            }
+        }
+        options.push("[a]bort");
+        
+        loop {
+            println!("  {}{}", "! ".red(), conflict.path.display());
+            println!("    {}", options.join(", ").yellow());
+            let answer = read_confirmation(allow_stdin)?.trim().to_lowercase();
+            match answer.as_str() {
+                "k" => {
+                    info!("Keeping original version of {}", conflict.path.display());
+                    skipped.push(conflict.index);
+                }
+                "t" => info!("Taking sandbox version of {}", conflict.path.display()),
+                "m" if mergeable => {
+                    let unmerged = keep_sandbox_version(state, &conflict.path, modified)?;
+                    if merge_into_sandbox(state, &conflict.path, original, modified)? {
+                        println!("    {}", "Merged cleanly".green());
+                    } else {
+                        println!("    {}", "Merged with conflict markers:".yellow());
+                        let merged = modified.join(&conflict.path);
+                        review::print_diff(Some(&original.join(&conflict.path)), Some(&merged), "    ");
+                        println!("    {}", "Apply the merge with its conflict markers? (y/n)".yellow());
+                        if read_confirmation(allow_stdin)?.trim().to_lowercase() != "y" {
+                            fs::copy(&unmerged, &merged)?;
+                            continue;
+                        }
+                    }
+                }
+                "o" if mergeable && merge_tool.is_some() => {
+                    if !run_merge_tool(merge_tool.unwrap_or_default(), state, &conflict.path, original, modified)? {
+                        println!("    {}", "Merge tool failed, choose again".red());
+                        continue;
+                    }
+                }
+                "a" => return Ok(false),
+                _ => continue,
+            }
+            break;
+        }
+    }
+    
+    let mut index = 0;
+    changes.retain(|_| {
+        index += 1;
+        !skipped.contains(&(index - 1))
+    });
+    Ok(true)
+}
+
+/// Three-way merge the original's current content with the sandbox version,
+/// storing the result as the sandbox version. Returns whether it was clean.
+fn merge_into_sandbox(state: &OriginalState, path: &Path, original: &Path, modified: &Path) -> std::io::Result<bool> {
+    let read_text = |path: &Path| -> std::io::Result<String> {
+        match fs::read(path) {
+            Ok(bytes) => String::from_utf8(bytes).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is not a text file", path.display()))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e),
+        }
+    };
+    
+    let base = read_text(&state.base_dir.path().join(path))?;
+    let mine = read_text(&original.join(path))?;
+    let theirs = read_text(&modified.join(path))?;
+    let (merged, clean) = merge::merge3(&base, &mine, &theirs);
+    fs::write(modified.join(path), merged)?;
+    Ok(clean)
+}
+
+/// Copy the sandbox version of `path` aside before it is merged; returns
+/// where it was copied to
+fn keep_sandbox_version(state: &OriginalState, path: &Path, modified: &Path) -> std::io::Result<PathBuf> {
+    let kept = state.base_dir.path().join(format!("{}.sandbox", path.display()));
+    if let Some(parent) = kept.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(modified.join(path), &kept)?;
+    Ok(kept)
+}
+
+/// Pre-merge the file and let the user's merge tool finish the job.
+///
+/// `{base}`, `{original}`, `{sandbox}` and `{merged}` in the command are
+/// replaced with the respective file paths; the tool edits `{merged}`.
+fn run_merge_tool(
+    tool: &str,
+    state: &OriginalState,
+    path: &Path,
+    original: &Path,
+    modified: &Path,
+) -> std::io::Result<bool> {
+    // Give the tool the unmerged sandbox version alongside the merge result
+    let theirs = keep_sandbox_version(state, path, modified)?;
+    let merged = modified.join(path);
+    merge_into_sandbox(state, path, original, modified)?;
+    
+    let command_line = tool
+        .replace("{base}", &shell_quote(&state.base_dir.path().join(path)))
+        .replace("{original}", &shell_quote(&original.join(path)))
+        .replace("{sandbox}", &shell_quote(&theirs))
+        .replace("{merged}", &shell_quote(&merged));
+    info!("Running merge tool: {}", command_line);
+    Ok(shell_command(&command_line).status()?.success())
+}
@@ -0,0 +1,120 @@
+//! Running the command in a container (`--backend docker[:image]` and
+//! `--backend podman[:image]`).
+//!
+//! The sandbox is filled by copying as usual and bind-mounted at `/sandbox`
+//! in a throwaway container, where the command runs as the current user.
+//! Only the sandbox is shared with the container, so the command gets the
+//! image's toolchain and can't touch the rest of the machine. `--no-network`,
+//! the resource limits and `--env` variables are passed on as container
+//! options.
+
+use std::path::Path;
+use std::process::Command;
+
+use log::{debug, warn};
+
+use crate::child::Limits;
+
+/// Where the sandbox is mounted in the container
+const MOUNT_POINT: &str = "/sandbox";
+const DEFAULT_IMAGE: &str = "debian:stable-slim";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    Docker,
+    Podman,
+}
+
+impl Engine {
+    fn program(self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Container {
+    pub engine: Engine,
+    pub image: String,
+}
+
+impl Container {
+    /// Parse `docker[:image]` or `podman[:image]`
+    pub fn parse(value: &str) -> Option<Self> {
+        let (engine, image) = value.split_once(':').unwrap_or((value, DEFAULT_IMAGE));
+        let engine = match engine {
+            "docker" => Engine::Docker,
+            "podman" => Engine::Podman,
+            _ => return None,
+        };
+        (!image.is_empty()).then(|| Container { engine, image: image.to_string() })
+    }
+    
+    /// A command running `program` in a new container with `sandbox` as its
+    /// working directory; its arguments are added by the caller
+    pub fn command(&self, sandbox: &Path, program: &str, network: bool, limits: Limits, env: &[(String, String)]) -> Command {
+        let mut command = Command::new(self.engine.program());
+        command.args(["run", "--rm", "--init", "--interactive", "--name", &name(sandbox)]);
+        if std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+            command.arg("--tty");
+        }
+        command
+            .arg("--volume")
+            .arg(format!("{}:{}", sandbox.display(), MOUNT_POINT))
+            .args(["--workdir", MOUNT_POINT])
+            .args(["--env", &format!("TUST_SANDBOX={}", MOUNT_POINT)])
+            .args(["--env", "TUST_ORIGINAL_DIR"])
+            .args(["--env", "GIT_CEILING_DIRECTORIES=/"]);
+        for (key, value) in env {
+            command.arg("--env").arg(format!("{}={}", key, value));
+        }
+        self.keep_user(&mut command);
+        if !network {
+            command.args(["--network", "none"]);
+        }
+        if let Some(memory) = limits.memory {
+            command.arg(format!("--memory={}", memory));
+        }
+        if let Some(cpu) = limits.cpu {
+            command.arg(format!("--ulimit=cpu={}", cpu));
+        }
+        if let Some(processes) = limits.processes {
+            command.arg(format!("--pids-limit={}", processes));
+        }
+        command.arg(&self.image).arg(program);
+        debug!("Running the command with {:?}", command);
+        command
+    }
+    
+    /// Remove the container of `sandbox` after its client was killed, which
+    /// leaves the container itself running
+    pub fn kill(&self, sandbox: &Path) {
+        let removed = Command::new(self.engine.program()).args(["rm", "--force", &name(sandbox)]).output();
+        match removed {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => warn!("Failed to remove container: {}", String::from_utf8_lossy(&output.stderr).trim()),
+            Err(e) => warn!("Failed to remove container: {}", e),
+        }
+    }
+    
+    /// Run as the current user, so files the command creates belong to them
+    #[cfg(unix)]
+    fn keep_user(&self, command: &mut Command) {
+        match self.engine {
+            // Rootless podman maps the user into the container itself
+            Engine::Podman => command.arg("--userns=keep-id"),
+            // SAFETY: getuid and getgid have no preconditions
+            Engine::Docker => command.arg(format!("--user={}:{}", unsafe { libc::getuid() }, unsafe { libc::getgid() })),
+        };
+    }
+    
+    #[cfg(not(unix))]
+    fn keep_user(&self, _command: &mut Command) {}
+}
+
+/// The container's name, after the sandbox directory
+fn name(sandbox: &Path) -> String {
+    sandbox.file_name().map_or_else(|| "tust".to_string(), |name| name.to_string_lossy().into_owned())
+}
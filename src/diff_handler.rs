@@ -0,0 +1,84 @@
+//! External diff commands for file types tust can't show meaningfully.
+//!
+//! `--diff-handler EXT=CMD` runs `CMD` for every modified file ending in
+//! `.EXT` and prints its output under the file in the change list, e.g.
+//! `sqlite=sqldiff {old} {new}` for databases. `{old}` and `{new}` are
+//! replaced with the original and sandbox paths; without them, both paths
+//! are appended to the command.
+
+use std::path::Path;
+
+use log::{debug, warn};
+
+use crate::{redact, shell_command, shell_quote};
+
+/// Longest handler output shown per file
+const MAX_LINES: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct DiffHandler {
+    extension: String,
+    command: String,
+}
+
+/// Parse an `EXT=CMD` argument (`*.EXT` and `.EXT` are accepted too)
+pub fn parse(value: &str) -> Result<DiffHandler, String> {
+    let (extension, command) = value.split_once('=').ok_or("expected EXT=CMD")?;
+    let extension = extension.trim().trim_start_matches('*').trim_start_matches('.');
+    if extension.is_empty() || command.trim().is_empty() {
+        return Err("expected EXT=CMD".to_string());
+    }
+    Ok(DiffHandler { extension: extension.to_string(), command: command.to_string() })
+}
+
+/// Handler for `path`, if one was given for its extension
+pub fn find<'a>(handlers: &'a [DiffHandler], path: &Path) -> Option<&'a DiffHandler> {
+    let extension = path.extension()?.to_string_lossy();
+    handlers.iter().find(|handler| handler.extension == extension)
+}
+
+/// Print the handler's output for `old` against `new`, indented under the change
+pub fn print_output(handler: &DiffHandler, old: &Path, new: &Path, indent: &str) {
+    match run(handler, old, new) {
+        Ok(output) => {
+            let lines: Vec<&str> = output.lines().collect();
+            for line in lines.iter().take(MAX_LINES) {
+                println!("{}{}", indent, redact::redact(line));
+            }
+            if lines.len() > MAX_LINES {
+                println!("{}... ({} more lines)", indent, lines.len() - MAX_LINES);
+            }
+        }
+        Err(e) => {
+            warn!("Diff handler for {} failed: {}", new.display(), e);
+            println!("{}(diff handler failed: {})", indent, e);
+        }
+    }
+}
+
+/// `command` with `{old}` and `{new}` replaced with the quoted paths, or
+/// both appended when it has neither
+pub fn expand(command: &str, old: &Path, new: &Path) -> String {
+    if command.contains("{old}") || command.contains("{new}") {
+        command.replace("{old}", &shell_quote(old)).replace("{new}", &shell_quote(new))
+    } else {
+        format!("{} {} {}", command, shell_quote(old), shell_quote(new))
+    }
+}
+
+fn run(handler: &DiffHandler, old: &Path, new: &Path) -> std::io::Result<String> {
+    let command_line = expand(&handler.command, old, new);
+    debug!("Running diff handler: {}", command_line);
+    
+    // Diff tools commonly exit with 1 when the inputs differ, so only
+    // missing output counts as a failure
+    let output = shell_command(&command_line).stdin(std::process::Stdio::null()).output()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    if text.trim().is_empty() {
+        text = String::from_utf8_lossy(&output.stderr).into_owned();
+    }
+    if text.trim().is_empty() && !output.status.success() {
+        return Err(std::io::Error::other(format!("exited with {}", output.status)));
+    }
+    Ok(text)
+}
@@ -0,0 +1,34 @@
+//! External diff viewers (`--difftool`).
+//!
+//! Answering `d` when choosing per change, or pressing `d` in `--tui`, opens
+//! the change's original and sandbox versions in the command given with
+//! `--difftool`, e.g. `meld`, `vimdiff` or `code --wait --diff`. `{old}` and
+//! `{new}` are replaced with the two paths, or appended when absent, like for
+//! `--diff-handler`. A side that doesn't exist, for a created or deleted
+//! file, is an empty file. tust waits for the tool to exit before going on.
+
+use std::path::Path;
+
+use log::info;
+
+use crate::{Change, diff_handler, review, shell_command};
+
+/// Open the versions of the file `change` touches in `tool`; `false` when
+/// the change has no content to compare (a directory rename or metadata)
+pub fn open(tool: &str, change: &Change, original: &Path, modified: &Path) -> std::io::Result<bool> {
+    let Some((old, new)) = review::versions(change, original, modified) else {
+        return Ok(false);
+    };
+    // Kept until the tool exited
+    let empty = tempfile::NamedTempFile::new_in(crate::sandbox_parent(None))?;
+    let command_line = diff_handler::expand(tool, old.as_deref().unwrap_or(empty.path()), new.as_deref().unwrap_or(empty.path()));
+    info!("Running difftool: {}", command_line);
+    let status = shell_command(&command_line).status()?;
+    // Many diff tools exit with 1 when the files differ; a shell that
+    // couldn't find the tool exits with 127
+    if status.code() == Some(127) {
+        let program = tool.split_whitespace().next().unwrap_or(tool);
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} was not found", program)));
+    }
+    Ok(true)
+}
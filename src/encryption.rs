@@ -0,0 +1,134 @@
+//! Encryption at rest of kept sessions, exported patches and undo records
+//! (`--encrypt`).
+//!
+//! Session records, their output logs, `--output-patch` files and the
+//! originals saved for `tust undo` can hold source code and secrets, and
+//! often end up in shared or backed up locations. With `--encrypt` they are
+//! written in the age format, to the age recipients given with
+//! `--encrypt-to` or, when there are none, with a passphrase. Reading tells
+//! encrypted files apart by the age header, so encrypted and plain sessions
+//! can sit side by side. They are decrypted with the identity file named by
+//! `TUST_IDENTITY` or the passphrase, taken from `TUST_PASSPHRASE` or asked
+//! for once on the terminal. A kept sandbox is not covered: the commands run
+//! in it need its files as they are.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use age::secrecy::SecretString;
+use log::debug;
+
+/// First bytes of every age file
+const MAGIC: &[u8] = b"age-encryption.org/";
+
+pub enum Key {
+    Recipients(Vec<age::x25519::Recipient>),
+    Passphrase(SecretString),
+}
+
+impl Key {
+    /// Encrypt to the age recipients `recipients` (`age1...` public keys),
+    /// or with a passphrase when there are none
+    pub fn new(recipients: &[String]) -> std::io::Result<Self> {
+        if recipients.is_empty() {
+            return passphrase(true).map(Key::Passphrase);
+        }
+        recipients
+            .iter()
+            .map(|recipient| {
+                recipient
+                    .parse()
+                    .map_err(|e| std::io::Error::other(format!("{:?} is not an age recipient: {}", recipient, e)))
+            })
+            .collect::<std::io::Result<_>>()
+            .map(Key::Recipients)
+    }
+    
+    fn encrypt(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let encryptor = match self {
+            Key::Recipients(recipients) => {
+                age::Encryptor::with_recipients(recipients.iter().map(|recipient| recipient as &dyn age::Recipient))
+                    .map_err(std::io::Error::other)?
+            }
+            Key::Passphrase(passphrase) => age::Encryptor::with_user_passphrase(passphrase.clone()),
+        };
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(data)?;
+        writer.finish()?;
+        Ok(encrypted)
+    }
+}
+
+/// Write `data` to `path`, encrypted with `key` when given
+pub fn write(path: &Path, data: &[u8], key: Option<&Key>) -> std::io::Result<()> {
+    match key {
+        Some(key) => {
+            debug!("Encrypting {}", path.display());
+            std::fs::write(path, key.encrypt(data)?)
+        }
+        None => std::fs::write(path, data),
+    }
+}
+
+/// Whether the file at `path` was written encrypted
+pub fn is_encrypted(path: &Path) -> bool {
+    let mut start = [0; MAGIC.len()];
+    std::fs::File::open(path).and_then(|mut file| file.read_exact(&mut start)).is_ok() && start == MAGIC
+}
+
+/// Read `path`, decrypting it if it was written encrypted
+pub fn read(path: &Path) -> std::io::Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    if !data.starts_with(MAGIC) {
+        return Ok(data);
+    }
+    
+    debug!("Decrypting {}", path.display());
+    let failed = |e: &dyn std::fmt::Display| std::io::Error::other(format!("failed to decrypt {}: {}", path.display(), e));
+    let decryptor = age::Decryptor::new_buffered(&data[..]).map_err(|e| failed(&e))?;
+    let identities: Vec<Box<dyn age::Identity>> = if decryptor.is_scrypt() {
+        vec![Box::new(age::scrypt::Identity::new(passphrase(false)?))]
+    } else {
+        let file = std::env::var("TUST_IDENTITY")
+            .map_err(|_| failed(&"it is encrypted to age recipients; set TUST_IDENTITY to an age identity file"))?;
+        age::IdentityFile::from_file(file)?.into_identities().map_err(|e| failed(&e))?
+    };
+    
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|identity| identity.as_ref()))
+        .map_err(|e| failed(&e))?;
+    let mut decrypted = Vec::new();
+    reader.read_to_end(&mut decrypted)?;
+    Ok(decrypted)
+}
+
+/// The passphrase from `TUST_PASSPHRASE`, or asked for on the terminal (twice
+/// when `confirm`, before anything is encrypted with it) the first time
+fn passphrase(confirm: bool) -> std::io::Result<SecretString> {
+    static PASSPHRASE: OnceLock<SecretString> = OnceLock::new();
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase.clone());
+    }
+    
+    let passphrase = match std::env::var("TUST_PASSPHRASE") {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let prompt = |prompt: &str| {
+                rpassword::prompt_password(prompt).map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("failed to ask for the passphrase ({}); set TUST_PASSPHRASE", e))
+                })
+            };
+            let passphrase = prompt("Passphrase for tust's encrypted files: ")?;
+            if confirm && prompt("Repeat the passphrase: ")? != passphrase {
+                return Err(std::io::Error::other("the passphrases differ"));
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        return Err(std::io::Error::other("the passphrase is empty"));
+    }
+    Ok(PASSPHRASE.get_or_init(|| SecretString::from(passphrase)).clone())
+}
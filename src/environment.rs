@@ -0,0 +1,71 @@
+//! The command's environment (`--env`, `--env-file` and `--clear-env`).
+//!
+//! The command inherits tust's environment unless `--clear-env` is given,
+//! which keeps only what programs need to start at all. Variables from
+//! `--env-file` files are set next, then those given with `--env`, so later
+//! ones win. tust's own variables (`TUST_SANDBOX` and the like) are set on
+//! top of all of them.
+
+use std::path::Path;
+use std::process::Command;
+
+/// Variables kept by `--clear-env`
+#[cfg(unix)]
+const KEPT: [&str; 4] = ["PATH", "HOME", "TERM", "LANG"];
+#[cfg(windows)]
+const KEPT: [&str; 7] = ["PATH", "PATHEXT", "SYSTEMROOT", "COMSPEC", "TEMP", "TMP", "USERPROFILE"];
+
+/// Parse the value of `--env`: `KEY=VALUE`, or `KEY` to pass on the value
+/// `KEY` has in tust's environment
+pub fn parse(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some(("", _)) => Err(format!("missing variable name in {:?}", value)),
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => match std::env::var(value) {
+            Ok(current) => Ok((value.to_string(), current)),
+            Err(_) => Err(format!("{} is not set; use {}=VALUE", value, value)),
+        },
+    }
+}
+
+/// Read the variables of a dotenv-style file: `KEY=VALUE` lines, optionally
+/// prefixed with `export` and with the value in quotes; blank lines and
+/// lines starting with `#` are skipped
+pub fn read_file(path: &Path) -> std::io::Result<Vec<(String, String)>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut vars = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=').filter(|(key, _)| !key.trim().is_empty()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("line {} is not KEY=VALUE", number + 1),
+            ));
+        };
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')]
+            .into_iter()
+            .find_map(|(open, close)| value.strip_prefix(open)?.strip_suffix(close))
+            .unwrap_or(value);
+        vars.push((key.trim().to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+/// Give `command` the environment: tust's own, or only the variables needed
+/// to start programs when `clear`, and `vars` on top
+pub fn apply(command: &mut Command, clear: bool, vars: &[(String, String)]) {
+    if clear {
+        command.env_clear();
+        for key in KEPT {
+            if let Some(value) = std::env::var_os(key) {
+                command.env(key, value);
+            }
+        }
+    }
+    command.envs(vars.iter().map(|(key, value)| (key, value)));
+}
@@ -0,0 +1,90 @@
+//! Golden-change checks (`--expect`).
+//!
+//! The expected patch is applied to a copy of the original, and that tree is
+//! compared with the sandbox after the command ran. Any difference means the
+//! command did not produce exactly the expected changes; differing text files
+//! are shown as a line diff of expected against actual content.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::info;
+
+use crate::{Change, compare_directories, copy_directory, create_sandbox, exclude_setup_changes, patch, print_change};
+
+/// Longest line diff shown per file
+const MAX_LINES: usize = 50;
+
+/// Check that `sandbox` is `original` with `patch_file` applied, ignoring
+/// paths written by setup commands. Prints the mismatches and returns
+/// whether there were none.
+pub fn check(
+    patch_file: &Path,
+    original: &Path,
+    sandbox: &Path,
+    setup_state: &HashMap<PathBuf, Option<blake3::Hash>>,
+) -> std::io::Result<bool> {
+    let text = fs::read_to_string(patch_file)?;
+    let patches = patch::parse(&text, None)?;
+    
+    let expected_dir = create_sandbox(None)?;
+    let expected = expected_dir.path();
+    copy_directory(original, expected)?;
+    patch::apply(&patches, expected).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("expected patch does not apply to the original: {}", e))
+    })?;
+    
+    let mismatches = exclude_setup_changes(sandbox, compare_directories(expected, sandbox)?, setup_state)?;
+    if mismatches.is_empty() {
+        info!("Changes match {}", patch_file.display());
+        println!("{}", format!("Changes match {}", patch_file.display()).green());
+        return Ok(true);
+    }
+    
+    info!("{} paths differ from {}", mismatches.len(), patch_file.display());
+    println!(
+        "{}",
+        format!("Changes do not match {}; compared with the expected result, the command:", patch_file.display())
+            .red()
+            .bold()
+    );
+    for mismatch in &mismatches {
+        print_change(mismatch);
+        match mismatch {
+            Change::Create(_) => println!("      {}", "created a file the patch does not".dimmed()),
+            Change::Delete(_) => println!("      {}", "is missing a file the patch creates or keeps".dimmed()),
+            Change::Modify(path) => print_line_diff(&expected.join(path), &sandbox.join(path))?,
+            Change::Chmod(_) => println!("      {}", "has different permissions than the patch expects".dimmed()),
+            Change::Xattr(_) => println!("      {}", "has different extended attributes than the patch expects".dimmed()),
+            Change::Rename(..) => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Lines only in the expected file (-) and only in the actual one (+)
+fn print_line_diff(expected: &Path, actual: &Path) -> std::io::Result<()> {
+    let (Ok(expected), Ok(actual)) = (String::from_utf8(fs::read(expected)?), String::from_utf8(fs::read(actual)?))
+    else {
+        println!("      {}", "binary content differs from the expected result".dimmed());
+        return Ok(());
+    };
+    
+    let lines: Vec<String> = diff::lines(&expected, &actual)
+        .into_iter()
+        .filter_map(|result| match result {
+            diff::Result::Left(line) => Some(format!("-{}", line).red().to_string()),
+            diff::Result::Right(line) => Some(format!("+{}", line).green().to_string()),
+            diff::Result::Both(..) => None,
+        })
+        .collect();
+    for line in lines.iter().take(MAX_LINES) {
+        println!("      {}", line);
+    }
+    if lines.len() > MAX_LINES {
+        println!("      ... ({} more lines)", lines.len() - MAX_LINES);
+    }
+    Ok(())
+}
@@ -0,0 +1,372 @@
+//! Paths kept out of the sandbox (`.gitignore`, `.tustignore`, `--exclude`,
+//! `--include`).
+//!
+//! Excluded paths are neither copied into the sandbox nor compared, so they
+//! can't show up as changes and large trees don't slow down the copy.
+//! `DEFAULT_EXCLUDES` covers the usual heavy directories unless
+//! `--no-default-excludes` is given, and tust's own `.tust` directory is
+//! always left out. The patterns use gitignore syntax: `.tustignore` in the
+//! project directory is read like a `.gitignore`, and each `--exclude` adds
+//! one more line. When
+//! `--include` is given, only files matching one of its patterns (or inside
+//! a matching directory) are copied and compared. `--one-file-system`
+//! excludes every mount point below the project the same way.
+//!
+//! Unless `--no-gitignore` is given, whatever git ignores is left out too:
+//! the `.gitignore` files inside the project and in its parents up to the
+//! repository root, `.git/info/exclude` and the user's global excludes file.
+//! They are always read from the project directory, so the sandbox and the
+//! original are filtered the same way even if the command edits them.
+//!
+//! With `--git-tracked`, git's index decides instead what is copied: only
+//! tracked files (and with `--git-untracked`, files that are neither tracked
+//! nor ignored) go into the sandbox, and only those are compared on the
+//! original's side. Anything the command creates is still reviewed.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+
+use ignore::Match;
+use ignore::gitignore::Gitignore;
+use colored::Colorize;
+use log::{info, warn};
+
+use crate::config::Patterns;
+
+pub const IGNORE_FILE: &str = ".tustignore";
+
+/// Version control data, dependencies and build output that commands rarely
+/// need in the sandbox. They come first, so `.tustignore` can re-include
+/// any of them with a `!` line.
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    ".git/",
+    ".hg/",
+    ".svn/",
+    "node_modules/",
+    "target/",
+    ".venv/",
+    "__pycache__/",
+    ".mypy_cache/",
+    ".pytest_cache/",
+    ".tox/",
+    ".gradle/",
+    ".next/",
+    ".cache/",
+];
+
+struct Filter {
+    exclude: Patterns,
+    include: Option<Patterns>,
+    gitignore: Option<GitIgnore>,
+}
+
+/// Everything git would consult to decide whether a project path is ignored
+struct GitIgnore {
+    project: PathBuf,
+    /// `.gitignore` files of directories inside the project, loaded as the
+    /// walks reach them and keyed by relative directory
+    inside: Mutex<HashMap<PathBuf, Option<Gitignore>>>,
+    /// `.gitignore` files of the project's parents up to the repository
+    /// root, then `.git/info/exclude` and the global excludes, in the order
+    /// they are consulted
+    outside: Vec<Gitignore>,
+}
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+
+/// Files from git's index and every directory containing one
+struct Tracked {
+    files: HashSet<PathBuf>,
+    dirs: HashSet<PathBuf>,
+}
+
+static TRACKED: OnceLock<Tracked> = OnceLock::new();
+
+/// `--max-depth` and `--max-file-size`
+struct Limits {
+    project: PathBuf,
+    max_depth: Option<usize>,
+    max_file_size: Option<u64>,
+    /// Paths already warned about, since every walk asks again
+    warned: Mutex<HashSet<PathBuf>>,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Leave out paths matching `exclude`, files not matching `include` if it
+/// isn't empty, and with `gitignore`, whatever git ignores in that project
+/// directory, in all following copies and comparisons
+pub fn set(exclude: &[String], include: &[String], gitignore: Option<&Path>) -> std::io::Result<()> {
+    let filter = Filter {
+        exclude: Patterns::new(exclude)?,
+        include: (!include.is_empty()).then(|| Patterns::new(include)).transpose()?,
+        gitignore: gitignore.map(GitIgnore::new),
+    };
+    let _ = FILTER.set(filter);
+    Ok(())
+}
+
+/// Lines of the `.tustignore` in `dir`, if there is one
+pub fn read_ignore_file(dir: &Path) -> std::io::Result<Vec<String>> {
+    match fs::read_to_string(dir.join(IGNORE_FILE)) {
+        Ok(text) => {
+            info!("Excluding the paths listed in {}", IGNORE_FILE);
+            Ok(text.lines().map(str::to_string).collect())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `path` (relative to the project) is left out
+pub fn is_excluded(path: &Path, is_dir: bool) -> bool {
+    let Some(filter) = FILTER.get() else {
+        return false;
+    };
+    if filter.exclude.matches_entry(path, is_dir)
+        || filter.gitignore.as_ref().is_some_and(|gitignore| gitignore.is_ignored(path, is_dir))
+    {
+        return true;
+    }
+    // Directories are always entered, since files inside may be included
+    if !is_dir && filter.include.as_ref().is_some_and(|include| !include.matches_entry(path, false)) {
+        return true;
+    }
+    exceeds_limits(path, is_dir)
+}
+
+/// Leave out directories more than `max_depth` levels below `project` and
+/// files in it larger than `max_file_size`, with a warning
+pub fn set_limits(project: &Path, max_depth: Option<usize>, max_file_size: Option<u64>) {
+    let limits = Limits { project: project.to_path_buf(), max_depth, max_file_size, warned: Mutex::new(HashSet::new()) };
+    let _ = LIMITS.set(limits);
+}
+
+/// Whether `path` is too deep or too large. Sizes are those in the project,
+/// so the sandbox and the original leave out the same files, and files the
+/// command creates are always reviewed.
+fn exceeds_limits(path: &Path, is_dir: bool) -> bool {
+    let Some(limits) = LIMITS.get() else {
+        return false;
+    };
+    let depth = path.components().count();
+    let reason = if limits.max_depth.is_some_and(|max| if is_dir { depth > max } else { depth > max + 1 }) {
+        "it is nested deeper than --max-depth".to_string()
+    } else if let Some(max) = limits.max_file_size
+        && !is_dir
+        && let Ok(metadata) = fs::symlink_metadata(limits.project.join(path))
+        && metadata.len() > max
+    {
+        format!("it is larger than --max-file-size ({})", crate::format_size(metadata.len()))
+    } else {
+        return false;
+    };
+    
+    if limits.warned.lock().unwrap_or_else(|e| e.into_inner()).insert(path.to_path_buf()) {
+        warn!("Leaving {} out of the sandbox: {}", path.display(), reason);
+        eprintln!("{}", format!("Warning: leaving {} out of the sandbox, {}", path.display(), reason).yellow());
+    }
+    true
+}
+
+impl GitIgnore {
+    fn new(project: &Path) -> Self {
+        let mut outside = Vec::new();
+        let mut repository = None;
+        for dir in project.ancestors() {
+            if dir != project {
+                outside.extend(read_gitignore(&dir.join(".gitignore")));
+            }
+            if dir.join(".git").exists() {
+                repository = Some(dir);
+                break;
+            }
+        }
+        // Outside a repository, only the project's own .gitignore files count
+        if repository.is_none() {
+            outside.clear();
+        }
+        if let Some(repository) = repository {
+            outside.extend(read_gitignore(&repository.join(".git").join("info").join("exclude")));
+            let (global, error) = Gitignore::global();
+            if let Some(e) = error {
+                warn!("Failed to read the global git excludes: {}", e);
+            }
+            outside.push(global);
+        }
+        
+        GitIgnore { project: project.to_path_buf(), inside: Mutex::new(HashMap::new()), outside }
+    }
+    
+    /// Whether git ignores `path`; the walks never enter ignored directories,
+    /// so only the path itself needs to be checked
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let full_path = self.project.join(path);
+        let mut inside = self.inside.lock().unwrap_or_else(|e| e.into_inner());
+        
+        // The closest .gitignore with a matching pattern decides
+        for dir in path.ancestors().skip(1) {
+            let gitignore = inside
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| read_gitignore(&self.project.join(dir).join(".gitignore")));
+            if let Some(decided) = gitignore.as_ref().and_then(|gitignore| decision(gitignore, &full_path, is_dir)) {
+                return decided;
+            }
+        }
+        self.outside.iter().find_map(|gitignore| decision(gitignore, &full_path, is_dir)).unwrap_or(false)
+    }
+}
+
+fn read_gitignore(path: &Path) -> Option<Gitignore> {
+    if !path.is_file() {
+        return None;
+    }
+    let (gitignore, error) = Gitignore::new(path);
+    if let Some(e) = error {
+        warn!("Problem reading {}: {}", path.display(), e);
+    }
+    Some(gitignore)
+}
+
+/// Whether `gitignore` ignores (`Some(true)`) or re-includes (`Some(false)`)
+/// `path`, or `None` if none of its patterns match
+fn decision(gitignore: &Gitignore, path: &Path, is_dir: bool) -> Option<bool> {
+    match gitignore.matched(path, is_dir) {
+        Match::None => None,
+        Match::Ignore(_) => Some(true),
+        Match::Whitelist(_) => Some(false),
+    }
+}
+
+/// Files git knows about in `dir`, relative to it: the tracked ones and, with
+/// `untracked`, those that are neither tracked nor ignored
+pub fn git_files(dir: &Path, untracked: bool) -> std::io::Result<Vec<PathBuf>> {
+    let mut command = Command::new("git");
+    command.current_dir(dir).args(["ls-files", "-z", "--cached"]);
+    if untracked {
+        command.args(["--others", "--exclude-standard"]);
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "git ls-files failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    
+    // git separates components with / on every platform
+    Ok(output
+        .stdout
+        .split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).split('/').collect())
+        .collect())
+}
+
+/// Only copy `files` into the sandbox, and only compare them on the
+/// original's side
+pub fn set_tracked(files: Vec<PathBuf>) {
+    info!("Copying only the {} files known to git", files.len());
+    let dirs = files.iter().flat_map(|file| file.ancestors().skip(1).map(Path::to_path_buf)).collect();
+    let _ = TRACKED.set(Tracked { files: files.into_iter().collect(), dirs });
+}
+
+/// Whether `--git-tracked` leaves `path` out of the sandbox
+pub fn is_untracked(path: &Path, is_dir: bool) -> bool {
+    let Some(tracked) = TRACKED.get() else {
+        return false;
+    };
+    if is_dir { !tracked.dirs.contains(path) } else { !tracked.files.contains(path) }
+}
+
+/// Patterns excluding each file system mounted below `dir`, for
+/// `--one-file-system`
+pub fn mount_excludes(dir: &Path) -> std::io::Result<Vec<String>> {
+    let dir = dir.canonicalize()?;
+    let mut points: Vec<PathBuf> = mount_points()?
+        .into_iter()
+        .filter_map(|point| point.strip_prefix(&dir).ok().map(Path::to_path_buf))
+        .filter(|relative| !relative.as_os_str().is_empty())
+        .collect();
+    points.sort();
+    points.dedup();
+    
+    let mut patterns = Vec::with_capacity(points.len());
+    for point in points {
+        let Some(name) = point.to_str() else {
+            warn!("Can't exclude mount point {} with a non-UTF-8 name", point.display());
+            continue;
+        };
+        info!("Not crossing into the file system mounted at {}", name);
+        patterns.push(format!("/{}/", escape_pattern(&name.replace('\\', "/"))));
+    }
+    Ok(patterns)
+}
+
+/// `text` with the characters gitignore patterns give a meaning escaped
+fn escape_pattern(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '?' | '[' | ']' | '{' | '}' | '!' | '#' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Where every file system is mounted, from `/proc/self/mountinfo`
+#[cfg(target_os = "linux")]
+fn mount_points() -> std::io::Result<Vec<PathBuf>> {
+    let info = fs::read_to_string("/proc/self/mountinfo")?;
+    // The mount point is the fifth field, with spaces and the like octal-escaped
+    Ok(info.lines().filter_map(|line| line.split(' ').nth(4)).map(|point| PathBuf::from(unescape_octal(point))).collect())
+}
+
+#[cfg(target_os = "linux")]
+fn unescape_octal(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let code = tail.get(..3).and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match code {
+            Some(code) if byte == b'\\' => {
+                bytes.push(code);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(target_os = "macos")]
+fn mount_points() -> std::io::Result<Vec<PathBuf>> {
+    let mut mounts: *mut libc::statfs = std::ptr::null_mut();
+    // SAFETY: getmntinfo points `mounts` at `count` entries it owns, which
+    // are only read before the next call
+    let count = unsafe { libc::getmntinfo(&mut mounts, libc::MNT_NOWAIT) };
+    if count <= 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mounts = unsafe { std::slice::from_raw_parts(mounts, count as usize) };
+    Ok(mounts
+        .iter()
+        // SAFETY: the kernel NUL-terminates the mount point
+        .map(|mount| unsafe { std::ffi::CStr::from_ptr(mount.f_mntonname.as_ptr()) })
+        .map(|point| PathBuf::from(point.to_string_lossy().into_owned()))
+        .collect())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn mount_points() -> std::io::Result<Vec<PathBuf>> {
+    // Volumes mounted into folders on Windows are junctions, which are kept
+    // as links rather than walked
+    Ok(Vec::new())
+}
@@ -0,0 +1,78 @@
+//! Hard-linked files.
+//!
+//! Files that are hard links to each other are copied once and linked again
+//! in the copy, both when filling the sandbox and when applying files the
+//! command created as links to each other. Trees that share data through
+//! hard links, like pnpm's store or other content-addressed caches, then take
+//! no more space in the sandbox than in the project.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::reflink;
+
+/// The copies made so far of files with more than one link, by inode
+#[derive(Default)]
+pub struct Links(HashMap<(u64, u64), PathBuf>);
+
+impl Links {
+    /// The recorded copy of a file that `path` is a hard link to
+    pub fn get(&self, path: &Path) -> Option<&Path> {
+        self.0.get(&key(path)?).map(PathBuf::as_path)
+    }
+    
+    /// Remember `copy` as the copy of `path`, if `path` has other links
+    pub fn record(&mut self, path: &Path, copy: &Path) {
+        if let Some(key) = key(path) {
+            self.0.entry(key).or_insert_with(|| copy.to_path_buf());
+        }
+    }
+}
+
+/// Source and destination of a file to copy
+type Pair = (PathBuf, PathBuf);
+
+/// Split `(from, to)` pairs into the files to copy and, for the other links
+/// to an inode, the `(first copy, to)` pairs to link once those are copied
+pub fn split(files: Vec<Pair>) -> (Vec<Pair>, Vec<Pair>) {
+    let mut links = Links::default();
+    let (mut copies, mut linked) = (Vec::with_capacity(files.len()), Vec::new());
+    for (from, to) in files {
+        match links.get(&from) {
+            Some(existing) => linked.push((existing.to_path_buf(), to)),
+            None => {
+                links.record(&from, &to);
+                copies.push((from, to));
+            }
+        }
+    }
+    debug!("{} files are hard links to files copied before them", linked.len());
+    (copies, linked)
+}
+
+/// Link `to` to the already copied `existing`, copying it where that fails
+pub fn create(existing: &Path, to: &Path) -> std::io::Result<()> {
+    if let Err(e) = fs::hard_link(existing, to) {
+        debug!("Failed to link {} to {}, copying it instead: {}", to.display(), existing.display(), e);
+        reflink::copy(existing, to)?;
+    }
+    Ok(())
+}
+
+/// Device and inode of a regular file with more than one link
+#[cfg(unix)]
+fn key(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    
+    let metadata = fs::symlink_metadata(path).ok()?;
+    (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn key(_path: &Path) -> Option<(u64, u64)> {
+    // File indices need an open handle on Windows; links are copied there
+    None
+}
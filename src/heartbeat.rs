@@ -0,0 +1,114 @@
+//! Single-line progress indicator for long-running commands.
+//!
+//! With `--heartbeat`, the command's output is captured instead of being
+//! passed through, and a spinner with the elapsed time and the latest output
+//! line is redrawn in place on stderr. When the command fails, the tail of
+//! its output is printed so the failure still has context.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, IsTerminal, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+use tokio::sync::mpsc;
+
+use crate::child::{self, ResourceUsage};
+use crate::output::Log;
+
+const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+const TAIL_LINES: usize = 20;
+const MAX_LINE_WIDTH: usize = 60;
+
+/// Capture the output of `command` for the heartbeat line instead of
+/// passing it through
+pub fn capture_output(command: &mut Command) {
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+}
+
+/// Wait for `process`, spawned after `capture_output`, while showing the
+/// heartbeat line
+pub async fn run(mut process: Child, log: Option<Log>) -> std::io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    if let Some(stdout) = process.stdout.take() {
+        forward_lines(stdout, sender.clone(), log.clone());
+    }
+    if let Some(stderr) = process.stderr.take() {
+        forward_lines(stderr, sender.clone(), log);
+    }
+    drop(sender);
+    
+    let waiter = tokio::task::spawn_blocking(move || child::wait(process));
+    tokio::pin!(waiter);
+    
+    let draw = std::io::stderr().is_terminal();
+    let start = Instant::now();
+    let mut tail: VecDeque<String> = VecDeque::with_capacity(TAIL_LINES);
+    let mut ticker = tokio::time::interval(Duration::from_millis(100));
+    let mut frame = 0;
+    
+    let result = loop {
+        tokio::select! {
+            result = &mut waiter => break result.map_err(std::io::Error::other)??,
+            Some(line) = receiver.recv() => push_line(&mut tail, line),
+            _ = ticker.tick() => {
+                if draw {
+                    frame = (frame + 1) % FRAMES.len();
+                    let last = tail.back().map(String::as_str).unwrap_or_default();
+                    eprint!("\r\x1b[2K{} {:>6.1}s  {}", FRAMES[frame].to_string().yellow(), start.elapsed().as_secs_f64(), last.dimmed());
+                    let _ = std::io::stderr().flush();
+                }
+            }
+        }
+    };
+    
+    // Collect whatever the command wrote right before exiting, without
+    // waiting on background processes that may still hold the pipes open
+    let _ = tokio::time::timeout(Duration::from_millis(200), async {
+        while let Some(line) = receiver.recv().await {
+            push_line(&mut tail, line);
+        }
+    })
+    .await;
+    
+    if draw {
+        eprint!("\r\x1b[2K");
+    }
+    
+    if !result.0.success() && !tail.is_empty() {
+        eprintln!("{}", "Last lines of output:".yellow());
+        for line in &tail {
+            eprintln!("  {}", line);
+        }
+    }
+    
+    Ok(result)
+}
+
+fn forward_lines(stream: impl std::io::Read + Send + 'static, sender: mpsc::UnboundedSender<String>, log: Option<Log>) {
+    std::thread::spawn(move || {
+        for line in std::io::BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if let Some(log) = &log {
+                log.write(format!("{}\n", line).as_bytes());
+            }
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+fn push_line(tail: &mut VecDeque<String>, line: String) {
+    // Keep only printable characters so the line can be redrawn in place
+    let line: String = crate::redact::redact(&line).chars().filter(|c| !c.is_control()).take(MAX_LINE_WIDTH).collect();
+    if line.trim().is_empty() {
+        return;
+    }
+    if tail.len() == TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line);
+}
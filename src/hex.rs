@@ -0,0 +1,84 @@
+//! Hex preview of changes to binary files (`--hex`, or `x` when choosing per
+//! change).
+//!
+//! The two versions are compared 16 bytes at a time, and the rows that
+//! differ are shown in the style of `hexdump -C`, the old row above the new
+//! one, each region after a row of context. Only files up to
+//! `MAX_SIZE` are previewed, and only the first `MAX_ROWS` differing rows,
+//! which covers small edits like a changed icon or a patched lockfile.
+
+use std::fs;
+use std::path::Path;
+
+use colored::Colorize;
+
+/// Bytes per row
+const WIDTH: usize = 16;
+
+/// Largest file that is previewed
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// Differing rows shown before the rest is only counted
+const MAX_ROWS: usize = 32;
+
+/// The hex preview of a file changing from `old` to `new` (`None` where it
+/// doesn't exist), as lines to print
+pub fn preview(old: Option<&Path>, new: Option<&Path>) -> Vec<String> {
+    let read = |path: Option<&Path>| -> Option<Vec<u8>> {
+        match path {
+            None => Some(Vec::new()),
+            Some(path) if fs::metadata(path).ok()?.len() <= MAX_SIZE => fs::read(path).ok(),
+            Some(_) => None,
+        }
+    };
+    let (Some(old), Some(new)) = (read(old), read(new)) else {
+        return vec![format!("(larger than {}, no hex preview)", crate::format_size(MAX_SIZE))];
+    };
+    
+    let rows = old.len().max(new.len()).div_ceil(WIDTH);
+    let row = |data: &[u8], index: usize| data.get(index * WIDTH..data.len().min((index + 1) * WIDTH)).unwrap_or_default().to_vec();
+    let differing: Vec<usize> = (0..rows).filter(|&index| row(&old, index) != row(&new, index)).collect();
+    if differing.is_empty() {
+        return vec!["(same content)".to_string()];
+    }
+    
+    let mut lines = Vec::new();
+    let mut last_shown = None;
+    for &index in differing.iter().take(MAX_ROWS) {
+        let context = index.saturating_sub(1);
+        if last_shown.is_some_and(|last| last + 1 < context) || (last_shown.is_none() && context > 0) {
+            lines.push("...".dimmed().to_string());
+        }
+        if context < index && last_shown.is_none_or(|last| last < context) {
+            lines.push(format!(" {}", format_row(context, &row(&new, context))).dimmed().to_string());
+        }
+        let (old_row, new_row) = (row(&old, index), row(&new, index));
+        if !old_row.is_empty() {
+            lines.push(format!("-{}", format_row(index, &old_row)).red().to_string());
+        }
+        if !new_row.is_empty() {
+            lines.push(format!("+{}", format_row(index, &new_row)).green().to_string());
+        }
+        last_shown = Some(index);
+    }
+    if differing.len() > MAX_ROWS {
+        lines.push(format!("... ({} more differing rows)", differing.len() - MAX_ROWS).dimmed().to_string());
+    }
+    lines
+}
+
+/// One row at row `index` in the style of `hexdump -C`
+fn format_row(index: usize, bytes: &[u8]) -> String {
+    let mut hex = String::new();
+    for position in 0..WIDTH {
+        match bytes.get(position) {
+            Some(byte) => hex.push_str(&format!("{:02x} ", byte)),
+            None => hex.push_str("   "),
+        }
+        if position == WIDTH / 2 - 1 {
+            hex.push(' ');
+        }
+    }
+    let text: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+    format!("{:08x}  {} |{}|", index * WIDTH, hex, text)
+}
@@ -0,0 +1,128 @@
+//! Syntax highlighting of diffs (`--diff`, `--diff-theme`).
+//!
+//! Diffs of files whose language syntect's bundled syntaxes recognize, by
+//! name or first line, are highlighted in the theme given with `--diff-theme`
+//! or `diff-theme` in `[defaults]`: one of the bundled themes or the path of
+//! a `.tmTheme` file, with `none` turning highlighting off. Both versions of
+//! a file are highlighted as a whole, so strings and comments spanning lines
+//! come out right in the hunks, and added and removed lines get a tinted
+//! background instead of a colored text, a stronger one for the words that
+//! changed (see `intraline`). Nothing is highlighted when colors
+//! are off, and files larger than `MAX_SIZE` stay plain.
+
+use std::ops::Range;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Style, Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+
+/// A highlighted line, as pieces of text in their style
+pub type Line = Vec<(Style, String)>;
+
+/// Largest file that is highlighted
+const MAX_SIZE: usize = 512 * 1024;
+
+/// The theme in use, `None` when highlighting is off
+static THEME: OnceLock<Option<Theme>> = OnceLock::new();
+static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+
+/// Background colors of added and removed lines and of the words that
+/// changed in them
+pub struct Tints {
+    pub added: &'static str,
+    pub added_emphasis: &'static str,
+    pub removed: &'static str,
+    pub removed_emphasis: &'static str,
+}
+
+/// Highlight in the theme `name` from now on (`none` for no highlighting)
+pub fn set_theme(name: &str) -> Result<(), String> {
+    let theme = if name == "none" { None } else { Some(load_theme(name)?) };
+    let _ = THEME.set(theme);
+    Ok(())
+}
+
+fn load_theme(name: &str) -> Result<Theme, String> {
+    let path = Path::new(name);
+    if path.extension().is_some_and(|extension| extension == "tmTheme") {
+        return ThemeSet::get_theme(path).map_err(|e| format!("{}: {}", name, e));
+    }
+    let mut themes = ThemeSet::load_defaults().themes;
+    let names = themes.keys().cloned().collect::<Vec<_>>().join(", ");
+    themes.remove(name).ok_or_else(|| format!("unknown theme {:?}; the bundled themes are {}", name, names))
+}
+
+fn theme() -> Option<&'static Theme> {
+    THEME.get_or_init(|| load_theme(DEFAULT_THEME).ok()).as_ref()
+}
+
+/// The lines of `text`, the content of a file named like `path`,
+/// highlighted and without line endings; `None` when they are not
+/// highlighted
+pub fn lines(path: &Path, text: &str) -> Option<Vec<Line>> {
+    if !colored::control::SHOULD_COLORIZE.should_colorize() || text.len() > MAX_SIZE {
+        return None;
+    }
+    let theme = theme()?;
+    let syntaxes = SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines);
+    let syntax = path
+        .extension()
+        .and_then(|extension| syntaxes.find_syntax_by_extension(&extension.to_string_lossy()))
+        .or_else(|| syntaxes.find_syntax_by_extension(&path.file_name()?.to_string_lossy()))
+        .or_else(|| syntaxes.find_syntax_by_first_line(text.lines().next()?))?;
+    if syntax.name == "Plain Text" {
+        return None;
+    }
+    
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    LinesWithEndings::from(text)
+        .map(|line| {
+            let ranges = highlighter.highlight_line(line, syntaxes).ok()?;
+            Some(ranges.into_iter().map(|(style, text)| (style, text.trim_end_matches(['\n', '\r']).to_string())).collect())
+        })
+        .collect()
+}
+
+/// `line` as terminal output on the background `tint`, with the byte ranges
+/// `emphasis` on `emphasis_tint`
+pub fn paint(line: &Line, tint: &str, emphasis_tint: &str, emphasis: &[Range<usize>]) -> String {
+    let mut painted = String::new();
+    let mut offset = 0;
+    for (style, text) in line {
+        let color = style.foreground;
+        for (piece, emphasized) in crate::intraline::split(text, offset, emphasis) {
+            let background = if emphasized { emphasis_tint } else { tint };
+            painted.push_str(&format!("\x1b[38;2;{};{};{}m{}{}", color.r, color.g, color.b, background, piece));
+        }
+        offset += text.len();
+    }
+    painted.push_str("\x1b[0m");
+    painted
+}
+
+/// Backgrounds for added and removed lines that suit the theme
+pub fn tints() -> Tints {
+    let light = theme()
+        .and_then(|theme| theme.settings.background)
+        .is_some_and(|color| u32::from(color.r) + u32::from(color.g) + u32::from(color.b) > 3 * 128);
+    if light {
+        Tints {
+            added: "\x1b[48;2;220;255;220m",
+            added_emphasis: "\x1b[48;2;160;240;160m",
+            removed: "\x1b[48;2;255;225;225m",
+            removed_emphasis: "\x1b[48;2;255;170;170m",
+        }
+    } else {
+        Tints {
+            added: "\x1b[48;2;0;60;0m",
+            added_emphasis: "\x1b[48;2;0;115;0m",
+            removed: "\x1b[48;2;75;0;0m",
+            removed_emphasis: "\x1b[48;2;140;0;0m",
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Commands run at points of a run (`[hooks]` and `.tust/hooks`).
+//!
+//! `pre-copy` runs before the sandbox is filled, `post-run` once the command
+//! exited, and `pre-apply` and `post-apply` around applying its changes. A
+//! hook is the shell command of that name in the configuration's `[hooks]`
+//! or, failing that, an executable of that name in the project's
+//! `.tust/hooks` directory, once the project is trusted (see `trust`). Hooks run in the project directory and learn about
+//! the run from `TUST_*` variables. A failing `pre-` hook stops tust before it
+//! goes on, a failing `post-` hook is only reported.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use colored::Colorize;
+use log::{info, warn};
+
+use crate::{config, redact};
+
+/// Where in a run a hook runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    PreCopy,
+    PostRun,
+    PreApply,
+    PostApply,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreCopy => "pre-copy",
+            Hook::PostRun => "post-run",
+            Hook::PreApply => "pre-apply",
+            Hook::PostApply => "post-apply",
+        }
+    }
+    
+    fn configured(self, hooks: &config::Hooks) -> Option<&str> {
+        match self {
+            Hook::PreCopy => hooks.pre_copy.as_deref(),
+            Hook::PostRun => hooks.post_run.as_deref(),
+            Hook::PreApply => hooks.pre_apply.as_deref(),
+            Hook::PostApply => hooks.post_apply.as_deref(),
+        }
+    }
+    
+    fn is_pre(self) -> bool {
+        matches!(self, Hook::PreCopy | Hook::PreApply)
+    }
+}
+
+/// The run hooks are told about
+pub struct Run<'a> {
+    pub hooks: &'a config::Hooks,
+    pub original: &'a Path,
+    pub sandbox: &'a Path,
+    pub command: &'a [String],
+    /// Set once the command exited
+    pub exit_code: Option<i32>,
+    /// Whether the project's hook executables are trusted, once looked up
+    pub trusted: std::cell::OnceCell<bool>,
+}
+
+impl Run<'_> {
+    /// Run `hook`, if there is one, telling it the number of `changes` when
+    /// known; fails when a `pre-` hook fails, a failing `post-` hook is only
+    /// reported
+    pub fn hook(&self, hook: Hook, changes: Option<usize>, machine_output: bool) -> std::io::Result<()> {
+        let mut command = match (hook.configured(self.hooks), self.executable(hook)) {
+            (Some(line), _) => {
+                info!("Running {} hook: {}", hook.name(), crate::redact::redact(line));
+                crate::shell_command(line)
+            }
+            (None, Some(path)) => {
+                info!("Running {} hook {}", hook.name(), path.display());
+                Command::new(path)
+            }
+            (None, None) => return Ok(()),
+        };
+        command
+            .current_dir(self.original)
+            .env("TUST_HOOK", hook.name())
+            .env("TUST_SANDBOX", self.sandbox)
+            .env("TUST_ORIGINAL_DIR", self.original)
+            .env("TUST_COMMAND", redact::redact(&self.command.join(" ")).as_ref())
+            .stdin(Stdio::null());
+        if let Some(code) = self.exit_code {
+            command.env("TUST_EXIT_CODE", code.to_string());
+        }
+        if let Some(changes) = changes {
+            command.env("TUST_CHANGES", changes.to_string());
+        }
+        if machine_output {
+            command.stdout(std::io::stderr());
+        }
+        
+        let result = match command.status() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(std::io::Error::other(format!("the {} hook failed ({})", hook.name(), status))),
+            Err(e) => Err(std::io::Error::new(e.kind(), format!("failed to run the {} hook: {}", hook.name(), e))),
+        };
+        match result {
+            Err(e) if !hook.is_pre() => {
+                warn!("{}", e);
+                eprintln!("{}", format!("Warning: {}", e).yellow());
+                Ok(())
+            }
+            result => result,
+        }
+    }
+    
+    /// The executable for `hook` in the project's `.tust/hooks`, if there is
+    /// one and the project is trusted
+    fn executable(&self, hook: Hook) -> Option<PathBuf> {
+        let path = crate::trust::hooks_dir(self.original).join(hook.name());
+        let metadata = path.metadata().ok().filter(|metadata| metadata.is_file())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            
+            if metadata.permissions().mode() & 0o111 == 0 {
+                warn!("Ignoring {}, which is not executable", path.display());
+                return None;
+            }
+        }
+        #[cfg(windows)]
+        let _ = metadata;
+        
+        let trusted = *self.trusted.get_or_init(|| {
+            let trusted = crate::trust::is_trusted(self.original).unwrap_or(false);
+            if !trusted {
+                let message = format!(
+                    "ignoring the hooks in {}, which are not trusted; check them and run `tust trust`",
+                    crate::trust::hooks_dir(self.original).display()
+                );
+                warn!("{}", message);
+                eprintln!("{}", format!("Warning: {}", message).yellow());
+            }
+            trusted
+        });
+        trusted.then_some(path)
+    }
+}
@@ -0,0 +1,125 @@
+//! Change inspectors (`--inspector`).
+//!
+//! An inspector is an executable `tust-NAME` on the `PATH`. Before the
+//! changes are shown, it runs in the project directory with the change set
+//! as a manifest (see `manifest`) on stdin and `TUST_SANDBOX` and
+//! `TUST_ORIGINAL_DIR` set, and answers with a JSON object on stdout, where
+//! every field is optional:
+//!
+//! ```json
+//! {
+//!   "notes": [{"path": "src/new.rs", "message": "no license header"}],
+//!   "veto": [{"path": "CODEOWNERS", "reason": "owned by the platform team"}],
+//!   "block": "reason to apply none of the changes",
+//!   "rewrote": true
+//! }
+//! ```
+//!
+//! Notes are shown with the changes, vetoed changes are left out like those
+//! to protected paths, and a block refuses all of them. An inspector that
+//! edits files in the sandbox says so with `rewrote`, and the changes are
+//! found again once all inspectors ran. Empty output means nothing to say; an
+//! inspector that fails or answers with something else stops tust.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::{debug, info};
+use serde::Deserialize;
+
+use crate::Change;
+
+/// An inspector's answer
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Answer {
+    notes: Vec<Note>,
+    veto: Vec<Veto>,
+    block: Option<String>,
+    rewrote: bool,
+}
+
+#[derive(Deserialize)]
+struct Note {
+    path: PathBuf,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Veto {
+    path: PathBuf,
+    reason: String,
+}
+
+/// What the inspectors said, each entry with the name of its inspector
+#[derive(Default)]
+pub struct Report {
+    pub notes: Vec<(String, PathBuf, String)>,
+    pub vetoes: Vec<(String, PathBuf, String)>,
+    pub blocks: Vec<(String, String)>,
+    /// Whether an inspector changed the sandbox
+    pub rewrote: bool,
+}
+
+impl Report {
+    /// Whether `change` touches a path an inspector vetoed
+    pub fn vetoes(&self, change: &Change) -> bool {
+        let paths = match change {
+            Change::Create(path) | Change::Modify(path) | Change::Delete(path) | Change::Chmod(path) | Change::Xattr(path) => {
+                [path, path]
+            }
+            Change::Rename(from, to) => [from, to],
+        };
+        self.vetoes.iter().any(|(_, vetoed, _)| paths.contains(&vetoed))
+    }
+}
+
+/// Run the inspectors `names` on `changes` between `original` and `sandbox`
+pub fn run(names: &[String], original: &Path, sandbox: &Path, changes: &[Change]) -> std::io::Result<Report> {
+    let manifest = crate::manifest::to_json(original, sandbox, changes)?;
+    let mut report = Report::default();
+    for name in names {
+        let answer = ask(name, original, sandbox, &manifest)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("inspector {}: {}", name, e)))?;
+        debug!("Inspector {}: {} notes, {} vetoes", name, answer.notes.len(), answer.veto.len());
+        report.notes.extend(answer.notes.into_iter().map(|note| (name.clone(), note.path, note.message)));
+        report.vetoes.extend(answer.veto.into_iter().map(|veto| (name.clone(), veto.path, veto.reason)));
+        report.blocks.extend(answer.block.map(|reason| (name.clone(), reason)));
+        report.rewrote |= answer.rewrote;
+    }
+    Ok(report)
+}
+
+fn ask(name: &str, original: &Path, sandbox: &Path, manifest: &[u8]) -> std::io::Result<Answer> {
+    let program = format!("tust-{}", name);
+    info!("Running inspector {}", program);
+    let mut process = Command::new(&program)
+        .current_dir(original)
+        .env("TUST_SANDBOX", sandbox)
+        .env("TUST_ORIGINAL_DIR", original)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => std::io::Error::new(e.kind(), format!("{} is not on the PATH", program)),
+            _ => e,
+        })?;
+    // Written from a thread, so a large answer can't block the input; an
+    // inspector may not read all of it, which is fine
+    let mut stdin = process.stdin.take().expect("stdin is piped");
+    let manifest = manifest.to_vec();
+    let writer = std::thread::spawn(move || {
+        let _ = stdin.write_all(&manifest);
+    });
+    let output = process.wait_with_output()?;
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("{} failed ({})", program, output.status)));
+    }
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Answer::default());
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid answer: {}", e)))
+}
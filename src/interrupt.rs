@@ -0,0 +1,230 @@
+//! Ctrl-C and termination signals while tust works in a sandbox.
+//!
+//! By default SIGINT, SIGTERM and SIGHUP end tust on the spot: the command
+//! keeps running as an orphan and the sandbox is left behind, half compared.
+//! Instead, while the command runs, the signal is passed on to it and tust
+//! waits for it to exit; otherwise it is only recorded. Either way tust stops
+//! at its next checkpoint, where the sandbox is removed before exiting. A
+//! second signal outside the command ends tust right away.
+//!
+//! A Ctrl-C the command catches and survives, as an interactive shell does,
+//! doesn't stop tust. On Windows, where a Ctrl-C reaches every process on the
+//! console, there is nothing to pass on.
+
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use colored::Colorize;
+use log::warn;
+
+/// Signal numbers used where the platform has no signals
+#[cfg(windows)]
+const SIGINT: i32 = 2;
+
+/// The first signal received, or 0
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+/// Whether it came from the terminal, which sends it to the command as well
+static FROM_TERMINAL: AtomicBool = AtomicBool::new(false);
+/// Process ID of the running command to pass signals on to, or 0; negative
+/// for a process group of its own
+static COMMAND: AtomicI32 = AtomicI32::new(0);
+
+/// A signal that stops tust
+#[derive(Clone, Copy, Debug)]
+pub struct Interrupt {
+    pub signal: i32,
+    pub from_terminal: bool,
+}
+
+impl Interrupt {
+    /// Exit code of a process ended by the signal, as shells report it
+    pub fn exit_code(self) -> i32 {
+        128 + self.signal
+    }
+}
+
+/// The signal received since `install`, if any
+pub fn received() -> Option<Interrupt> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(Interrupt { signal, from_terminal: FROM_TERMINAL.load(Ordering::SeqCst) }),
+    }
+}
+
+/// Fail with `ErrorKind::Interrupted` once a signal was received, to cut
+/// long operations short
+pub fn check() -> std::io::Result<()> {
+    match received() {
+        Some(_) => Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "interrupted")),
+        None => Ok(()),
+    }
+}
+
+/// Pass signals on to the just spawned command with process ID `pid` until
+/// `stop_forwarding`
+pub fn forward_to(pid: u32) {
+    #[cfg(unix)]
+    let pid = {
+        let pid = pid as libc::pid_t;
+        // SAFETY: getpgid and getpgrp have no preconditions
+        if unsafe { libc::getpgid(pid) == pid && libc::getpgrp() != pid } { -pid } else { pid }
+    };
+    #[cfg(windows)]
+    let pid = pid as i32;
+    COMMAND.store(pid, Ordering::SeqCst);
+}
+
+/// Stop passing signals on once the command exited with `status`. A signal
+/// from the terminal the command survived is forgotten.
+pub fn stop_forwarding(status: Option<ExitStatus>) {
+    COMMAND.store(0, Ordering::SeqCst);
+    if let Some(interrupt) = received()
+        && interrupt.from_terminal
+        && status.is_some_and(|status| !killed_by(status, interrupt.signal))
+    {
+        log::debug!("The command handled signal {}, carrying on", interrupt.signal);
+        RECEIVED.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Report the interrupt and exit with the code a shell expects; the caller
+/// removes the sandbox first
+pub fn exit(interrupt: Interrupt) -> ! {
+    warn!("Interrupted by signal {}", interrupt.signal);
+    eprintln!("{}", "Interrupted".yellow());
+    std::process::exit(interrupt.exit_code());
+}
+
+#[cfg(unix)]
+fn killed_by(status: ExitStatus, signal: i32) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    
+    status.signal() == Some(signal) || status.code() == Some(128 + signal)
+}
+
+#[cfg(windows)]
+fn killed_by(status: ExitStatus, _signal: i32) -> bool {
+    const STATUS_CONTROL_C_EXIT: u32 = 0xC000_013A;
+    
+    status.code() == Some(STATUS_CONTROL_C_EXIT as i32)
+}
+
+#[cfg(unix)]
+const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Handle the signals from here on, unless tust was started with them ignored
+#[cfg(unix)]
+pub fn install() {
+    for signal in SIGNALS {
+        // SAFETY: `action` is fully initialized before it is used, and
+        // `handle` only calls async-signal-safe functions
+        unsafe {
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signal, std::ptr::null(), &mut previous) != 0 || previous.sa_sigaction == libc::SIG_IGN {
+                continue;
+            }
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Leave the signals to their default action again, for prompts that should
+/// end on Ctrl-C
+#[cfg(unix)]
+pub fn uninstall() {
+    for signal in SIGNALS {
+        // SAFETY: only replaces tust's own handler; ignored signals stay ignored
+        unsafe {
+            let mut current: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signal, std::ptr::null(), &mut current) == 0 && current.sa_sigaction != libc::SIG_IGN {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn handle(signal: libc::c_int, info: *mut libc::siginfo_t, _context: *mut libc::c_void) {
+    // SAFETY: the kernel passes a valid siginfo with SA_SIGINFO
+    let from_terminal = !sent_by_process(unsafe { (*info).si_code });
+    let first = RECEIVED.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst).is_ok();
+    if first {
+        FROM_TERMINAL.store(from_terminal, Ordering::SeqCst);
+    }
+    let command = COMMAND.load(Ordering::SeqCst);
+    // A signal from the terminal reached the command already, unless it
+    // has a process group of its own
+    if (command > 0 && !from_terminal) || command < 0 {
+        // SAFETY: kill is async-signal-safe
+        unsafe { libc::kill(command, signal) };
+    } else if command == 0 && !first {
+        // SAFETY: signal and raise are async-signal-safe; the default
+        // action ends tust
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    } else if command == 0 {
+        let message = b"\nInterrupted, stopping (again to quit right away)\n";
+        // SAFETY: write is async-signal-safe, and `message` is valid
+        unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+    }
+}
+
+/// Whether a signal with code `code` was sent with `kill` rather than by the
+/// kernel, as the terminal sends them
+#[cfg(target_os = "linux")]
+fn sent_by_process(code: libc::c_int) -> bool {
+    // SI_USER, SI_QUEUE, SI_TKILL and the like are zero or below
+    code <= 0
+}
+
+#[cfg(target_os = "macos")]
+fn sent_by_process(code: libc::c_int) -> bool {
+    code == libc::SI_USER || code == libc::SI_QUEUE
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn sent_by_process(_code: libc::c_int) -> bool {
+    true
+}
+
+#[cfg(windows)]
+pub fn install() {
+    use windows_sys::Win32::System::Console::SetConsoleCtrlHandler;
+    
+    // SAFETY: `handle` is a valid handler for the whole life of the process
+    unsafe { SetConsoleCtrlHandler(Some(handle), 1) };
+}
+
+#[cfg(windows)]
+pub fn uninstall() {
+    use windows_sys::Win32::System::Console::SetConsoleCtrlHandler;
+    
+    // SAFETY: removes the handler added by `install`
+    unsafe { SetConsoleCtrlHandler(Some(handle), 0) };
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn handle(event: u32) -> windows_sys::core::BOOL {
+    use windows_sys::Win32::System::Console::{CTRL_BREAK_EVENT, CTRL_C_EVENT};
+    
+    if event != CTRL_C_EVENT && event != CTRL_BREAK_EVENT {
+        return 0;
+    }
+    let first = RECEIVED.compare_exchange(0, SIGINT, Ordering::SeqCst, Ordering::SeqCst).is_ok();
+    if first {
+        FROM_TERMINAL.store(true, Ordering::SeqCst);
+        if COMMAND.load(Ordering::SeqCst) == 0 {
+            // Handlers run on a thread of their own, so this may print
+            eprintln!("{}", "\nInterrupted, stopping (again to quit right away)".yellow());
+        }
+    }
+    // The command got the Ctrl-C as well; outside it, a second one falls
+    // through to the default handler, which ends tust
+    (first || COMMAND.load(Ordering::SeqCst) != 0).into()
+}
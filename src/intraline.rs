@@ -0,0 +1,126 @@
+//! Word-level highlighting within changed lines (`--diff`).
+//!
+//! In a hunk, each run of removed lines followed by added lines is paired up
+//! line by line, and the two lines of a pair are compared word by word, like
+//! `git diff --word-diff` does. The words that differ are emphasized, so a
+//! one-token change in a long generated line stands out. Pairs that share
+//! less than half of their text are left alone, since emphasizing nearly
+//! everything says nothing.
+
+use std::ops::Range;
+
+/// Most words in a line that is compared word by word
+const MAX_WORDS: usize = 1000;
+
+/// Byte ranges of a line
+pub type Ranges = Vec<Range<usize>>;
+
+/// For each of the `lines` of a hunk, given as their marker (`' '`, `'-'` or
+/// `'+'`) and text, the byte ranges of the text to emphasize
+pub fn emphasis(lines: &[(char, &str)]) -> Vec<Ranges> {
+    let mut emphasis = vec![Vec::new(); lines.len()];
+    let run = |start: usize, marker: char| start..start + lines[start..].iter().take_while(|(m, _)| *m == marker).count();
+    let mut index = 0;
+    while index < lines.len() {
+        let removed = run(index, '-');
+        let added = run(removed.end, '+');
+        if !removed.is_empty() {
+            for (old, new) in removed.zip(added.clone()) {
+                if let Some((old_ranges, new_ranges)) = changed(lines[old].1, lines[new].1) {
+                    emphasis[old] = old_ranges;
+                    emphasis[new] = new_ranges;
+                }
+            }
+        }
+        index = added.end.max(index + 1);
+    }
+    emphasis
+}
+
+/// The pieces of `text`, which starts at byte `offset` of its line, each with
+/// whether it lies within one of the sorted `ranges` of the line
+pub fn split<'a>(text: &'a str, offset: usize, ranges: &[Range<usize>]) -> Vec<(&'a str, bool)> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let position = offset + start;
+        let (end, emphasized) = match ranges.iter().find(|range| range.end > position) {
+            Some(range) if range.start <= position => (range.end - offset, true),
+            Some(range) => (range.start - offset, false),
+            None => (text.len(), false),
+        };
+        let end = end.min(text.len());
+        pieces.push((&text[start..end], emphasized));
+        start = end;
+    }
+    pieces
+}
+
+/// The ranges of the words of `old` and `new` that differ, or `None` when the
+/// lines have too little in common
+fn changed(old: &str, new: &str) -> Option<(Ranges, Ranges)> {
+    let (old_words, new_words) = (words(old), words(new));
+    if old_words.len() > MAX_WORDS || new_words.len() > MAX_WORDS {
+        return None;
+    }
+    let old_texts: Vec<&str> = old_words.iter().map(|word| &old[word.clone()]).collect();
+    let new_texts: Vec<&str> = new_words.iter().map(|word| &new[word.clone()]).collect();
+    
+    let (mut old_ranges, mut new_ranges) = (Vec::new(), Vec::new());
+    let (mut old_index, mut new_index, mut shared) = (0, 0, 0);
+    for result in diff::slice(&old_texts, &new_texts) {
+        match result {
+            diff::Result::Both(word, _) => {
+                shared += word.len();
+                old_index += 1;
+                new_index += 1;
+            }
+            diff::Result::Left(_) => {
+                push(&mut old_ranges, old_words[old_index].clone());
+                old_index += 1;
+            }
+            diff::Result::Right(_) => {
+                push(&mut new_ranges, new_words[new_index].clone());
+                new_index += 1;
+            }
+        }
+    }
+    (shared * 2 >= old.len().max(new.len())).then_some((old_ranges, new_ranges))
+}
+
+/// Add `range` to `ranges`, joining it to the last one when they touch
+fn push(ranges: &mut Ranges, range: Range<usize>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+/// The words of `text`: runs of letters, digits and underscores, runs of
+/// whitespace, and every other character on its own
+fn words(text: &str) -> Vec<Range<usize>> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            1
+        } else if c.is_whitespace() {
+            2
+        } else {
+            0
+        }
+    };
+    let mut words = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        if class(c) != 0 {
+            while let Some(&(position, next)) = chars.peek()
+                && class(next) == class(c)
+            {
+                end = position + next.len_utf8();
+                chars.next();
+            }
+        }
+        words.push(start..end);
+    }
+    words
+}
@@ -1,35 +1,1007 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use colored::Colorize;
 use log::{debug, error, info, warn};
-use env_logger;
-use tempfile;
+use rayon::prelude::*;
+
+mod audit;
+mod backend;
+mod cache;
+mod baseline;
+mod bwrap;
+mod child;
+mod config;
+mod conflict;
+mod container;
+mod diff_handler;
+mod difftool;
+mod encryption;
+mod environment;
+mod expect;
+mod filter;
+mod hardlink;
+mod heartbeat;
+mod hex;
+mod highlight;
+mod hooks;
+mod inspector;
+mod interrupt;
+mod intraline;
+mod manifest;
+mod matrix;
+mod notify;
+mod merge;
+mod output;
+mod pager;
+mod patch;
+mod progress;
+mod pty;
+mod quota;
+mod redact;
+mod reflink;
+mod review;
+mod seatbelt;
+mod session;
+mod shell_init;
+mod signature;
+mod snapshot;
+mod space;
+mod special;
+mod symlink;
+mod throttle;
+mod trust;
+mod tui;
+mod undo;
+mod verify;
+mod xattrs;
+
+/// Exit code of `--check` when the command changed something, distinct from
+/// errors (1) and usage errors (2)
+const CHECK_FAILED_EXIT_CODE: i32 = 3;
+
+/// Exit code when the command ran longer than `--timeout`, as with
+/// coreutils' `timeout`
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Separates the commands of a chain run one after the other in the same
+/// sandbox (`tust -- cmd1 ++ cmd2`)
+const CHAIN_SEPARATOR: &str = "++";
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, disable_help_subcommand = true)]
 struct Args {
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+    
     #[arg(long, short, help = "Clean up all tust temporary directories")]
     clean: bool,
     
+    #[arg(long, help = "Pass tust's stdin through to the command (confirmation is read from the terminal)")]
+    stdin_passthrough: bool,
+    
+    #[arg(
+        long,
+        value_name = "FILE|-",
+        conflicts_with = "stdin_passthrough",
+        help = "Feed FILE to the command's stdin, or tust's stdin with - (like --stdin-passthrough)"
+    )]
+    stdin: Option<PathBuf>,
+    
+    #[arg(
+        long,
+        value_name = "FD",
+        num_args = 0..=1,
+        require_equals = true,
+        help = "Print sandbox begin/end markers with its path to stdout, or to file descriptor FD"
+    )]
+    print_sandbox: Option<Option<i32>>,
+    
+    #[arg(long, value_name = "ID", help = "Seed the sandbox from a baseline created with `tust baseline`")]
+    from_baseline: Option<String>,
+    
+    #[arg(long, help = "Capture the command's output and show a spinner with elapsed time and its latest line instead")]
+    heartbeat: bool,
+    
+    #[arg(long, conflicts_with_all = ["heartbeat", "tty"], help = "Don't show the command's output; the last lines are still shown if it fails")]
+    quiet_command: bool,
+    
+    #[arg(long, help = "Show a desktop notification with the exit status and number of changes once the command finished")]
+    notify: bool,
+    
+    #[arg(long, value_name = "URL", help = "Also post the outcome as JSON to URL (with curl); implies --notify")]
+    notify_webhook: Option<String>,
+    
+    #[arg(long, value_name = "RATE", value_parser = parse_size, help = "Limit tust's own copy/compare I/O to RATE bytes per second (e.g. 20M)")]
+    bwlimit: Option<u64>,
+    
+    #[arg(long, help = "Run tust and the command in the idle I/O scheduling class (Linux)")]
+    nice_io: bool,
+    
+    #[arg(
+        long,
+        value_name = "N",
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        help = "Run the command with niceness N (-20 to 19; mapped to a priority class on Windows)"
+    )]
+    nice: Option<i32>,
+    
+    #[arg(
+        long,
+        conflicts_with = "heartbeat",
+        help = "Run the command in a pseudo-terminal, so it shows progress bars, prompts and colors even when tust's output is redirected (Unix)"
+    )]
+    tty: bool,
+    
+    #[arg(long, help = "Run the command without network access (Linux and macOS)")]
+    no_network: bool,
+    
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = environment::parse,
+        help = "Set an environment variable for the command; KEY alone passes on its current value (repeatable)"
+    )]
+    env: Vec<(String, String)>,
+    
+    #[arg(long, value_name = "FILE", help = "Set the variables of a KEY=VALUE file for the command (repeatable)")]
+    env_file: Vec<PathBuf>,
+    
+    #[arg(long, help = "Don't pass tust's environment on to the command, except PATH, HOME, TERM and LANG")]
+    clear_env: bool,
+    
+    #[arg(
+        long,
+        value_name = "KEY=V1,V2",
+        value_parser = matrix::parse,
+        conflicts_with_all = ["yes", "patch", "tui", "check", "expect", "output_patch", "format"],
+        help = "Run the command once per value of the variable, in separate sandboxes, and compare the changes (repeatable: every combination)"
+    )]
+    matrix: Vec<matrix::Axis>,
+    
+    /// Set when tust runs itself for one variant of the matrix
+    #[arg(long, hide = true)]
+    no_matrix: bool,
+    
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, help = "Limit the memory each process of the command may map to SIZE (e.g. 4G; Unix)")]
+    max_memory: Option<u64>,
+    
+    #[arg(long, value_name = "SECONDS", help = "Kill processes of the command that use more than SECONDS of CPU time (Unix)")]
+    max_cpu: Option<u64>,
+    
+    #[arg(long, value_name = "N", help = "Limit the processes your user may run at once while the command runs (Unix)")]
+    max_procs: Option<u64>,
+    
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        help = "Kill the command if it runs longer than DURATION (e.g. 90s, 5m, 1h)"
+    )]
+    timeout: Option<Duration>,
+    
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 1..,
+        conflicts_with = "expect",
+        help = "Only copy these files or directories into the sandbox and review changes to them (end the list with --)"
+    )]
+    files: Vec<PathBuf>,
+    
+    #[arg(long, value_name = "GLOB", help = "Don't copy or compare paths matching GLOB (gitignore syntax, like .tustignore; repeatable)")]
+    exclude: Vec<String>,
+    
+    #[arg(long, value_name = "GLOB", help = "Only copy and compare files matching GLOB (gitignore syntax; repeatable)")]
+    include: Vec<String>,
+    
+    #[arg(long, help = "Also copy and compare paths ignored by git")]
+    no_gitignore: bool,
+    
+    #[arg(long, help = "Also copy and compare .git, node_modules, target and other heavy directories")]
+    no_default_excludes: bool,
+    
+    #[arg(long, help = "Only copy the files in git's index into the sandbox, which is faster in large repositories")]
+    git_tracked: bool,
+    
+    #[arg(long, requires = "git_tracked", help = "With --git-tracked, also copy files that are neither tracked nor ignored")]
+    git_untracked: bool,
+    
+    #[arg(long, value_name = "CMD", help = "Shell command to prepare the sandbox before the main command; its changes are not reviewed (repeatable)")]
+    setup: Vec<String>,
+    
+    #[arg(long, value_name = "CMD", help = "Shell command run against the result (e.g. `cargo test`); changes are only offered if it succeeds")]
+    validate: Option<String>,
+    
+    #[arg(long, requires = "validate", help = "Offer to apply the changes even if validation fails")]
+    force: bool,
+    
+    #[arg(long, value_name = "CMD", help = "Open a change in CMD when answering d per change or pressing d in --tui (e.g. 'meld {old} {new}'); {old} and {new} are replaced with the original and sandbox paths, or appended when absent")]
+    difftool: Option<String>,
+    
+    #[arg(long, value_name = "CMD", help = "Merge tool for files changed in the original since review; {base}, {original}, {sandbox} and {merged} are replaced with file paths")]
+    merge_tool: Option<String>,
+    
+    #[arg(long, value_name = "SECONDS", default_value_t = 10, help = "How long to retry applying files that are locked by another process (Windows)")]
+    lock_timeout: u64,
+    
+    #[arg(long, value_name = "PATCH", help = "Check that the command makes exactly the changes in PATCH and exit non-zero if not, instead of offering to apply")]
+    expect: Option<PathBuf>,
+    
+    #[arg(long, value_enum, default_value_t = manifest::Format::Human, help = "How to print the changes; json and ndjson print them to stdout with hashes and sizes, without asking to apply")]
+    format: manifest::Format,
+    
+    #[arg(long, value_name = "PATH", help = "Write a JSON manifest of the changes with hashes, sizes and modes before and after to PATH")]
+    manifest: Option<PathBuf>,
+    
+    #[arg(long, value_name = "FILE", help = "Write the changes to FILE as a unified diff for `git apply` or `patch -p1`")]
+    output_patch: Option<PathBuf>,
+    
+    #[arg(long, help = "Write a detached signature next to the --output-patch file and the --keep session record (FILE.asc with gpg, or FILE.minisig with a minisign --sign-key)")]
+    sign: bool,
+    
+    #[arg(long, value_name = "KEY", help = "Key to --sign with: a minisign secret key file, or a gpg key id (default: gpg's default key)")]
+    sign_key: Option<String>,
+    
+    #[arg(long, help = "Encrypt the --keep session record and output, the --output-patch file and the files saved for undo with age, with a passphrase unless --encrypt-to is given; kept sandboxes stay plain")]
+    encrypt: bool,
+    
+    #[arg(long, value_name = "RECIPIENT", help = "Age public key to --encrypt to instead of using a passphrase (repeatable; implies --encrypt)")]
+    encrypt_to: Vec<String>,
+    
+    #[arg(long, help = "Show a unified diff of every changed text file in the review")]
+    diff: bool,
+    
+    #[arg(long, help = "Show the changed bytes of modified binary files as a hex dump in the review")]
+    hex: bool,
+    
+    #[arg(long, help = "Print the change list directly instead of through $TUST_PAGER, $PAGER or less")]
+    no_pager: bool,
+    
+    #[arg(long, value_name = "NAME", help = "Highlight diffs in the bundled theme NAME or the .tmTheme file NAME; none turns highlighting off")]
+    diff_theme: Option<String>,
+    
+    #[arg(long, help = "List every file of a new directory instead of one line with its number of files and size")]
+    expand: bool,
+    
+    #[arg(long, value_enum, value_name = "KIND", help = "Only show changes of this kind in the review and when choosing per change; the others are still applied (repeatable)")]
+    only: Vec<review::Kind>,
+    
+    #[arg(long, value_name = "GLOB", help = "Only show changes to paths matching GLOB (gitignore syntax) in the review and when choosing per change; the others are still applied (repeatable)")]
+    show: Vec<String>,
+    
+    #[arg(long, help = "Choose the changes to apply one by one, and individual hunks of modified text files")]
+    patch: bool,
+    
+    #[arg(long, conflicts_with = "patch", help = "Review the changes on a full-screen view with their diffs and choose which to apply")]
+    tui: bool,
+    
+    #[arg(long, short, conflicts_with_all = ["no", "patch", "tui"], help = "Apply the changes without asking")]
+    yes: bool,
+    
+    #[arg(long, conflicts_with_all = ["patch", "tui"], help = "Only show the changes and exit without applying them")]
+    no: bool,
+    
+    #[arg(
+        long,
+        conflicts_with_all = ["yes", "no", "patch", "tui", "expect"],
+        help = "Show the changes without asking and exit with code 3 if there are any (for CI)"
+    )]
+    check: bool,
+    
+    #[arg(long, value_name = "EXT=CMD", value_parser = diff_handler::parse, help = "Show the output of CMD for modified files ending in .EXT; {old} and {new} are replaced with the file paths (repeatable)")]
+    diff_handler: Vec<diff_handler::DiffHandler>,
+    
+    #[arg(long, value_name = "REGEX", help = "Mask matches (or just their capture groups) in printed and logged commands and in the output shown (repeatable)")]
+    redact: Vec<regex::Regex>,
+    
+    #[arg(long, value_name = "PATH", help = "Build a copy of the current directory with the changes applied at PATH")]
+    preview_dir: Option<PathBuf>,
+    
+    #[arg(long, help = "Keep the sandbox after tust exits and record the run as a session")]
+    keep: bool,
+    
+    #[arg(long, value_name = "DIR", help = "Create the sandbox in DIR (default: $TUST_TMPDIR, then the system temp directory); on the project's filesystem, files are cloned instead of copied where supported")]
+    temp_dir: Option<PathBuf>,
+    
+    #[arg(
+        long,
+        conflicts_with_all = ["backend", "from_baseline"],
+        help = "Hard-link files into the sandbox instead of copying them; faster, but a command that writes a file in place also changes it in the current directory"
+    )]
+    link: bool,
+    
+    #[arg(
+        long,
+        value_name = "BACKEND",
+        value_parser = backend::parse,
+        default_value = "copy",
+        help = "How to create the sandbox: copy the files, or snapshot the project's btrfs subvolume, ZFS dataset or APFS directory (auto picks one where available); docker[:image] and podman[:image] copy the files and run the command in a container, bwrap under bubblewrap (Linux) and sandbox-exec under a profile that only allows writes to the sandbox (macOS)"
+    )]
+    backend: backend::Backend,
+    
+    #[arg(
+        long,
+        conflicts_with_all = ["files", "from_baseline", "backend", "link", "temp_dir", "keep"],
+        help = "Reuse the sandbox of the previous run from .tust/cache, copying only files that changed since"
+    )]
+    cache: bool,
+    
+    #[arg(long, help = "Compare the contents of every file, also those whose size and modification time are unchanged")]
+    paranoid: bool,
+    
+    #[arg(long, help = "Copy and compare what symlinks point to instead of the links themselves")]
+    follow_symlinks: bool,
+    
+    #[arg(long, short = 'x', help = "Don't copy or compare file systems mounted inside the current directory (bind mounts, network shares)")]
+    one_file_system: bool,
+    
+    #[arg(long, value_name = "N", help = "Leave directories nested more than N levels deep out of the sandbox")]
+    max_depth: Option<usize>,
+    
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, help = "Leave files larger than SIZE (e.g. 500M) out of the sandbox")]
+    max_file_size: Option<u64>,
+    
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "Kill the command if it writes more than SIZE (e.g. 2G) to the sandbox"
+    )]
+    max_sandbox_size: Option<u64>,
+    
+    #[arg(long, help = "Don't save the files the changes replace for `tust undo`")]
+    no_undo: bool,
+    
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        default_value = "1G",
+        help = "Save at most SIZE of replaced files for `tust undo` per apply; larger ones can't be undone"
+    )]
+    max_undo_size: u64,
+    
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Run the executable tust-NAME on the changes before they are shown; it can annotate, veto or rewrite them, or refuse them all (repeatable)"
+    )]
+    inspector: Vec<String>,
+    
+    #[arg(long, value_name = "NAME", help = "Apply the [profiles.NAME] section of the global and project configuration")]
+    profile: Option<String>,
+    
+    #[arg(
+        long,
+        short,
+        help = "Run the command line through your shell ($SHELL -c, cmd /C on Windows), so pipelines, globs, && and variables work"
+    )]
+    shell: bool,
+    
     #[arg(trailing_var_arg = true)]
     command: Vec<String>,
 }
 
+/// Fill in options from the `[defaults]` of the configuration. List options
+/// are combined with the command line; single values given on the command
+/// line win.
+fn apply_config_defaults(args: &mut Args, matches: &ArgMatches, defaults: config::Defaults) {
+    fn invalid(e: String) -> ! {
+        exit_with_error("Invalid default in configuration", e)
+    }
+    
+    args.heartbeat |= defaults.heartbeat.unwrap_or_default();
+    args.diff |= defaults.diff.unwrap_or_default();
+    args.keep |= defaults.keep.unwrap_or_default();
+    args.nice_io |= defaults.nice_io.unwrap_or_default();
+    args.notify |= defaults.notify.unwrap_or_default();
+    if args.notify_webhook.is_none() {
+        args.notify_webhook = defaults.notify_webhook;
+    }
+    // Asking for any kind of review on the command line overrides a default --yes
+    if defaults.yes.unwrap_or_default() && !(args.no || args.check || args.patch || args.tui || args.expect.is_some()) {
+        args.yes = true;
+    }
+    args.setup.splice(0..0, defaults.setup);
+    if args.validate.is_none() {
+        args.validate = defaults.validate;
+    }
+    if args.merge_tool.is_none() {
+        args.merge_tool = defaults.merge_tool;
+    }
+    if args.difftool.is_none() {
+        args.difftool = defaults.difftool;
+    }
+    if args.sign_key.is_none() {
+        args.sign_key = defaults.sign_key;
+    }
+    args.encrypt |= defaults.encrypt.unwrap_or_default();
+    args.encrypt_to.extend(defaults.encrypt_to);
+    if args.diff_theme.is_none() {
+        args.diff_theme = defaults.diff_theme;
+    }
+    if let Some(lock_timeout) = defaults.lock_timeout
+        && matches.value_source("lock_timeout") == Some(ValueSource::DefaultValue)
+    {
+        args.lock_timeout = lock_timeout;
+    }
+    args.no_undo |= defaults.no_undo.unwrap_or_default();
+    if let Some(max_undo_size) = defaults.max_undo_size
+        && matches.value_source("max_undo_size") == Some(ValueSource::DefaultValue)
+    {
+        args.max_undo_size = parse_size(&max_undo_size).unwrap_or_else(|e| invalid(e));
+    }
+    for pattern in defaults.redact {
+        args.redact.push(regex::Regex::new(&pattern).unwrap_or_else(|e| invalid(e.to_string())));
+    }
+    for handler in defaults.diff_handler {
+        args.diff_handler.push(diff_handler::parse(&handler).unwrap_or_else(|e| invalid(e)));
+    }
+    for axis in defaults.matrix {
+        args.matrix.push(matrix::parse(&axis).unwrap_or_else(|e| invalid(e)));
+    }
+    args.inspector.extend(defaults.inspector);
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Copy the current directory into a shared baseline and print its id
+    Baseline,
+    
+    /// Record a snapshot of the current directory and print its id
+    Snapshot {
+        #[arg(long, help = "Also store the content of the files, not just their hashes")]
+        content: bool,
+        
+        #[arg(long, help = "Keep taking content snapshots periodically until interrupted")]
+        watch: bool,
+        
+        #[arg(long, value_name = "SECONDS", default_value_t = 300, help = "Time between snapshots in --watch mode")]
+        interval: u64,
+        
+        #[arg(long, help = "List stored snapshots of the current directory")]
+        list: bool,
+    },
+    
+    /// Show what changed in the current directory since a snapshot
+    DiffSince {
+        #[arg(value_name = "SNAPSHOT_ID")]
+        id: String,
+    },
+    
+    /// Compare two arbitrary directories
+    Diff {
+        #[arg(value_name = "OLD_DIR")]
+        old: PathBuf,
+        
+        #[arg(value_name = "NEW_DIR")]
+        new: PathBuf,
+        
+        #[arg(long, help = "Show a unified diff of every changed text file")]
+        diff: bool,
+        
+        #[arg(long, value_name = "EXT=CMD", value_parser = diff_handler::parse, help = "Show the output of CMD for modified files ending in .EXT (repeatable)")]
+        diff_handler: Vec<diff_handler::DiffHandler>,
+        
+        #[arg(long, value_enum, default_value_t = manifest::Format::Human, help = "How to print the differences; json and ndjson print them with hashes and sizes")]
+        format: manifest::Format,
+        
+        #[arg(long, value_name = "FILE", help = "Write the differences to FILE as a unified diff for `git apply` or `patch -p1`")]
+        output_patch: Option<PathBuf>,
+        
+        #[arg(long, help = "Print the differences directly instead of through $TUST_PAGER, $PAGER or less")]
+        no_pager: bool,
+    },
+    
+    /// Apply a patch written by --output-patch (or any unified diff), like `patch apply`
+    Apply(PatchArgs),
+    
+    /// Work with unified diff patches
+    Patch {
+        #[command(subcommand)]
+        action: PatchCommand,
+    },
+    
+    /// Check whether a kept session or a patch still applies to its directory, without changing anything
+    Verify {
+        #[arg(value_name = "SESSION_ID|PATCH")]
+        target: String,
+        
+        #[arg(long, short = 'p', value_name = "N", help = "Strip N leading path components of a patch (default: strip git-style a/ and b/ prefixes)")]
+        strip: Option<usize>,
+    },
+    
+    /// Review and restore the current directory to the state of a snapshot
+    Rollback {
+        #[arg(value_name = "SNAPSHOT_ID")]
+        id: String,
+    },
+    
+    /// Review and undo a change set tust applied, by default the latest one
+    Undo {
+        #[arg(value_name = "ID")]
+        id: Option<String>,
+        
+        #[arg(long, conflicts_with = "id", help = "List the applied change sets that can be undone")]
+        list: bool,
+    },
+    
+    /// Let the project's .tust.toml and .tust/hooks apply without asking and run hooks
+    Trust {
+        #[arg(long, help = "Stop trusting the project's configuration")]
+        revoke: bool,
+    },
+    
+    /// List, inspect and delete sandboxes kept with --keep
+    Sessions {
+        #[command(subcommand)]
+        action: SessionsCommand,
+    },
+    
+    /// Run the command of a kept session again in a fresh sandbox
+    Replay {
+        #[arg(value_name = "SESSION_ID")]
+        id: String,
+        
+        #[command(flatten)]
+        trust: TrustArgs,
+    },
+    
+    /// Run another command in the sandbox of a kept session and review what its commands changed together
+    Exec {
+        #[arg(value_name = "SESSION_ID")]
+        id: String,
+        
+        #[command(flatten)]
+        trust: TrustArgs,
+        
+        #[arg(trailing_var_arg = true, required = true)]
+        command: Vec<String>,
+    },
+    
+    /// Run two commands in sandboxes from the same baseline and compare their changes: ab -- CMD_A -- CMD_B
+    Ab {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        commands: Vec<String>,
+    },
+    
+    /// Inspect the log of applied changes
+    Audit {
+        #[command(subcommand)]
+        action: AuditCommand,
+    },
+    
+    /// Start an interactive shell in the sandbox and review what changed once it exits
+    Shell,
+    
+    /// Print shell integration code (eval it from your shell's rc file)
+    ShellInit {
+        #[arg(value_enum)]
+        shell: shell_init::Shell,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PatchCommand {
+    /// Preview a unified diff against the current directory and apply it after confirmation
+    Apply(PatchArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct PatchArgs {
+    #[arg(value_name = "PATCH", help = "Patch file, or - to read it from stdin")]
+    file: PathBuf,
+    
+    #[arg(long, short = 'p', value_name = "N", help = "Strip N leading path components (default: strip git-style a/ and b/ prefixes)")]
+    strip: Option<usize>,
+    
+    #[command(flatten)]
+    trust: TrustArgs,
+}
+
+/// Signature checks of patches and kept sessions
+#[derive(clap::Args, Debug)]
+struct TrustArgs {
+    #[arg(long, help = "Refuse patches and sessions without a signature from a trusted key")]
+    strict: bool,
+    
+    #[arg(long, value_name = "FILE", help = "Public key (minisign or gpg) trusted to sign patches and sessions (repeatable)")]
+    trusted_key: Vec<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum SessionsCommand {
+    /// List the kept sessions
+    List,
+    
+    /// Show the command, timestamps, exit code and changes of a session
+    Show {
+        #[arg(value_name = "SESSION_ID")]
+        id: String,
+    },
+    
+    /// Print the sandbox path of a session, by default the latest one whose sandbox still exists
+    Path {
+        #[arg(value_name = "SESSION_ID")]
+        id: Option<String>,
+    },
+    
+    /// Delete a session and its sandbox
+    Rm {
+        #[arg(value_name = "SESSION_ID")]
+        id: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuditCommand {
+    /// Check that no entry of the audit log was altered or removed
+    Verify,
+}
+
 #[tokio::main]
 async fn main() {
     // Initialize the logger
     env_logger::init();
     
-    let args = Args::parse();
+    // Keep the matches to tell flags given on the command line from defaults
+    let mut matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    // The exit code of an interactive shell is that of whatever ran last
+    let mut interactive = false;
+    
+    // `tust exec` continues a kept session: the command runs in its sandbox,
+    // with the options the session was started with
+    let mut resumed = None;
+    if let Some(Commands::Exec { id, trust, command }) = &args.subcommand {
+        let session = session::open(id).unwrap_or_else(|e| exit_with_error("Failed to open session", e));
+        // The record holds the options the command runs with
+        let record = session::record_path(id).unwrap_or_else(|e| exit_with_error("Failed to open session", e));
+        check_signature(Some(&record), trust, "Session", &format!("continue session {}", id));
+        if !session.sandbox.is_dir() {
+            exit_with_error("Failed to open session", format!("its sandbox {} was deleted", session.sandbox.display()));
+        }
+        if let Err(e) = std::env::set_current_dir(&session.directory) {
+            exit_with_error(&format!("Failed to enter {}", session.directory.display()), e);
+        }
+        let (id, command) = (id.clone(), command.clone());
+        matches = Args::command()
+            .try_get_matches_from(std::iter::once("tust".to_string()).chain(session.args.iter().cloned()))
+            .unwrap_or_else(|e| exit_with_error("Failed to read the options of the session", e));
+        args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        // Setup commands already ran in the sandbox, and its input was for
+        // the first command
+        args.subcommand = None;
+        args.command = command;
+        args.setup.clear();
+        args.stdin = None;
+        args.cache = false;
+        args.keep = true;
+        resumed = Some((id, session));
+    }
+    
+    match args.subcommand {
+        Some(Commands::Baseline) => {
+            let current_dir = std::env::current_dir()
+                .unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+            match baseline::create(&current_dir) {
+                Ok(baseline) => {
+                    info!("Created baseline {}", baseline.id);
+                    println!("{}", baseline.id);
+                }
+                Err(e) => exit_with_error("Failed to create baseline", e),
+            }
+            return;
+        }
+        Some(Commands::Snapshot { content, watch, interval, list }) => {
+            let current_dir = std::env::current_dir()
+                .unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+            if list {
+                if let Err(e) = snapshot::list(Some(&current_dir)) {
+                    exit_with_error("Failed to list snapshots", e);
+                }
+                return;
+            }
+            if watch {
+                println!("{}", format!("Taking a snapshot every {} seconds, press Ctrl-C to stop", interval).yellow());
+                if let Err(e) = snapshot::watch(&current_dir, std::time::Duration::from_secs(interval.max(1))).await {
+                    exit_with_error("Failed to take snapshot", e);
+                }
+                return;
+            }
+            match snapshot::create(&current_dir, content) {
+                Ok(snapshot) => {
+                    info!("Created snapshot {}", snapshot.id);
+                    println!("{}", snapshot.id);
+                }
+                Err(e) => exit_with_error("Failed to create snapshot", e),
+            }
+            return;
+        }
+        Some(Commands::DiffSince { id }) => {
+            let current_dir = std::env::current_dir()
+                .unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+            let snapshot = snapshot::open(&id).unwrap_or_else(|e| exit_with_error("Failed to open snapshot", e));
+            let changes = snapshot::changes_since(&snapshot, &current_dir)
+                .unwrap_or_else(|e| exit_with_error("Failed to compare against snapshot", e));
+            
+            if changes.is_empty() {
+                println!("{}", format!("No changes since snapshot {}", id).green());
+            } else {
+                println!("{}", format!("Changes since snapshot {}:", id).blue().bold());
+                print_changes(&changes);
+            }
+            return;
+        }
+        Some(Commands::Diff { old, new, diff, diff_handler, format, output_patch, no_pager }) => {
+            for dir in [&old, &new] {
+                if !dir.is_dir() {
+                    exit_with_error("Failed to compare directories", format!("{} is not a directory", dir.display()));
+                }
+            }
+            
+            let changes = compare_directories(&old, &new)
+                .unwrap_or_else(|e| exit_with_error("Failed to compare directories", e));
+            if let Some(patch_path) = &output_patch {
+                write_patch(patch_path, &old, &new, &changes, None);
+            }
+            if format != manifest::Format::Human {
+                if let Err(e) = manifest::print(format, &old, &new, &changes, None) {
+                    exit_with_error("Failed to print differences", e);
+                }
+            } else if changes.is_empty() {
+                println!("{}", "No differences".green());
+            } else {
+                let _pager = if no_pager { None } else { pager::start() };
+                review::print_changes(&changes, &old, &new, &review::ReviewOptions {
+                    diff,
+                    handlers: &diff_handler,
+                    expand: false,
+                    focus: None,
+                    hex: false,
+                });
+            }
+            return;
+        }
+        Some(Commands::Apply(PatchArgs { file, strip, trust }))
+        | Some(Commands::Patch { action: PatchCommand::Apply(PatchArgs { file, strip, trust }) }) => {
+            let current_dir = std::env::current_dir()
+                .unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+            let from_stdin = file.as_os_str() == "-";
+            let text = if from_stdin {
+                std::io::read_to_string(std::io::stdin())
+            } else {
+                encryption::read(&file).and_then(|data| String::from_utf8(data).map_err(std::io::Error::other))
+            };
+            let text = text.unwrap_or_else(|e| exit_with_error("Failed to read patch", e));
+            
+            check_signature((!from_stdin).then_some(file.as_path()), &trust, "Patch", "apply patch");
+            let patches = patch::parse(&text, strip).unwrap_or_else(|e| exit_with_error("Failed to parse patch", e));
+            
+            // Apply the patch to a sandbox copy so it is reviewed like a command run
+            let temp_dir = create_sandbox(None).unwrap_or_else(|e| exit_with_error("Failed to create temporary directory", e));
+            if let Err(e) = copy_directory(&current_dir, temp_dir.path()) {
+                exit_with_error("Failed to copy directory contents", e);
+            }
+            if let Err(e) = patch::apply(&patches, temp_dir.path()) {
+                exit_with_error("Patch does not apply", e);
+            }
+            
+            let changes = compare_directories(&current_dir, temp_dir.path())
+                .unwrap_or_else(|e| exit_with_error("Failed to compare directories", e));
+            if changes.is_empty() {
+                println!("{}", "No changes would be made".green());
+                return;
+            }
+            
+            println!("{}", "\nChanges that would be made:".blue().bold());
+            print_changes(&changes);
+            confirm_and_apply(&current_dir, temp_dir.path(), changes, &ApplyOptions {
+                allow_stdin: !from_stdin,
+                merge_tool: None,
+                difftool: None,
+                lock_timeout: Duration::from_secs(args.lock_timeout),
+                by_hunk: false,
+                tui: false,
+                yes: false,
+                machine_output: false,
+                hooks: None,
+                focus: None,
+                seed: None,
+                temp_dir: args.temp_dir.as_deref(),
+                key: None,
+                undo: (!args.no_undo).then_some(args.max_undo_size),
+            });
+            return;
+        }
+        Some(Commands::Rollback { id }) => {
+            let current_dir = std::env::current_dir()
+                .unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+            let snapshot = snapshot::open(&id).unwrap_or_else(|e| exit_with_error("Failed to open snapshot", e));
+            let (changes, restore_dir) = snapshot::rollback_changes(&snapshot, &current_dir)
+                .unwrap_or_else(|e| exit_with_error("Failed to prepare rollback", e));
+            
+            if changes.is_empty() {
+                println!("{}", format!("Already at the state of snapshot {}", id).green());
+                return;
+            }
+            
+            println!("{}", format!("\nChanges to roll back to snapshot {}:", id).blue().bold());
+            print_changes(&changes);
+            confirm_and_apply(&current_dir, restore_dir.path(), changes, &ApplyOptions {
+                allow_stdin: true,
+                merge_tool: None,
+                difftool: None,
+                lock_timeout: Duration::from_secs(args.lock_timeout),
+                by_hunk: false,
+                tui: false,
+                yes: false,
+                machine_output: false,
+                hooks: None,
+                focus: None,
+                seed: None,
+                temp_dir: args.temp_dir.as_deref(),
+                key: None,
+                undo: (!args.no_undo).then_some(args.max_undo_size),
+            });
+            return;
+        }
+        Some(Commands::Undo { id: _, list: true }) => {
+            if let Err(e) = undo::list() {
+                exit_with_error("Failed to list applied changes", e);
+            }
+            return;
+        }
+        Some(Commands::Undo { id, list: false }) => {
+            let (id, record, files) = undo::open(id.as_deref()).unwrap_or_else(|e| exit_with_error("Failed to open applied changes", e));
+            let decrypted = undo::decrypt(&files).unwrap_or_else(|e| exit_with_error("Failed to decrypt applied changes", e));
+            let files = decrypted.as_ref().map_or(files, |decrypted| decrypted.path().to_path_buf());
+            let changes = undo::changes(&record).unwrap_or_else(|e| exit_with_error("Refusing to undo", e));
+            
+            println!(
+                "{}",
+                format!("\nChanges to undo {} in {}:", id, record.directory.display()).blue().bold()
+            );
+            print_changes(&changes);
+            confirm_and_apply(&record.directory, &files, changes, &ApplyOptions {
+                allow_stdin: true,
+                merge_tool: None,
+                difftool: None,
+                lock_timeout: Duration::from_secs(args.lock_timeout),
+                by_hunk: false,
+                tui: false,
+                yes: false,
+                machine_output: false,
+                hooks: None,
+                focus: None,
+                seed: None,
+                temp_dir: args.temp_dir.as_deref(),
+                key: None,
+                undo: (!args.no_undo).then_some(args.max_undo_size),
+            });
+            return;
+        }
+        Some(Commands::Sessions { action }) => {
+            let result = match &action {
+                SessionsCommand::List => session::list(),
+                SessionsCommand::Show { id } => session::show(id),
+                SessionsCommand::Path { id } => session::sandbox(id.as_deref()).map(|sandbox| println!("{}", sandbox.display())),
+                SessionsCommand::Rm { id } => session::remove(id).map(|()| {
+                    info!("Removed session {}", id);
+                    println!("{}", format!("Removed session {}", id).green());
+                }),
+            };
+            if let Err(e) = result {
+                exit_with_error("Failed to access sessions", e);
+            }
+            return;
+        }
+        Some(Commands::Replay { id, trust }) => {
+            let session = session::open(&id).unwrap_or_else(|e| exit_with_error("Failed to open session", e));
+            let record = session::record_path(&id).unwrap_or_else(|e| exit_with_error("Failed to open session", e));
+            check_signature(Some(&record), &trust, "Session", &format!("replay session {}", id));
+            let exe = std::env::current_exe().unwrap_or_else(|e| exit_with_error("Failed to locate tust", e));
+            info!("Replaying session {} in {}", id, session.directory.display());
+            println!(
+                "{}",
+                format!("Replaying session {}: {}", id, redact::redact(&session.command.join(" "))).yellow()
+            );
+            
+            // Run tust again with the recorded options, so the replay gets a
+            // fresh copy of the directory and goes through the same review
+            let status = Command::new(exe)
+                .args(&session.args)
+                .current_dir(&session.directory)
+                .status()
+                .unwrap_or_else(|e| exit_with_error("Failed to replay session", e));
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Some(Commands::Verify { target, strip }) => {
+            // A number is a session id unless a patch file has that name
+            let result = if target.parse::<u64>().is_ok() && !Path::new(&target).exists() {
+                verify::session(&target)
+            } else {
+                let current_dir = std::env::current_dir()
+                    .unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+                verify::patch(Path::new(&target), strip, &current_dir)
+            };
+            match result {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(e) => exit_with_error(&format!("Failed to verify {}", target), e),
+            }
+            return;
+        }
+        Some(Commands::Audit { action: AuditCommand::Verify }) => {
+            match audit::verify() {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(e) => exit_with_error("Failed to verify audit log", e),
+            }
+            return;
+        }
+        Some(Commands::Trust { revoke: false }) => {
+            let current_dir = std::env::current_dir()
+                .unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+            if let Err(e) = trust::trust(&current_dir) {
+                exit_with_error("Failed to trust the project configuration", e);
+            }
+            println!(
+                "{}",
+                format!("Trusting {} and {} as they are now", config::FILE_NAME, trust::hooks_dir(Path::new("")).display()).green()
+            );
+            return;
+        }
+        Some(Commands::Trust { revoke: true }) => {
+            let current_dir = std::env::current_dir()
+                .unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+            match trust::revoke(&current_dir) {
+                Ok(true) => println!("{}", "No longer trusting the project configuration".green()),
+                Ok(false) => println!("{}", "The project configuration was not trusted".green()),
+                Err(e) => exit_with_error("Failed to revoke trust", e),
+            }
+            return;
+        }
+        Some(Commands::ShellInit { shell }) => {
+            let profiles = config::global_profiles()
+                .unwrap_or_else(|e| exit_with_error("Failed to load configuration", e));
+            print!("{}", shell_init::script(shell, &profiles));
+            return;
+        }
+        Some(Commands::Shell) => {
+            args.command = vec![user_shell()];
+            interactive = true;
+        }
+        Some(Commands::Ab { commands }) => {
+            // The first `--` ends tust's own arguments and is gone already
+            let commands = commands.strip_prefix(&["--".to_string()]).unwrap_or(&commands);
+            let (cmd_a, cmd_b) = commands
+                .iter()
+                .position(|arg| arg == "--")
+                .map(|at| (&commands[..at], &commands[at + 1..]))
+                .filter(|(cmd_a, cmd_b)| !cmd_a.is_empty() && !cmd_b.is_empty())
+                .unwrap_or_else(|| exit_with_error("Invalid arguments", "expected ab -- CMD_A -- CMD_B"));
+            match matrix::ab(cmd_a, cmd_b) {
+                Ok(code) => std::process::exit(code),
+                Err(e) => exit_with_error("Failed to compare the commands", e),
+            }
+        }
+        Some(Commands::Exec { .. }) | None => {}
+    }
     
     // Handle --clean flag
     if args.clean {
         info!("Starting cleanup of temporary directories");
-        match clean_temporary_directories() {
+        match clean_temporary_directories(args.temp_dir.as_deref()) {
             Ok(()) => {
                 info!("Cleanup completed successfully");
                 println!("{}", "Cleanup completed successfully".green());
@@ -43,13 +1015,131 @@ async fn main() {
         return;
     }
     
+    let config = config::load(Path::new("."), args.profile.as_deref())
+        .unwrap_or_else(|e| exit_with_error("Failed to load configuration", e));
+    if !config.untrusted.is_empty() {
+        let message = format!(
+            "ignoring {} in {}, which is not trusted; check it and run `tust trust`",
+            config.untrusted.join(", "),
+            config::FILE_NAME
+        );
+        warn!("{}", message);
+        eprintln!("{}", format!("Warning: {}", message).yellow());
+    }
+    apply_config_defaults(&mut args, &matches, config.defaults);
+    if args.sign && args.output_patch.is_none() && !args.keep {
+        exit_with_error("Invalid --sign", "there is nothing to sign without --output-patch or --keep");
+    }
+    // Set up before the command runs, so a passphrase is asked for first
+    // Whatever is applied is saved for undo as well
+    let encryption = ((args.encrypt || !args.encrypt_to.is_empty())
+        && (args.keep || args.output_patch.is_some() || !(args.no || args.check)))
+        .then(|| encryption::Key::new(&args.encrypt_to).unwrap_or_else(|e| exit_with_error("Failed to set up encryption", e)));
+    let ignored =
+        config::Patterns::new(&config.ignore).unwrap_or_else(|e| exit_with_error("Invalid ignore pattern in configuration", e));
+    let protected = config::Patterns::new(&config.protected)
+        .unwrap_or_else(|e| exit_with_error("Invalid protected pattern in configuration", e));
+    
+    let mut exclude: Vec<String> = if args.no_default_excludes {
+        Vec::new()
+    } else {
+        filter::DEFAULT_EXCLUDES.iter().map(|pattern| pattern.to_string()).collect()
+    };
+    exclude.extend(
+        filter::read_ignore_file(Path::new("."))
+            .unwrap_or_else(|e| exit_with_error(&format!("Failed to read {}", filter::IGNORE_FILE), e)),
+    );
+    exclude.extend(args.exclude.iter().cloned());
+    let project = std::env::current_dir().unwrap_or_else(|e| exit_with_error("Failed to get current directory", e));
+    if args.one_file_system {
+        exclude.extend(filter::mount_excludes(&project).unwrap_or_else(|e| exit_with_error("Failed to list mount points", e)));
+    }
+    // Last, so nothing can re-include the cache into itself
+    exclude.push(format!("/{}/", cache::DIR));
+    if let Err(e) = filter::set(&exclude, &args.include, (!args.no_gitignore).then_some(project.as_path())) {
+        exit_with_error("Invalid --exclude or --include pattern", e);
+    }
+    if args.max_depth.is_some() || args.max_file_size.is_some() {
+        filter::set_limits(&project, args.max_depth, args.max_file_size);
+    }
+    if args.git_tracked {
+        let files = filter::git_files(&project, args.git_untracked)
+            .unwrap_or_else(|e| exit_with_error("Failed to list the files known to git", e));
+        filter::set_tracked(files);
+    }
+    
+    if args.follow_symlinks {
+        symlink::set_follow();
+    }
+    if !args.paranoid {
+        TRUST_MTIME.store(true, Ordering::Relaxed);
+    }
+    if args.link {
+        info!("Hard-linking files into the sandbox; writes in place will reach the current directory");
+    }
+    if let Some(rate) = args.bwlimit {
+        info!("Limiting I/O to {}/s", format_size(rate));
+        throttle::set_limit(rate);
+    }
+    if args.nice_io {
+        throttle::nice_io();
+    }
+    if !args.redact.is_empty() {
+        redact::set_patterns(args.redact.clone());
+    }
+    if let Some(theme) = &args.diff_theme
+        && let Err(e) = highlight::set_theme(theme)
+    {
+        exit_with_error("Invalid --diff-theme", e);
+    }
+    
     if args.command.is_empty() {
         error!("No command provided");
         eprintln!("{}", "Error: No command provided".red());
         std::process::exit(1);
     }
+    if !args.matrix.is_empty() && !args.no_matrix {
+        // Each variant writes a manifest of its own for the comparison
+        if args.manifest.is_some() {
+            exit_with_error("Invalid options", "--manifest can't be combined with --matrix");
+        }
+        match matrix::run(&args.matrix, args.no) {
+            Ok(code) => std::process::exit(code),
+            Err(e) => exit_with_error("Failed to run the matrix", e),
+        }
+    }
+    // A chain runs as one shell command line, which stops at the first
+    // command that fails
+    let chain: Vec<&[String]> = args.command.split(|arg| arg == CHAIN_SEPARATOR).collect();
+    if chain.iter().any(|command| command.is_empty()) {
+        exit_with_error("Invalid command", format!("empty command in a {} chain", CHAIN_SEPARATOR));
+    }
+    if args.shell || chain.len() > 1 {
+        let command_line = chain
+            .iter()
+            .map(|command| {
+                if args.shell {
+                    command.join(" ")
+                } else {
+                    command.iter().map(|arg| shell_quote(Path::new(arg))).collect::<Vec<_>>().join(" ")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" && ");
+        // Containers don't have the user's shell, but every image has sh
+        let shell = match (args.shell, args.backend.container()) {
+            (true, None) => user_shell(),
+            (false, None) if cfg!(windows) => "cmd".to_string(),
+            _ => "sh".to_string(),
+        };
+        args.command = if cfg!(windows) && args.backend.container().is_none() {
+            vec![shell, "/C".to_string(), command_line]
+        } else {
+            vec![shell, "-c".to_string(), command_line]
+        };
+    }
     
-    info!("Executing command: {:?}", args.command);
+    info!("Executing command: {}", redact::redact(&format!("{:?}", args.command)));
     
     // Get current directory
     let current_dir = match std::env::current_dir() {
@@ -64,48 +1154,463 @@ async fn main() {
         }
     };
     
-    // Create temporary directory with prefix for easy identification
-    let temp_dir = match tempfile::Builder::new()
-        .prefix("tust-")
-        .tempdir() {
-        Ok(dir) => {
-            let temp_path = dir.path();
-            info!("Created temporary directory: {}", temp_path.display());
-            dir
+    // Either the live directory or a shared baseline seeds the sandbox and is
+    // what changes are computed against; they are always applied to the live one
+    let baseline = args.from_baseline.as_deref().map(|id| {
+        let baseline = baseline::open(id).unwrap_or_else(|e| exit_with_error("Failed to open baseline", e));
+        if baseline.source().ok().as_deref() != Some(current_dir.as_path()) {
+            warn!("Baseline {} was not taken from {}", id, current_dir.display());
+            eprintln!("{}", format!("Warning: baseline {} was not taken from this directory", id).yellow());
+        }
+        baseline
+    });
+    let seed_dir = baseline.as_ref().map_or_else(|| current_dir.clone(), |baseline| baseline.tree());
+    let scope = (!args.files.is_empty()).then(|| {
+        scope_paths(&current_dir, &args.files).unwrap_or_else(|e| exit_with_error("Invalid --files path", e))
+    });
+    
+    let cache = if args.cache {
+        let cache = cache::open(&current_dir).unwrap_or_else(|e| exit_with_error("Failed to open the sandbox cache", e));
+        if cache.is_none() {
+            eprintln!("{}", "Warning: the sandbox cache is in use by another run, using a temporary sandbox".yellow());
         }
+        cache
+    } else {
+        None
+    };
+    
+    // Opened before the sandbox is filled, so a wrong path fails right away
+    let stdin_file = match args.stdin.as_deref() {
+        Some(path) if path == Path::new("-") => {
+            args.stdin_passthrough = true;
+            None
+        }
+        Some(path) => Some(
+            fs::File::open(path).unwrap_or_else(|e| exit_with_error(&format!("Failed to open {}", path.display()), e)),
+        ),
+        None => None,
+    };
+    let mut env = Vec::new();
+    for path in &args.env_file {
+        env.extend(environment::read_file(path).unwrap_or_else(|e| exit_with_error(&format!("Failed to read {}", path.display()), e)));
+    }
+    env.extend(args.env.iter().cloned());
+    
+    let mut temp_dir = None;
+    let sandbox = match (&resumed, &cache) {
+        (Some((_, session)), _) => session.sandbox.clone(),
+        (None, Some(cache)) => cache.path(),
+        (None, None) => {
+            // Create temporary directory with prefix for easy identification
+            let created = if args.keep {
+                create_kept_sandbox(args.temp_dir.as_deref())
+            } else {
+                create_sandbox(args.temp_dir.as_deref())
+            };
+            let dir = match created {
+                Ok(dir) => {
+                    info!("Created temporary directory: {}", dir.path().display());
+                    dir
+                }
+                Err(e) => {
+                    error!("Failed to create temporary directory: {}", e);
+                    eprintln!("{}", format!("Error: Failed to create temporary directory: {}", e).red());
+                    std::process::exit(1);
+                }
+            };
+            let path = dir.path().to_path_buf();
+            temp_dir = Some(dir);
+            path
+        }
+    };
+    let temp_path = sandbox.as_path();
+    let mut hook_run = hooks::Run {
+        hooks: &config.hooks,
+        original: &current_dir,
+        sandbox: temp_path,
+        command: &args.command,
+        exit_code: None,
+        trusted: std::cell::OnceCell::new(),
+    };
+    
+    // Keep stdout clean for machine-readable output
+    let machine_output = args.format != manifest::Format::Human;
+    let print_status = |message: colored::ColoredString| {
+        if machine_output {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    };
+    
+    if interactive {
+        print_status("Starting a shell in a temporary directory; exit it to review the changes...".yellow());
+    } else if let Some((id, _)) = &resumed {
+        print_status(format!("Testing command in the sandbox of session {}...", id).yellow());
+    } else {
+        print_status("Testing command in temporary directory...".yellow());
+    }
+    
+    // A resumed session's sandbox is already filled
+    if resumed.is_none()
+        && let Err(e) = hook_run.hook(hooks::Hook::PreCopy, None, machine_output)
+    {
+        drop(temp_dir);
+        exit_with_error("Not running the command", e);
+    }
+    let snapshot = if resumed.is_some() { Ok(None) } else { backend::create(&args.backend, &seed_dir, temp_path) };
+    let mut snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
         Err(e) => {
-            error!("Failed to create temporary directory: {}", e);
-            eprintln!("{}", format!("Error: Failed to create temporary directory: {}", e).red());
-            std::process::exit(1);
+            drop(temp_dir);
+            exit_with_error("Failed to snapshot the current directory", e)
         }
     };
-    let temp_path = temp_dir.path();
+    // From here on, Ctrl-C stops tust at the next checkpoint, which removes
+    // the sandbox first
+    interrupt::install();
     
-    info!("Copying current directory contents to temporary directory");
-    println!("{}", "Testing command in temporary directory...".yellow());
+    // Fingerprint the originals before the sandbox is filled, so edits made
+    // to them while the command runs are conflicts rather than overwritten.
+    // A resumed session's sandbox was filled when it was first run, so the
+    // fingerprints from then are the ones that count.
+    let stored_seed = match &resumed {
+        Some((id, _)) => session::open_seed(id),
+        None => Ok(None),
+    };
+    let seed = stored_seed.and_then(|stored| match stored {
+        Some(seed) => Ok(seed),
+        None => SortedWalk::new(&current_dir, scope.as_deref(), true)
+            .and_then(|files| conflict::Seed::record(&current_dir, files, args.paranoid, args.temp_dir.as_deref())),
+    });
+    let seed = match seed {
+        Ok(seed) => seed,
+        Err(e) => {
+            drop(temp_dir);
+            exit_with_error("Failed to record the state of the original files", e)
+        }
+    };
     
-    // Copy current directory contents to temporary directory
-    if let Err(e) = copy_directory(&current_dir, temp_path) {
+    // Copy current directory contents to temporary directory, with progress
+    // based on a quick scan of what is about to be copied
+    let copied = if snapshot.is_some() || resumed.is_some() {
+        Ok(())
+    } else {
+        info!("Copying current directory contents to temporary directory");
+        progress::scan(&seed_dir, scope.as_deref()).and_then(|size| {
+            debug!("Pre-scan: {} files, {} bytes", size.files, size.bytes);
+            // Hard links and an up-to-date cache take next to no space
+            if !args.link && cache.is_none() {
+                space::check(temp_path, &seed_dir, size.bytes)?;
+            }
+            let mut progress = progress::CopyProgress::new(&current_dir, size);
+            match (&cache, &scope) {
+                (Some(cache), _) => cache.sync(&seed_dir, &mut |bytes| progress.advance(bytes))?,
+                (None, None) => {
+                    copy_directory_with_progress(&seed_dir, temp_path, args.link, &mut |bytes| progress.advance(bytes))?
+                }
+                (None, Some(paths)) => {
+                    copy_paths_with_progress(&seed_dir, temp_path, paths, args.link, &mut |bytes| progress.advance(bytes))?
+                }
+            }
+            progress.finish();
+            Ok(())
+        })
+    };
+    if let Some(interrupt) = interrupt::received() {
+        exit_interrupted(interrupt, snapshot, temp_dir, seed);
+    }
+    if let Err(e) = copied {
+        drop(temp_dir);
+        drop(seed);
+        if e.kind() == std::io::ErrorKind::StorageFull {
+            exit_with_error(
+                "Not enough space for the sandbox",
+                format!("{}; leave large paths out with --exclude or put the sandbox elsewhere with --temp-dir", e),
+            );
+        }
         error!("Failed to copy directory contents: {}", e);
         eprintln!("{}", format!("Error: Failed to copy directory contents: {}", e).red());
         std::process::exit(1);
     }
     
+    // Run setup commands and remember what they changed, so the review only
+    // shows what the main command did on top of the prepared environment
+    let setup_state = if args.setup.is_empty() {
+        HashMap::new()
+    } else {
+        for setup in &args.setup {
+            info!("Running setup command in temporary directory: {}", redact::redact(setup));
+            print_status(format!("Running setup: {}", redact::redact(setup)).yellow());
+            let mut command = shell_command(setup);
+            environment::apply(&mut command, args.clear_env, &env);
+            command
+                .current_dir(temp_path)
+                .env("TUST_SANDBOX", temp_path)
+                .env("TUST_ORIGINAL_DIR", &current_dir)
+                .env("GIT_CEILING_DIRECTORIES", git_ceiling(temp_path))
+                .stdin(Stdio::null());
+            if machine_output {
+                command.stdout(std::io::stderr());
+            }
+            let status = output::status(&mut command, machine_output);
+            if let Some(interrupt) = interrupt::received() {
+                exit_interrupted(interrupt, snapshot, temp_dir, seed);
+            }
+            let (context, e) = match status {
+                Ok(status) if status.success() => continue,
+                Ok(status) => (
+                    "Setup command failed",
+                    format!("{} exited with code {}", redact::redact(setup), status.code().unwrap_or(-1)),
+                ),
+                Err(e) => ("Failed to execute setup command", e.to_string()),
+            };
+            // Exiting skips destructors, so clean up the sandbox first
+            drop(snapshot);
+            drop(temp_dir);
+            drop(seed);
+            exit_with_error(context, e)
+        }
+        
+        match compare_scoped(&seed_dir, temp_path, scope.as_deref())
+            .and_then(|changes| record_setup_state(temp_path, &changes))
+        {
+            Ok(state) => state,
+            Err(e) => {
+                drop(snapshot);
+                drop(temp_dir);
+                drop(seed);
+                exit_with_error("Failed to record setup changes", e)
+            }
+        }
+    };
+    
+    // With --keep the sandbox stays from here on, as the run is recorded as a
+    // session; one that failed to be prepared is removed like any other
+    if args.keep {
+        if let Some(dir) = &mut temp_dir {
+            dir.disable_cleanup(true);
+        }
+        if let Some(snapshot) = &mut snapshot {
+            snapshot.keep();
+        }
+    }
+    
+    let mut sandbox_markers = match args.print_sandbox.map(sandbox_marker_writer).transpose() {
+        Ok(writer) => writer,
+        Err(e) => {
+            error!("Failed to open sandbox marker output: {}", e);
+            eprintln!("{}", format!("Error: Failed to open sandbox marker output: {}", e).red());
+            std::process::exit(1);
+        }
+    };
+    if let Some(writer) = sandbox_markers.as_mut() {
+        let _ = writeln!(writer, "TUST_SANDBOX_BEGIN {}", temp_path.display());
+        let _ = writer.flush();
+    }
+    
+    // Piped stdin only reaches the command when explicitly requested, so the
+    // confirmation prompt never competes with the command for the stream
+    let forward_stdin = stdin_file.is_none() && (args.stdin_passthrough || std::io::stdin().is_terminal());
+    let stdin = if let Some(file) = &stdin_file {
+        debug!("Feeding {} to the command", args.stdin.as_deref().unwrap_or(Path::new("")).display());
+        file.try_clone().map(Stdio::from).unwrap_or_else(|e| exit_with_error("Failed to open stdin for the command", e))
+    } else if forward_stdin {
+        Stdio::inherit()
+    } else {
+        debug!("stdin is not a terminal, not forwarding it to the command");
+        Stdio::null()
+    };
+    
     // Run the command in the temporary directory
-    info!("Running command in temporary directory: {:?}", args.command);
-    let status = match Command::new(&args.command[0])
+    info!("Running command in temporary directory: {}", redact::redact(&format!("{:?}", args.command)));
+    let limits = child::Limits { memory: args.max_memory, cpu: args.max_cpu, processes: args.max_procs };
+    let container = args.backend.container();
+    let mut command = if let Some(container) = container {
+        container.command(temp_path, &args.command[0], !args.no_network, limits, &env)
+    } else if args.backend == backend::Backend::Bwrap {
+        bwrap::command(temp_path, &args.command[0], !args.no_network)
+    } else if args.backend == backend::Backend::SandboxExec {
+        seatbelt::command(temp_path, &args.command[0], !args.no_network)
+    } else if args.no_network {
+        debug!("Running command without network access");
+        child::without_network(&args.command[0])
+            .unwrap_or_else(|e| exit_with_error("Can't run the command without network access", e))
+    } else {
+        Command::new(&args.command[0])
+    };
+    // Containers start with the image's environment, and get the variables
+    // as options
+    if container.is_none() {
+        environment::apply(&mut command, args.clear_env, &env);
+    }
+    command
         .args(&args.command[1..])
         .current_dir(temp_path)
-        .status() {
-        Ok(status) => status,
+        .env("TUST_SANDBOX", temp_path)
+        .env("TUST_ORIGINAL_DIR", &current_dir)
+        .env("GIT_CEILING_DIRECTORIES", git_ceiling(temp_path))
+        .stdin(stdin);
+    if machine_output {
+        command.stdout(std::io::stderr());
+    }
+    if let Some(nice) = args.nice {
+        debug!("Running command with niceness {}", nice);
+        child::set_priority(&mut command, nice);
+    }
+    if container.is_none() && (limits.memory.is_some() || limits.cpu.is_some() || limits.processes.is_some()) {
+        debug!("Running command with {:?}", limits);
+        if let Err(e) = child::set_limits(&mut command, limits) {
+            exit_with_error("Can't limit the resources of the command", e);
+        }
+    }
+    let started = std::time::Instant::now();
+    let started_at = session::now();
+    if args.heartbeat {
+        heartbeat::capture_output(&mut command);
+    }
+    // With --keep the output is recorded for the session, and with
+    // --quiet-command for the last lines on failure. A command that would
+    // write to a terminal gets a pseudo-terminal to keep it that way.
+    let log = (args.keep || args.quiet_command)
+        .then(|| output::Log::new().inspect_err(|e| warn!("Not recording the output: {}", e)).ok())
+        .flatten();
+    let recorded = log.is_some() && !args.heartbeat && !args.tty;
+    // With --redact the output is shown through tust, which masks it
+    let relayed = recorded || (redact::active() && !args.heartbeat && !args.quiet_command && !args.tty);
+    let relay_terminal = relayed && !args.quiet_command && pty::is_terminal_output(machine_output);
+    if relayed && !relay_terminal {
+        output::capture(&mut command);
+    } else if args.quiet_command {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    // The command's output is set apart from tust's own messages by rules
+    let show_output = !args.heartbeat && !args.quiet_command;
+    if show_output {
+        eprintln!("{}", output::rule("output").dimmed());
+    }
+    let pty = (args.tty || relay_terminal).then(|| {
+        debug!("Running command in a pseudo-terminal");
+        pty::attach(&mut command).unwrap_or_else(|e| exit_with_error("Failed to open a pseudo-terminal", e))
+    });
+    // Processes the command leaves behind are killed on timeouts, quotas
+    // and interrupts
+    child::adopt_orphans();
+    let (result, exceeded, timed_out) = match command.spawn() {
+        Ok(mut process) => {
+            child::contain(&process);
+            interrupt::forward_to(process.id());
+            // The command holds the terminal's other side, which has to be
+            // closed here for the output to end with the command
+            drop(command);
+            let forwarding = pty.map(|pty| {
+                let input = stdin_file.or_else(|| forward_stdin.then(pty::stdin).flatten());
+                pty.forward(input, machine_output, log.clone())
+            });
+            let copier = (relayed && !relay_terminal)
+                .then(|| output::tee(&mut process, log.as_ref().filter(|_| recorded), machine_output, !args.quiet_command));
+            let watcher = args.max_sandbox_size.map(|limit| quota::Watcher::start(temp_path, limit, process.id()));
+            let deadline = args.timeout.map(|timeout| child::Deadline::start(process.id(), timeout));
+            let result = if args.heartbeat { heartbeat::run(process, log.clone()).await } else { child::wait(process) };
+            if let Some(forwarding) = forwarding {
+                forwarding.finish();
+            }
+            if let Some(copier) = copier {
+                copier.finish();
+            }
+            (result, watcher.and_then(quota::Watcher::stop), deadline.is_some_and(child::Deadline::stop))
+        }
+        Err(e) => (Err(e), None, false),
+    };
+    interrupt::stop_forwarding(result.as_ref().ok().map(|(status, _)| *status));
+    if show_output {
+        eprintln!("{}", output::rule("end of output").dimmed());
+    }
+    let interrupted = interrupt::received();
+    if let Some(container) = container
+        && (exceeded.is_some() || timed_out || interrupted.is_some())
+    {
+        container.kill(temp_path);
+    }
+    if let Some(interrupt) = interrupted {
+        child::kill_leftovers();
+        exit_interrupted(interrupt, snapshot, temp_dir, seed);
+    }
+    if let Some(growth) = exceeded {
+        drop(temp_dir);
+        drop(seed);
+        exit_with_error(
+            "Command killed",
+            format!("it wrote {} to the sandbox, more than --max-sandbox-size allows", format_size(growth)),
+        );
+    }
+    let (status, usage) = match result {
+        Ok((status, resources)) => {
+            let usage = child::Usage { duration: started.elapsed(), resources };
+            let summary = match resources {
+                Some(resources) => format!("Command finished in {:.1}s ({})", usage.duration.as_secs_f64(), resources),
+                None => format!("Command finished in {:.1}s", usage.duration.as_secs_f64()),
+            };
+            info!("{}", summary);
+            eprintln!("{}", summary.dimmed());
+            (status, usage)
+        }
         Err(e) => {
             error!("Failed to execute command: {}", e);
             eprintln!("{}", format!("Error: Failed to execute command: {}", e).red());
             std::process::exit(1);
         }
     };
+    hook_run.exit_code = status.code();
+    let _ = hook_run.hook(hooks::Hook::PostRun, None, machine_output);
+    
+    if let Some(writer) = sandbox_markers.as_mut() {
+        let _ = writeln!(writer, "TUST_SANDBOX_END {} {}", temp_path.display(), status.code().unwrap_or(-1));
+        let _ = writer.flush();
+    }
     
-    if !status.success() {
+    let mut session = args.keep.then(|| session::Session {
+        args: std::env::args_os().skip(1).map(|arg| arg.to_string_lossy().into_owned()).collect(),
+        command: args.command.clone(),
+        directory: current_dir.clone(),
+        sandbox: temp_path.to_path_buf(),
+        started: started_at,
+        finished: session::now(),
+        exit_code: status.code(),
+        usage: Some(usage),
+        changes: None,
+    });
+    // A resumed session keeps its options and lists every command run in it
+    if let (Some(session), Some((_, resumed))) = (session.as_mut(), &resumed) {
+        session.args = resumed.args.clone();
+        session.command = [resumed.command.as_slice(), &[CHAIN_SEPARATOR.to_string()], &args.command].concat();
+        session.started = resumed.started;
+    }
+    let resumed_id = resumed.as_ref().map(|(id, _)| id.as_str());
+    
+    if !status.success() && !interactive {
+        // --heartbeat already showed the last lines
+        if let Some(log) = log.as_ref().filter(|_| !args.heartbeat) {
+            let tail = log.tail();
+            if !tail.is_empty() {
+                eprintln!("{}", "Last lines of output:".yellow());
+                for line in tail {
+                    eprintln!("  {}", line);
+                }
+            }
+        }
+        if let Some(session) = &session {
+            keep_session(&args, session, &seed, resumed_id, log.as_ref(), encryption.as_ref());
+        }
+        notify_outcome(&args, &current_dir, status.code(), None, started.elapsed());
+        drop(temp_dir);
+        drop(seed);
+        if timed_out {
+            let timeout = args.timeout.unwrap_or_default();
+            error!("Command timed out after {:?}", timeout);
+            eprintln!("{}", format!("Command timed out after {:?}", timeout).red());
+            std::process::exit(TIMEOUT_EXIT_CODE);
+        }
         let exit_code = status.code().unwrap_or(-1);
         error!("Command failed with exit code: {}", exit_code);
         eprintln!("{}", format!("Command failed with exit code: {}", exit_code).red());
@@ -116,7 +1621,9 @@ async fn main() {
     
     // Compare directories to find changes
     info!("Comparing directories to find changes");
-    let changes = match compare_directories(&current_dir, temp_path) {
+    let mut changes = match compare_scoped(&seed_dir, temp_path, scope.as_deref())
+        .and_then(|changes| exclude_setup_changes(temp_path, changes, &setup_state))
+    {
         Ok(changes) => {
             info!("Found {} changes", changes.len());
             changes
@@ -127,134 +1634,1346 @@ async fn main() {
             std::process::exit(1);
         }
     };
+    if let Some(interrupt) = interrupt::received() {
+        exit_interrupted(interrupt, snapshot, temp_dir, seed);
+    }
+    
+    // Changes to ignored paths are never reviewed, and changes to protected
+    // paths are only reported
+    changes.retain(|change| !ignored.matches_change(change));
+    let (protected_changes, mut changes): (Vec<Change>, Vec<Change>) =
+        changes.into_iter().partition(|change| protected.matches_change(change));
+    if !protected_changes.is_empty() {
+        warn!("Leaving out {} changes to protected paths", protected_changes.len());
+        if machine_output {
+            eprintln!("{}", format!("{} changes to protected paths will not be applied", protected_changes.len()).yellow());
+        } else {
+            println!("{}", "\nChanges to protected paths that will not be applied:".yellow().bold());
+            print_changes(&protected_changes);
+        }
+    }
+    // Never copied into the sandbox, so anything there is the command's doing
+    if symlink::exists(&temp_path.join(cache::DIR)) {
+        let message = format!("the command wrote to {}/ in the sandbox, which holds tust's hooks and is never applied", cache::DIR);
+        warn!("{}", message);
+        eprintln!("{}", format!("Warning: {}", message).yellow());
+    }
+    
+    // Inspectors get the last word on what is offered
+    let mut notes = Vec::new();
+    if !args.inspector.is_empty() {
+        let report = match inspector::run(&args.inspector, &current_dir, temp_path, &changes) {
+            Ok(report) => report,
+            Err(e) => {
+                drop(snapshot);
+                drop(temp_dir);
+                drop(seed);
+                exit_with_error("Failed to inspect the changes", e);
+            }
+        };
+        if report.rewrote {
+            info!("An inspector changed the sandbox, comparing again");
+            changes = compare_scoped(&seed_dir, temp_path, scope.as_deref())
+                .and_then(|changes| exclude_setup_changes(temp_path, changes, &setup_state))
+                .unwrap_or_else(|e| exit_with_error("Failed to compare directories", e));
+            changes.retain(|change| !ignored.matches_change(change) && !protected.matches_change(change));
+        }
+        let vetoed = changes.len();
+        changes.retain(|change| !report.vetoes(change));
+        let vetoed = vetoed - changes.len();
+        if vetoed > 0 {
+            warn!("Leaving out {} changes vetoed by inspectors", vetoed);
+            if machine_output {
+                eprintln!("{}", format!("{} changes vetoed by inspectors will not be applied", vetoed).yellow());
+            } else {
+                println!("{}", "\nChanges vetoed by inspectors that will not be applied:".yellow().bold());
+                for (name, path, reason) in &report.vetoes {
+                    println!("  {} {}", path.display(), format!("({}: {})", name, reason).dimmed());
+                }
+            }
+        }
+        if !report.blocks.is_empty() {
+            if !machine_output {
+                println!("{}", "\nChanges that were rejected:".blue().bold());
+                print_changes(&changes);
+            }
+            for (name, reason) in &report.blocks {
+                error!("Inspector {} refused the changes: {}", name, reason);
+                eprintln!("{}", format!("Inspector {} refuses to apply these changes: {}", name, reason).red());
+            }
+            drop(snapshot);
+            drop(temp_dir);
+            drop(seed);
+            std::process::exit(1);
+        }
+        notes = report.notes;
+    }
+    notify_outcome(&args, &current_dir, status.code(), Some(changes.len()), started.elapsed());
+    
+    if let Some(session) = session.as_mut() {
+        session.changes = Some(changes.iter().map(|change| session::SessionChange::of(change, &current_dir)).collect());
+        keep_session(&args, session, &seed, resumed_id, log.as_ref(), encryption.as_ref());
+    }
+    
+    if let Some(manifest_path) = &args.manifest {
+        if let Err(e) = manifest::write(manifest_path, &current_dir, temp_path, &changes) {
+            exit_with_error("Failed to write manifest", e);
+        }
+        info!("Wrote manifest to {}", manifest_path.display());
+    }
+    
+    if let Some(patch_path) = &args.output_patch {
+        write_patch(patch_path, &current_dir, temp_path, &changes, encryption.as_ref());
+        if args.sign {
+            match signature::sign(patch_path, args.sign_key.as_deref()) {
+                Ok(signature) => info!("Wrote signature to {}", signature.display()),
+                Err(e) => exit_with_error("Failed to sign patch", e),
+            }
+        }
+    }
+    
+    if let Some(expected) = &args.expect {
+        let matched = expect::check(expected, &seed_dir, temp_path, &setup_state)
+            .unwrap_or_else(|e| exit_with_error("Failed to check expected changes", e));
+        // Exiting skips destructors, and harness runs shouldn't pile up sandboxes
+        drop(snapshot);
+        drop(temp_dir);
+        drop(seed);
+        std::process::exit(if matched { 0 } else { 1 });
+    }
+    
+    if changes.is_empty() {
+        info!("No changes would be made");
+        if machine_output {
+            if let Err(e) = manifest::print(args.format, &current_dir, temp_path, &changes, Some(&usage)) {
+                exit_with_error("Failed to print changes", e);
+            }
+        } else {
+            println!("{}", "No changes would be made".green());
+        }
+        return;
+    }
+    
+    if let Some(validate) = &args.validate {
+        match run_validation(validate, temp_path, &current_dir, machine_output) {
+            Ok(true) => {
+                info!("Validation passed");
+                print_status("Validation passed".green());
+            }
+            Ok(false) if args.force => {
+                warn!("Validation failed, continuing because of --force");
+                eprintln!("{}", "Warning: validation failed, offering changes anyway because of --force".yellow());
+            }
+            Ok(false) => {
+                error!("Validation failed, refusing to apply changes");
+                if !machine_output {
+                    println!("{}", "\nChanges that were rejected:".blue().bold());
+                    print_changes(&changes);
+                }
+                eprintln!("{}", "Validation failed, refusing to apply these changes (use --force to override)".red());
+                // Exiting skips destructors; a kept sandbox stays regardless
+                drop(snapshot);
+                drop(temp_dir);
+                drop(seed);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                drop(snapshot);
+                drop(temp_dir);
+                drop(seed);
+                exit_with_error("Failed to run validation", e)
+            }
+        }
+    }
+    
+    if let Some(preview_dir) = &args.preview_dir {
+        if let Err(e) = build_preview(&current_dir, temp_path, &changes, preview_dir) {
+            exit_with_error("Failed to build preview directory", e);
+        }
+        info!("Built preview in {}", preview_dir.display());
+        print_status(format!("Preview of the result: {}", preview_dir.display()).blue());
+    }
+    
+    let focus = review::Focus::new(&args.only, &args.show).unwrap_or_else(|e| exit_with_error("Invalid --show pattern", e));
+    if machine_output {
+        if let Err(e) = manifest::print(args.format, &current_dir, temp_path, &changes, Some(&usage)) {
+            exit_with_error("Failed to print changes", e);
+        }
+        if !args.yes && !args.check {
+            return;
+        }
+    }
+    // The list may be long; the pager is done before anything is asked, and
+    // only started when something is asked at all
+    {
+        let _pager = if machine_output || args.no_pager || args.yes || args.check { None } else { pager::start() };
+        if !machine_output {
+            // Display changes to user
+            info!("Displaying {} changes to user", changes.len());
+            println!("{}", "\nChanges that would be made:".blue().bold());
+            review::print_changes(&changes, &current_dir, temp_path, &review::ReviewOptions {
+                diff: args.diff,
+                handlers: &args.diff_handler,
+                expand: args.expand,
+                focus: focus.as_ref(),
+                hex: args.hex,
+            });
+        }
+        if !notes.is_empty() {
+            print_status("\nNotes from inspectors:".blue().bold());
+            for (name, path, message) in &notes {
+                print_status(format!("  {}: {} {}", path.display(), message, format!("({})", name).dimmed()).normal());
+            }
+        }
+    }
+    
+    if args.check {
+        info!("--check found {} changes", changes.len());
+        // Exiting skips destructors, so clean up the sandbox first
+        drop(snapshot);
+        drop(temp_dir);
+        drop(seed);
+        std::process::exit(CHECK_FAILED_EXIT_CODE);
+    }
+    if args.no {
+        info!("Not applying changes because of --no");
+        return;
+    }
+    
+    if let Some(interrupt) = interrupt::received() {
+        exit_interrupted(interrupt, snapshot, temp_dir, seed);
+    }
+    // Applying is never cut short, and prompts end on Ctrl-C as usual
+    interrupt::uninstall();
+    confirm_and_apply(&current_dir, temp_path, changes, &ApplyOptions {
+        allow_stdin: !args.stdin_passthrough,
+        merge_tool: args.merge_tool.as_deref(),
+        difftool: args.difftool.as_deref(),
+        lock_timeout: Duration::from_secs(args.lock_timeout),
+        by_hunk: args.patch,
+        tui: args.tui,
+        yes: args.yes,
+        machine_output,
+        hooks: Some(&hook_run),
+        focus: focus.as_ref(),
+        seed: Some(&seed),
+        temp_dir: args.temp_dir.as_deref(),
+        key: encryption.as_ref(),
+        undo: (!args.no_undo).then_some(args.max_undo_size),
+    });
+}
+
+/// Tell the user how the command went, if they asked to be notified
+fn notify_outcome(args: &Args, directory: &Path, exit_code: Option<i32>, changes: Option<usize>, elapsed: Duration) {
+    if args.notify || args.notify_webhook.is_some() {
+        let outcome = notify::Outcome::new(&args.command, directory, exit_code, changes, elapsed);
+        notify::send(&outcome, args.notify_webhook.as_deref());
+    }
+}
+
+/// Remove the sandbox, unless kept, and the seed's clones, and exit after
+/// `interrupt`
+fn exit_interrupted(
+    interrupt: interrupt::Interrupt,
+    snapshot: Option<backend::Snapshot>,
+    temp_dir: Option<tempfile::TempDir>,
+    seed: conflict::Seed,
+) -> ! {
+    drop(snapshot);
+    drop(temp_dir);
+    drop(seed);
+    interrupt::exit(interrupt)
+}
+
+/// Check the signature of `file` (`None` when it was read from stdin),
+/// `noun` naming what it holds, and exit unless `--strict` is satisfied
+fn check_signature(file: Option<&Path>, trust: &TrustArgs, noun: &str, action: &str) {
+    let verification = match file {
+        Some(file) => signature::verify(file, &trust.trusted_key)
+            .unwrap_or_else(|e| exit_with_error(&format!("Failed to check {} signature", noun.to_lowercase()), e)),
+        None => signature::Verification::Unsigned,
+    };
+    match verification {
+        signature::Verification::Verified(tool) => {
+            println!("{}", format!("{} signature verified ({})", noun, tool).green());
+        }
+        signature::Verification::Unsigned if trust.strict => {
+            exit_with_error(&format!("Refusing to {}", action), "it is not signed");
+        }
+        signature::Verification::Untrusted(reason) if trust.strict => {
+            exit_with_error(&format!("Refusing to {}", action), reason);
+        }
+        signature::Verification::Unsigned => warn!("{} is not signed", noun),
+        signature::Verification::Untrusted(reason) => {
+            warn!("{} signature not verified: {}", noun, reason);
+            println!("{}", format!("Warning: {} signature not verified: {}", noun.to_lowercase(), reason).yellow());
+        }
+    }
+}
+
+/// Record a kept sandbox and the `seed` it was filled after, updating session
+/// `resumed` when continuing one with `tust exec`, encrypted with `key` when
+/// given, sign the record with `--sign`, and tell the user where to find it
+fn keep_session(
+    args: &Args,
+    session: &session::Session,
+    seed: &conflict::Seed,
+    resumed: Option<&str>,
+    output: Option<&output::Log>,
+    key: Option<&encryption::Key>,
+) {
+    let saved = match resumed {
+        Some(id) => session::update(id, session, seed, output, key).map(|()| id.to_string()),
+        None => session::save(session, seed, output, key).map(|id| id.to_string()),
+    };
+    match saved {
+        Ok(id) => {
+            info!("Kept sandbox {} as session {}", session.sandbox.display(), id);
+            eprintln!("{}", format!("Kept sandbox as session {}: {}", id, session.sandbox.display()).blue());
+            if args.sign {
+                match session::record_path(&id).and_then(|record| signature::sign(&record, args.sign_key.as_deref())) {
+                    Ok(signature) => info!("Wrote signature to {}", signature.display()),
+                    Err(e) => {
+                        warn!("Failed to sign session: {}", e);
+                        eprintln!("{}", format!("Warning: failed to sign session {} ({})", id, e).yellow());
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            // The sandbox itself is still there, so this is not fatal
+            warn!("Failed to record session: {}", e);
+            eprintln!(
+                "{}",
+                format!("Warning: failed to record session ({}), sandbox kept at {}", e, session.sandbox.display()).yellow()
+            );
+        }
+    }
+}
+
+/// How the confirmation and apply step may interact with the user
+struct ApplyOptions<'a> {
+    /// Whether answers may be read from stdin (see `read_confirmation`)
+    allow_stdin: bool,
+    merge_tool: Option<&'a str>,
+    /// Opens a change when choosing
+    difftool: Option<&'a str>,
+    /// How long to keep retrying files that are locked by another process
+    lock_timeout: Duration,
+    /// Skip the y/n prompt and go through changes and hunks one by one
+    by_hunk: bool,
+    /// Skip the y/n prompt and show the full-screen review
+    tui: bool,
+    /// Apply everything without asking
+    yes: bool,
+    /// Stdout carries machine-readable output, so messages go to stderr
+    machine_output: bool,
+    /// The run whose hooks to run around the apply
+    hooks: Option<&'a hooks::Run<'a>>,
+    /// Which changes to offer when choosing; the others are accepted
+    focus: Option<&'a review::Focus>,
+    /// The original files from before the command ran, when there was one
+    seed: Option<&'a conflict::Seed>,
+    /// Where to put the merge bases (`--temp-dir`)
+    temp_dir: Option<&'a Path>,
+    /// Encrypts the undo record (`--encrypt`)
+    key: Option<&'a encryption::Key>,
+    /// How many bytes of replaced files to save for undo; `None` with `--no-undo`
+    undo: Option<u64>,
+}
+
+/// Ask the user to confirm the displayed changes and apply them to `original`,
+/// copying new content from `modified`. Exits the process on errors.
+fn confirm_and_apply(original: &Path, modified: &Path, mut changes: Vec<Change>, options: &ApplyOptions) {
+    // A changed project configuration could make later runs apply without
+    // asking or run hooks outside the sandbox, so it deserves a closer look
+    let config_file = Path::new(config::FILE_NAME);
+    if changes.iter().any(|change| match change {
+        Change::Rename(..) => false,
+        Change::Create(path) | Change::Modify(path) | Change::Delete(path) | Change::Chmod(path) | Change::Xattr(path) => {
+            path == config_file
+        }
+    }) {
+        let message = format!(
+            "these changes touch {}, tust's project configuration; its `yes` and hooks stay off until you run `tust trust` again",
+            config::FILE_NAME
+        );
+        warn!("{}", message);
+        eprintln!("{}", format!("Warning: {}", message).yellow());
+    }
+    
+    // Remember what the originals look like while the user is deciding
+    let mut state = conflict::capture(original, &changes, options.seed, options.temp_dir)
+        .unwrap_or_else(|e| exit_with_error("Failed to record the state of the original files", e));
+    
+    // Ask for user confirmation, or let the user pick the changes to apply
+    let accepted = if options.yes {
+        info!("Applying without confirmation because of --yes");
+        None
+    } else if options.tui {
+        match select_shown(&changes, options.focus, |shown| tui::select(shown, original, modified, options.difftool)) {
+            Ok(Some(accepted)) => Some(accepted),
+            Ok(None) => {
+                info!("User aborted the operation");
+                println!("{}", "Aborted".red());
+                return;
+            }
+            Err(e) => exit_with_error("Failed to show the review screen", e),
+        }
+    } else {
+        let input = if options.by_hunk {
+            println!("\n{}", "Choose the changes to apply:".yellow());
+            "s".to_string()
+        } else {
+            info!("Asking user for confirmation");
+            println!("\n{}", "Would you like to apply these changes? (y/n, or s to choose per change)".yellow());
+            match read_confirmation(options.allow_stdin) {
+                Ok(input) => input,
+                Err(e) => {
+                    error!("Failed to read input: {}", e);
+                    eprintln!("{}", format!("Error: Failed to read input: {}", e).red());
+                    std::process::exit(1);
+                }
+            }
+        };
+        
+        match input.trim().to_lowercase().as_str() {
+            "y" => None,
+            "s" => select_shown(&changes, options.focus, |shown| {
+                review::select(shown, original, modified, options.allow_stdin, options.by_hunk, options.difftool).map(Some)
+            })
+            .unwrap_or_else(|e| exit_with_error("Failed to read input", e)),
+            _ => {
+                info!("User aborted the operation");
+                println!("{}", "Aborted".red());
+                return;
+            }
+        }
+    };
+    
+    if let Some(accepted) = accepted {
+        state.retain(&accepted);
+        let mut index = 0;
+        changes.retain(|_| {
+            index += 1;
+            accepted[index - 1]
+        });
+        if changes.is_empty() {
+            info!("User rejected every change");
+            println!("{}", "No changes selected".red());
+            return;
+        }
+    }
+    
+    let conflicts = state
+        .find_conflicts(original)
+        .unwrap_or_else(|e| exit_with_error("Failed to check the original files", e));
+    if !conflicts.is_empty() && options.yes {
+        exit_with_error(
+            "Refusing to apply changes",
+            format!("{} file(s) changed in the original directory in the meantime", conflicts.len()),
+        );
+    }
+    if !conflicts.is_empty() {
+        match conflict::resolve(&state, &conflicts, &mut changes, original, modified, options.allow_stdin, options.merge_tool) {
+            Ok(true) => {}
+            Ok(false) => {
+                info!("User aborted while resolving conflicts");
+                println!("{}", "Aborted".red());
+                return;
+            }
+            Err(e) => exit_with_error("Failed to resolve conflicts", e),
+        }
+    }
+    
+    if let Some(run) = options.hooks
+        && let Err(e) = run.hook(hooks::Hook::PreApply, Some(changes.len()), options.machine_output)
+    {
+        exit_with_error("Refusing to apply changes", e);
+    }
+    
+    info!("User confirmed, applying {} changes", changes.len());
+    
+    let audit_changes = audit::describe(original, modified, &changes)
+        .unwrap_or_else(|e| exit_with_error("Failed to hash the changed files", e));
+    if let Some(max_size) = options.undo {
+        match undo::record(original, &changes, &audit_changes, options.key, max_size) {
+            Ok(id) => debug!("Recorded undo {}", id),
+            Err(e) => exit_with_error("Failed to save the files for undo", e),
+        }
+    } else {
+        debug!("Not saving the files for undo because of --no-undo");
+    }
+    
+    // Apply changes to original directory
+    let result = apply_changes(original, modified, &changes, options.lock_timeout);
+    if let Err(e) = audit::record(original, audit_changes, result.as_ref().err().map(ToString::to_string)) {
+        exit_with_error("Failed to write audit log", e);
+    }
+    if let Err(e) = result {
+        error!("Failed to apply changes: {}", e);
+        eprintln!("{}", format!("Error: Failed to apply changes: {}", e).red());
+        std::process::exit(1);
+    }
+    
+    info!("Changes applied successfully");
+    if options.machine_output {
+        eprintln!("{}", "Changes applied successfully".green());
+    } else {
+        println!("{}", "Changes applied successfully".green());
+    }
+    
+    if let Some(run) = options.hooks {
+        // The changes are already in place, so a failing hook is only reported
+        let _ = run.hook(hooks::Hook::PostApply, Some(changes.len()), options.machine_output);
+    }
+}
+
+/// Let `select` choose among the changes `focus` shows, accepting the
+/// others; `None` when it was aborted
+fn select_shown(
+    changes: &[Change],
+    focus: Option<&review::Focus>,
+    select: impl FnOnce(&[Change]) -> std::io::Result<Option<Vec<bool>>>,
+) -> std::io::Result<Option<Vec<bool>>> {
+    let shown = review::shown(changes, focus);
+    let offered: Vec<Change> = changes.iter().zip(&shown).filter(|(_, shown)| **shown).map(|(change, _)| change.clone()).collect();
+    let Some(chosen) = select(&offered)? else {
+        return Ok(None);
+    };
+    let mut chosen = chosen.into_iter();
+    Ok(Some(shown.iter().map(|shown| !shown || chosen.next().unwrap_or(false)).collect()))
+}
+
+/// Per-user directory for tust's persistent state (snapshots and the like)
+fn data_dir() -> std::io::Result<PathBuf> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("tust"))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "could not determine the user data directory"))
+}
+
+/// Command that runs `command_line` through the platform shell
+fn shell_command(command_line: &str) -> Command {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command
+    };
+    command.arg(command_line);
+    command
+}
+
+/// The user's shell for `--shell`: `$SHELL`, or `sh` without one; `cmd` on
+/// Windows
+fn user_shell() -> String {
+    if cfg!(windows) {
+        return "cmd".to_string();
+    }
+    std::env::var("SHELL").ok().filter(|shell| !shell.is_empty()).unwrap_or_else(|| "sh".to_string())
+}
+
+/// Quote `path` for use in a `shell_command` command line
+fn shell_quote(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if cfg!(windows) {
+        format!("\"{}\"", path)
+    } else {
+        format!("'{}'", path.replace('\'', "'\\''"))
+    }
+}
+
+/// Materialize what `original` would look like after applying `changes`
+fn build_preview(original: &Path, modified: &Path, changes: &[Change], preview_dir: &Path) -> std::io::Result<()> {
+    if preview_dir.exists() && fs::read_dir(preview_dir)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not empty", preview_dir.display()),
+        ));
+    }
     
-    if changes.is_empty() {
-        info!("No changes would be made");
-        println!("{}", "No changes would be made".green());
-        return;
+    // Copying a directory into itself would never terminate
+    fs::create_dir_all(preview_dir)?;
+    if preview_dir.canonicalize()?.starts_with(original.canonicalize()?) {
+        let _ = fs::remove_dir(preview_dir);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the preview directory must be outside the current directory",
+        ));
     }
     
-    // Display changes to user
-    info!("Displaying {} changes to user", changes.len());
-    println!("{}", "\nChanges that would be made:".blue().bold());
-    for change in &changes {
+    copy_directory(original, preview_dir)?;
+    apply_changes(preview_dir, modified, changes, Duration::ZERO)
+}
+
+/// Run the validation command against a throwaway copy of the sandbox, so
+/// build artifacts and caches it creates never end up in the change set.
+/// With `machine_output`, everything is printed to stderr to keep stdout clean.
+fn run_validation(validate: &str, sandbox: &Path, original: &Path, machine_output: bool) -> std::io::Result<bool> {
+    // Next to the sandbox, so the copy can be cloned from it too
+    let validation_dir = create_sandbox(sandbox.parent())?;
+    copy_directory(sandbox, validation_dir.path())?;
+    
+    info!("Running validation command: {}", redact::redact(validate));
+    let mut command = shell_command(validate);
+    command
+        .current_dir(validation_dir.path())
+        .env("TUST_SANDBOX", validation_dir.path())
+        .env("TUST_ORIGINAL_DIR", original)
+        .stdin(Stdio::null());
+    if machine_output {
+        eprintln!("{}", format!("Validating: {}", redact::redact(validate)).yellow());
+        command.stdout(std::io::stderr());
+    } else {
+        println!("{}", format!("Validating: {}", redact::redact(validate)).yellow());
+    }
+    let status = output::status(&mut command, machine_output)?;
+    
+    Ok(status.success())
+}
+
+/// State of every path touched by the setup commands: the hash of the file
+/// they left behind, or `None` if they deleted it
+fn record_setup_state(
+    sandbox: &Path,
+    changes: &[Change],
+) -> std::io::Result<HashMap<PathBuf, Option<blake3::Hash>>> {
+    let mut state = HashMap::new();
+    for change in changes {
         match change {
-            Change::Create(path) => {
-                debug!("Would create: {}", path.display());
-                println!("  {}{}", "+ ".green(), path.display());
-            }
-            Change::Modify(path) => {
-                debug!("Would modify: {}", path.display());
-                println!("  {}{}", "~ ".yellow(), path.display());
+            Change::Create(path) | Change::Modify(path) | Change::Chmod(path) | Change::Xattr(path) => {
+                state.insert(path.clone(), Some(hash_file(&sandbox.join(path))?));
             }
             Change::Delete(path) => {
-                debug!("Would delete: {}", path.display());
-                println!("  {}{}", "- ".red(), path.display());
+                state.insert(path.clone(), None);
+            }
+            Change::Rename(from, to) => {
+                state.insert(from.clone(), None);
+                let mut files = HashSet::new();
+                collect_files(&sandbox.join(to), to, &mut files)?;
+                for file in files {
+                    let hash = hash_file(&sandbox.join(&file))?;
+                    state.insert(file, Some(hash));
+                }
             }
         }
     }
+    debug!("Setup commands touched {} paths", state.len());
+    Ok(state)
+}
+
+/// Drop changes that only reflect what the setup commands did, i.e. paths
+/// still in the exact state setup left them in
+fn exclude_setup_changes(
+    sandbox: &Path,
+    changes: Vec<Change>,
+    setup_state: &HashMap<PathBuf, Option<blake3::Hash>>,
+) -> std::io::Result<Vec<Change>> {
+    if setup_state.is_empty() {
+        return Ok(changes);
+    }
     
-    // Ask for user confirmation
-    info!("Asking user for confirmation");
-    println!("\n{}", "Would you like to apply these changes? (y/n)".yellow());
-    
-    let mut input = String::new();
-    if let Err(e) = std::io::stdin().read_line(&mut input) {
-        error!("Failed to read input: {}", e);
-        eprintln!("{}", format!("Error: Failed to read input: {}", e).red());
-        std::process::exit(1);
+    let mut kept = Vec::with_capacity(changes.len());
+    for change in changes {
+        let from_setup = match &change {
+            Change::Create(path) | Change::Modify(path) | Change::Chmod(path) | Change::Xattr(path) => match setup_state.get(path) {
+                Some(Some(hash)) => hash_file(&sandbox.join(path))? == *hash,
+                _ => false,
+            },
+            Change::Delete(path) => matches!(setup_state.get(path), Some(None)),
+            Change::Rename(..) => false,
+        };
+        
+        if from_setup {
+            debug!("Ignoring change made by setup: {:?}", change);
+        } else {
+            kept.push(change);
+        }
+    }
+    Ok(kept)
+}
+
+/// Human-readable byte count, e.g. `1.5 MB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
     
-    if input.trim().to_lowercase() != "y" {
-        info!("User aborted the operation");
-        println!("{}", "Aborted".red());
-        return;
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Parse a duration in seconds with an optional ms/s/m/h suffix, e.g. `90s`
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let seconds: f64 = match suffix.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown duration suffix in {:?}", value)),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("invalid duration {:?}", value))?;
+    Duration::try_from_secs_f64(number * seconds).map_err(|_| format!("invalid duration {:?}", value))
+}
+
+/// Parse a byte count with an optional K/M/G/T suffix (powers of 1024), e.g. `20M`
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("unknown size suffix in {:?}", value)),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("invalid size {:?}", value))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Log and report a fatal error, then exit
+fn exit_with_error(context: &str, e: impl std::fmt::Display) -> ! {
+    error!("{}: {}", context, e);
+    eprintln!("{}", format!("Error: {}: {}", context, e).red());
+    std::process::exit(1);
+}
+
+/// Destination for `--print-sandbox` markers: stdout, or the given file descriptor
+fn sandbox_marker_writer(fd: Option<i32>) -> std::io::Result<Box<dyn Write>> {
+    match fd {
+        None | Some(1) => Ok(Box::new(std::io::stdout())),
+        Some(2) => Ok(Box::new(std::io::stderr())),
+        Some(fd) => open_fd(fd),
     }
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> std::io::Result<Box<dyn Write>> {
+    use std::os::fd::BorrowedFd;
     
-    info!("User confirmed, applying {} changes", changes.len());
+    if fd < 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid file descriptor {}", fd)));
+    }
+    // Duplicate the descriptor first so an fd that was never opened is reported
+    // as an error instead of being written to blindly
+    // SAFETY: the descriptor is only borrowed for the duration of the call
+    let owned = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    Ok(Box::new(fs::File::from(owned)))
+}
+
+#[cfg(windows)]
+fn open_fd(fd: i32) -> std::io::Result<Box<dyn Write>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("writing to file descriptor {} is not supported on Windows", fd),
+    ))
+}
+
+/// Read the user's answer to a confirmation prompt.
+///
+/// When stdin is not a terminal (tust is at the end of a pipeline, or the
+/// stream belongs to the command), the answer is read from the controlling
+/// terminal instead. Falls back to stdin when there is no terminal at all,
+/// unless `allow_stdin` is false because the stream was already consumed,
+/// and fails instead of reading an empty answer when stdin is closed.
+fn read_confirmation(allow_stdin: bool) -> std::io::Result<String> {
+    let mut input = String::new();
     
-    // Apply changes to original directory
-    if let Err(e) = apply_changes(&current_dir, temp_path, &changes) {
-        error!("Failed to apply changes: {}", e);
-        eprintln!("{}", format!("Error: Failed to apply changes: {}", e).red());
-        std::process::exit(1);
+    if !allow_stdin || !std::io::stdin().is_terminal() {
+        match open_terminal() {
+            Ok(terminal) => {
+                debug!("Reading confirmation from the controlling terminal");
+                std::io::BufReader::new(terminal).read_line(&mut input)?;
+                return Ok(input);
+            }
+            Err(e) if !allow_stdin => {
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!("stdin is already in use and no terminal is available: {}", e),
+                ));
+            }
+            Err(e) => debug!("No controlling terminal available: {}", e),
+        }
     }
     
-    info!("Changes applied successfully");
-    println!("{}", "Changes applied successfully".green());
+    if std::io::stdin().read_line(&mut input)? == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "stdin is closed and no terminal is available to answer; use --yes or --no",
+        ));
+    }
+    Ok(input)
+}
+
+#[cfg(unix)]
+fn open_terminal() -> std::io::Result<fs::File> {
+    fs::File::open("/dev/tty")
+}
+
+#[cfg(windows)]
+fn open_terminal() -> std::io::Result<fs::File> {
+    fs::OpenOptions::new().read(true).write(true).open("CONIN$")
+}
+
+/// Print one line per change with a colored marker for its kind
+fn print_changes(changes: &[Change]) {
+    for change in changes {
+        print_change(change);
+    }
+}
+
+/// Write `changes` to `path` as a unified diff (`--output-patch`), warning
+/// about the files left out
+fn write_patch(path: &Path, original: &Path, modified: &Path, changes: &[Change], key: Option<&encryption::Key>) {
+    match patch::write(path, original, modified, changes, key) {
+        Ok(skipped) => {
+            info!("Wrote patch to {}", path.display());
+            for path in skipped {
+                warn!("{} is not a text file and was left out of the patch", path.display());
+                eprintln!(
+                    "{}",
+                    format!("Warning: {} is not a text file and was left out of the patch", path.display()).yellow()
+                );
+            }
+        }
+        Err(e) => exit_with_error("Failed to write patch", e),
+    }
+}
+
+fn print_change(change: &Change) {
+    print_change_named(change, "  ", None);
+}
+
+/// Print `change` after `indent`, with its path shown as `name` (the path
+/// of a rename's source) when given
+fn print_change_named(change: &Change, indent: &str, name: Option<&Path>) {
+    match change {
+        Change::Create(path) => {
+            debug!("Would create: {}", path.display());
+            println!("{}{}{}", indent, "+ ".green(), name.unwrap_or(path).display());
+        }
+        Change::Modify(path) => {
+            debug!("Would modify: {}", path.display());
+            println!("{}{}{}", indent, "~ ".yellow(), name.unwrap_or(path).display());
+        }
+        Change::Delete(path) => {
+            debug!("Would delete: {}", path.display());
+            println!("{}{}{}", indent, "- ".red(), name.unwrap_or(path).display());
+        }
+        Change::Rename(from, to) => {
+            debug!("Would rename: {} -> {}", from.display(), to.display());
+            println!("{}{}{} -> {}", indent, "> ".blue(), name.unwrap_or(from).display(), to.display());
+        }
+        Change::Chmod(path) => {
+            debug!("Would change permissions: {}", path.display());
+            println!("{}{}{}", indent, "* ".magenta(), name.unwrap_or(path).display());
+        }
+        Change::Xattr(path) => {
+            debug!("Would change extended attributes: {}", path.display());
+            println!("{}{}{}", indent, "@ ".cyan(), name.unwrap_or(path).display());
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Change {
     Create(PathBuf),
     Modify(PathBuf),
     Delete(PathBuf),
+    Rename(PathBuf, PathBuf),
+    /// Same content, different permissions (e.g. `chmod +x`)
+    Chmod(PathBuf),
+    /// Same content and permissions, different extended attributes
+    Xattr(PathBuf),
 }
 
 fn copy_directory(src: &Path, dest: &Path) -> std::io::Result<()> {
+    copy_directory_with_progress(src, dest, false, &mut |_| {})
+}
+
+/// Like `copy_directory`, calling `on_file` with the size of every copied
+/// file; with `link`, files are hard-linked instead where possible
+fn copy_directory_with_progress(
+    src: &Path,
+    dest: &Path,
+    link: bool,
+    on_file: &mut (dyn FnMut(u64) + Send),
+) -> std::io::Result<()> {
+    copy_tree(src, dest, Path::new(""), link, on_file)
+}
+
+/// Copy `src` to `dest`, where both are at `relative` in their trees, which
+/// decides what `filter` leaves out. The directories are created while
+/// walking the tree, then the files are copied in parallel.
+fn copy_tree(
+    src: &Path,
+    dest: &Path,
+    relative: &Path,
+    link: bool,
+    on_file: &mut (dyn FnMut(u64) + Send),
+) -> std::io::Result<()> {
+    let mut files = Vec::new();
+    create_tree(src, dest, relative, &mut files)?;
+    copy_files(files, link, on_file)
+}
+
+/// Copy `(from, to)` pairs in parallel. Files that are hard links to each
+/// other are copied once and linked again afterwards; with `link` every
+/// file is hard-linked to the original instead where possible.
+fn copy_files(files: Vec<(PathBuf, PathBuf)>, link: bool, on_file: &mut (dyn FnMut(u64) + Send)) -> std::io::Result<()> {
+    let (files, links) = if link { (files, Vec::new()) } else { hardlink::split(files) };
+    debug!("Copying {} files in parallel", files.len());
+    
+    let on_file = std::sync::Mutex::new(on_file);
+    files.par_iter().try_for_each(|(from, to)| {
+        interrupt::check()?;
+        let bytes = if link { reflink::link(from, to)? } else { reflink::copy(from, to)? };
+        (on_file.lock().unwrap_or_else(|e| e.into_inner()))(bytes);
+        Ok::<_, std::io::Error>(())
+    })?;
+    
+    let on_file = on_file.into_inner().unwrap_or_else(|e| e.into_inner());
+    for (existing, to) in links {
+        hardlink::create(&existing, &to)?;
+        on_file(0);
+    }
+    Ok(())
+}
+
+/// Create the directories of `src` below `dest` and collect the files to copy
+fn create_tree(src: &Path, dest: &Path, relative: &Path, files: &mut Vec<(PathBuf, PathBuf)>) -> std::io::Result<()> {
     fs::create_dir_all(dest)?;
     
     for entry in fs::read_dir(src)? {
         let entry = entry?;
         let entry_path = entry.path();
         let dest_path = dest.join(entry.file_name());
+        let relative_path = relative.join(entry.file_name());
+        let is_dir = symlink::is_dir(&entry_path);
+        if filter::is_excluded(&relative_path, is_dir) || filter::is_untracked(&relative_path, is_dir) {
+            debug!("Excluded from the sandbox: {}", relative_path.display());
+            continue;
+        }
         
-        if entry_path.is_dir() {
-            copy_directory(&entry_path, &dest_path)?;
+        if is_dir {
+            create_tree(&entry_path, &dest_path, &relative_path, files)?;
+        } else if special::is_special(&entry_path) {
+            special::copy(&entry_path, &dest_path, &relative_path)?;
         } else {
-            fs::copy(&entry_path, &dest_path)?;
+            files.push((entry_path, dest_path));
         }
     }
     
     Ok(())
 }
 
+/// Copy only `paths` (relative to `src`, as returned by `scope_paths`) into `dest`
+fn copy_paths_with_progress(
+    src: &Path,
+    dest: &Path,
+    paths: &[PathBuf],
+    link: bool,
+    on_file: &mut (dyn FnMut(u64) + Send),
+) -> std::io::Result<()> {
+    let mut files = Vec::new();
+    for path in paths {
+        let (src_path, dest_path) = (src.join(path), dest.join(path));
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        
+        let is_dir = symlink::is_dir(&src_path);
+        if filter::is_excluded(path, is_dir) || filter::is_untracked(path, is_dir) {
+            debug!("Excluded from the sandbox: {}", path.display());
+        } else if is_dir {
+            create_tree(&src_path, &dest_path, path, &mut files)?;
+        } else if special::is_special(&src_path) {
+            special::copy(&src_path, &dest_path, path)?;
+        } else if symlink::exists(&src_path) {
+            files.push((src_path, dest_path));
+        } else {
+            // The command may create it; only its creation will be reviewed
+            debug!("Scoped path does not exist yet: {}", path.display());
+        }
+    }
+    copy_files(files, link, on_file)
+}
+
+/// Turn `--files` arguments into sorted paths relative to `base`, dropping
+/// paths that are inside another given directory
+fn scope_paths(base: &Path, files: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut paths = Vec::with_capacity(files.len());
+    for file in files {
+        let relative = if file.is_absolute() {
+            file.strip_prefix(base).map_err(|_| format!("{} is outside {}", file.display(), base.display()))?
+        } else {
+            file.as_path()
+        };
+        let mut path = PathBuf::new();
+        for component in relative.components() {
+            match component {
+                std::path::Component::Normal(name) => path.push(name),
+                std::path::Component::CurDir => {}
+                _ => return Err(format!("{} must be inside {}", file.display(), base.display())),
+            }
+        }
+        if path.as_os_str().is_empty() {
+            return Err(format!("{} is the whole directory; leave out --files instead", file.display()));
+        }
+        paths.push(path);
+    }
+    
+    paths.sort();
+    let mut scope: Vec<PathBuf> = Vec::with_capacity(paths.len());
+    for path in paths {
+        if !scope.last().is_some_and(|last| path.starts_with(last)) {
+            scope.push(path);
+        }
+    }
+    Ok(scope)
+}
+
 fn compare_directories(
     original: &Path,
     modified: &Path,
+) -> std::io::Result<Vec<Change>> {
+    compare_scoped(original, modified, None)
+}
+
+/// Like `compare_directories`, but with `scope` only looks at the given
+/// relative paths (files or directories) and everything under them
+fn compare_scoped(
+    original: &Path,
+    modified: &Path,
+    scope: Option<&[PathBuf]>,
 ) -> std::io::Result<Vec<Change>> {
     let mut changes = Vec::new();
+    // Changes in path order whose `Modify` entries are only candidates until
+    // the contents are compared, a batch at a time in parallel
+    let mut batch = Vec::new();
+    
+    // Walk both trees in the same sorted order and merge-join them, so only
+    // the directories on the current path are held in memory. What the
+    // command creates must show up even with --git-tracked, so only the
+    // original is limited to the files that were copied.
+    let mut original_files = SortedWalk::new(original, scope, true)?;
+    let mut modified_files = SortedWalk::new(modified, scope, false)?;
+    let mut original_file = original_files.next().transpose()?;
+    let mut modified_file = modified_files.next().transpose()?;
     
-    // Get all files in both directories
-    let mut original_files = HashSet::new();
-    collect_files(original, Path::new(""), &mut original_files)?;
+    loop {
+        if batch.len() >= COMPARE_BATCH {
+            compare_batch(&mut batch, original, modified, &mut changes)?;
+        }
+        match (original_file.take(), modified_file.take()) {
+            (None, None) => break,
+            (Some(old), Some(new)) if old == new => {
+                batch.push(Change::Modify(old));
+                original_file = original_files.next().transpose()?;
+                modified_file = modified_files.next().transpose()?;
+            }
+            (Some(old), new) if new.as_ref().is_none_or(|new| old < *new) => {
+                batch.push(Change::Delete(old));
+                original_file = original_files.next().transpose()?;
+                modified_file = new;
+            }
+            (old, new) => {
+                batch.extend(new.map(Change::Create));
+                original_file = old;
+                modified_file = modified_files.next().transpose()?;
+            }
+        }
+    }
+    compare_batch(&mut batch, original, modified, &mut changes)?;
     
-    let mut modified_files = HashSet::new();
-    collect_files(modified, Path::new(""), &mut modified_files)?;
+    // With --git-tracked, untracked files of the original weren't walked,
+    // so a file "created" on top of one actually modifies it
+    let mut changes_over_untracked = Vec::with_capacity(changes.len());
+    for change in changes {
+        match change {
+            Change::Create(path) if filter::is_untracked(&path, false) && original.join(&path).is_file() => {
+                changes_over_untracked.extend(file_change(original, modified, &path)?);
+            }
+            change => changes_over_untracked.push(change),
+        }
+    }
     
-    // Find new files
-    for file in &modified_files {
-        if !original_files.contains(file) {
-            changes.push(Change::Create(file.clone()));
+    detect_directory_renames(original, modified, changes_over_untracked)
+}
+
+/// Files found in both trees are compared in parallel batches of this many changes
+const COMPARE_BATCH: usize = 1024;
+
+/// Move the changes of `batch` to `changes`, resolving each `Modify` entry
+/// with `file_change`
+fn compare_batch(
+    batch: &mut Vec<Change>,
+    original: &Path,
+    modified: &Path,
+    changes: &mut Vec<Change>,
+) -> std::io::Result<()> {
+    let resolved = batch
+        .par_iter()
+        .map(|change| match change {
+            Change::Modify(path) => file_change(original, modified, path),
+            change => Ok(Some(change.clone())),
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    batch.clear();
+    changes.extend(resolved.into_iter().flatten());
+    Ok(())
+}
+
+/// How the file at `path`, present in both trees, changed: `Modify` when
+/// its content differs, `Chmod` when only its permissions do and `Xattr`
+/// when only its extended attributes do. Metadata must never turn into a
+/// content rewrite.
+fn file_change(original: &Path, modified: &Path, path: &Path) -> std::io::Result<Option<Change>> {
+    let (original_path, modified_path) = (original.join(path), modified.join(path));
+    if !same_content(&original_path, &modified_path)? {
+        return Ok(Some(Change::Modify(path.to_path_buf())));
+    }
+    // Symlinks have no permissions of their own that could be applied
+    if symlink::target(&original_path).is_some() {
+        return Ok(None);
+    }
+    if fs::metadata(&original_path)?.permissions() != fs::metadata(&modified_path)?.permissions() {
+        return Ok(Some(Change::Chmod(path.to_path_buf())));
+    }
+    let same_attributes = xattrs::read(&original_path)? == xattrs::read(&modified_path)?;
+    Ok((!same_attributes).then(|| Change::Xattr(path.to_path_buf())))
+}
+
+/// Set for command runs unless --paranoid is given: the sandbox is copied
+/// with the original modification times, so a file with the same size and
+/// time as in the original was left alone by the command
+static TRUST_MTIME: AtomicBool = AtomicBool::new(false);
+
+/// Content identity of two files, ignoring any metadata differences
+fn same_content(original_path: &Path, modified_path: &Path) -> std::io::Result<bool> {
+    // Symlinks are the same when they point to the same place
+    match (symlink::target(original_path), symlink::target(modified_path)) {
+        (None, None) => {}
+        (original_target, modified_target) => return Ok(original_target == modified_target),
+    }
+    let (original_metadata, modified_metadata) = (fs::metadata(original_path)?, fs::metadata(modified_path)?);
+    if original_metadata.len() != modified_metadata.len() {
+        return Ok(false);
+    }
+    if TRUST_MTIME.load(Ordering::Relaxed)
+        && let (Ok(original_time), Ok(modified_time)) = (original_metadata.modified(), modified_metadata.modified())
+        && original_time == modified_time
+    {
+        return Ok(true);
+    }
+    // Still hard-linked (--link): the command didn't replace the file
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if (original_metadata.dev(), original_metadata.ino()) == (modified_metadata.dev(), modified_metadata.ino()) {
+            return Ok(true);
         }
     }
     
-    // Find deleted files
-    for file in &original_files {
-        if !modified_files.contains(file) {
-            changes.push(Change::Delete(file.clone()));
+    // Hashing streams the files instead of reading them into memory, and
+    // both sides are read at once
+    let (original_hash, modified_hash) = rayon::join(|| hash_file(original_path), || hash_file(modified_path));
+    Ok(original_hash? == modified_hash?)
+}
+
+/// Collapse delete+create pairs into a single rename when a whole directory
+/// disappeared and another one appeared with exactly the same files.
+fn detect_directory_renames(
+    original: &Path,
+    modified: &Path,
+    changes: Vec<Change>,
+) -> std::io::Result<Vec<Change>> {
+    // Group deleted files by the topmost directory that no longer exists,
+    // and created files by the topmost directory that did not exist before
+    let mut removed_dirs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    let mut added_dirs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for change in &changes {
+        match change {
+            Change::Delete(path) => {
+                if let Some(dir) = topmost_missing_dir(path, modified) {
+                    removed_dirs.entry(dir).or_default().push(path.clone());
+                }
+            }
+            Change::Create(path) => {
+                if let Some(dir) = topmost_missing_dir(path, original) {
+                    added_dirs.entry(dir).or_default().push(path.clone());
+                }
+            }
+            _ => {}
         }
     }
     
-    // Find modified files
-    for file in original_files.intersection(&modified_files) {
-        let original_path = original.join(file);
-        let modified_path = modified.join(file);
-        
-        if fs::metadata(&original_path)?.len() != fs::metadata(&modified_path)?.len() {
-            changes.push(Change::Modify(file.clone()));
-            continue;
+    if removed_dirs.is_empty() || added_dirs.is_empty() {
+        return Ok(changes);
+    }
+    
+    let mut added_by_signature = HashMap::new();
+    for (dir, files) in &added_dirs {
+        let signature = directory_signature(modified, dir, files)?;
+        added_by_signature.entry(signature).or_insert_with(Vec::new).push(dir.clone());
+    }
+    
+    let mut renames = Vec::new();
+    for (dir, files) in &removed_dirs {
+        let signature = directory_signature(original, dir, files)?;
+        if let Some(candidates) = added_by_signature.get_mut(&signature)
+            && let Some(target) = candidates.pop()
+        {
+            debug!("Detected directory rename: {} -> {}", dir.display(), target.display());
+            renames.push((dir.clone(), target));
         }
-        
-        let original_content = fs::read(&original_path)?;
-        let modified_content = fs::read(&modified_path)?;
-        
-        if original_content != modified_content {
-            changes.push(Change::Modify(file.clone()));
+    }
+    
+    if renames.is_empty() {
+        return Ok(changes);
+    }
+    
+    let mut result: Vec<Change> = changes
+        .into_iter()
+        .filter(|change| match change {
+            Change::Delete(path) => !renames.iter().any(|(from, _)| path.starts_with(from)),
+            Change::Create(path) => !renames.iter().any(|(_, to)| path.starts_with(to)),
+            _ => true,
+        })
+        .collect();
+    result.extend(renames.into_iter().map(|(from, to)| Change::Rename(from, to)));
+    
+    Ok(result)
+}
+
+/// Find the outermost ancestor directory of `path` that does not exist under `base`
+fn topmost_missing_dir(path: &Path, base: &Path) -> Option<PathBuf> {
+    let mut current = PathBuf::new();
+    let parent = path.parent()?;
+    for component in parent.components() {
+        current.push(component);
+        if !base.join(&current).is_dir() {
+            return Some(current);
+        }
+    }
+    None
+}
+
+/// Sorted list of (path relative to `dir`, content hash) for every file in `dir`
+fn directory_signature(
+    base: &Path,
+    dir: &Path,
+    files: &[PathBuf],
+) -> std::io::Result<Vec<(PathBuf, blake3::Hash)>> {
+    let mut signature = Vec::with_capacity(files.len());
+    for file in files {
+        let hash = hash_file(&base.join(file))?;
+        let relative = file.strip_prefix(dir).unwrap_or(file).to_path_buf();
+        signature.push((relative, hash));
+    }
+    signature.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(signature)
+}
+
+fn hash_file(path: &Path) -> std::io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    // A symlink is identified by its target, which can't collide with a file
+    // because of the prefix
+    if let Some(target) = symlink::target(path) {
+        hasher.update(b"tust symlink\0");
+        hasher.update(target.as_os_str().as_encoded_bytes());
+        return Ok(hasher.finalize());
+    }
+    hasher.update_reader(fs::File::open(path)?)?;
+    throttle::consume(hasher.count());
+    Ok(hasher.finalize())
+}
+
+/// Files under a directory as relative paths, in `Path` order. Directories
+/// are read one at a time, so memory use follows the depth of the tree
+/// rather than the number of files in it.
+struct SortedWalk {
+    base: PathBuf,
+    /// Skip what `--git-tracked` leaves out of the sandbox
+    tracked_only: bool,
+    /// Remaining entries of each directory on the current path, as
+    /// (relative path, whether it is a directory)
+    stack: Vec<std::vec::IntoIter<(PathBuf, bool)>>,
+}
+
+impl SortedWalk {
+    /// Walk all of `base`, or only the `scope` paths under it (which must be
+    /// sorted and not nested in each other). `tracked_only` also skips the
+    /// paths `--git-tracked` doesn't copy.
+    fn new(base: &Path, scope: Option<&[PathBuf]>, tracked_only: bool) -> std::io::Result<Self> {
+        let mut walk = SortedWalk { base: base.to_path_buf(), tracked_only, stack: Vec::new() };
+        match scope {
+            None => walk.enter(Path::new(""))?,
+            Some(paths) => {
+                let roots: Vec<(PathBuf, bool)> = paths
+                    .iter()
+                    .filter(|path| symlink::exists(&base.join(path)) && !special::is_special(&base.join(path)))
+                    .map(|path| (path.clone(), symlink::is_dir(&base.join(path))))
+                    .filter(|(path, is_dir)| !walk.skips(path, *is_dir))
+                    .collect();
+                walk.stack.push(roots.into_iter());
+            }
+        }
+        Ok(walk)
+    }
+    
+    fn enter(&mut self, dir: &Path) -> std::io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.base.join(dir))? {
+            let entry = entry?;
+            let (path, is_dir) = (dir.join(entry.file_name()), symlink::is_dir(&entry.path()));
+            if !self.skips(&path, is_dir) && !special::is_special(&entry.path()) {
+                entries.push((path, is_dir));
+            }
         }
+        // Sorting names within each directory yields paths in `Path` order
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        self.stack.push(entries.into_iter());
+        Ok(())
+    }
+    
+    fn skips(&self, path: &Path, is_dir: bool) -> bool {
+        filter::is_excluded(path, is_dir) || (self.tracked_only && filter::is_untracked(path, is_dir))
     }
+}
+
+impl Iterator for SortedWalk {
+    type Item = std::io::Result<PathBuf>;
     
-    Ok(changes)
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some((path, is_dir)) = self.stack.last_mut()?.next() else {
+                self.stack.pop();
+                continue;
+            };
+            if !is_dir {
+                return Some(Ok(path));
+            }
+            if let Err(e) = self.enter(&path) {
+                return Some(Err(e));
+            }
+        }
+    }
 }
 
 fn collect_files(base: &Path, prefix: &Path, files: &mut HashSet<PathBuf>) -> std::io::Result<()> {
@@ -264,10 +2983,10 @@ fn collect_files(base: &Path, prefix: &Path, files: &mut HashSet<PathBuf>) -> st
         let entry_name = entry.file_name();
         let current_path = prefix.join(entry_name);
         
-        if entry_path.is_dir() {
+        if symlink::is_dir(&entry_path) {
             // Recursively collect files from subdirectory, preserving the path prefix
             collect_files(&entry_path, &current_path, files)?;
-        } else {
+        } else if !special::is_special(&entry_path) {
             files.insert(current_path);
         }
     }
@@ -279,41 +2998,164 @@ fn apply_changes(
     original: &Path,
     modified: &Path,
     changes: &[Change],
+    lock_timeout: Duration,
 ) -> std::io::Result<()> {
+    // Files locked by another process are retried and then reported
+    // together, so one open editor doesn't stop the rest of the apply
+    let mut locked = Vec::new();
+    let mut links = hardlink::Links::default();
     for change in changes {
-        match change {
-            Change::Create(path) => {
-                let original_path = original.join(path);
-                let modified_path = modified.join(path);
-                
-                if let Some(parent) = original_path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-                
-                fs::copy(modified_path, original_path)?;
+        match retry_while_locked(lock_timeout, || apply_change(original, modified, change, &mut links)) {
+            Ok(()) => {}
+            Err(e) if is_locked_error(&e) => {
+                warn!("Giving up on locked file: {:?}: {}", change, e);
+                locked.push(change);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    
+    if !locked.is_empty() {
+        let paths: Vec<String> = locked
+            .iter()
+            .map(|change| match change {
+                Change::Create(path)
+                | Change::Modify(path)
+                | Change::Delete(path)
+                | Change::Chmod(path)
+                | Change::Xattr(path) => path.display().to_string(),
+                Change::Rename(from, to) => format!("{} -> {}", from.display(), to.display()),
+            })
+            .collect();
+        return Err(std::io::Error::other(format!(
+            "{} change(s) could not be applied because the files are in use by another process \
+             (e.g. open in an editor or being scanned by antivirus): {}",
+            locked.len(),
+            paths.join(", ")
+        )));
+    }
+    
+    Ok(())
+}
+
+/// Apply one change; `links` tracks created files so those the command
+/// created as hard links to each other are linked in the original too
+fn apply_change(original: &Path, modified: &Path, change: &Change, links: &mut hardlink::Links) -> std::io::Result<()> {
+    match change {
+        Change::Create(path) => {
+            let original_path = original.join(path);
+            let modified_path = modified.join(path);
+            
+            if let Some(parent) = original_path.parent() {
+                fs::create_dir_all(parent)?;
             }
-            Change::Modify(path) => {
-                let original_path = original.join(path);
-                let modified_path = modified.join(path);
-                
-                fs::copy(modified_path, original_path)?;
+            
+            match links.get(&modified_path) {
+                Some(existing) => hardlink::create(existing, &original_path)?,
+                None => replace_file(&modified_path, &original_path)?,
             }
-            Change::Delete(path) => {
-                let original_path = original.join(path);
-                fs::remove_file(original_path)?;
+            links.record(&modified_path, &original_path);
+        }
+        Change::Modify(path) => {
+            let original_path = original.join(path);
+            let modified_path = modified.join(path);
+            
+            replace_file(&modified_path, &original_path)?;
+        }
+        Change::Delete(path) => {
+            let original_path = original.join(path);
+            fs::remove_file(original_path)?;
+        }
+        Change::Rename(from, to) => {
+            let original_to = original.join(to);
+            
+            if let Some(parent) = original_to.parent() {
+                fs::create_dir_all(parent)?;
             }
+            
+            fs::rename(original.join(from), original_to)?;
+        }
+        Change::Chmod(path) => {
+            fs::set_permissions(original.join(path), fs::metadata(modified.join(path))?.permissions())?;
+            xattrs::copy(&modified.join(path), &original.join(path))?;
+        }
+        Change::Xattr(path) => {
+            xattrs::copy(&modified.join(path), &original.join(path))?;
         }
     }
     
     Ok(())
 }
 
-/// Clean up all temporary directories created by tust
-fn clean_temporary_directories() -> std::io::Result<()> {
-    // Get the system temporary directory
-    let temp_dir = std::env::temp_dir();
+/// Put a copy of `modified` (a file or a symlink) at `original`
+fn replace_file(modified: &Path, original: &Path) -> std::io::Result<()> {
+    // Copying onto a symlink would write to wherever it points
+    if (symlink::target(modified).is_some() || symlink::target(original).is_some()) && symlink::exists(original) {
+        fs::remove_file(original)?;
+    }
+    // Written in place, keeping hard links, with the sandbox's metadata
+    reflink::copy(modified, original).map(|_| ())
+}
+
+/// Retry `operation` with exponential backoff for up to `timeout` while it
+/// fails because a file is locked by another process
+fn retry_while_locked<T>(timeout: Duration, mut operation: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    let deadline = std::time::Instant::now() + timeout;
+    let mut delay = Duration::from_millis(50);
+    loop {
+        match operation() {
+            Err(e) if is_locked_error(&e) && std::time::Instant::now() + delay < deadline => {
+                debug!("File is locked, retrying in {:?}: {}", delay, e);
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(Duration::from_secs(1));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `e` means another process holds the file open (Windows sharing
+/// and lock violations; other platforms don't lock files this way)
+fn is_locked_error(e: &std::io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    cfg!(windows) && matches!(e.raw_os_error(), Some(32) | Some(33))
+}
+
+/// `GIT_CEILING_DIRECTORIES` for commands run in `sandbox`, so git can't
+/// find a repository above it, such as the project's own when the sandbox
+/// is `.tust/cache` or `--temp-dir` points into the project
+fn git_ceiling(sandbox: &Path) -> std::ffi::OsString {
+    let existing = std::env::var_os("GIT_CEILING_DIRECTORIES").unwrap_or_default();
+    let ceilings = sandbox.parent().map(Path::to_path_buf).into_iter().chain(std::env::split_paths(&existing));
+    std::env::join_paths(ceilings).unwrap_or(existing)
+}
+
+/// Directory sandboxes go in: `--temp-dir`, then `TUST_TMPDIR`, then the
+/// system temporary directory
+fn sandbox_parent(temp_dir: Option<&Path>) -> PathBuf {
+    temp_dir
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os("TUST_TMPDIR").filter(|dir| !dir.is_empty()).map(PathBuf::from))
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Create an empty sandbox in `sandbox_parent(temp_dir)`
+fn create_sandbox(temp_dir: Option<&Path>) -> std::io::Result<tempfile::TempDir> {
+    tempfile::Builder::new().prefix("tust-").tempdir_in(sandbox_parent(temp_dir))
+}
+
+/// Create a sandbox for a session kept with `--keep`, which `--clean` skips
+fn create_kept_sandbox(temp_dir: Option<&Path>) -> std::io::Result<tempfile::TempDir> {
+    tempfile::Builder::new().prefix(session::SANDBOX_PREFIX).tempdir_in(sandbox_parent(temp_dir))
+}
+
+/// Clean up all temporary directories created by tust in `sandbox_parent(temp_dir)`,
+/// except the sandboxes of kept sessions
+#[allow(clippy::collapsible_if)]
+fn clean_temporary_directories(temp_dir: Option<&Path>) -> std::io::Result<()> {
+    let temp_dir = sandbox_parent(temp_dir);
     debug!("Scanning temporary directory: {}", temp_dir.display());
-    let mut cleaned_count = 0;
+    let mut cleaned_count = backend::clean_zfs()?;
     
     // Iterate through all entries in the temporary directory
     for entry in fs::read_dir(temp_dir)? {
@@ -324,7 +3166,9 @@ fn clean_temporary_directories() -> std::io::Result<()> {
         if entry_path.is_dir() {
             if let Some(dir_name) = entry_path.file_name() {
                 if let Some(dir_name_str) = dir_name.to_str() {
-                    if dir_name_str.starts_with("tust-") {
+                    if dir_name_str.starts_with(session::SANDBOX_PREFIX) {
+                        debug!("Skipping the sandbox of a kept session: {}", entry_path.display());
+                    } else if dir_name_str.starts_with("tust-") {
                         debug!("Found tust temporary directory: {}", entry_path.display());
                         // Delete the directory and its contents
                         match fs::remove_dir_all(&entry_path) {
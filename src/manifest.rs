@@ -0,0 +1,157 @@
+//! Machine-readable description of a change set.
+//!
+//! Lists every change with the BLAKE3 hash, size and mode of the file before
+//! and after, marking changes to binary files with `"binary": true`.
+//! `--manifest` writes it to a file, so deployment tooling can check that
+//! what ends up on disk is exactly what was reviewed, and
+//! `--format json`/`ndjson` prints it instead of the colored change list;
+//! the JSON document also has the command's duration and resource usage.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::child::Usage;
+use crate::{Change, hash_file, patch, symlink};
+
+/// How the change list is printed
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Colored list for people
+    Human,
+    /// One JSON document with the directory and all changes
+    Json,
+    /// One JSON object per change and line
+    Ndjson,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    directory: &'a Path,
+    /// What the command used, in `--format json` output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<&'a Usage>,
+    changes: Vec<ManifestChange>,
+}
+
+#[derive(Serialize)]
+struct ManifestChange {
+    kind: &'static str,
+    path: PathBuf,
+    /// Destination of a directory rename
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before: Option<FileState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after: Option<FileState>,
+    /// Set when either version of the file is binary
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    binary: bool,
+}
+
+#[derive(Serialize)]
+struct FileState {
+    hash: String,
+    size: u64,
+    /// Unix permission bits in octal (e.g. "0644"); absent on other platforms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+}
+
+impl FileState {
+    fn of(path: &Path) -> std::io::Result<Self> {
+        // A symlink's own size and mode, not those of what it points to
+        let metadata = if symlink::target(path).is_some() { fs::symlink_metadata(path)? } else { fs::metadata(path)? };
+        Ok(FileState { hash: hash_file(path)?.to_hex().to_string(), size: metadata.len(), mode: mode(&metadata) })
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    
+    Some(format!("{:04o}", metadata.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &fs::Metadata) -> Option<String> {
+    None
+}
+
+fn entries(original: &Path, modified: &Path, changes: &[Change]) -> std::io::Result<Vec<ManifestChange>> {
+    let mut entries = Vec::with_capacity(changes.len());
+    for change in changes {
+        let (kind, path, to, before, after) = match change {
+            Change::Create(path) => ("create", path, None, None, Some(FileState::of(&modified.join(path))?)),
+            Change::Modify(path) => (
+                "modify",
+                path,
+                None,
+                Some(FileState::of(&original.join(path))?),
+                Some(FileState::of(&modified.join(path))?),
+            ),
+            Change::Chmod(path) => (
+                "chmod",
+                path,
+                None,
+                Some(FileState::of(&original.join(path))?),
+                Some(FileState::of(&modified.join(path))?),
+            ),
+            Change::Xattr(path) => (
+                "xattr",
+                path,
+                None,
+                Some(FileState::of(&original.join(path))?),
+                Some(FileState::of(&modified.join(path))?),
+            ),
+            Change::Delete(path) => ("delete", path, None, Some(FileState::of(&original.join(path))?), None),
+            Change::Rename(from, to) => ("rename", from, Some(to.clone()), None, None),
+        };
+        let binary = match change {
+            Change::Create(path) => patch::is_binary(&modified.join(path)),
+            Change::Modify(path) => patch::is_binary(&original.join(path)) || patch::is_binary(&modified.join(path)),
+            Change::Delete(path) => patch::is_binary(&original.join(path)),
+            Change::Rename(..) | Change::Chmod(_) | Change::Xattr(_) => false,
+        };
+        entries.push(ManifestChange { kind, path: path.clone(), to, before, after, binary });
+    }
+    Ok(entries)
+}
+
+/// Write the manifest of `changes` between `original` and `modified` to `path`
+pub fn write(path: &Path, original: &Path, modified: &Path, changes: &[Change]) -> std::io::Result<()> {
+    fs::write(path, to_json(original, modified, changes)?)
+}
+
+/// The manifest of `changes` between `original` and `modified` as JSON
+pub fn to_json(original: &Path, modified: &Path, changes: &[Change]) -> std::io::Result<Vec<u8>> {
+    let manifest = Manifest { directory: original, usage: None, changes: entries(original, modified, changes)? };
+    let mut data = serde_json::to_vec_pretty(&manifest).map_err(std::io::Error::other)?;
+    data.push(b'\n');
+    Ok(data)
+}
+
+/// Print the changes to stdout in a machine-readable `format`, with the
+/// command's `usage` in the JSON document
+pub fn print(format: Format, original: &Path, modified: &Path, changes: &[Change], usage: Option<&Usage>) -> std::io::Result<()> {
+    let entries = entries(original, modified, changes)?;
+    let mut stdout = std::io::stdout().lock();
+    match format {
+        Format::Human => {}
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut stdout, &Manifest { directory: original, usage, changes: entries })?;
+            writeln!(stdout)?;
+        }
+        Format::Ndjson => {
+            for entry in entries {
+                serde_json::to_writer(&mut stdout, &entry)?;
+                writeln!(stdout)?;
+            }
+        }
+    }
+    stdout.flush()
+}
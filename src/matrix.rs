@@ -0,0 +1,208 @@
+//! Running the command under several environment variants (`--matrix`).
+//!
+//! `--matrix KEY=V1,V2` (repeatable, or `matrix` in the configuration's
+//! `[defaults]`) runs the command once for every combination of the values,
+//! each in a sandbox of its own: tust runs itself again per variant with the
+//! variables set through `--env`, which shows that variant's changes without
+//! applying them. The manifests of the runs are then compared, listing the
+//! paths that end up differently across the variants.
+//!
+//! `tust ab -- cmdA -- cmdB` compares two commands the same way, both
+//! sandboxes seeded from one baseline of the project.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use colored::Colorize;
+use log::{debug, info};
+use serde::Deserialize;
+
+/// What a run changed: a description of each change by path
+type Changes = BTreeMap<PathBuf, String>;
+
+/// One `--matrix` variable and the values it takes
+#[derive(Clone, Debug)]
+pub struct Axis {
+    pub key: String,
+    pub values: Vec<String>,
+}
+
+/// Parse the value of `--matrix`: `KEY=V1,V2,...`
+pub fn parse(value: &str) -> Result<Axis, String> {
+    let (key, values) = value.split_once('=').ok_or_else(|| format!("expected KEY=V1,V2 in {:?}", value))?;
+    if key.is_empty() {
+        return Err(format!("missing variable name in {:?}", value));
+    }
+    Ok(Axis { key: key.to_string(), values: values.split(',').map(str::to_string).collect() })
+}
+
+/// A run's manifest, as far as the comparison needs it
+#[derive(Deserialize)]
+struct Manifest {
+    changes: Vec<ManifestChange>,
+}
+
+#[derive(Deserialize)]
+struct ManifestChange {
+    kind: String,
+    path: PathBuf,
+    #[serde(default)]
+    to: Option<PathBuf>,
+    #[serde(default)]
+    after: Option<FileState>,
+}
+
+#[derive(Deserialize)]
+struct FileState {
+    hash: String,
+}
+
+/// Every combination of the values of `axes`, as `--env` assignments
+fn variants(axes: &[Axis]) -> Vec<Vec<(String, String)>> {
+    axes.iter().fold(vec![Vec::new()], |variants, axis| {
+        variants
+            .iter()
+            .flat_map(|variant| {
+                axis.values.iter().map(move |value| {
+                    let mut variant = variant.clone();
+                    variant.push((axis.key.clone(), value.clone()));
+                    variant
+                })
+            })
+            .collect()
+    })
+}
+
+fn label(variant: &[(String, String)]) -> String {
+    variant.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(" ")
+}
+
+/// Run tust with its own arguments once per variant of `axes`, adding `--no`
+/// unless `reviewing_only` says it is given already, then print how the
+/// variants' changes differ; returns the exit code
+pub fn run(axes: &[Axis], reviewing_only: bool) -> std::io::Result<i32> {
+    let manifests = tempfile::Builder::new().prefix("tust-matrix-").tempdir_in(crate::sandbox_parent(None))?;
+    let variants = variants(axes);
+    info!("Running {} matrix variants", variants.len());
+    
+    let mut results = Vec::new();
+    for (index, variant) in variants.iter().enumerate() {
+        let label = label(variant);
+        println!("{}", format!("\nVariant {}/{}: {}", index + 1, variants.len(), label).blue().bold());
+        let mut args: Vec<OsString> = Vec::new();
+        for (key, value) in variant {
+            args.extend(["--env".into(), format!("{}={}", key, value).into()]);
+        }
+        if !reviewing_only {
+            args.push("--no".into());
+        }
+        args.extend(std::env::args_os().skip(1));
+        let changes = run_variant(&label, args, &manifests.path().join(format!("{}.json", index)))?;
+        results.push((label, changes));
+    }
+    
+    compare(&results);
+    Ok(exit_code(&results))
+}
+
+/// Run `cmd_a` and `cmd_b` (`tust ab`) in sandboxes seeded from one fresh
+/// baseline of the current directory, showing the changes of each against
+/// it, then print how their changes differ; returns the exit code
+pub fn ab(cmd_a: &[String], cmd_b: &[String]) -> std::io::Result<i32> {
+    let current_dir = std::env::current_dir()?;
+    let manifests = tempfile::Builder::new().prefix("tust-ab-").tempdir_in(crate::sandbox_parent(None))?;
+    let baseline = crate::baseline::create(&current_dir)?;
+    info!("Comparing {:?} and {:?} from baseline {}", cmd_a, cmd_b, baseline.id);
+    
+    let mut results = Vec::new();
+    for (label, command) in [("A", cmd_a), ("B", cmd_b)] {
+        println!("{}", format!("\nCommand {}: {}", label, crate::redact::redact(&command.join(" "))).blue().bold());
+        let mut args: Vec<OsString> = vec!["--from-baseline".into(), baseline.id.clone().into(), "--no".into(), "--".into()];
+        args.extend(command.iter().map(OsString::from));
+        match run_variant(label, args, &manifests.path().join(format!("{}.json", label))) {
+            Ok(changes) => results.push((label.to_string(), changes)),
+            Err(e) => {
+                baseline.remove();
+                return Err(e);
+            }
+        }
+    }
+    baseline.remove();
+    
+    compare(&results);
+    Ok(exit_code(&results))
+}
+
+/// Run tust for one variant with `args`, writing its manifest to `manifest`;
+/// `None` when the run failed
+fn run_variant(label: &str, args: Vec<OsString>, manifest: &Path) -> std::io::Result<Option<Changes>> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("--no-matrix").arg("--manifest").arg(manifest).args(args);
+    debug!("Running variant {:?}", command);
+    let status = command.status()?;
+    let changes = if status.success() { read(manifest) } else { None };
+    if changes.is_none() {
+        println!("{}", format!("{} failed ({})", label, status).red());
+    }
+    Ok(changes)
+}
+
+/// 0 when every variant ran, 1 otherwise
+fn exit_code(results: &[(String, Option<Changes>)]) -> i32 {
+    if results.iter().all(|(_, changes)| changes.is_some()) { 0 } else { 1 }
+}
+
+/// The changes in the manifest at `path`, described by path
+fn read(path: &Path) -> Option<Changes> {
+    let data = std::fs::read(path).ok()?;
+    let manifest: Manifest = serde_json::from_slice(&data).ok()?;
+    Some(
+        manifest
+            .changes
+            .into_iter()
+            .map(|change| {
+                let description = match (&change.to, &change.after) {
+                    (Some(to), _) => format!("{} to {}", change.kind, to.display()),
+                    (None, Some(after)) => format!("{} ({})", change.kind, &after.hash[..after.hash.len().min(12)]),
+                    (None, None) => change.kind.clone(),
+                };
+                (change.path, description)
+            })
+            .collect(),
+    )
+}
+
+/// Print the paths the variants that ran changed differently
+fn compare(results: &[(String, Option<Changes>)]) {
+    let ran: Vec<_> = results.iter().filter_map(|(label, changes)| Some((label, changes.as_ref()?))).collect();
+    println!("{}", format!("\nComparison of {} variants:", ran.len()).blue().bold());
+    if ran.len() < 2 {
+        println!("  Nothing to compare");
+        return;
+    }
+    
+    let mut paths: Vec<&PathBuf> = ran.iter().flat_map(|(_, changes)| changes.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut same = 0;
+    let mut different = 0;
+    for path in paths {
+        let outcomes: Vec<Option<&String>> = ran.iter().map(|(_, changes)| changes.get(path)).collect();
+        if outcomes.windows(2).all(|pair| pair[0] == pair[1]) {
+            same += 1;
+            continue;
+        }
+        different += 1;
+        println!("  {}", path.display().to_string().bold());
+        for ((label, _), outcome) in ran.iter().zip(outcomes) {
+            println!("    {:<30} {}", label, outcome.map_or_else(|| "unchanged".dimmed().to_string(), String::clone));
+        }
+    }
+    if different == 0 {
+        println!("{}", format!("  All {} changed paths are the same in every variant", same).green());
+    } else {
+        println!("  {} of {} changed paths differ between the variants", different, different + same);
+    }
+}
@@ -0,0 +1,113 @@
+//! Line-based three-way merge.
+
+/// Merge the changes from `base` to `mine` and from `base` to `theirs`.
+///
+/// Returns the merged text and whether it merged cleanly. Regions changed
+/// differently on both sides are kept with conflict markers.
+pub fn merge3(base: &str, mine: &str, theirs: &str) -> (String, bool) {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let mine: Vec<&str> = mine.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+    
+    let mine_match = match_lines(&base, &mine);
+    let theirs_match = match_lines(&base, &theirs);
+    
+    let mut merged = String::new();
+    let mut clean = true;
+    let (mut b, mut m, mut t) = (0, 0, 0);
+    loop {
+        // Next base line that is still present, unchanged, on both sides
+        let sync = (b..base.len()).find_map(|i| match (mine_match[i], theirs_match[i]) {
+            (Some(mj), Some(tk)) if mj >= m && tk >= t => Some((i, mj, tk)),
+            _ => None,
+        });
+        
+        let (bi, mj, tk) = sync.unwrap_or((base.len(), mine.len(), theirs.len()));
+        clean &= merge_chunk(&base[b..bi], &mine[m..mj], &theirs[t..tk], &mut merged);
+        
+        if sync.is_none() {
+            break;
+        }
+        merged.push_str(base[bi]);
+        (b, m, t) = (bi + 1, mj + 1, tk + 1);
+    }
+    
+    (merged, clean)
+}
+
+/// For every line of `base`, the index of the same line in `other` if it survived
+fn match_lines(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    let (mut b, mut o) = (0, 0);
+    for result in diff::slice(base, other) {
+        match result {
+            diff::Result::Both(..) => {
+                matches[b] = Some(o);
+                b += 1;
+                o += 1;
+            }
+            diff::Result::Left(_) => b += 1,
+            diff::Result::Right(_) => o += 1,
+        }
+    }
+    matches
+}
+
+fn merge_chunk(base: &[&str], mine: &[&str], theirs: &[&str], merged: &mut String) -> bool {
+    let side = if mine == base || mine == theirs {
+        theirs
+    } else if theirs == base {
+        mine
+    } else {
+        push_marked(merged, "<<<<<<< original", mine);
+        push_marked(merged, "=======", theirs);
+        merged.push_str(">>>>>>> sandbox\n");
+        return false;
+    };
+    
+    side.iter().for_each(|line| merged.push_str(line));
+    true
+}
+
+fn push_marked(merged: &mut String, marker: &str, lines: &[&str]) {
+    if !merged.is_empty() && !merged.ends_with('\n') {
+        merged.push('\n');
+    }
+    merged.push_str(marker);
+    merged.push('\n');
+    lines.iter().for_each(|line| merged.push_str(line));
+    if !merged.ends_with('\n') {
+        merged.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn changes_to_different_lines_merge_cleanly() {
+        let base = "a\nb\nc\nd\n";
+        assert_eq!(merge3(base, "A\nb\nc\nd\n", "a\nb\nC\n"), ("A\nb\nC\n".to_string(), true));
+        assert_eq!(merge3(base, "a\nb\nc\nd\ne\n", "a\nc\nd\n"), ("a\nc\nd\ne\n".to_string(), true));
+    }
+    
+    #[test]
+    fn the_same_change_on_both_sides_merges_cleanly() {
+        assert_eq!(merge3("a\nb\nc\n", "a\nX\nc\n", "a\nX\nc\n"), ("a\nX\nc\n".to_string(), true));
+    }
+    
+    #[test]
+    fn different_changes_to_the_same_lines_conflict() {
+        let (merged, clean) = merge3("a\nb\nc\n", "a\nmine\nc\n", "a\ntheirs\nc\n");
+        assert!(!clean);
+        assert_eq!(merged, "a\n<<<<<<< original\nmine\n=======\ntheirs\n>>>>>>> sandbox\nc\n");
+    }
+    
+    #[test]
+    fn markers_start_on_their_own_line() {
+        let (merged, clean) = merge3("a\nb", "a\nmine", "a\ntheirs");
+        assert!(!clean);
+        assert_eq!(merged, "a\n<<<<<<< original\nmine\n=======\ntheirs\n>>>>>>> sandbox\n");
+    }
+}
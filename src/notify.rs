@@ -0,0 +1,114 @@
+//! Notifications when the command finished (`--notify`).
+//!
+//! Once the command exited and its changes are known, a desktop notification
+//! says how it went, so a long build under tust doesn't need watching. With
+//! `--notify-webhook` the same is also posted as JSON to a URL, using
+//! `curl`. Neither waits for delivery, and a failure is only a warning.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use colored::Colorize;
+use log::{debug, warn};
+use serde::Serialize;
+
+/// How the run went, as notified
+#[derive(Serialize)]
+pub struct Outcome {
+    pub command: String,
+    pub directory: String,
+    /// `None` when the command was killed by a signal
+    pub exit_code: Option<i32>,
+    /// `None` when the command failed and nothing was compared
+    pub changes: Option<usize>,
+    pub duration_secs: f64,
+}
+
+impl Outcome {
+    pub fn new(command: &[String], directory: &Path, exit_code: Option<i32>, changes: Option<usize>, duration: Duration) -> Self {
+        Outcome {
+            command: crate::redact::redact(&command.join(" ")).into_owned(),
+            directory: directory.display().to_string(),
+            exit_code,
+            changes,
+            duration_secs: duration.as_secs_f64(),
+        }
+    }
+    
+    fn title(&self) -> String {
+        match self.exit_code {
+            Some(0) => "tust: command finished".to_string(),
+            Some(code) => format!("tust: command failed with exit code {}", code),
+            None => "tust: command was killed".to_string(),
+        }
+    }
+    
+    fn body(&self) -> String {
+        let changes = match self.changes {
+            Some(0) => "no changes".to_string(),
+            Some(1) => "1 change to review".to_string(),
+            Some(changes) => format!("{} changes to review", changes),
+            None => "nothing to review".to_string(),
+        };
+        format!("{} ({:.0}s, {})", self.command, self.duration_secs, changes)
+    }
+}
+
+/// Show `outcome` on the desktop and post it to `webhook` if given
+pub fn send(outcome: &Outcome, webhook: Option<&str>) {
+    if let Err(e) = desktop(&outcome.title(), &outcome.body()).stdin(Stdio::null()).stdout(Stdio::null()).spawn() {
+        report("show a desktop notification", e);
+    }
+    if let Some(url) = webhook {
+        let payload = serde_json::to_string(outcome).unwrap_or_default();
+        debug!("Posting the outcome to {}", url);
+        let posted = Command::new("curl")
+            .args(["--silent", "--show-error", "--max-time", "10", "--header", "Content-Type: application/json"])
+            .arg("--data-binary")
+            .arg(payload)
+            .arg(url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn();
+        if let Err(e) = posted {
+            report("post to the notification webhook", e);
+        }
+    }
+}
+
+fn report(action: &str, e: std::io::Error) {
+    warn!("Failed to {}: {}", action, e);
+    eprintln!("{}", format!("Warning: failed to {}: {}", action, e).yellow());
+}
+
+#[cfg(target_os = "macos")]
+fn desktop(title: &str, body: &str) -> Command {
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut command = Command::new("osascript");
+    command.arg("-e").arg(format!("display notification {} with title {}", quote(body), quote(title)));
+    command
+}
+
+#[cfg(windows)]
+fn desktop(title: &str, body: &str) -> Command {
+    let quote = |text: &str| format!("'{}'", text.replace('\'', "''"));
+    let script = format!(
+        "Add-Type -AssemblyName System.Windows.Forms; \
+         $icon = New-Object System.Windows.Forms.NotifyIcon; \
+         $icon.Icon = [System.Drawing.SystemIcons]::Information; $icon.Visible = $true; \
+         $icon.ShowBalloonTip(10000, {}, {}, 'Info'); Start-Sleep -Seconds 10; $icon.Dispose()",
+        quote(title),
+        quote(body)
+    );
+    let mut command = Command::new("powershell");
+    command.args(["-NoProfile", "-WindowStyle", "Hidden", "-Command"]).arg(script);
+    command
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn desktop(title: &str, body: &str) -> Command {
+    let mut command = Command::new("notify-send");
+    command.args(["--app-name", "tust"]).arg(title).arg(body);
+    command
+}
@@ -0,0 +1,231 @@
+//! The command's output, recorded for its session (`--keep`).
+//!
+//! The output is still shown while the command runs, and also copied to a
+//! log that is stored as `output.log` with the session. A failed run then
+//! keeps its context after the terminal has scrolled away or the sandbox is
+//! gone, and `tust sessions show` prints its last lines. With
+//! `--quiet-command` the output is only recorded, for those last lines.
+//! With `--redact` the output is shown through tust too, so the patterns can
+//! be masked; the log holds it as the command wrote it.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::warn;
+
+/// Lines of output shown when the command fails, and by `sessions show`
+pub const TAIL_LINES: usize = 20;
+
+/// Width of the rules around the command's output
+const RULE_WIDTH: usize = 60;
+
+/// Longest partial line `Shown` holds back for redaction
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Where the command's output is recorded until it is stored with the session
+#[derive(Clone)]
+pub struct Log(Arc<Mutex<tempfile::NamedTempFile>>);
+
+impl Log {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Log(Arc::new(Mutex::new(tempfile::NamedTempFile::new_in(crate::sandbox_parent(None))?))))
+    }
+    
+    pub fn write(&self, data: &[u8]) {
+        if let Ok(mut file) = self.0.lock()
+            && let Err(e) = file.write_all(data)
+        {
+            warn!("Failed to record the command's output: {}", e);
+        }
+    }
+    
+    /// Store the recorded output at `to`
+    pub fn save(&self, to: &Path, key: Option<&crate::encryption::Key>) -> std::io::Result<()> {
+        let file = self.0.lock().map_err(|_| std::io::Error::other("output log is poisoned"))?;
+        match key {
+            Some(key) => crate::encryption::write(to, &std::fs::read(file.path())?, Some(key)),
+            None => std::fs::copy(file.path(), to).map(|_| ()),
+        }
+    }
+    
+    /// The last lines of the recorded output
+    pub fn tail(&self) -> Vec<String> {
+        match self.0.lock() {
+            Ok(file) => tail(file.path()),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// Have `command` write its output into pipes that `tee` copies
+pub fn capture(command: &mut Command) {
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+}
+
+/// Run `command` to completion like `Command::status`, but with its output
+/// shown through tust when there are redaction patterns to mask; its stdout
+/// goes to tust's stderr when `to_stderr`
+pub fn status(command: &mut Command, to_stderr: bool) -> std::io::Result<ExitStatus> {
+    if !crate::redact::active() {
+        return command.status();
+    }
+    capture(command);
+    let mut process = command.spawn()?;
+    let copier = tee(&mut process, None, to_stderr, true);
+    let status = process.wait();
+    copier.finish();
+    status
+}
+
+/// Copy the output `process` writes into the pipes set up by `capture` to
+/// `log`, if any, and, when `show`, to tust's own stdout (stderr when
+/// `to_stderr`) and stderr; wait for the returned copier once the command
+/// exited
+pub fn tee(process: &mut Child, log: Option<&Log>, to_stderr: bool, show: bool) -> Copier {
+    let (sender, finished) = std::sync::mpsc::channel();
+    let mut copiers = 0;
+    let streams: [(Option<Box<dyn Read + Send>>, bool); 2] = [
+        (process.stdout.take().map(|out| Box::new(out) as Box<dyn Read + Send>), to_stderr),
+        (process.stderr.take().map(|err| Box::new(err) as Box<dyn Read + Send>), true),
+    ];
+    for (stream, to_stderr) in streams {
+        let Some(mut stream) = stream else {
+            continue;
+        };
+        let (log, sender) = (log.cloned(), sender.clone());
+        copiers += 1;
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 8192];
+            let mut shown = Shown::new(to_stderr);
+            while let Ok(read) = stream.read(&mut buffer) {
+                if read == 0 {
+                    break;
+                }
+                if let Some(log) = &log {
+                    log.write(&buffer[..read]);
+                }
+                if show {
+                    let _ = shown.write(&buffer[..read]);
+                }
+            }
+            let _ = shown.flush();
+            let _ = sender.send(());
+        });
+    }
+    Copier { finished, copiers }
+}
+
+/// The command's output on its way to tust's stdout, or stderr when
+/// `to_stderr`, with the `--redact` patterns masked. They are matched against
+/// whole lines, so the end of the output after the last line break is held
+/// back until the line is completed or `flush` is called.
+pub struct Shown {
+    to_stderr: bool,
+    pending: Vec<u8>,
+}
+
+impl Shown {
+    pub fn new(to_stderr: bool) -> Self {
+        Shown { to_stderr, pending: Vec::new() }
+    }
+    
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if !crate::redact::active() {
+            return self.emit(data);
+        }
+        
+        self.pending.extend_from_slice(data);
+        // Progress bars redraw with carriage returns instead of line breaks
+        let end = match self.pending.iter().rposition(|byte| matches!(byte, b'\n' | b'\r')) {
+            Some(index) => index + 1,
+            None if self.pending.len() > MAX_PENDING => self.pending.len(),
+            None => return Ok(()),
+        };
+        let lines: Vec<u8> = self.pending.drain(..end).collect();
+        self.emit_redacted(&lines)
+    }
+    
+    /// Show the partial line held back, when the output went quiet or ended
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        let rest = std::mem::take(&mut self.pending);
+        if rest.is_empty() {
+            return Ok(());
+        }
+        self.emit_redacted(&rest)
+    }
+    
+    fn emit_redacted(&self, data: &[u8]) -> std::io::Result<()> {
+        let text = String::from_utf8_lossy(data);
+        match crate::redact::redact(&text) {
+            std::borrow::Cow::Owned(redacted) => self.emit(redacted.as_bytes()),
+            std::borrow::Cow::Borrowed(_) => self.emit(data),
+        }
+    }
+    
+    fn emit(&self, data: &[u8]) -> std::io::Result<()> {
+        if self.to_stderr {
+            std::io::stderr().write_all(data).and_then(|_| std::io::stderr().flush())
+        } else {
+            std::io::stdout().write_all(data).and_then(|_| std::io::stdout().flush())
+        }
+    }
+}
+
+/// The threads started by `tee`
+pub struct Copier {
+    finished: std::sync::mpsc::Receiver<()>,
+    copiers: usize,
+}
+
+impl Copier {
+    /// Wait for the rest of the output, but not for background processes
+    /// that may keep the pipes open after the command exited
+    pub fn finish(self) {
+        for _ in 0..self.copiers {
+            if self.finished.recv_timeout(Duration::from_millis(200)).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// A horizontal rule labelled `label`, set around the command's output
+pub fn rule(label: &str) -> String {
+    format!("── {} {}", label, "─".repeat(RULE_WIDTH.saturating_sub(label.chars().count() + 4)))
+}
+
+/// The last `TAIL_LINES` lines of the file at `path`, which may be encrypted
+pub fn tail(path: &Path) -> Vec<String> {
+    const MAX_TAIL_BYTES: u64 = 64 * 1024;
+    
+    if crate::encryption::is_encrypted(path) {
+        return crate::encryption::read(path).map(|data| last_lines(&data)).unwrap_or_default();
+    }
+    let Ok(mut file) = File::open(path) else {
+        return Vec::new();
+    };
+    let length = file.metadata().map_or(0, |metadata| metadata.len());
+    let mut data = Vec::new();
+    if file.seek(SeekFrom::Start(length.saturating_sub(MAX_TAIL_BYTES))).is_err() || file.read_to_end(&mut data).is_err() {
+        return Vec::new();
+    }
+    last_lines(&data)
+}
+
+fn last_lines(data: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(data);
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            // Progress bars redraw with carriage returns; keep what was last shown
+            let line = line.rsplit('\r').find(|part| !part.is_empty()).unwrap_or_default();
+            crate::redact::redact(line).into_owned()
+        })
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    lines[lines.len().saturating_sub(TAIL_LINES)..].to_vec()
+}
@@ -0,0 +1,115 @@
+//! Paging of the change list (`$PAGER`).
+//!
+//! When tust's output is a terminal, the change list and its diffs go
+//! through `$TUST_PAGER` or `$PAGER`, `less` by default, which gets
+//! `LESS=FRX` unless `LESS` is set: output that fits on the screen is then
+//! printed as it is, and colors are kept. tust waits for the pager to quit
+//! before it asks about the changes. `--no-pager`, or an empty pager or
+//! `cat`, turns paging off. Only on Unix.
+
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+#[cfg(unix)]
+use std::process::{Child, Stdio};
+#[cfg(unix)]
+use std::thread::JoinHandle;
+
+#[cfg(unix)]
+use colored::Colorize;
+#[cfg(unix)]
+use log::{info, warn};
+
+/// A running pager that stdout goes to; dropping it waits for the pager to
+/// quit and puts stdout back
+#[cfg(unix)]
+pub struct Pager {
+    /// tust's own stdout
+    stdout: OwnedFd,
+    forwarder: Option<JoinHandle<()>>,
+    process: Child,
+}
+
+#[cfg(not(unix))]
+pub struct Pager;
+
+/// Send stdout through the pager until the returned `Pager` is dropped, if
+/// stdout is a terminal and paging isn't off
+#[cfg(unix)]
+pub fn start() -> Option<Pager> {
+    use std::io::IsTerminal;
+    
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    let command = std::env::var("TUST_PAGER").or_else(|_| std::env::var("PAGER")).unwrap_or_else(|_| "less".to_string());
+    if command.trim().is_empty() || command.trim() == "cat" {
+        return None;
+    }
+    match spawn(&command) {
+        Ok(pager) => Some(pager),
+        Err(e) => {
+            warn!("Failed to start the pager {}: {}", command, e);
+            eprintln!("{}", format!("Warning: failed to start the pager {}: {}", command, e).yellow());
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn start() -> Option<Pager> {
+    None
+}
+
+#[cfg(unix)]
+fn spawn(command: &str) -> std::io::Result<Pager> {
+    // Colors are decided for the terminal, before stdout becomes a pipe
+    colored::control::SHOULD_COLORIZE.should_colorize();
+    
+    info!("Paging through {}", command);
+    let mut shell = crate::shell_command(command);
+    if std::env::var_os("LESS").is_none() {
+        shell.env("LESS", "FRX");
+    }
+    let mut process = shell.stdin(Stdio::piped()).spawn()?;
+    let mut input = process.stdin.take().expect("stdin is piped");
+    let (mut reader, writer) = std::io::pipe()?;
+    std::io::stdout().flush()?;
+    let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+    // SAFETY: both descriptors are open, and fd 1 is only replaced
+    if unsafe { libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    drop(writer);
+    
+    // Forwarded from a thread that drops the rest once the pager quit, so
+    // printing never fails on a closed pipe
+    let forwarder = std::thread::spawn(move || {
+        let mut buffer = [0; 8192];
+        let mut open = true;
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => open = open && input.write_all(&buffer[..read]).is_ok(),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    });
+    Ok(Pager { stdout, forwarder: Some(forwarder), process })
+}
+
+#[cfg(unix)]
+impl Drop for Pager {
+    fn drop(&mut self) {
+        let _ = std::io::stdout().flush();
+        // SAFETY: both descriptors are open; this closes the pipe's last
+        // write end, which ends the forwarding
+        unsafe { libc::dup2(self.stdout.as_raw_fd(), libc::STDOUT_FILENO) };
+        if let Some(forwarder) = self.forwarder.take() {
+            let _ = forwarder.join();
+        }
+        let _ = self.process.wait();
+    }
+}
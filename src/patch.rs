@@ -0,0 +1,756 @@
+//! Unified diff parsing, generation and application.
+//!
+//! External patches are applied to a sandbox copy of the project first, so
+//! they go through the same preview-then-confirm path as a command run and
+//! the real directory is only touched through `apply_changes`. Like `git
+//! apply`, paths beyond a symlink are refused and links are never written
+//! through, so not even the sandbox copy can be escaped. The same
+//! structures describe the diffs tust shows for the changes it found.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use log::debug;
+
+use crate::{Change, collect_files, symlink};
+
+/// Unchanged lines around each change in written patches, as `diff -u` does
+const CONTEXT_LINES: usize = 3;
+
+/// All changes a patch makes to a single file
+#[derive(Debug)]
+pub struct FilePatch {
+    /// `None` when the file is created by the patch
+    pub old_path: Option<PathBuf>,
+    /// `None` when the file is deleted by the patch
+    pub new_path: Option<PathBuf>,
+    /// Whether git-style `new file mode` or `new mode` headers make the file
+    /// executable (`Some(true)`) or not executable (`Some(false)`)
+    pub executable: Option<bool>,
+    /// Whether a `new file mode 120000` header creates a symlink, whose
+    /// target is the content
+    pub symlink: bool,
+    pub hunks: Vec<Hunk>,
+}
+
+#[derive(Debug)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub new_start: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+/// One line of a hunk, including its line terminator (if it had one)
+#[derive(Debug)]
+pub enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+impl Hunk {
+    /// Lines the hunk expects to find in the old file
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect()
+    }
+    
+    /// Lines the hunk leaves in the new file
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Add(text) => Some(text.as_str()),
+                HunkLine::Remove(_) => None,
+            })
+            .collect()
+    }
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Parse a unified diff. `strip` removes that many leading path components
+/// (like `patch -p`); when `None`, git-style `a/` and `b/` prefixes are removed.
+pub fn parse(text: &str, strip: Option<usize>) -> std::io::Result<Vec<FilePatch>> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut patches = Vec::new();
+    let mut executable = None;
+    let mut is_symlink = false;
+    // Path of the current `diff --git` section until its `---`/`+++` header,
+    // which a section that only changes the mode doesn't have
+    let mut section_path: Option<String> = None;
+    let mut i = 0;
+    
+    while i <= lines.len() {
+        // Extended git headers between `diff --git` and `---`
+        let line = lines.get(i).map_or("", |line| line.trim_end());
+        if i == lines.len() || line.starts_with("diff --git ") {
+            if let Some(path) = section_path.take()
+                && executable.is_some()
+            {
+                let path = strip_path(&path, strip.unwrap_or(1))?;
+                debug!("Parsed mode change for {}", path.display());
+                patches.push(FilePatch {
+                    old_path: Some(path.clone()),
+                    new_path: Some(path),
+                    executable: executable.take(),
+                    symlink: false,
+                    hunks: Vec::new(),
+                });
+            }
+            executable = None;
+            is_symlink = false;
+            section_path = line.rsplit_once(" b/").map(|(_, name)| format!("b/{}", name));
+        } else if line == "new file mode 120000" {
+            is_symlink = true;
+        } else if let Some(mode) = line.strip_prefix("new file mode ").or_else(|| line.strip_prefix("new mode ")) {
+            executable = Some(mode.ends_with("755"));
+        }
+        if i == lines.len() {
+            break;
+        }
+        if !(lines[i].starts_with("--- ") && lines.get(i + 1).is_some_and(|next| next.starts_with("+++ "))) {
+            i += 1;
+            continue;
+        }
+        
+        let old_name = header_path(&lines[i][4..]);
+        let new_name = header_path(&lines[i + 1][4..]);
+        let git_style = old_name.as_deref().is_none_or(|name| name.starts_with("a/"))
+            && new_name.as_deref().is_none_or(|name| name.starts_with("b/"));
+        let strip = strip.unwrap_or(if git_style { 1 } else { 0 });
+        let mut patch = FilePatch {
+            old_path: old_name.map(|name| strip_path(&name, strip)).transpose()?,
+            new_path: new_name.map(|name| strip_path(&name, strip)).transpose()?,
+            executable: executable.take(),
+            symlink: std::mem::take(&mut is_symlink),
+            hunks: Vec::new(),
+        };
+        section_path = None;
+        i += 2;
+        
+        while i < lines.len() && lines[i].starts_with("@@ ") {
+            let (hunk, next) = parse_hunk(&lines, i)?;
+            patch.hunks.push(hunk);
+            i = next;
+        }
+        
+        debug!("Parsed patch for {:?} -> {:?} with {} hunks", patch.old_path, patch.new_path, patch.hunks.len());
+        patches.push(patch);
+    }
+    
+    if patches.is_empty() {
+        return Err(invalid("no file changes found in patch".to_string()));
+    }
+    Ok(patches)
+}
+
+/// Path from a `---`/`+++` header, or `None` for /dev/null
+fn header_path(header: &str) -> Option<String> {
+    // Anything after a tab is a timestamp
+    let name = header.split('\t').next().unwrap_or_default().trim_end_matches(['\r', '\n']);
+    (name != "/dev/null").then(|| name.to_string())
+}
+
+fn strip_path(name: &str, strip: usize) -> std::io::Result<PathBuf> {
+    let path: PathBuf = Path::new(name).components().skip(strip).collect();
+    // Never let a patch write outside the directory it is applied to
+    if path.as_os_str().is_empty() || path.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(invalid(format!("unsupported path in patch: {}", name)));
+    }
+    Ok(path)
+}
+
+fn parse_hunk(lines: &[&str], start: usize) -> std::io::Result<(Hunk, usize)> {
+    let header = lines[start];
+    let bad_header = || invalid(format!("malformed hunk header: {}", header.trim_end()));
+    let ranges = header
+        .strip_prefix("@@ ")
+        .and_then(|rest| rest.split(" @@").next())
+        .ok_or_else(bad_header)?;
+    let mut ranges = ranges.split(' ');
+    let (old_start, mut old_remaining) = parse_range(ranges.next(), '-').ok_or_else(bad_header)?;
+    let (new_start, mut new_remaining) = parse_range(ranges.next(), '+').ok_or_else(bad_header)?;
+    
+    let mut hunk = Hunk { old_start, new_start, lines: Vec::new() };
+    let mut i = start + 1;
+    while (old_remaining > 0 || new_remaining > 0) && i < lines.len() {
+        let line = lines[i];
+        let (kind, text) = line.split_at(line.len().min(1));
+        // Some tools drop the leading space of empty context lines
+        let (kind, text) = if line == "\n" || line == "\r\n" { (" ", line) } else { (kind, text) };
+        match kind {
+            " " => {
+                old_remaining = old_remaining.saturating_sub(1);
+                new_remaining = new_remaining.saturating_sub(1);
+                hunk.lines.push(HunkLine::Context(text.to_string()));
+            }
+            "-" => {
+                old_remaining = old_remaining.saturating_sub(1);
+                hunk.lines.push(HunkLine::Remove(text.to_string()));
+            }
+            "+" => {
+                new_remaining = new_remaining.saturating_sub(1);
+                hunk.lines.push(HunkLine::Add(text.to_string()));
+            }
+            "\\" => {}
+            _ => return Err(invalid(format!("unexpected line in hunk: {}", line.trim_end()))),
+        }
+        i += 1;
+        
+        // "\ No newline at end of file" applies to the line before it
+        if lines.get(i).is_some_and(|next| next.starts_with('\\')) {
+            if let Some(HunkLine::Context(text) | HunkLine::Remove(text) | HunkLine::Add(text)) = hunk.lines.last_mut() {
+                let trimmed = text.strip_suffix('\n').unwrap_or(text);
+                *text = trimmed.to_string();
+            }
+            i += 1;
+        }
+    }
+    
+    if old_remaining > 0 || new_remaining > 0 {
+        return Err(invalid(format!("truncated hunk: {}", header.trim_end())));
+    }
+    Ok((hunk, i))
+}
+
+fn parse_range(range: Option<&str>, prefix: char) -> Option<(usize, usize)> {
+    let range = range?.strip_prefix(prefix)?;
+    match range.split_once(',') {
+        Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+/// Apply every file patch to the tree at `root`
+pub fn apply(patches: &[FilePatch], root: &Path) -> std::io::Result<()> {
+    for patch in patches {
+        apply_file(patch, root)?;
+    }
+    Ok(())
+}
+
+/// Check that `patch` applies to the tree at `root` without changing anything
+pub fn check(patch: &FilePatch, root: &Path) -> std::io::Result<()> {
+    match mode_only(patch) {
+        Some((path, _)) => check_mode_target(root, path),
+        None => patched(patch, root).map(drop),
+    }
+}
+
+/// Refuse a path that leads through a symlink in the tree at `root`, as
+/// `git apply` does: writing there would follow the link, possibly out of
+/// the tree (including to a link created by an earlier part of the patch)
+fn check_beyond_symlink(root: &Path, path: &Path) -> std::io::Result<()> {
+    let mut prefix = root.to_path_buf();
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        prefix.push(component);
+        if fs::symlink_metadata(&prefix).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Err(invalid(format!("{}: beyond a symbolic link", path.display())));
+        }
+    }
+    Ok(())
+}
+
+/// Check the file whose mode a mode-only patch changes, which must be a
+/// regular file so that setting its mode follows no link
+fn check_mode_target(root: &Path, path: &Path) -> std::io::Result<()> {
+    check_beyond_symlink(root, path)?;
+    match fs::symlink_metadata(root.join(path)) {
+        Err(_) => Err(invalid(format!("{}: file to be patched does not exist", path.display()))),
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            Err(invalid(format!("{}: can't change the mode of a symbolic link", path.display())))
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+/// The path and new executable bit of a patch that only changes the mode
+fn mode_only(patch: &FilePatch) -> Option<(&Path, bool)> {
+    if patch.hunks.is_empty()
+        && let (Some(old_path), Some(new_path)) = (&patch.old_path, &patch.new_path)
+        && old_path == new_path
+        && let Some(executable) = patch.executable
+    {
+        return Some((new_path, executable));
+    }
+    None
+}
+
+fn apply_file(patch: &FilePatch, root: &Path) -> std::io::Result<()> {
+    // Only the mode changes; the file doesn't have to be text
+    if let Some((path, executable)) = mode_only(patch) {
+        check_mode_target(root, path)?;
+        return set_executable(&root.join(path), executable);
+    }
+    
+    let content = patched(patch, root)?;
+    match (&patch.old_path, &patch.new_path) {
+        (Some(old_path), None) => fs::remove_file(root.join(old_path)),
+        (old_path, Some(new_path)) => {
+            let target = root.join(new_path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // A patched symlink stays one, with the content as its target
+            let was_symlink = old_path.as_ref().is_some_and(|old_path| symlink::target(&root.join(old_path)).is_some());
+            if patch.symlink || was_symlink {
+                if symlink::exists(&target) {
+                    fs::remove_file(&target)?;
+                }
+                symlink::create(Path::new(&content), &target)?;
+            } else {
+                // Replace a link rather than write through it
+                if fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                    fs::remove_file(&target)?;
+                }
+                fs::write(&target, content)?;
+            }
+            if let Some(executable) = patch.executable {
+                set_executable(&target, executable)?;
+            }
+            if let Some(old_path) = old_path
+                && old_path != new_path
+            {
+                fs::remove_file(root.join(old_path))?;
+            }
+            Ok(())
+        }
+        (None, None) => Ok(()),
+    }
+}
+
+/// The content of the file `patch` leaves at `root`, after checking that the
+/// files it expects are there and every hunk applies
+fn patched(patch: &FilePatch, root: &Path) -> std::io::Result<String> {
+    let display_path = patch.new_path.as_ref().or(patch.old_path.as_ref()).map(|p| p.display().to_string());
+    let display_path = display_path.unwrap_or_default();
+    for path in patch.old_path.iter().chain(&patch.new_path) {
+        check_beyond_symlink(root, path)?;
+    }
+    if let (Some(old_path), Some(new_path)) = (&patch.old_path, &patch.new_path)
+        && old_path != new_path
+        && symlink::exists(&root.join(new_path))
+    {
+        return Err(invalid(format!("{}: file to be renamed to already exists", new_path.display())));
+    }
+    
+    let original = match &patch.old_path {
+        Some(old_path) => {
+            let text = read_text(Some(&root.join(old_path)));
+            if text.is_none() && !symlink::exists(&root.join(old_path)) {
+                return Err(invalid(format!("{}: file to be patched does not exist", old_path.display())));
+            }
+            text.ok_or_else(|| invalid(format!("{}: not a text file", old_path.display())))?
+        }
+        None => {
+            if let Some(new_path) = &patch.new_path
+                && symlink::exists(&root.join(new_path))
+            {
+                return Err(invalid(format!("{}: file to be created already exists", new_path.display())));
+            }
+            String::new()
+        }
+    };
+    
+    let content = apply_hunks(&original, &patch.hunks)
+        .map_err(|index| invalid(format!("{}: hunk {} does not apply", display_path, index + 1)))?;
+    
+    if let (Some(old_path), None) = (&patch.old_path, &patch.new_path)
+        && !content.is_empty()
+    {
+        return Err(invalid(format!("{}: file to be deleted is not empty after patching", old_path.display())));
+    }
+    Ok(content)
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    // Like git, executable for whoever can read the file
+    permissions.set_mode(if executable { mode | (mode & 0o444) >> 2 } else { mode & !0o111 });
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// `original` with `hunks` applied, or the index of the first hunk that
+/// could not be placed
+pub fn apply_hunks(original: &str, hunks: &[Hunk]) -> Result<String, usize> {
+    let mut lines: Vec<&str> = original.split_inclusive('\n').collect();
+    let mut result: Vec<String> = Vec::new();
+    // Original lines already handled, and how far the previous hunk was from
+    // where its header said it would be
+    let mut consumed = 0;
+    let mut drift: isize = 0;
+    for (index, hunk) in hunks.iter().enumerate() {
+        let old_lines = hunk.old_lines();
+        // Line numbers are 1-based, except that a hunk removing nothing
+        // names the line it inserts after
+        let anchor = if old_lines.is_empty() { hunk.old_start } else { hunk.old_start.saturating_sub(1) };
+        let expected = (anchor as isize + drift - consumed as isize).max(0) as usize;
+        let position = find_hunk(&lines, &old_lines, expected).ok_or(index)?;
+        drift = (consumed + position) as isize - anchor as isize;
+        
+        result.extend(lines.drain(..position).map(str::to_string));
+        lines.drain(..old_lines.len());
+        result.extend(hunk.new_lines().into_iter().map(str::to_string));
+        consumed += position + old_lines.len();
+    }
+    result.extend(lines.into_iter().map(str::to_string));
+    Ok(result.concat())
+}
+
+/// Find where `needle` occurs in `haystack`, preferring the spot closest to `expected`
+fn find_hunk(haystack: &[&str], needle: &[&str], expected: usize) -> Option<usize> {
+    let matches_at = |position: usize| {
+        position + needle.len() <= haystack.len() && haystack[position..position + needle.len()] == *needle
+    };
+    
+    let last = haystack.len().checked_sub(needle.len())?;
+    let expected = expected.min(last);
+    (0..=last)
+        .flat_map(|distance| [expected.checked_sub(distance), (distance > 0).then_some(expected + distance)])
+        .flatten()
+        .find(|&position| position <= last && matches_at(position))
+}
+
+/// Hunks turning `old` into `new`, each with up to `context` unchanged lines
+/// around its changes
+pub fn hunks(old: &str, new: &str, context: usize) -> Vec<Hunk> {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let results = diff::slice(&old_lines, &new_lines);
+    
+    // Group changed lines whose context would touch or overlap
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (index, result) in results.iter().enumerate() {
+        if matches!(result, diff::Result::Both(..)) {
+            continue;
+        }
+        let (start, end) = (index.saturating_sub(context), (index + 1 + context).min(results.len()));
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    
+    let (mut old_line, mut new_line, mut position) = (0, 0, 0);
+    let mut hunks = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        for result in &results[position..start] {
+            old_line += usize::from(!matches!(result, diff::Result::Right(_)));
+            new_line += usize::from(!matches!(result, diff::Result::Left(_)));
+        }
+        
+        let lines: Vec<HunkLine> = results[start..end]
+            .iter()
+            .map(|result| match result {
+                diff::Result::Both(line, _) => HunkLine::Context(line.to_string()),
+                diff::Result::Left(line) => HunkLine::Remove(line.to_string()),
+                diff::Result::Right(line) => HunkLine::Add(line.to_string()),
+            })
+            .collect();
+        let mut hunk = Hunk { old_start: old_line, new_start: new_line, lines };
+        // Ranges start at the first line, unless they are empty
+        if !hunk.old_lines().is_empty() {
+            hunk.old_start += 1;
+        }
+        if !hunk.new_lines().is_empty() {
+            hunk.new_start += 1;
+        }
+        hunks.push(hunk);
+        position = start;
+    }
+    hunks
+}
+
+/// One hunk as unified diff text, starting with its `@@` header
+pub fn format_hunk(hunk: &Hunk) -> String {
+    let mut text = format!(
+        "@@ -{},{} +{},{} @@\n",
+        hunk.old_start,
+        hunk.old_lines().len(),
+        hunk.new_start,
+        hunk.new_lines().len()
+    );
+    for line in &hunk.lines {
+        let (marker, content) = match line {
+            HunkLine::Context(content) => (' ', content),
+            HunkLine::Remove(content) => ('-', content),
+            HunkLine::Add(content) => ('+', content),
+        };
+        text.push(marker);
+        text.push_str(content);
+        if !content.ends_with('\n') {
+            text.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+    text
+}
+
+/// Write `changes` between `original` and `modified` to `path` as a git-style
+/// unified diff that `git apply` and `patch -p1` accept. Files that aren't
+/// text can't be expressed that way; they are left out and returned. The
+/// patch is encrypted with `key` when given.
+pub fn write(
+    path: &Path,
+    original: &Path,
+    modified: &Path,
+    changes: &[Change],
+    key: Option<&crate::encryption::Key>,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut text = String::new();
+    let mut skipped = Vec::new();
+    for change in changes {
+        let files = match change {
+            Change::Create(path) => vec![(None, Some(path.clone()))],
+            Change::Modify(path) => vec![(Some(path.clone()), Some(path.clone()))],
+            Change::Delete(path) => vec![(Some(path.clone()), None)],
+            Change::Chmod(path) => {
+                let (old_mode, new_mode) = (git_mode(&original.join(path))?, git_mode(&modified.join(path))?);
+                if old_mode == new_mode {
+                    debug!("Leaving {} out of the patch, git only records the executable bit", path.display());
+                } else {
+                    let name = patch_name(path);
+                    text.push_str(&format!("diff --git a/{} b/{}\nold mode {}\nnew mode {}\n", name, name, old_mode, new_mode));
+                }
+                continue;
+            }
+            Change::Xattr(path) => {
+                debug!("Leaving {} out of the patch, git doesn't record extended attributes", path.display());
+                continue;
+            }
+            // A moved directory becomes a delete and a create for every file
+            Change::Rename(from, to) => {
+                let mut names = HashSet::new();
+                collect_files(&modified.join(to), Path::new(""), &mut names)?;
+                let mut names: Vec<PathBuf> = names.into_iter().collect();
+                names.sort();
+                names
+                    .into_iter()
+                    .flat_map(|name| [(Some(from.join(&name)), None), (None, Some(to.join(&name)))])
+                    .collect()
+            }
+        };
+        
+        for (old, new) in files {
+            let old_file = old.as_ref().map(|old| original.join(old));
+            let new_file = new.as_ref().map(|new| modified.join(new));
+            let (Some(old_text), Some(new_text)) = (read_text(old_file.as_deref()), read_text(new_file.as_deref()))
+            else {
+                let name = new.or(old).unwrap_or_default();
+                debug!("Leaving {} out of the patch, it is not a text file", name.display());
+                skipped.push(name);
+                continue;
+            };
+            
+            let (old_name, new_name) = (old.as_deref().map(patch_name), new.as_deref().map(patch_name));
+            let name = new_name.as_deref().or(old_name.as_deref()).unwrap_or_default();
+            text.push_str(&format!("diff --git a/{} b/{}\n", old_name.as_deref().unwrap_or(name), name));
+            match (&old_file, &new_file) {
+                (None, Some(new_file)) => text.push_str(&format!("new file mode {}\n", git_mode(new_file)?)),
+                (Some(old_file), None) => text.push_str(&format!("deleted file mode {}\n", git_mode(old_file)?)),
+                (Some(old_file), Some(new_file)) => {
+                    let (old_mode, new_mode) = (git_mode(old_file)?, git_mode(new_file)?);
+                    if old_mode != new_mode {
+                        text.push_str(&format!("old mode {}\nnew mode {}\n", old_mode, new_mode));
+                    }
+                }
+                (None, None) => {}
+            }
+            text.push_str(&format!("--- {}\n", old_name.map_or("/dev/null".to_string(), |name| format!("a/{}", name))));
+            text.push_str(&format!("+++ {}\n", new_name.map_or("/dev/null".to_string(), |name| format!("b/{}", name))));
+            for hunk in hunks(&old_text, &new_text, CONTEXT_LINES) {
+                text.push_str(&format_hunk(&hunk));
+            }
+        }
+    }
+    crate::encryption::write(path, text.as_bytes(), key)?;
+    Ok(skipped)
+}
+
+/// Path as written in patch headers, always with forward slashes
+fn patch_name(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Content of a text file, an empty string for a missing one, and `None`
+/// for anything that isn't text. A symlink's content is its target, as in git.
+pub fn read_text(path: Option<&Path>) -> Option<String> {
+    let Some(path) = path else {
+        return Some(String::new());
+    };
+    if let Some(target) = symlink::target(path) {
+        return target.to_str().map(|target| target.replace('\\', "/"));
+    }
+    let text = String::from_utf8(fs::read(path).ok()?).ok()?;
+    (!text.contains('\0')).then_some(text)
+}
+
+/// Whether the file at `path` holds binary content: a NUL byte or invalid
+/// UTF-8 in its first bytes, where git looks as well. Symlinks and missing
+/// files are not binary.
+pub fn is_binary(path: &Path) -> bool {
+    use std::io::Read;
+    
+    const CHECKED_BYTES: u64 = 8000;
+    
+    if symlink::target(path).is_some() {
+        return false;
+    }
+    let mut start = Vec::new();
+    let Ok(_) = fs::File::open(path).and_then(|file| file.take(CHECKED_BYTES).read_to_end(&mut start)) else {
+        return false;
+    };
+    // A character cut off at the end of what was read is fine
+    start.contains(&0) || std::str::from_utf8(&start).is_err_and(|e| e.error_len().is_some())
+}
+
+/// Git's mode for a file, which only records whether it is executable or
+/// a symlink
+#[cfg(unix)]
+fn git_mode(path: &Path) -> std::io::Result<&'static str> {
+    use std::os::unix::fs::PermissionsExt;
+    
+    if symlink::target(path).is_some() {
+        return Ok("120000");
+    }
+    let executable = fs::metadata(path)?.permissions().mode() & 0o111 != 0;
+    Ok(if executable { "100755" } else { "100644" })
+}
+
+#[cfg(not(unix))]
+fn git_mode(path: &Path) -> std::io::Result<&'static str> {
+    Ok(if symlink::target(path).is_some() { "120000" } else { "100644" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// The hunks of a patch to a single file, given from its first `@@` line
+    fn hunks_of(diff: &str) -> Vec<Hunk> {
+        parse(&format!("--- a/f\n+++ b/f\n{}", diff), None).unwrap().remove(0).hunks
+    }
+    
+    #[test]
+    fn written_patches_apply_back() {
+        let (original, modified, target) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for root in [original.path(), target.path()] {
+            fs::write(root.join("a.txt"), "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n").unwrap();
+            fs::write(root.join("gone.txt"), "bye\n").unwrap();
+        }
+        fs::write(modified.path().join("a.txt"), "one\nTWO\nthree\nfour\nfive\nsix\nseven\neight\nNINE\nten\neleven").unwrap();
+        fs::create_dir(modified.path().join("sub")).unwrap();
+        fs::write(modified.path().join("sub/new.txt"), "hello\n").unwrap();
+        let changes = [
+            Change::Modify(PathBuf::from("a.txt")),
+            Change::Delete(PathBuf::from("gone.txt")),
+            Change::Create(PathBuf::from("sub/new.txt")),
+        ];
+        
+        let patch_file = original.path().join("changes.patch");
+        assert!(write(&patch_file, original.path(), modified.path(), &changes, None).unwrap().is_empty());
+        let patches = parse(&fs::read_to_string(&patch_file).unwrap(), None).unwrap();
+        assert_eq!(patches.len(), 3);
+        apply(&patches, target.path()).unwrap();
+        
+        for name in ["a.txt", "sub/new.txt"] {
+            assert_eq!(
+                fs::read_to_string(target.path().join(name)).unwrap(),
+                fs::read_to_string(modified.path().join(name)).unwrap(),
+                "{}",
+                name
+            );
+        }
+        assert!(!target.path().join("gone.txt").exists());
+    }
+    
+    #[test]
+    fn hunks_apply_where_their_context_moved() {
+        let hunks = hunks_of("@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n");
+        assert_eq!(apply_hunks("x\ny\nz\na\nb\nc\n", &hunks).unwrap(), "x\ny\nz\na\nB\nc\n");
+    }
+    
+    #[test]
+    fn later_hunks_follow_the_offset_of_earlier_ones() {
+        let hunks = hunks_of("@@ -1,2 +1,2 @@\n a\n-b\n+B\n@@ -5,2 +5,2 @@\n e\n-f\n+F\n");
+        let original = "new\nnew\na\nb\nc\nd\ne\nf\n";
+        assert_eq!(apply_hunks(original, &hunks).unwrap(), "new\nnew\na\nB\nc\nd\ne\nF\n");
+    }
+    
+    #[test]
+    fn repeated_context_is_matched_closest_to_the_header() {
+        let hunks = hunks_of("@@ -6,1 +6,1 @@\n-x\n+y\n");
+        assert_eq!(apply_hunks("x\n1\n2\n3\n4\nx\n5\n", &hunks).unwrap(), "x\n1\n2\n3\n4\ny\n5\n");
+        assert_eq!(find_hunk(&["x\n", "1\n", "x\n"], &["x\n"], 1), Some(0));
+        assert_eq!(find_hunk(&["x\n", "1\n", "x\n"], &["x\n"], 2), Some(2));
+    }
+    
+    #[test]
+    fn hunks_need_their_context_exactly() {
+        // No fuzz: a context line that differs keeps the hunk from applying
+        let hunks = hunks_of("@@ -1,2 +1,2 @@\n a\n-b\n+B\n@@ -3,1 +3,1 @@\n-c\n+C\n");
+        assert_eq!(apply_hunks("A\nb\nc\n", &hunks), Err(0));
+        assert_eq!(apply_hunks("a\nb\nd\n", &hunks), Err(1));
+        assert_eq!(find_hunk(&["a\n"], &["a\n", "b\n"], 0), None);
+    }
+    
+    #[test]
+    fn insertions_into_an_empty_file() {
+        let hunks = hunks_of("@@ -0,0 +1,2 @@\n+a\n+b\n");
+        assert_eq!(apply_hunks("", &hunks).unwrap(), "a\nb\n");
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn refuses_paths_beyond_a_symlink_the_patch_creates() {
+        let outside = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let text = format!(
+            "diff --git a/esc b/esc\nnew file mode 120000\n--- /dev/null\n+++ b/esc\n@@ -0,0 +1 @@\n+{}\n\\ No newline at end of file\n\
+             diff --git a/esc/pwned b/esc/pwned\nnew file mode 100644\n--- /dev/null\n+++ b/esc/pwned\n@@ -0,0 +1 @@\n+x\n",
+            outside.path().display()
+        );
+        let patches = parse(&text, None).unwrap();
+        
+        let error = apply(&patches, root.path()).unwrap_err();
+        assert!(error.to_string().contains("beyond a symbolic link"), "{}", error);
+        assert!(!outside.path().join("pwned").exists());
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn never_renames_onto_a_symlink() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("target"), "a\n").unwrap();
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("old"), "a\n").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("target"), root.path().join("new")).unwrap();
+        
+        // A rename onto an existing path is refused rather than followed
+        let text = "diff --git a/old b/new\n--- a/old\n+++ b/new\n@@ -1 +1 @@\n-a\n+b\n";
+        let patches = parse(text, None).unwrap();
+        assert!(apply(&patches, root.path()).is_err());
+        assert_eq!(fs::read_to_string(outside.path().join("target")).unwrap(), "a\n");
+    }
+}
@@ -0,0 +1,206 @@
+//! Progress and ETA for the copy phase.
+//!
+//! The source tree is scanned up front for its file count and size. While
+//! copying, throughput and the remaining time are shown on stderr, and the
+//! measured throughput is stored per project so the next run can give an
+//! estimate before the first byte is copied.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::hardlink::Links;
+use crate::{filter, format_size, special, symlink};
+
+/// Runs shorter than this are too noisy to learn a throughput from
+const MIN_RECORDED_BYTES: u64 = 1024 * 1024;
+
+/// Number of files and total bytes below a directory
+#[derive(Default, Debug, Clone, Copy)]
+pub struct TreeSize {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Walk `src` (or just the `--files` paths in it) the same way the copy does
+/// and add up what it would copy; hard links to the same file count once
+pub fn scan(src: &Path, scope: Option<&[PathBuf]>) -> std::io::Result<TreeSize> {
+    let mut size = TreeSize::default();
+    let links = &mut Links::default();
+    match scope {
+        None => scan_into(src, Path::new(""), &mut size, links)?,
+        Some(paths) => {
+            for path in paths {
+                let full_path = src.join(path);
+                let is_dir = symlink::is_dir(&full_path);
+                if filter::is_excluded(path, is_dir) || filter::is_untracked(path, is_dir) {
+                    continue;
+                }
+                if is_dir {
+                    scan_into(&full_path, path, &mut size, links)?;
+                } else if symlink::exists(&full_path) && !special::is_special(&full_path) {
+                    size.files += 1;
+                    size.bytes += file_size(&full_path, links)?;
+                }
+            }
+        }
+    }
+    Ok(size)
+}
+
+fn scan_into(src: &Path, relative: &Path, size: &mut TreeSize, links: &mut Links) -> std::io::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let (entry_path, relative_path) = (entry.path(), relative.join(entry.file_name()));
+        let is_dir = symlink::is_dir(&entry_path);
+        if filter::is_excluded(&relative_path, is_dir) || filter::is_untracked(&relative_path, is_dir) {
+            continue;
+        }
+        if is_dir {
+            scan_into(&entry_path, &relative_path, size, links)?;
+        } else if !special::is_special(&entry_path) {
+            size.files += 1;
+            size.bytes += file_size(&entry_path, links)?;
+        }
+    }
+    Ok(())
+}
+
+/// Bytes the copy will report for `path`; symlinks and further hard links
+/// to a file are copied as links
+fn file_size(path: &Path, links: &mut Links) -> std::io::Result<u64> {
+    if symlink::target(path).is_some() || links.get(path).is_some() {
+        return Ok(0);
+    }
+    links.record(path, path);
+    Ok(fs::metadata(path)?.len())
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CopyHistory {
+    /// Measured bytes per second, keyed by project directory
+    throughput: HashMap<PathBuf, f64>,
+}
+
+fn history_path() -> std::io::Result<PathBuf> {
+    Ok(crate::data_dir()?.join("copy-history.json"))
+}
+
+fn load_history() -> CopyHistory {
+    history_path()
+        .and_then(fs::read)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Live progress display for one copy
+pub struct CopyProgress {
+    project: PathBuf,
+    total: TreeSize,
+    copied: TreeSize,
+    historic_throughput: Option<f64>,
+    start: Instant,
+    last_draw: Option<Instant>,
+    draw: bool,
+}
+
+impl CopyProgress {
+    pub fn new(project: &Path, total: TreeSize) -> Self {
+        let historic_throughput = load_history().throughput.get(project).copied();
+        let progress = CopyProgress {
+            project: project.to_path_buf(),
+            total,
+            copied: TreeSize::default(),
+            historic_throughput,
+            start: Instant::now(),
+            last_draw: None,
+            draw: std::io::stderr().is_terminal(),
+        };
+        
+        let estimate = historic_throughput
+            .map(|rate| format!(", about {} based on previous runs", format_duration(total.bytes as f64 / rate)))
+            .unwrap_or_default();
+        eprintln!(
+            "{}",
+            format!("Copying {} files ({}){}", total.files, format_size(total.bytes), estimate).dimmed()
+        );
+        progress
+    }
+    
+    /// Record one copied file of `bytes` bytes
+    pub fn advance(&mut self, bytes: u64) {
+        self.copied.files += 1;
+        self.copied.bytes += bytes;
+        
+        if self.draw && self.last_draw.is_none_or(|last| last.elapsed() >= Duration::from_millis(100)) {
+            self.last_draw = Some(Instant::now());
+            let elapsed = self.start.elapsed().as_secs_f64();
+            let rate = (elapsed > 0.5).then(|| self.copied.bytes as f64 / elapsed);
+            let remaining = self.total.bytes.saturating_sub(self.copied.bytes) as f64;
+            let eta = rate
+                .or(self.historic_throughput)
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| format!(", {} left", format_duration(remaining / rate)))
+                .unwrap_or_default();
+            let rate = rate.map(|rate| format!(" at {}/s", format_size(rate as u64))).unwrap_or_default();
+            eprint!(
+                "\r\x1b[2K{}",
+                format!(
+                    "{}/{} files, {} of {}{}{}",
+                    self.copied.files,
+                    self.total.files,
+                    format_size(self.copied.bytes),
+                    format_size(self.total.bytes),
+                    rate,
+                    eta
+                )
+                .dimmed()
+            );
+            let _ = std::io::stderr().flush();
+        }
+    }
+    
+    /// Clear the progress line and remember this run's throughput
+    pub fn finish(self) {
+        if self.draw && self.last_draw.is_some() {
+            eprint!("\r\x1b[2K");
+        }
+        
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if self.copied.bytes < MIN_RECORDED_BYTES || elapsed <= 0.0 {
+            return;
+        }
+        let rate = self.copied.bytes as f64 / elapsed;
+        debug!("Copy throughput: {}/s", format_size(rate as u64));
+        
+        let mut history = load_history();
+        history.throughput.insert(self.project, rate);
+        let saved = history_path().and_then(|path| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, serde_json::to_vec(&history).map_err(std::io::Error::other)?)
+        });
+        if let Err(e) = saved {
+            debug!("Failed to save copy history: {}", e);
+        }
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    if seconds < 60 {
+        format!("{}s", seconds.max(1))
+    } else if seconds < 3600 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60)
+    }
+}
@@ -0,0 +1,282 @@
+//! Running the command in a pseudo-terminal (`--tty`, Unix).
+//!
+//! Tools that check whether they write to a terminal switch off progress
+//! bars, prompts and colors when they don't. With `--tty` the command gets a
+//! terminal of its own whatever tust's output is connected to: its output is
+//! copied to tust's, and when tust's input is a terminal it is switched to
+//! raw mode and forwarded, so Ctrl-C and Ctrl-Z reach the command through the
+//! pseudo-terminal. Window size changes are passed on as well.
+
+use std::fs::File;
+use std::process::Command;
+
+use crate::output::Log;
+
+/// The controlling side of a pseudo-terminal the command is attached to
+pub struct Pty {
+    #[cfg_attr(not(unix), allow(dead_code))]
+    master: File,
+}
+
+/// Copies between tust and the command's terminal while the command runs
+pub struct Forwarding {
+    #[cfg(unix)]
+    done: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    #[cfg(unix)]
+    threads: Vec<std::thread::JoinHandle<()>>,
+    #[cfg(unix)]
+    resize: Option<tokio::task::JoinHandle<()>>,
+    #[cfg(unix)]
+    _raw: Option<RawMode>,
+}
+
+/// Give `command` a new pseudo-terminal as its controlling terminal and
+/// standard input, output and error
+#[cfg(unix)]
+pub fn attach(command: &mut Command) -> std::io::Result<Pty> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::Stdio;
+    
+    let size = window_size();
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: openpty only writes the two descriptors; the name and terminal
+    // settings may be null, and `size` outlives the call
+    let opened = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            size.as_ref().map_or(std::ptr::null(), |size| size as *const libc::winsize),
+        )
+    };
+    if opened != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: openpty returned two new descriptors, owned from here on
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    for fd in [&master, &slave] {
+        // SAFETY: `fd` is open; the command only gets the copies of the
+        // terminal that become its standard streams
+        unsafe { libc::fcntl(std::os::fd::AsRawFd::as_raw_fd(fd), libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    command.stdin(Stdio::from(slave.try_clone()?)).stdout(Stdio::from(slave.try_clone()?)).stderr(Stdio::from(slave));
+    // SAFETY: setsid and ioctl are async-signal-safe; standard input is the
+    // terminal by the time this runs
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(Pty { master: File::from(master) })
+}
+
+#[cfg(not(unix))]
+pub fn attach(_command: &mut Command) -> std::io::Result<Pty> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "only supported on Unix"))
+}
+
+impl Pty {
+    /// Start copying the command's output to tust's stdout (stderr when
+    /// `to_stderr`) and to `log`, and `input`, if any, to the command;
+    /// called once the command was spawned
+    #[cfg(unix)]
+    pub fn forward(self, input: Option<File>, to_stderr: bool, log: Option<Log>) -> Forwarding {
+        use std::io::{IsTerminal, Write};
+        use std::os::fd::AsRawFd;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        
+        let done = Arc::new(AtomicBool::new(false));
+        let mut threads = Vec::new();
+        let raw = input.as_ref().filter(|input| input.is_terminal()).and_then(|_| RawMode::enable());
+        
+        let mut master = self.master;
+        if let Some(mut input) = input {
+            if let Ok(mut to_command) = master.try_clone() {
+                let done = Arc::clone(&done);
+                threads.push(std::thread::spawn(move || {
+                    use std::io::Read;
+                    
+                    let mut buffer = [0u8; 4096];
+                    while !done.load(Ordering::Relaxed) {
+                        if !readable(input.as_raw_fd()) {
+                            continue;
+                        }
+                        let read = match input.read(&mut buffer) {
+                            Ok(read) => read,
+                            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                            Err(_) => 0,
+                        };
+                        if read == 0 {
+                            // End of input becomes end of file for the command
+                            let _ = to_command.write_all(b"\x04");
+                            break;
+                        }
+                        if to_command.write_all(&buffer[..read]).is_err() {
+                            break;
+                        }
+                    }
+                }));
+            }
+        } else {
+            // There is nothing to read; a command asking for input gets end
+            // of file instead of waiting forever
+            let _ = master.write_all(b"\x04");
+        }
+        
+        let resize = match (size_source(), master.try_clone()) {
+            (Some(_), Ok(terminal)) => Some(tokio::spawn(async move {
+                let Ok(mut changes) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change()) else {
+                    return;
+                };
+                while changes.recv().await.is_some() {
+                    if let Some(size) = window_size() {
+                        // SAFETY: `terminal` is open and `size` is a valid winsize
+                        unsafe { libc::ioctl(terminal.as_raw_fd(), libc::TIOCSWINSZ as _, &size) };
+                    }
+                }
+            })),
+            _ => None,
+        };
+        
+        let done_reading = Arc::clone(&done);
+        threads.push(std::thread::spawn(move || {
+            use std::io::Read;
+            
+            let mut buffer = [0u8; 4096];
+            let mut shown = crate::output::Shown::new(to_stderr);
+            let fd = master.as_raw_fd();
+            loop {
+                if !readable(fd) {
+                    // A prompt has no line break to wait for
+                    if shown.flush().is_err() {
+                        break;
+                    }
+                    // Background processes may keep the terminal open after
+                    // the command exited; stop once it goes quiet
+                    if done_reading.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    continue;
+                }
+                // Reading fails with EIO once nothing has the terminal open
+                let read = match master.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => read,
+                };
+                if let Some(log) = &log {
+                    log.write(&buffer[..read]);
+                }
+                if shown.write(&buffer[..read]).is_err() {
+                    break;
+                }
+            }
+            let _ = shown.flush();
+        }));
+        
+        Forwarding { done, threads, resize, _raw: raw }
+    }
+    
+    #[cfg(not(unix))]
+    pub fn forward(self, _input: Option<File>, _to_stderr: bool, _log: Option<Log>) -> Forwarding {
+        Forwarding {}
+    }
+}
+
+/// Whether the command's output would go to a terminal: to tust's stdout, or
+/// its stderr when `to_stderr`
+pub fn is_terminal_output(to_stderr: bool) -> bool {
+    use std::io::IsTerminal;
+    
+    cfg!(unix) && if to_stderr { std::io::stderr().is_terminal() } else { std::io::stdout().is_terminal() }
+}
+
+/// tust's own standard input, to forward to the command
+#[cfg(unix)]
+pub fn stdin() -> Option<File> {
+    use std::os::fd::AsFd;
+    
+    std::io::stdin().as_fd().try_clone_to_owned().ok().map(File::from)
+}
+
+#[cfg(not(unix))]
+pub fn stdin() -> Option<File> {
+    None
+}
+
+impl Forwarding {
+    /// Copy what is left of the command's output and restore tust's
+    /// terminal; called once the command exited
+    pub fn finish(self) {
+        #[cfg(unix)]
+        {
+            self.done.store(true, std::sync::atomic::Ordering::Relaxed);
+            for thread in self.threads {
+                let _ = thread.join();
+            }
+            if let Some(resize) = self.resize {
+                resize.abort();
+            }
+        }
+    }
+}
+
+/// tust's terminal switched to raw mode, restored on drop
+#[cfg(unix)]
+struct RawMode(libc::termios);
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> Option<Self> {
+        let mut settings = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: `settings` is only read after tcgetattr filled it in
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, settings.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let saved = unsafe { settings.assume_init() };
+        let mut raw = saved;
+        // SAFETY: `raw` is a valid termios
+        unsafe {
+            libc::cfmakeraw(&mut raw);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+        }
+        Some(RawMode(saved))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in `enable`
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+/// Whether `fd` has something to read within a tenth of a second
+#[cfg(unix)]
+fn readable(fd: libc::c_int) -> bool {
+    let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    // SAFETY: `poll` is a single valid pollfd
+    unsafe { libc::poll(&mut poll, 1, 100) > 0 }
+}
+
+/// The first of tust's standard streams that is a terminal
+#[cfg(unix)]
+fn size_source() -> Option<libc::c_int> {
+    // SAFETY: isatty has no preconditions
+    [libc::STDOUT_FILENO, libc::STDIN_FILENO, libc::STDERR_FILENO].into_iter().find(|fd| unsafe { libc::isatty(*fd) } == 1)
+}
+
+/// Size of the terminal tust runs in, if any
+#[cfg(unix)]
+fn window_size() -> Option<libc::winsize> {
+    let fd = size_source()?;
+    // SAFETY: winsize is plain data that TIOCGWINSZ fills in
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    (unsafe { libc::ioctl(fd, libc::TIOCGWINSZ as _, &mut size) } == 0).then_some(size)
+}
@@ -0,0 +1,89 @@
+//! Sandbox size limit while the command runs (`--max-sandbox-size`).
+//!
+//! A background thread measures the space the sandbox takes up every so
+//! often and kills the command, with everything it started, once the sandbox
+//! has grown by more than the limit since the command started. Measuring
+//! walks the whole sandbox, so the interval grows with the time a walk takes.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::child;
+
+const MIN_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watches the sandbox of a running command
+pub struct Watcher {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Option<u64>>,
+}
+
+impl Watcher {
+    /// Start watching `sandbox` for the command with process ID `pid`
+    pub fn start(sandbox: &Path, limit: u64, pid: u32) -> Self {
+        let sandbox = sandbox.to_path_buf();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = std::thread::spawn(move || watch(&sandbox, limit, pid, &stopped));
+        Watcher { stop, thread }
+    }
+    
+    /// Stop watching once the command exited; returns how much the sandbox
+    /// had grown when the command was killed for exceeding the limit
+    pub fn stop(self) -> Option<u64> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap_or(None)
+    }
+}
+
+fn watch(sandbox: &Path, limit: u64, pid: u32, stop: &AtomicBool) -> Option<u64> {
+    let initial = used_space(sandbox);
+    debug!("Sandbox takes {} bytes before the command runs, limit is {} more", initial, limit);
+    while !stop.load(Ordering::Relaxed) {
+        let started = Instant::now();
+        let growth = used_space(sandbox).saturating_sub(initial);
+        if growth > limit {
+            warn!("Sandbox grew by {} bytes, killing the command", growth);
+            child::kill_tree(pid);
+            return Some(growth);
+        }
+        std::thread::sleep(MIN_INTERVAL.max(started.elapsed() * 4));
+    }
+    None
+}
+
+/// Space taken by the files below `path`; files that disappear while
+/// walking are skipped
+fn used_space(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    let mut total = 0;
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        total += if metadata.is_dir() { used_space(&entry.path()) } else { allocated(&metadata) };
+    }
+    total
+}
+
+/// Bytes actually allocated to a file, which is less than its size for
+/// sparse files
+#[cfg(unix)]
+fn allocated(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated(metadata: &fs::Metadata) -> u64 {
+    metadata.len()
+}
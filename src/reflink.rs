@@ -0,0 +1,273 @@
+//! Copy-on-write file copies for the sandbox.
+//!
+//! On filesystems that support it (btrfs, XFS, bcachefs on Linux; APFS on
+//! macOS) files are cloned instead of copied, which takes the same time
+//! whatever their size and uses no extra space until the command writes to
+//! them. The sandbox has to be on the same filesystem as the project for
+//! that, which `--temp-dir` allows. Everywhere else, and after the first
+//! clone the filesystem refuses, files are copied byte for byte. Either way
+//! the copy keeps the file's modification time, so build tools in the
+//! sandbox don't see every file as new and comparing the sandbox can skip
+//! files whose size and time still match (see `--paranoid`). When tust runs
+//! as root, copies keep their owner and group too. Applying a file back
+//! keeps the same metadata from the sandbox, and writes or clones it into
+//! the original file in place, so hard links to it stay intact.
+//!
+//! Extended attributes are copied along (see `xattrs`). Sparse files are
+//! copied with their holes intact rather than filled in,
+//! and files hard-linked to each other are copied once (see `hardlink`).
+//!
+//! `--link` goes further and hard-links the files into the sandbox. Nothing
+//! is copied at all, and a file the command replaces (writing a new file and
+//! renaming it over the old one, or deleting and recreating it) gets a new
+//! inode in the sandbox only. A file written in place is the same file in
+//! both trees, though, so that write reaches the project directly and is not
+//! seen as a change.
+
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::debug;
+
+use crate::{symlink, throttle, xattrs};
+
+/// Cleared once cloning turned out not to work between the two filesystems
+static TRY_CLONE: AtomicBool = AtomicBool::new(true);
+
+/// Copy `from` to `to`, cloning the file where possible; returns its size.
+/// Symlinks are copied as symlinks, with a size of 0.
+pub fn copy(from: &Path, to: &Path) -> std::io::Result<u64> {
+    if let Some(target) = symlink::target(from) {
+        symlink::create(&target, to)?;
+        keep_owner(from, to)?;
+        return Ok(0);
+    }
+    let bytes = copy_contents(from, to)?;
+    copy_metadata(from, to)?;
+    Ok(bytes)
+}
+
+/// Clone `from` to `to` without falling back to copying; returns whether it
+/// was cloned. Symlinks never are.
+pub fn clone(from: &Path, to: &Path) -> std::io::Result<bool> {
+    if !TRY_CLONE.load(Ordering::Relaxed) || symlink::target(from).is_some() {
+        return Ok(false);
+    }
+    match clone_file(from, to) {
+        Ok(()) => Ok(true),
+        Err(e) if is_unsupported(&e) => {
+            debug!("Cloning files is not supported here: {}", e);
+            TRY_CLONE.store(false, Ordering::Relaxed);
+            Ok(false)
+        }
+        Err(e) => {
+            debug!("Failed to clone {}: {}", from.display(), e);
+            Ok(false)
+        }
+    }
+}
+
+/// Give the copy `to` the owner (when running as root), extended attributes
+/// and modification time of `from`
+pub fn copy_metadata(from: &Path, to: &Path) -> std::io::Result<()> {
+    // Changing the owner drops setuid bits and file capabilities, so it
+    // comes first
+    keep_owner(from, to)?;
+    xattrs::copy(from, to)?;
+    keep_modified(from, to)
+}
+
+/// Copy the contents and permissions of `from` to `to`, cloning where
+/// possible and keeping the holes of sparse files; returns its size
+fn copy_contents(from: &Path, to: &Path) -> std::io::Result<u64> {
+    if TRY_CLONE.load(Ordering::Relaxed) {
+        match clone_file(from, to) {
+            Ok(()) => return Ok(fs::metadata(to)?.len()),
+            Err(e) if is_unsupported(&e) => {
+                debug!("Cloning files is not supported here, copying them instead: {}", e);
+                TRY_CLONE.store(false, Ordering::Relaxed);
+            }
+            Err(e) => debug!("Failed to clone {}, copying it instead: {}", from.display(), e),
+        }
+    }
+    
+    #[cfg(target_os = "linux")]
+    if is_sparse(from) {
+        return copy_sparse(from, to);
+    }
+    let bytes = fs::copy(from, to)?;
+    // Reading and writing each count against --bwlimit
+    throttle::consume(bytes * 2);
+    Ok(bytes)
+}
+
+/// Whether `path` takes up less space than its size, i.e. has holes
+#[cfg(target_os = "linux")]
+fn is_sparse(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    
+    fs::metadata(path).is_ok_and(|metadata| metadata.blocks() * 512 < metadata.len())
+}
+
+/// Copy only the data of a sparse file, leaving its holes as holes in the copy
+#[cfg(target_os = "linux")]
+fn copy_sparse(from: &Path, to: &Path) -> std::io::Result<u64> {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::fd::AsRawFd;
+    
+    let mut source = fs::File::open(from)?;
+    let metadata = source.metadata()?;
+    let mut target = fs::File::create(to)?;
+    target.set_len(metadata.len())?;
+    
+    let mut copied = 0;
+    let mut offset = 0;
+    while offset < metadata.len() {
+        // SAFETY: lseek only moves the offset of the open descriptor
+        let data = unsafe { libc::lseek(source.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = std::io::Error::last_os_error();
+            // ENXIO: nothing but a hole up to the end of the file
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(e);
+        }
+        // SAFETY: as above
+        let hole = unsafe { libc::lseek(source.as_raw_fd(), data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        
+        let (data, hole) = (data as u64, hole as u64);
+        source.seek(SeekFrom::Start(data))?;
+        target.seek(SeekFrom::Start(data))?;
+        copied += std::io::copy(&mut (&mut source).take(hole - data), &mut target)?;
+        offset = hole;
+    }
+    debug!("Copied {} of {} bytes of sparse file {}", copied, metadata.len(), from.display());
+    
+    target.set_permissions(metadata.permissions())?;
+    throttle::consume(copied * 2);
+    Ok(metadata.len())
+}
+
+/// Give `to` the modification time of `from`
+fn keep_modified(from: &Path, to: &Path) -> std::io::Result<()> {
+    let modified = fs::metadata(from)?.modified()?;
+    let mut options = fs::OpenOptions::new();
+    // Setting times needs no write access on Unix, so read-only files work
+    #[cfg(not(windows))]
+    options.read(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_WRITE_ATTRIBUTES
+        options.access_mode(0x100);
+    }
+    options.open(to)?.set_modified(modified)
+}
+
+/// Give `to` the owner and group of `from`. Only root may do that; anyone
+/// else's copies belong to them.
+#[cfg(unix)]
+fn keep_owner(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    
+    // SAFETY: geteuid has no preconditions and can't fail
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+    let metadata = fs::symlink_metadata(from)?;
+    std::os::unix::fs::lchown(to, Some(metadata.uid()), Some(metadata.gid()))?;
+    // The setuid and setgid bits don't survive the change of owner
+    if metadata.is_file() && metadata.mode() & 0o6000 != 0 {
+        fs::set_permissions(to, metadata.permissions())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn keep_owner(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Hard-link `from` to `to`, copying it instead where that fails (another
+/// filesystem, or one without hard links); returns its size
+pub fn link(from: &Path, to: &Path) -> std::io::Result<u64> {
+    if symlink::target(from).is_some() {
+        return copy(from, to);
+    }
+    match fs::hard_link(from, to) {
+        Ok(()) => Ok(fs::metadata(to)?.len()),
+        Err(e) => {
+            debug!("Failed to link {}, copying it instead: {}", from.display(), e);
+            copy(from, to)
+        }
+    }
+}
+
+/// Clone `from` onto `to`. An existing `to` is cloned onto in place, so its
+/// hard links stay intact, and is left untouched when cloning fails.
+#[cfg(target_os = "linux")]
+fn clone_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    
+    let source = fs::File::open(from)?;
+    let (target, created) = match fs::OpenOptions::new().write(true).create_new(true).open(to) {
+        Ok(target) => (target, true),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (fs::OpenOptions::new().write(true).open(to)?, false),
+        Err(e) => return Err(e),
+    };
+    // SAFETY: both descriptors stay open for the duration of the call
+    let result = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    if result != 0 {
+        let e = std::io::Error::last_os_error();
+        drop(target);
+        if created {
+            let _ = fs::remove_file(to);
+        }
+        return Err(e);
+    }
+    // Cloning only grows the file; what lies beyond the source is left over
+    let metadata = source.metadata()?;
+    target.set_len(metadata.len())?;
+    target.set_permissions(metadata.permissions())
+}
+
+#[cfg(target_os = "macos")]
+fn clone_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL-terminated strings; clonefile also
+    // copies the permissions
+    if unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn clone_file(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Whether `e` means the filesystems can't clone at all, rather than that
+/// this one file couldn't be cloned
+fn is_unsupported(e: &std::io::Error) -> bool {
+    if e.kind() == std::io::ErrorKind::Unsupported || e.kind() == std::io::ErrorKind::CrossesDevices {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        matches!(e.raw_os_error(), Some(libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL | libc::ENOTTY | libc::ENOSYS))
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
@@ -0,0 +1,602 @@
+//! Detailed change listing and per-file selection for the review step.
+//!
+//! The changes are listed as a tree of the directories they are in, with
+//! the number of changes below each, so large change sets stay scannable;
+//! a directory with a single change below it is folded into that change's
+//! line, and a directory that is new as a whole is one line with its number
+//! of files and size unless `--expand` (or `--diff`) is given. On top of the list, `--diff` shows a unified diff of every changed
+//! text file, and `--diff-handler` replaces it with the output of an
+//! external command for matching file types. `--only` and `--show` narrow
+//! the list, and the changes offered one by one, to some kinds of changes or
+//! paths; the changes they hide are still applied. Answering `s` at the
+//! confirmation prompt steps through the changes one by one instead, and
+//! `--patch` also splits modified text files into hunks to pick from.
+//!
+//! The list ends with a summary in the spirit of `git diff --stat`: changes
+//! by kind, lines added and removed, how much the size grows or shrinks, and
+//! the largest files involved.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::config::Patterns;
+use crate::diff_handler::{self, DiffHandler};
+use crate::{Change, difftool, hex, highlight, intraline, patch, print_change, print_change_named, read_confirmation, redact, symlink, xattrs};
+
+/// Unchanged lines shown around each change
+const CONTEXT_LINES: usize = 3;
+
+/// Largest files named in the summary
+const LARGEST_FILES: usize = 3;
+
+/// Text files above this size are left out of the line counts, which would
+/// take long to work out
+const MAX_COUNTED_SIZE: u64 = 1024 * 1024;
+
+pub struct ReviewOptions<'a> {
+    /// Show unified diffs of changed text files
+    pub diff: bool,
+    pub handlers: &'a [DiffHandler],
+    /// List every file of a new directory
+    pub expand: bool,
+    pub focus: Option<&'a Focus>,
+    /// Show the changed bytes of modified binary files
+    pub hex: bool,
+}
+
+/// A kind of change to focus on with `--only`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Created,
+    /// Modified content, permissions or extended attributes
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+/// The changes `--only` and `--show` ask to see
+pub struct Focus {
+    kinds: Vec<Kind>,
+    paths: Option<Patterns>,
+}
+
+impl Focus {
+    /// A focus on `kinds` of changes to paths matching `patterns`, either of
+    /// which may be empty to not narrow by it; `None` when both are
+    pub fn new(kinds: &[Kind], patterns: &[String]) -> std::io::Result<Option<Self>> {
+        if kinds.is_empty() && patterns.is_empty() {
+            return Ok(None);
+        }
+        let paths = if patterns.is_empty() { None } else { Some(Patterns::new(patterns)?) };
+        Ok(Some(Focus { kinds: kinds.to_vec(), paths }))
+    }
+    
+    pub fn shows(&self, change: &Change) -> bool {
+        let kind = match change {
+            Change::Create(_) => Kind::Created,
+            Change::Modify(_) | Change::Chmod(_) | Change::Xattr(_) => Kind::Modified,
+            Change::Delete(_) => Kind::Deleted,
+            Change::Rename(..) => Kind::Renamed,
+        };
+        (self.kinds.is_empty() || self.kinds.contains(&kind)) && self.paths.as_ref().is_none_or(|paths| paths.matches_change(change))
+    }
+}
+
+/// Whether `focus`, if any, shows each of `changes`
+pub fn shown(changes: &[Change], focus: Option<&Focus>) -> Vec<bool> {
+    changes.iter().map(|change| focus.is_none_or(|focus| focus.shows(change))).collect()
+}
+
+/// What the tree view lists changes between
+struct Listing<'a> {
+    original: &'a Path,
+    modified: &'a Path,
+    options: &'a ReviewOptions<'a>,
+}
+
+/// Print the change list with the details requested in `options`
+pub fn print_changes(changes: &[Change], original: &Path, modified: &Path, options: &ReviewOptions) {
+    let mut root = Directory::default();
+    let shown = shown(changes, options.focus);
+    for (change, _) in changes.iter().zip(&shown).filter(|(_, shown)| **shown) {
+        root.insert(change);
+    }
+    root.print_contents(Path::new(""), "  ", &Listing { original, modified, options });
+    let hidden = changes.len() - root.count;
+    if hidden > 0 {
+        let changes = if hidden == 1 { "change" } else { "changes" };
+        println!("{}", format!("  ({} more {} not shown because of --only/--show)", hidden, changes).dimmed());
+    }
+    print_summary(changes, original, modified);
+}
+
+/// The changes below a directory, for the tree view
+#[derive(Default)]
+struct Directory<'a> {
+    directories: BTreeMap<&'a OsStr, Directory<'a>>,
+    changes: Vec<&'a Change>,
+    /// Changes here and in all directories below
+    count: usize,
+}
+
+impl<'a> Directory<'a> {
+    fn insert(&mut self, change: &'a Change) {
+        let mut directory = self;
+        directory.count += 1;
+        for component in listed_path(change).parent().into_iter().flat_map(Path::iter) {
+            directory = directory.directories.entry(component).or_default();
+            directory.count += 1;
+        }
+        directory.changes.push(change);
+    }
+    
+    /// The only change below, if there is just one
+    fn single(&self) -> Option<&'a Change> {
+        if self.count != 1 {
+            return None;
+        }
+        match self.changes.first() {
+            Some(change) => Some(change),
+            None => self.directories.values().next()?.single(),
+        }
+    }
+    
+    /// Every change below
+    fn all(&self) -> Vec<&'a Change> {
+        let mut changes = self.changes.clone();
+        changes.extend(self.directories.values().flat_map(Directory::all));
+        changes
+    }
+    
+    /// Print the directories and changes below `path` after `indent`
+    fn print_contents(&self, path: &Path, indent: &str, listing: &Listing) {
+        let nested = format!("{}  ", indent);
+        for (name, directory) in &self.directories {
+            let mut label = PathBuf::from(name);
+            let mut directory = directory;
+            let new = fs::symlink_metadata(listing.original.join(path).join(name)).is_err();
+            if new && directory.count > 1 && !listing.options.expand && !listing.options.diff {
+                let size: u64 = directory
+                    .all()
+                    .iter()
+                    .filter_map(|change| fs::symlink_metadata(listing.modified.join(listed_path(change))).ok())
+                    .map(|metadata| metadata.len())
+                    .sum();
+                let summary = format!(" ({} files, {})", directory.count, crate::format_size(size));
+                println!("{}{}{}{}", indent, "+ ".green(), format!("{}/", label.display()).bold(), summary.dimmed());
+                continue;
+            }
+            if let Some(change) = directory.single() {
+                let shown = listed_path(change)
+                    .strip_prefix(path.join(&label))
+                    .map_or_else(|_| listed_path(change).to_path_buf(), |rest| label.join(rest));
+                print_change_named(change, indent, Some(&shown));
+                print_details(change, indent, listing);
+                continue;
+            }
+            // Directories with nothing but one directory in them share a line
+            while directory.changes.is_empty()
+                && directory.directories.len() == 1
+                && let Some((name, only)) = directory.directories.iter().next()
+            {
+                label.push(name);
+                directory = only;
+            }
+            println!("{}{}{}", indent, format!("{}/", label.display()).bold(), format!(" ({})", directory.count).dimmed());
+            directory.print_contents(&path.join(&label), &nested, listing);
+        }
+        for change in &self.changes {
+            print_change_named(change, indent, listed_path(change).file_name().map(Path::new));
+            print_details(change, indent, listing);
+        }
+    }
+}
+
+/// The path a change is listed under: a rename's source
+fn listed_path(change: &Change) -> &Path {
+    match change {
+        Change::Create(path) | Change::Modify(path) | Change::Delete(path) | Change::Chmod(path) | Change::Xattr(path) => path,
+        Change::Rename(from, _) => from,
+    }
+}
+
+/// Print the details of `change` requested for `listing` below its line,
+/// which is at `indent`
+fn print_details(change: &Change, indent: &str, listing: &Listing) {
+    let (original, modified, options) = (listing.original, listing.modified, listing.options);
+    let indent = format!("{}    ", indent);
+    if let Change::Chmod(path) = change {
+        println!("{}{}", indent, mode_change(&original.join(path), &modified.join(path)).dimmed());
+        return;
+    }
+    if let Change::Xattr(path) = change {
+        println!("{}{}", indent, attribute_change(&original.join(path), &modified.join(path)).dimmed());
+        return;
+    }
+    if let Change::Modify(path) = change
+        && let Some(handler) = diff_handler::find(options.handlers, path)
+    {
+        diff_handler::print_output(handler, &original.join(path), &modified.join(path), &indent);
+        return;
+    }
+    
+    if options.hex
+        && let Change::Modify(path) = change
+        && (patch::is_binary(&original.join(path)) || patch::is_binary(&modified.join(path)))
+    {
+        for line in hex::preview(Some(&original.join(path)), Some(&modified.join(path))) {
+            println!("{}{}", indent, line);
+        }
+        return;
+    }
+    if options.diff
+        && let Some((old, new)) = versions(change, original, modified)
+    {
+        print_diff(old.as_deref(), new.as_deref(), &indent);
+    }
+}
+
+/// Print how many changes of each kind there are, the lines added and
+/// removed, the change in size and the largest files involved
+fn print_summary(changes: &[Change], original: &Path, modified: &Path) {
+    let size = |path: Option<&Path>| path.and_then(|path| fs::symlink_metadata(path).ok()).map_or(0, |metadata| metadata.len());
+    let mut kinds = [
+        ("created", 0),
+        ("modified", 0),
+        ("deleted", 0),
+        ("renamed", 0),
+        ("with new permissions", 0),
+        ("with new attributes", 0),
+    ];
+    let (mut added, mut removed, mut uncounted) = (0, 0, 0);
+    let mut delta: i64 = 0;
+    let mut largest = Vec::new();
+    for change in changes {
+        let kind = match change {
+            Change::Create(_) => 0,
+            Change::Modify(_) => 1,
+            Change::Delete(_) => 2,
+            Change::Rename(..) => 3,
+            Change::Chmod(_) => 4,
+            Change::Xattr(_) => 5,
+        };
+        kinds[kind].1 += 1;
+        let Some((old, new)) = versions(change, original, modified) else {
+            continue;
+        };
+        let (old_size, new_size) = (size(old.as_deref()), size(new.as_deref()));
+        delta += new_size as i64 - old_size as i64;
+        if let Change::Create(path) | Change::Modify(path) | Change::Delete(path) = change {
+            largest.push((old_size.max(new_size), path));
+        }
+        
+        let text = (old_size.max(new_size) <= MAX_COUNTED_SIZE)
+            .then(|| Some((patch::read_text(old.as_deref())?, patch::read_text(new.as_deref())?)))
+            .flatten();
+        let Some((old_text, new_text)) = text else {
+            uncounted += 1;
+            continue;
+        };
+        for hunk in patch::hunks(&old_text, &new_text, 0) {
+            for line in hunk.lines {
+                match line {
+                    patch::HunkLine::Add(_) => added += 1,
+                    patch::HunkLine::Remove(_) => removed += 1,
+                    patch::HunkLine::Context(_) => {}
+                }
+            }
+        }
+    }
+    
+    let kinds: Vec<String> =
+        kinds.iter().filter(|(_, count)| *count > 0).map(|(kind, count)| format!("{} {}", count, kind)).collect();
+    let mut lines = format!("{} {} lines", format!("+{}", added).green(), format!("-{}", removed).red());
+    if uncounted > 0 {
+        let files = if uncounted == 1 { "file" } else { "files" };
+        lines.push_str(&format!(" ({} binary or large {} not counted)", uncounted, files));
+    }
+    let sign = if delta < 0 { "-" } else { "+" };
+    println!(
+        "\n{}: {}, {}{}",
+        format!("{} change{} ({})", changes.len(), if changes.len() == 1 { "" } else { "s" }, kinds.join(", ")).bold(),
+        lines,
+        sign,
+        crate::format_size(delta.unsigned_abs())
+    );
+    largest.sort_by_key(|(size, _)| std::cmp::Reverse(*size));
+    if largest.len() > 1 {
+        let named: Vec<String> = largest
+            .iter()
+            .take(LARGEST_FILES)
+            .map(|(size, path)| format!("{} ({})", path.display(), crate::format_size(*size)))
+            .collect();
+        println!("{}", format!("Largest: {}", named.join(", ")).dimmed());
+    }
+}
+
+/// Ask about each change in turn; returns which ones the user accepted.
+///
+/// With `by_hunk`, modified text files are offered hunk by hunk. When only
+/// some hunks are accepted, the file in `modified` is rewritten to the
+/// original with just those hunks, so the regular apply picks that up.
+/// Answering `d` opens the change in `difftool` when given, instead of
+/// printing its diff.
+pub fn select(
+    changes: &[Change],
+    original: &Path,
+    modified: &Path,
+    allow_stdin: bool,
+    by_hunk: bool,
+    difftool: Option<&str>,
+) -> std::io::Result<Vec<bool>> {
+    let mut accepted = vec![false; changes.len()];
+    for (index, change) in changes.iter().enumerate() {
+        if by_hunk
+            && let Change::Modify(path) = change
+            && let Some(old) = patch::read_text(Some(&original.join(path)))
+            && let Some(new) = patch::read_text(Some(&modified.join(path)))
+        {
+            print_change(change);
+            let (selection, quit) = select_hunks(&old, &new, allow_stdin)?;
+            match selection {
+                Selection::All => accepted[index] = true,
+                Selection::Nothing => {}
+                Selection::Partial(content) => {
+                    fs::write(modified.join(path), content)?;
+                    accepted[index] = true;
+                }
+            }
+            if quit {
+                return Ok(accepted);
+            }
+            continue;
+        }
+        
+        loop {
+            print_change(change);
+            println!("    {}", "Apply this change? [y]es, [n]o, [a]ll remaining, [q]uit, [d]iff, he[x]".yellow());
+            match read_confirmation(allow_stdin)?.trim().to_lowercase().as_str() {
+                "y" => accepted[index] = true,
+                "n" => {}
+                "a" => {
+                    accepted[index..].fill(true);
+                    return Ok(accepted);
+                }
+                "q" => return Ok(accepted),
+                "d" if let Some(tool) = difftool => {
+                    match difftool::open(tool, change, original, modified) {
+                        Ok(true) => {}
+                        Ok(false) => println!("      {}", "(no file content to compare)".dimmed()),
+                        Err(e) => println!("      {}", format!("(difftool failed: {})", e).dimmed()),
+                    }
+                    continue;
+                }
+                "d" => {
+                    print_change(change);
+                    let options = ReviewOptions { diff: true, handlers: &[], expand: true, focus: None, hex: false };
+                    print_details(change, "  ", &Listing { original, modified, options: &options });
+                    continue;
+                }
+                "x" => {
+                    match versions(change, original, modified) {
+                        Some((old, new)) if old.iter().chain(&new).any(|path| patch::is_binary(path)) => {
+                            for line in hex::preview(old.as_deref(), new.as_deref()) {
+                                println!("      {}", line);
+                            }
+                        }
+                        _ => println!("      {}", "(not a binary file, see its diff with d)".dimmed()),
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+            break;
+        }
+    }
+    Ok(accepted)
+}
+
+enum Selection {
+    All,
+    Nothing,
+    /// The new content with only the accepted hunks applied
+    Partial(String),
+}
+
+/// Ask about each hunk from `old` to `new`; also returns whether the user quit
+fn select_hunks(old: &str, new: &str, allow_stdin: bool) -> std::io::Result<(Selection, bool)> {
+    let hunks = patch::hunks(old, new, CONTEXT_LINES);
+    let mut accepted = vec![false; hunks.len()];
+    let mut quit = false;
+    
+    'hunks: for (index, hunk) in hunks.iter().enumerate() {
+        loop {
+            print_hunk(hunk, None, "      ");
+            println!(
+                "    {}",
+                format!("Apply this hunk ({}/{})? [y]es, [n]o, [a]ll remaining in this file, [q]uit", index + 1, hunks.len())
+                    .yellow()
+            );
+            match read_confirmation(allow_stdin)?.trim().to_lowercase().as_str() {
+                "y" => accepted[index] = true,
+                "n" => {}
+                "a" => {
+                    accepted[index..].fill(true);
+                    break 'hunks;
+                }
+                "q" => {
+                    quit = true;
+                    break 'hunks;
+                }
+                _ => continue,
+            }
+            break;
+        }
+    }
+    
+    let selection = if accepted.iter().all(|accepted| *accepted) {
+        Selection::All
+    } else if !accepted.contains(&true) {
+        Selection::Nothing
+    } else {
+        let selected: Vec<patch::Hunk> =
+            hunks.into_iter().zip(&accepted).filter(|(_, accepted)| **accepted).map(|(hunk, _)| hunk).collect();
+        let content = patch::apply_hunks(old, &selected)
+            .map_err(|_| std::io::Error::other("the selected hunks could not be combined"))?;
+        Selection::Partial(content)
+    };
+    Ok((selection, quit))
+}
+
+/// Paths of the file before and after a change (`None` where it doesn't
+/// exist), or `None` for directory renames and metadata changes
+pub fn versions(change: &Change, original: &Path, modified: &Path) -> Option<(Option<PathBuf>, Option<PathBuf>)> {
+    match change {
+        Change::Create(path) => Some((None, Some(modified.join(path)))),
+        Change::Modify(path) => Some((Some(original.join(path)), Some(modified.join(path)))),
+        Change::Delete(path) => Some((Some(original.join(path)), None)),
+        Change::Rename(..) | Change::Chmod(_) | Change::Xattr(_) => None,
+    }
+}
+
+/// The permissions of a file before and after a `Chmod`, e.g. `mode 0644 -> 0755`
+fn mode_change(old: &Path, new: &Path) -> String {
+    let describe = |path: &Path| fs::metadata(path).map_or_else(|_| "?".to_string(), |metadata| format_mode(&metadata));
+    format!("mode {} -> {}", describe(old), describe(new))
+}
+
+/// The extended attributes an `Xattr` change adds, removes or changes
+fn attribute_change(old: &Path, new: &Path) -> String {
+    match xattrs::changed_names(old, new) {
+        Ok(names) => format!("attributes {}", names.join(", ")),
+        Err(e) => format!("attributes changed ({})", e),
+    }
+}
+
+#[cfg(unix)]
+fn format_mode(metadata: &fs::Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    
+    format!("{:04o}", metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn format_mode(metadata: &fs::Metadata) -> String {
+    if metadata.permissions().readonly() { "read-only" } else { "writable" }.to_string()
+}
+
+/// Unified diff of a change as plain lines, for displays that style it themselves
+pub fn diff_lines(change: &Change, original: &Path, modified: &Path) -> Vec<String> {
+    let Some((old, new)) = versions(change, original, modified) else {
+        return vec![match change {
+            Change::Chmod(path) => mode_change(&original.join(path), &modified.join(path)),
+            Change::Xattr(path) => attribute_change(&original.join(path), &modified.join(path)),
+            _ => "(directory moved, contents unchanged)".to_string(),
+        }];
+    };
+    let (Some(old_text), Some(new_text)) = (patch::read_text(old.as_deref()), patch::read_text(new.as_deref())) else {
+        return vec![no_diff(old.as_deref(), new.as_deref())];
+    };
+    
+    patch::hunks(&old_text, &new_text, CONTEXT_LINES)
+        .iter()
+        .flat_map(|hunk| {
+            let text = patch::format_hunk(hunk);
+            text.lines().map(|line| redact::redact(line).into_owned()).collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Print a unified diff between two versions of a file (`None` when absent)
+pub fn print_diff(old: Option<&Path>, new: Option<&Path>, indent: &str) {
+    let (Some(old_text), Some(new_text)) = (patch::read_text(old), patch::read_text(new)) else {
+        println!("{}{}", indent, no_diff(old, new).dimmed());
+        return;
+    };
+    
+    // Symlink targets are paths, not code in the language of the link's name
+    let highlighted = new
+        .or(old)
+        .filter(|_| [old, new].into_iter().flatten().all(|path| symlink::target(path).is_none()))
+        .and_then(|path| Some((highlight::lines(path, &old_text)?, highlight::lines(path, &new_text)?)));
+    for hunk in patch::hunks(&old_text, &new_text, CONTEXT_LINES) {
+        print_hunk(&hunk, highlighted.as_ref().map(|(old_lines, new_lines)| (&old_lines[..], &new_lines[..])), indent);
+    }
+}
+
+/// What is shown instead of a diff between versions of a file that aren't
+/// both text, e.g. `binary file changed (1.2 KB → 3.4 KB)`
+fn no_diff(old: Option<&Path>, new: Option<&Path>) -> String {
+    if !old.into_iter().chain(new).any(patch::is_binary) {
+        return "(unreadable file, no diff shown)".to_string();
+    }
+    let size = |path: &Path| fs::symlink_metadata(path).map_or_else(|_| "?".to_string(), |metadata| crate::format_size(metadata.len()));
+    match (old, new) {
+        (Some(old), Some(new)) => format!("binary file changed ({} → {})", size(old), size(new)),
+        (None, Some(new)) => format!("binary file added ({})", size(new)),
+        (Some(old), None) => format!("binary file removed ({})", size(old)),
+        (None, None) => "binary file".to_string(),
+    }
+}
+
+/// Print `hunk`, with its lines taken from the `highlighted` lines of the old
+/// and new file when given, and the words that changed within a line
+/// emphasized; lines with something redacted are never highlighted
+fn print_hunk(hunk: &patch::Hunk, highlighted: Option<(&[highlight::Line], &[highlight::Line])>, indent: &str) {
+    println!("{}{}", indent, patch::format_hunk(hunk).lines().next().unwrap_or_default().cyan());
+    let texts: Vec<(char, Cow<str>)> = hunk
+        .lines
+        .iter()
+        .map(|line| {
+            let (marker, content) = match line {
+                patch::HunkLine::Context(content) => (' ', content),
+                patch::HunkLine::Remove(content) => ('-', content),
+                patch::HunkLine::Add(content) => ('+', content),
+            };
+            (marker, redact::redact(content.trim_end_matches(['\n', '\r'])))
+        })
+        .collect();
+    let emphasis = intraline::emphasis(&texts.iter().map(|(marker, text)| (*marker, text.as_ref())).collect::<Vec<_>>());
+    let tints = highlight::tints();
+    
+    let (mut old_index, mut new_index) = (hunk.old_start.saturating_sub(1), hunk.new_start.saturating_sub(1));
+    for ((line, (marker, text)), emphasis) in hunk.lines.iter().zip(&texts).zip(&emphasis) {
+        let (content, source, tint, emphasis_tint) = match line {
+            patch::HunkLine::Context(content) => {
+                old_index += 1;
+                new_index += 1;
+                (content, highlighted.and_then(|(_, new_lines)| new_lines.get(new_index - 1)), "", "")
+            }
+            patch::HunkLine::Remove(content) => {
+                old_index += 1;
+                (content, highlighted.and_then(|(old_lines, _)| old_lines.get(old_index - 1)), tints.removed, tints.removed_emphasis)
+            }
+            patch::HunkLine::Add(content) => {
+                new_index += 1;
+                (content, highlighted.and_then(|(_, new_lines)| new_lines.get(new_index - 1)), tints.added, tints.added_emphasis)
+            }
+        };
+        let color = |text: &str| match marker {
+            '-' => text.red(),
+            '+' => text.green(),
+            _ => text.normal(),
+        };
+        let shown = match source.filter(|_| matches!(text, Cow::Borrowed(_))) {
+            Some(source) => highlight::paint(source, tint, emphasis_tint, emphasis),
+            None => intraline::split(text, 0, emphasis)
+                .into_iter()
+                .map(|(piece, emphasized)| if emphasized { color(piece).reversed() } else { color(piece) }.to_string())
+                .collect(),
+        };
+        println!("{}{}{}", indent, color(&marker.to_string()), shown);
+        if !content.ends_with('\n') {
+            println!("{}\\ No newline at end of file", indent);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! Running the command under `sandbox-exec` (`--backend sandbox-exec`, macOS).
+//!
+//! The sandbox is filled by copying as usual; the command then runs with a
+//! generated Seatbelt profile that denies writes everywhere but the sandbox
+//! and the terminal and null devices, so a command that writes outside the
+//! sandbox fails instead of changing the machine behind tust's back.
+
+use std::path::Path;
+use std::process::Command;
+
+use log::debug;
+
+/// A command running `program` under `sandbox-exec` with writes limited to
+/// `sandbox`; its arguments are added by the caller
+pub fn command(sandbox: &Path, program: &str, network: bool) -> Command {
+    let mut command = Command::new("sandbox-exec");
+    command.arg("-p").arg(profile(sandbox, network)).arg(program);
+    debug!("Running the command with {:?}", command);
+    command
+}
+
+fn profile(sandbox: &Path, network: bool) -> String {
+    // Rules match resolved paths, and temporary directories on macOS are
+    // below symlinks (/tmp, /var)
+    let sandbox = sandbox.canonicalize().unwrap_or_else(|_| sandbox.to_path_buf());
+    let sandbox = sandbox.to_string_lossy().replace('\\', "\\\\").replace('"', "\\\"");
+    let mut profile = format!(
+        "(version 1)\n\
+         (allow default)\n\
+         (deny file-write*)\n\
+         (allow file-write* (subpath \"{}\") (literal \"/dev/null\") (literal \"/dev/zero\") (regex #\"^/dev/(tty|fd/)\"))\n",
+        sandbox
+    );
+    if !network {
+        profile.push_str("(deny network*)\n(allow network* (local ip \"localhost:*\"))\n");
+    }
+    profile
+}
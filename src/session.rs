@@ -0,0 +1,332 @@
+//! Kept sandboxes (`--keep`).
+//!
+//! Normally the sandbox is deleted when tust exits. With `--keep` it stays
+//! in place and a record of the run is stored under the user's data
+//! directory: the tust invocation, the command, the directories involved,
+//! timestamps, the exit code, the changes that were found, the command's
+//! output (`output.log`) and the fingerprints of the original files from
+//! before the sandbox was filled (`seed.json`, see `conflict`). The sandbox itself is not moved, so it can be
+//! inspected at the printed path. `tust sessions list|show|rm` works with the
+//! stored sessions, `tust sessions path` prints a sandbox's path (`tustcd` in
+//! `shell_init`), and `tust exec` runs more commands in their sandboxes.
+//! Since `tust replay` and `tust exec` run what a record says, `--sign` adds
+//! a detached signature to it (see `signature`), and `--encrypt` stores the
+//! record and output encrypted (see `encryption`).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use colored::Colorize;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::conflict::Seed;
+use crate::encryption::{self, Key};
+use crate::{Change, output, print_change, redact};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    /// Arguments tust was started with
+    pub args: Vec<String>,
+    pub command: Vec<String>,
+    /// Directory the command was reviewed against
+    pub directory: PathBuf,
+    pub sandbox: PathBuf,
+    /// Seconds since the Unix epoch
+    pub started: u64,
+    pub finished: u64,
+    /// `None` when the command was killed by a signal
+    pub exit_code: Option<i32>,
+    /// Of the latest command; missing in older sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<crate::child::Usage>,
+    /// `None` when the command failed before changes were computed
+    pub changes: Option<Vec<SessionChange>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionChange {
+    pub kind: String,
+    pub path: PathBuf,
+    /// Destination of a directory rename
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<PathBuf>,
+    /// BLAKE3 hash of the original file the change replaces, checked by
+    /// `tust verify`; missing in older sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+}
+
+impl SessionChange {
+    /// Describe `change` with the hash of its file in the `original` tree
+    pub fn of(change: &Change, original: &Path) -> Self {
+        let (kind, path, to) = match change {
+            Change::Create(path) => ("create", path, None),
+            Change::Modify(path) => ("modify", path, None),
+            Change::Delete(path) => ("delete", path, None),
+            Change::Chmod(path) => ("chmod", path, None),
+            Change::Xattr(path) => ("xattr", path, None),
+            Change::Rename(from, to) => ("rename", from, Some(to.clone())),
+        };
+        let before = match change {
+            Change::Create(_) | Change::Rename(..) => None,
+            _ => crate::hash_file(&original.join(path)).ok().map(|hash| hash.to_hex().to_string()),
+        };
+        SessionChange { kind: kind.to_string(), path: path.clone(), to, before }
+    }
+    
+    fn to_change(&self) -> Change {
+        match (self.kind.as_str(), &self.to) {
+            ("create", _) => Change::Create(self.path.clone()),
+            ("delete", _) => Change::Delete(self.path.clone()),
+            ("chmod", _) => Change::Chmod(self.path.clone()),
+            ("xattr", _) => Change::Xattr(self.path.clone()),
+            ("rename", Some(to)) => Change::Rename(self.path.clone(), to.clone()),
+            _ => Change::Modify(self.path.clone()),
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, as stored in session records
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Prefix of kept sandboxes, which `--clean` leaves to `tust sessions rm`
+pub const SANDBOX_PREFIX: &str = "tust-kept-";
+
+/// The record in a session's directory
+const RECORD: &str = "session.json";
+
+/// The command's output in a session's directory
+const OUTPUT_LOG: &str = "output.log";
+
+/// The fingerprints of the original files in a session's directory
+const SEED: &str = "seed.json";
+
+fn sessions_dir() -> std::io::Result<PathBuf> {
+    Ok(crate::data_dir()?.join("sessions"))
+}
+
+/// Store the record of a kept sandbox, with the fingerprints of the original
+/// files and the command's output if it was recorded, and return its id
+pub fn save(session: &Session, seed: &Seed, output: Option<&output::Log>, key: Option<&Key>) -> std::io::Result<u64> {
+    let dir = sessions_dir()?;
+    fs::create_dir_all(&dir)?;
+    
+    // Sequential ids, like snapshots, so they are easy to type
+    let mut id = session_ids(&dir)?.into_iter().max().unwrap_or(0);
+    let root = loop {
+        id += 1;
+        let root = dir.join(id.to_string());
+        match fs::create_dir(&root) {
+            Ok(()) => break root,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    
+    info!("Storing session {} for sandbox {}", id, session.sandbox.display());
+    if let Err(e) = write(&root, session, seed, output, key) {
+        let _ = fs::remove_dir_all(&root);
+        return Err(e);
+    }
+    Ok(id)
+}
+
+/// Replace the record of session `id` after another command ran in its
+/// sandbox (`tust exec`)
+pub fn update(
+    id: &str,
+    session: &Session,
+    seed: &Seed,
+    output: Option<&output::Log>,
+    key: Option<&Key>,
+) -> std::io::Result<()> {
+    open(id)?;
+    let id: u64 = id.parse().map_err(std::io::Error::other)?;
+    info!("Updating session {} for sandbox {}", id, session.sandbox.display());
+    write(&sessions_dir()?.join(id.to_string()), session, seed, output, key)
+}
+
+fn write(root: &Path, session: &Session, seed: &Seed, output: Option<&output::Log>, key: Option<&Key>) -> std::io::Result<()> {
+    let data = serde_json::to_vec_pretty(session).map_err(std::io::Error::other)?;
+    let seed = serde_json::to_vec(seed).map_err(std::io::Error::other)?;
+    encryption::write(&root.join(RECORD), &data, key)
+        .and_then(|()| encryption::write(&root.join(SEED), &seed, key))
+        .and_then(|()| output.map_or(Ok(()), |output| output.save(&root.join(OUTPUT_LOG), key)))
+}
+
+fn session_ids(dir: &Path) -> std::io::Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// The file holding the record of session `id`, signed with `--sign`
+pub fn record_path(id: &str) -> std::io::Result<PathBuf> {
+    let id: u64 = id
+        .parse()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::NotFound, format!("no session with id {}", id)))?;
+    Ok(sessions_dir()?.join(id.to_string()).join(RECORD))
+}
+
+/// Look up a stored session by id
+pub fn open(id: &str) -> std::io::Result<Session> {
+    let not_found = || std::io::Error::new(std::io::ErrorKind::NotFound, format!("no session with id {}", id));
+    let data = match encryption::read(&record_path(id).map_err(|_| not_found())?) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_slice(&data).map_err(std::io::Error::other)
+}
+
+/// The fingerprints of the original files stored with session `id`; `None`
+/// for sessions kept before they were stored
+pub fn open_seed(id: &str) -> std::io::Result<Option<Seed>> {
+    let path = record_path(id)?.with_file_name(SEED);
+    match encryption::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).map(Some).map_err(std::io::Error::other),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The sandbox of session `id`, or of the latest session whose sandbox still
+/// exists (for `tustcd`)
+pub fn sandbox(id: Option<&str>) -> std::io::Result<PathBuf> {
+    if let Some(id) = id {
+        let session = open(id)?;
+        if !session.sandbox.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("the sandbox {} of session {} was deleted", session.sandbox.display(), id),
+            ));
+        }
+        return Ok(session.sandbox);
+    }
+    
+    let dir = sessions_dir()?;
+    let ids = if dir.is_dir() { session_ids(&dir)? } else { Vec::new() };
+    for id in ids.into_iter().rev() {
+        let session = open(&id.to_string())?;
+        if session.sandbox.is_dir() {
+            return Ok(session.sandbox);
+        }
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no kept session has a sandbox left"))
+}
+
+/// Print one line per stored session
+pub fn list() -> std::io::Result<()> {
+    let dir = sessions_dir()?;
+    let ids = if dir.is_dir() { session_ids(&dir)? } else { Vec::new() };
+    if ids.is_empty() {
+        println!("{}", "No sessions".green());
+        return Ok(());
+    }
+    
+    let now = now();
+    for id in ids {
+        let session = open(&id.to_string())?;
+        println!(
+            "  {:>4}  {:>10}  {:>8}  {:>8}  {}  {}",
+            id.to_string().bold(),
+            crate::snapshot::format_age(now.saturating_sub(session.started)),
+            exit_label(session.exit_code),
+            session.changes.as_ref().map_or("-".to_string(), |changes| format!("{} changes", changes.len())),
+            session.directory.display(),
+            redact::redact(&session.command.join(" ")),
+        );
+    }
+    Ok(())
+}
+
+fn exit_label(exit_code: Option<i32>) -> String {
+    match exit_code {
+        Some(0) => "ok".to_string(),
+        Some(code) => format!("exit {}", code),
+        None => "killed".to_string(),
+    }
+}
+
+/// Print the details of a stored session and the changes it found
+pub fn show(id: &str) -> std::io::Result<()> {
+    let session = open(id)?;
+    let now = now();
+    println!("{}", format!("Session {}", id).blue().bold());
+    println!("  Command:   {}", redact::redact(&session.command.join(" ")));
+    println!("  Directory: {}", session.directory.display());
+    println!(
+        "  Sandbox:   {}{}",
+        session.sandbox.display(),
+        if session.sandbox.is_dir() { "" } else { " (deleted)" }
+    );
+    println!(
+        "  Started:   {} (ran for {}s)",
+        crate::snapshot::format_age(now.saturating_sub(session.started)),
+        session.finished.saturating_sub(session.started)
+    );
+    println!("  Result:    {}", exit_label(session.exit_code));
+    if let Some(resources) = session.usage.and_then(|usage| usage.resources) {
+        println!("  Resources: {}", resources);
+    }
+    let log = sessions_dir()?.join(id).join(OUTPUT_LOG);
+    if log.is_file() {
+        println!("  Output:    {}", log.display());
+    }
+    
+    match &session.changes {
+        None => println!("{}", "\nNo changes recorded, the command failed".yellow()),
+        Some(changes) if changes.is_empty() => println!("{}", "\nNo changes".green()),
+        Some(changes) => {
+            let count = |kind: &str| changes.iter().filter(|change| change.kind == kind).count();
+            println!(
+                "{}",
+                format!(
+                    "\n{} created, {} modified, {} deleted, {} renamed, {} with new permissions, {} with new attributes:",
+                    count("create"),
+                    count("modify"),
+                    count("delete"),
+                    count("rename"),
+                    count("chmod"),
+                    count("xattr")
+                )
+                .blue()
+                .bold()
+            );
+            for change in changes {
+                print_change(&change.to_change());
+            }
+        }
+    }
+    
+    let tail = output::tail(&log);
+    if !tail.is_empty() {
+        println!("{}", "\nLast lines of output:".blue().bold());
+        for line in tail {
+            println!("  {}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Delete a stored session and its sandbox
+pub fn remove(id: &str) -> std::io::Result<()> {
+    let session = open(id)?;
+    // Only ever delete directories tust created, whatever the record says
+    let is_sandbox = session
+        .sandbox
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("tust-"));
+    if is_sandbox && session.sandbox.is_dir() {
+        info!("Deleting sandbox {}", session.sandbox.display());
+        fs::remove_dir_all(&session.sandbox)?;
+    }
+    fs::remove_dir_all(sessions_dir()?.join(id))
+}
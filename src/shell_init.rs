@@ -0,0 +1,87 @@
+//! Shell integration snippets emitted by `tust shell-init <shell>`.
+//!
+//! Commands run by tust see `TUST_SANDBOX` (the sandbox path) and
+//! `TUST_ORIGINAL_DIR` (the directory the changes would be applied to), so a
+//! shell started through tust can tell it is inside a sandbox. The snippets
+//! mark the prompt while inside one, define `tustcd` to jump into the sandbox
+//! of the latest kept session (or of the session given), and add a
+//! `tust-NAME` alias for each profile in the global configuration.
+
+use clap::ValueEnum;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+const BASH: &str = r#"# tust shell integration for bash
+# Add to ~/.bashrc: eval "$(tust shell-init bash)"
+__tust_prompt() {
+    if [ -n "$TUST_SANDBOX" ]; then
+        printf '(tust) '
+    fi
+}
+case "$PS1" in
+    *__tust_prompt*) ;;
+    *) PS1='$(__tust_prompt)'"$PS1" ;;
+esac
+tustcd() {
+    local sandbox
+    sandbox="$(command tust sessions path "$@")" && cd "$sandbox"
+}
+"#;
+
+const ZSH: &str = r#"# tust shell integration for zsh
+# Add to ~/.zshrc: eval "$(tust shell-init zsh)"
+__tust_prompt() {
+    if [[ -n "$TUST_SANDBOX" ]]; then
+        print -n '(tust) '
+    fi
+}
+setopt PROMPT_SUBST
+if [[ "$PROMPT" != *__tust_prompt* ]]; then
+    PROMPT='$(__tust_prompt)'"$PROMPT"
+fi
+tustcd() {
+    local sandbox
+    sandbox="$(command tust sessions path "$@")" && cd "$sandbox"
+}
+"#;
+
+const FISH: &str = r#"# tust shell integration for fish
+# Add to ~/.config/fish/config.fish: tust shell-init fish | source
+function __tust_prompt
+    if set -q TUST_SANDBOX
+        echo -n '(tust) '
+    end
+end
+if not functions -q __tust_original_fish_prompt
+    functions -c fish_prompt __tust_original_fish_prompt
+    function fish_prompt
+        __tust_prompt
+        __tust_original_fish_prompt
+    end
+end
+function tustcd
+    set -l sandbox (command tust sessions path $argv); and cd $sandbox
+end
+"#;
+
+/// Shell code that sets up the prompt marker, `tustcd` and an alias for each
+/// of the `profiles` for `shell`
+pub fn script(shell: Shell, profiles: &[String]) -> String {
+    let mut script = match shell {
+        Shell::Bash => BASH,
+        Shell::Zsh => ZSH,
+        Shell::Fish => FISH,
+    }
+    .to_string();
+    // Names that need quoting make poor commands anyway
+    let names = profiles.iter().filter(|name| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    for name in names {
+        script.push_str(&format!("alias tust-{}='tust --profile {}'\n", name, name));
+    }
+    script
+}
@@ -0,0 +1,177 @@
+//! Detached signatures of patches and session records: written with `--sign`
+//! next to the `--output-patch` file and the record of a `--keep` session,
+//! and checked by `tust patch apply`, `tust replay` and `tust exec`.
+//!
+//! A signature is looked up next to the file: `<file>.minisig` is checked
+//! with `minisign` and `<file>.asc` or `<file>.sig` with `gpg`, in both cases
+//! only against the keys given with `--trusted-key`. A gpg signature from a
+//! key that is merely in the user's keyring is not trusted; its fingerprint
+//! is reported so it can be exported and passed as a trusted key. Signing
+//! writes `<file>.minisig` when the key is a minisign secret key file and
+//! `<file>.asc` with gpg otherwise.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::{debug, info};
+
+pub enum Verification {
+    /// Signed by a trusted key; holds the tool that verified it
+    Verified(&'static str),
+    Unsigned,
+    /// A signature exists but could not be verified against a trusted key
+    Untrusted(String),
+}
+
+/// Signature suffixes in the order they are looked for
+const SUFFIXES: [&str; 3] = [".minisig", ".asc", ".sig"];
+
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(file.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Write a detached signature of `file` next to it, with the minisign secret
+/// key file `key` or else the gpg key `key` (the default key when `None`),
+/// and return its path
+pub fn sign(file: &Path, key: Option<&str>) -> std::io::Result<PathBuf> {
+    // A signature left from an earlier version would be checked instead
+    for suffix in SUFFIXES {
+        let stale = with_suffix(file, suffix);
+        if stale.is_file() {
+            debug!("Removing old signature {}", stale.display());
+            std::fs::remove_file(stale)?;
+        }
+    }
+    
+    let (mut command, signature, tool) = match key {
+        Some(key) if Path::new(key).is_file() => {
+            let signature = with_suffix(file, ".minisig");
+            let mut command = Command::new("minisign");
+            command.args(["-S", "-s", key, "-m"]).arg(file).arg("-x").arg(&signature);
+            (command, signature, "minisign")
+        }
+        key => {
+            let signature = with_suffix(file, ".asc");
+            let mut command = Command::new("gpg");
+            command.args(["--batch", "--yes", "--armor", "--detach-sign"]);
+            if let Some(key) = key {
+                command.args(["--local-user", key]);
+            }
+            command.arg("--output").arg(&signature).arg(file);
+            (command, signature, "gpg")
+        }
+    };
+    
+    info!("Signing {} with {}", file.display(), tool);
+    // Not quiet: the tool may ask for the key's passphrase
+    let status = command
+        .status()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("failed to run {} to sign {}: {}", tool, file.display(), e)))?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("{} failed to sign {}", tool, file.display())));
+    }
+    Ok(signature)
+}
+
+/// Check the detached signature of `file`
+pub fn verify(file: &Path, trusted_keys: &[PathBuf]) -> std::io::Result<Verification> {
+    let with_suffix = |suffix: &str| with_suffix(file, suffix);
+    
+    let minisig = with_suffix(".minisig");
+    if minisig.is_file() {
+        return verify_minisign(file, &minisig, trusted_keys);
+    }
+    for suffix in &SUFFIXES[1..] {
+        let signature = with_suffix(suffix);
+        if signature.is_file() {
+            return verify_gpg(file, &signature, trusted_keys);
+        }
+    }
+    
+    debug!("No signature found for {}", file.display());
+    Ok(Verification::Unsigned)
+}
+
+fn verify_minisign(file: &Path, signature: &Path, trusted_keys: &[PathBuf]) -> std::io::Result<Verification> {
+    if trusted_keys.is_empty() {
+        return Ok(Verification::Untrusted("minisign signatures need a public key given with --trusted-key".to_string()));
+    }
+    
+    for key in trusted_keys {
+        let verified = run_quietly(
+            Command::new("minisign")
+                .args(["-V", "-q", "-p"])
+                .arg(key)
+                .arg("-m")
+                .arg(file)
+                .arg("-x")
+                .arg(signature),
+            "minisign",
+        )?;
+        if verified {
+            info!("Signature of {} verified with minisign key {}", file.display(), key.display());
+            return Ok(Verification::Verified("minisign"));
+        }
+    }
+    Ok(Verification::Untrusted(format!("{} does not match any trusted key", signature.display())))
+}
+
+fn verify_gpg(file: &Path, signature: &Path, trusted_keys: &[PathBuf]) -> std::io::Result<Verification> {
+    if trusted_keys.is_empty() {
+        // Any key in the user's keyring is not a trusted one: report who
+        // signed so the key can be passed with --trusted-key
+        return Ok(Verification::Untrusted(match gpg_signer(Command::new("gpg").arg("--batch"), file, signature)? {
+            Some(key) => format!("signed by gpg key {}, which is not a --trusted-key", key),
+            None => format!("{} could not be verified by gpg", signature.display()),
+        }));
+    }
+    
+    // Verify against a throwaway keyring holding only the trusted keys
+    let home = tempfile::Builder::new().prefix("tust-").tempdir_in(crate::sandbox_parent(None))?;
+    for key in trusted_keys {
+        let imported = run_quietly(
+            Command::new("gpg").arg("--batch").arg("--homedir").arg(home.path()).arg("--import").arg(key),
+            "gpg",
+        )?;
+        if !imported {
+            debug!("{} is not a gpg key, skipping", key.display());
+        }
+    }
+    
+    match gpg_signer(Command::new("gpg").arg("--batch").arg("--homedir").arg(home.path()), file, signature)? {
+        Some(key) => {
+            info!("Signature of {} verified with gpg key {}", file.display(), key);
+            Ok(Verification::Verified("gpg"))
+        }
+        None => Ok(Verification::Untrusted(format!("{} does not match any trusted key", signature.display()))),
+    }
+}
+
+/// Fingerprint of the key that made the valid signature `signature` of
+/// `file`, if gpg can verify it
+fn gpg_signer(command: &mut Command, file: &Path, signature: &Path) -> std::io::Result<Option<String>> {
+    let output = command
+        .args(["--status-fd", "1", "--verify"])
+        .arg(signature)
+        .arg(file)
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("failed to run gpg to check the signature: {}", e)))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG ")?.split_whitespace().next().map(str::to_string)))
+}
+
+/// Run a verification tool, treating a missing tool as an error
+fn run_quietly(command: &mut Command, tool: &str) -> std::io::Result<bool> {
+    let status = command.stdout(Stdio::null()).stderr(Stdio::null()).status().map_err(|e| {
+        std::io::Error::new(e.kind(), format!("failed to run {} to check the signature: {}", tool, e))
+    })?;
+    Ok(status.success())
+}
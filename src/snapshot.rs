@@ -0,0 +1,346 @@
+//! Baseline snapshots of a directory, taken without running a command.
+//!
+//! `tust snapshot` records a manifest with the size and BLAKE3 hash of every
+//! file under the user's data directory. With `--content` (always on in
+//! `--watch` mode) the files themselves go into a content-addressed object
+//! store shared by all snapshots, so unchanged files are stored only once and
+//! `tust rollback <id>` can restore the tree. `tust diff-since <id>` reports
+//! what changed since then using the same change kinds as a sandbox run: a
+//! file whose content is unchanged but whose permissions differ is a `Chmod`,
+//! never a `Modify`, and rolling it back only restores its permissions.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use colored::Colorize;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::Change;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub source: PathBuf,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    /// Whether every file's content is in the object store
+    #[serde(default)]
+    pub content: bool,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: String,
+    /// Permission bits on Unix; missing in older snapshots and for symlinks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+/// A snapshot stored on disk
+pub struct Snapshot {
+    pub id: u64,
+    root: PathBuf,
+}
+
+impl Snapshot {
+    pub fn manifest(&self) -> std::io::Result<Manifest> {
+        let data = fs::read(self.root.join("manifest.json"))?;
+        serde_json::from_slice(&data).map_err(std::io::Error::other)
+    }
+}
+
+fn snapshots_dir() -> std::io::Result<PathBuf> {
+    Ok(crate::data_dir()?.join("snapshots"))
+}
+
+fn objects_dir() -> std::io::Result<PathBuf> {
+    Ok(snapshots_dir()?.join("objects"))
+}
+
+/// Record a manifest of `source`, optionally storing the content of its files
+pub fn create(source: &Path, with_content: bool) -> std::io::Result<Snapshot> {
+    let manifest = build_manifest(source, with_content)?;
+    store(manifest)
+}
+
+fn store(manifest: Manifest) -> std::io::Result<Snapshot> {
+    let dir = snapshots_dir()?;
+    fs::create_dir_all(&dir)?;
+    
+    // Ids are sequential so they are easy to type and sort
+    let mut id = snapshot_ids(&dir)?.into_iter().max().unwrap_or(0);
+    let root = loop {
+        id += 1;
+        let root = dir.join(id.to_string());
+        match fs::create_dir(&root) {
+            Ok(()) => break root,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    let snapshot = Snapshot { id, root };
+    
+    info!("Storing snapshot {} of {} ({} files)", snapshot.id, manifest.source.display(), manifest.files.len());
+    let data = serde_json::to_vec_pretty(&manifest).map_err(std::io::Error::other)?;
+    if let Err(e) = fs::write(snapshot.root.join("manifest.json"), data) {
+        let _ = fs::remove_dir_all(&snapshot.root);
+        return Err(e);
+    }
+    
+    Ok(snapshot)
+}
+
+fn snapshot_ids(dir: &Path) -> std::io::Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+fn build_manifest(source: &Path, with_content: bool) -> std::io::Result<Manifest> {
+    let mut paths = HashSet::new();
+    crate::collect_files(source, Path::new(""), &mut paths)?;
+    let mut paths: Vec<PathBuf> = paths.into_iter().collect();
+    paths.sort();
+    
+    let objects = objects_dir()?;
+    if with_content {
+        fs::create_dir_all(&objects)?;
+    }
+    
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let full_path = source.join(&path);
+        let hash = crate::hash_file(&full_path)?.to_hex().to_string();
+        
+        if with_content {
+            let object = objects.join(&hash);
+            if !object.exists() {
+                // Copy under a temporary name so a crash never leaves a truncated object
+                let partial = objects.join(format!("{}.partial", hash));
+                fs::copy(&full_path, &partial)?;
+                fs::rename(&partial, &object)?;
+            }
+        }
+        
+        files.push(ManifestEntry {
+            size: fs::metadata(&full_path)?.len(),
+            hash,
+            mode: mode(&full_path)?,
+            path,
+        });
+    }
+    
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    Ok(Manifest { source: source.to_path_buf(), created, content: with_content, files })
+}
+
+/// Look up an existing snapshot by id
+pub fn open(id: &str) -> std::io::Result<Snapshot> {
+    let not_found = || std::io::Error::new(std::io::ErrorKind::NotFound, format!("no snapshot with id {}", id));
+    let id: u64 = id.parse().map_err(|_| not_found())?;
+    let root = snapshots_dir()?.join(id.to_string());
+    if !root.join("manifest.json").is_file() {
+        return Err(not_found());
+    }
+    
+    Ok(Snapshot { id, root })
+}
+
+/// Print the stored snapshots, optionally only those taken of `source`
+pub fn list(source: Option<&Path>) -> std::io::Result<()> {
+    let dir = snapshots_dir()?;
+    if !dir.is_dir() {
+        println!("{}", "No snapshots".green());
+        return Ok(());
+    }
+    
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut shown = 0;
+    for id in snapshot_ids(&dir)? {
+        let manifest = Snapshot { id, root: dir.join(id.to_string()) }.manifest()?;
+        if source.is_some_and(|source| source != manifest.source) {
+            continue;
+        }
+        
+        shown += 1;
+        println!(
+            "  {:>4}  {:>10}  {:>6} files  {}{}",
+            id.to_string().bold(),
+            format_age(now.saturating_sub(manifest.created)),
+            manifest.files.len(),
+            manifest.source.display(),
+            if manifest.content { "" } else { "  (hashes only)" },
+        );
+    }
+    
+    if shown == 0 {
+        println!("{}", "No snapshots".green());
+    }
+    Ok(())
+}
+
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{}s ago", seconds),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+/// Take a content snapshot of `source` every `interval`, skipping snapshots
+/// when nothing changed since the previous one. Runs until interrupted.
+pub async fn watch(source: &Path, interval: Duration) -> std::io::Result<()> {
+    let mut previous: Option<Vec<ManifestEntry>> = None;
+    loop {
+        let manifest = build_manifest(source, true)?;
+        if previous.as_ref() == Some(&manifest.files) {
+            debug!("No changes since the previous snapshot");
+        } else {
+            let files = manifest.files.len();
+            let snapshot = store(manifest)?;
+            println!("  {} snapshot {} ({} files)", "+".green(), snapshot.id, files);
+            previous = Some(snapshot.manifest()?.files);
+        }
+        
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Changes that turn the snapshot's state into the current state of `dir`
+pub fn changes_since(snapshot: &Snapshot, dir: &Path) -> std::io::Result<Vec<Change>> {
+    let manifest = snapshot.manifest()?;
+    let (recorded, current) = index(&manifest, dir)?;
+    
+    let mut changes = Vec::new();
+    for file in &current {
+        match recorded.get(file.as_path()) {
+            None => changes.push(Change::Create(file.clone())),
+            Some(entry) if differs(&dir.join(file), entry)? => changes.push(Change::Modify(file.clone())),
+            Some(entry) if mode_differs(&dir.join(file), entry)? => changes.push(Change::Chmod(file.clone())),
+            Some(_) => {}
+        }
+    }
+    
+    for entry in &manifest.files {
+        if !current.contains(&entry.path) {
+            changes.push(Change::Delete(entry.path.clone()));
+        }
+    }
+    
+    Ok(changes)
+}
+
+/// Changes that bring `dir` back to the snapshot's state, together with a
+/// directory holding the snapshot's version of every created or modified file
+pub fn rollback_changes(snapshot: &Snapshot, dir: &Path) -> std::io::Result<(Vec<Change>, tempfile::TempDir)> {
+    let manifest = snapshot.manifest()?;
+    if !manifest.content {
+        return Err(std::io::Error::other(format!(
+            "snapshot {} only has hashes; take snapshots with --content to roll back",
+            snapshot.id
+        )));
+    }
+    
+    let (_, current) = index(&manifest, dir)?;
+    let restore_dir = tempfile::Builder::new().prefix("tust-").tempdir_in(crate::sandbox_parent(None))?;
+    let objects = objects_dir()?;
+    
+    let mut changes = Vec::new();
+    for entry in &manifest.files {
+        let current_path = dir.join(&entry.path);
+        let change = if !current.contains(&entry.path) {
+            Change::Create(entry.path.clone())
+        } else if differs(&current_path, entry)? {
+            Change::Modify(entry.path.clone())
+        } else if mode_differs(&current_path, entry)? {
+            Change::Chmod(entry.path.clone())
+        } else {
+            continue;
+        };
+        
+        let restored = restore_dir.path().join(&entry.path);
+        if let Some(parent) = restored.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Change::Chmod(_) = change {
+            // Only the permissions are restored; the file keeps its attributes
+            fs::copy(&current_path, &restored)?;
+            crate::xattrs::copy(&current_path, &restored)?;
+        } else {
+            fs::copy(objects.join(&entry.hash), &restored)?;
+        }
+        set_mode(&restored, entry.mode)?;
+        changes.push(change);
+    }
+    
+    let recorded: HashSet<&Path> = manifest.files.iter().map(|entry| entry.path.as_path()).collect();
+    for file in current {
+        if !recorded.contains(file.as_path()) {
+            changes.push(Change::Delete(file));
+        }
+    }
+    
+    Ok((changes, restore_dir))
+}
+
+fn index<'a>(
+    manifest: &'a Manifest,
+    dir: &Path,
+) -> std::io::Result<(HashMap<&'a Path, &'a ManifestEntry>, HashSet<PathBuf>)> {
+    let recorded = manifest
+        .files
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry))
+        .collect();
+    
+    let mut current = HashSet::new();
+    crate::collect_files(dir, Path::new(""), &mut current)?;
+    
+    Ok((recorded, current))
+}
+
+fn differs(path: &Path, entry: &ManifestEntry) -> std::io::Result<bool> {
+    Ok(fs::metadata(path)?.len() != entry.size || crate::hash_file(path)?.to_hex().as_str() != entry.hash)
+}
+
+/// Whether the permissions of `path` differ from those recorded, if any were
+fn mode_differs(path: &Path, entry: &ManifestEntry) -> std::io::Result<bool> {
+    Ok(entry.mode.is_some() && mode(path)? != entry.mode)
+}
+
+/// The permission bits of `path`, `None` for symlinks and on other platforms
+#[cfg(unix)]
+fn mode(path: &Path) -> std::io::Result<Option<u32>> {
+    use std::os::unix::fs::PermissionsExt;
+    
+    let metadata = fs::symlink_metadata(path)?;
+    Ok((!metadata.file_type().is_symlink()).then(|| metadata.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn mode(_path: &Path) -> std::io::Result<Option<u32>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Free space check before the sandbox is filled.
+//!
+//! The pre-scan already knows how much will be copied, so a sandbox that
+//! can't fit is refused up front instead of failing halfway through the
+//! copy with a full disk.
+
+use std::path::Path;
+
+use colored::Colorize;
+use log::{debug, warn};
+
+use crate::format_size;
+
+/// Fail with `StorageFull` if `needed` bytes don't fit on the filesystem of
+/// `sandbox`. On the project's own filesystem the copies may be clones that
+/// take no space, so there it only warns.
+pub fn check(sandbox: &Path, project: &Path, needed: u64) -> std::io::Result<()> {
+    let Some(free) = available(sandbox) else {
+        debug!("Can't tell the free space for {}", sandbox.display());
+        return Ok(());
+    };
+    debug!("Sandbox needs {} bytes, {} are free", needed, free);
+    if needed <= free {
+        return Ok(());
+    }
+    
+    let message = format!(
+        "the sandbox needs about {} but only {} is free in {}",
+        format_size(needed),
+        format_size(free),
+        sandbox.parent().unwrap_or(sandbox).display()
+    );
+    if same_filesystem(sandbox, project) {
+        warn!("{}, trying anyway since files may be cloned", message);
+        eprintln!("{}", format!("Warning: {}, trying anyway since files may be cloned", message).yellow());
+        return Ok(());
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::StorageFull, message))
+}
+
+/// Bytes an unprivileged process can still write on the filesystem of `path`
+#[cfg(unix)]
+fn available(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read after statvfs
+    // filled it in
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let (blocks, block_size) = (stat.f_bavail as u64, stat.f_frsize as u64);
+    Some(blocks.saturating_mul(block_size))
+}
+
+#[cfg(not(unix))]
+fn available(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_a: &Path, _b: &Path) -> bool {
+    false
+}
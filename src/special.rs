@@ -0,0 +1,80 @@
+//! FIFOs, sockets and device nodes in the project.
+//!
+//! They have no content that could be copied or compared, and opening one
+//! can block forever or talk to hardware, so tust never reads them. FIFOs are
+//! recreated empty in the sandbox, since commands may expect them to exist;
+//! sockets and devices are left out with a warning. Either way they are not
+//! part of the comparison, so nothing the command does to them is applied.
+
+use std::path::Path;
+
+use colored::Colorize;
+use log::{debug, warn};
+
+/// What kind of special file `path` is, if it is one; symlinks count as
+/// what they point to when they are followed
+#[cfg(unix)]
+pub fn kind(path: &Path) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+    
+    if crate::symlink::target(path).is_some() {
+        return None;
+    }
+    let file_type = std::fs::metadata(path).ok()?.file_type();
+    if file_type.is_fifo() {
+        Some("FIFO")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        Some("device node")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kind(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+pub fn is_special(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+/// Recreate the special file `from` at `to` if it is a FIFO, and warn that
+/// anything else is left out; `relative` is its path in the project
+pub fn copy(from: &Path, to: &Path, relative: &Path) -> std::io::Result<()> {
+    match kind(from) {
+        Some("FIFO") => {
+            debug!("Creating FIFO {}", relative.display());
+            create_fifo(from, to)
+        }
+        Some(kind) => {
+            warn!("Leaving {} {} out of the sandbox", kind, relative.display());
+            eprintln!("{}", format!("Warning: leaving {} {} out of the sandbox", kind, relative.display()).yellow());
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn create_fifo(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+    
+    let mode = std::fs::metadata(from)?.permissions().mode() & 0o7777;
+    let path = CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: `path` is a valid NUL-terminated string
+    if unsafe { libc::mkfifo(path.as_ptr(), mode as libc::mode_t) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // mkfifo applies the umask
+    std::fs::set_permissions(to, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn create_fifo(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! Symbolic links in the project.
+//!
+//! Symlinks are copied into the sandbox as symlinks, compared by their
+//! targets and applied back as symlinks, so a link to a directory is never
+//! walked (no loops, no copies of whatever it points to) and a link out of
+//! the project never lets tust read or write through it. With
+//! `--follow-symlinks` they are treated as the files and directories they
+//! point to instead, except for links that can't be followed: dangling ones
+//! and links to a directory containing them, which would never end.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::debug;
+
+static FOLLOW: AtomicBool = AtomicBool::new(false);
+
+/// Treat symlinks as what they point to from now on (`--follow-symlinks`)
+pub fn set_follow() {
+    FOLLOW.store(true, Ordering::Relaxed);
+}
+
+/// The target of `path` if it is a symlink that is kept as a link
+pub fn target(path: &Path) -> Option<PathBuf> {
+    let metadata = std::fs::symlink_metadata(path).ok()?;
+    if !metadata.file_type().is_symlink() {
+        return None;
+    }
+    if FOLLOW.load(Ordering::Relaxed) && !is_dangling(path) && !is_loop(path) {
+        return None;
+    }
+    std::fs::read_link(path).ok()
+}
+
+/// Whether a walk descends into `path`: a directory, and with
+/// `--follow-symlinks` a symlink to one
+pub fn is_dir(path: &Path) -> bool {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => target(path).is_none() && path.is_dir(),
+        Ok(metadata) => metadata.is_dir(),
+        Err(_) => false,
+    }
+}
+
+/// Whether anything, even a dangling symlink, exists at `path`
+pub fn exists(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok()
+}
+
+/// Create a symlink at `link` pointing to `target`
+pub fn create(target: &Path, link: &Path) -> std::io::Result<()> {
+    debug!("Creating symlink {} -> {}", link.display(), target.display());
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        // Windows needs to know whether the link is to a directory
+        let resolved = link.parent().map_or_else(|| target.to_path_buf(), |parent| parent.join(target));
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        }
+    }
+}
+
+fn is_dangling(path: &Path) -> bool {
+    std::fs::metadata(path).is_err()
+}
+
+/// Whether `path` links to a directory it is inside of
+fn is_loop(path: &Path) -> bool {
+    let (Ok(target), Some(Ok(parent))) = (path.canonicalize(), path.parent().map(Path::canonicalize)) else {
+        return false;
+    };
+    target.is_dir() && parent.starts_with(target)
+}
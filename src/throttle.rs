@@ -0,0 +1,66 @@
+//! I/O throttling for the copy and compare phases.
+//!
+//! `--bwlimit` caps how many bytes per second tust itself reads and writes,
+//! and `--nice-io` moves tust (and therefore the command it starts) into the
+//! idle I/O scheduling class, so a run on a huge tree doesn't starve
+//! everything else on the machine.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+struct Budget {
+    bytes_per_second: u64,
+    start: Instant,
+    consumed: u64,
+}
+
+static BUDGET: OnceLock<Mutex<Budget>> = OnceLock::new();
+
+/// Limit all following `consume` calls to `bytes_per_second` in total
+pub fn set_limit(bytes_per_second: u64) {
+    let budget = Budget { bytes_per_second: bytes_per_second.max(1), start: Instant::now(), consumed: 0 };
+    let _ = BUDGET.set(Mutex::new(budget));
+}
+
+/// Account for `bytes` of I/O, sleeping as long as needed to stay within the limit
+pub fn consume(bytes: u64) {
+    let Some(budget) = BUDGET.get() else {
+        return;
+    };
+    
+    let delay = {
+        let mut budget = budget.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        budget.consumed += bytes;
+        let due = Duration::from_secs_f64(budget.consumed as f64 / budget.bytes_per_second as f64);
+        due.saturating_sub(budget.start.elapsed())
+    };
+    
+    if !delay.is_zero() {
+        std::thread::sleep(delay);
+    }
+}
+
+/// Put this process, and every process it starts, in the idle I/O class
+#[cfg(target_os = "linux")]
+pub fn nice_io() {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    
+    // SAFETY: ioprio_set only takes integer arguments; 0 means the calling process
+    let result = unsafe {
+        libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT)
+    };
+    if result == 0 {
+        log::debug!("Switched to the idle I/O scheduling class");
+    } else {
+        warn!("Failed to lower I/O priority: {}", std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn nice_io() {
+    warn!("--nice-io is only supported on Linux; I/O priority is unchanged");
+}
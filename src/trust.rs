@@ -0,0 +1,116 @@
+//! Trusted project configurations (`tust trust`).
+//!
+//! A project's `.tust.toml` and `.tust/hooks` sit in the very directory a
+//! sandboxed command's changes are applied to, and a cloned repository
+//! brings its own. A `yes = true`, a hook, a key that runs a command outside
+//! the sandbox (`setup`, `validate`, `merge-tool`, `difftool`,
+//! `diff-handler`, `inspector`) or one that sends data somewhere
+//! (`notify-webhook`, `encrypt-to`) is therefore only honoured once the user
+//! ran `tust trust` in the project, which records a hash of `.tust.toml` and
+//! every file in `.tust/hooks` under the user's data directory. Any later
+//! edit to them withdraws the trust until `tust trust` is run again. The
+//! other keys only shape the sandboxed run and its review and are always
+//! read.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use crate::config;
+
+fn trusted_path() -> std::io::Result<PathBuf> {
+    Ok(crate::data_dir()?.join("trusted.json"))
+}
+
+/// Trusted project directories and the hash of their configuration
+fn read_trusted() -> std::io::Result<BTreeMap<PathBuf, String>> {
+    match fs::read(trusted_path()?) {
+        Ok(data) => serde_json::from_slice(&data).map_err(std::io::Error::other),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_trusted(trusted: &BTreeMap<PathBuf, String>) -> std::io::Result<()> {
+    let path = trusted_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_vec_pretty(trusted).map_err(std::io::Error::other)?)
+}
+
+/// Hash of the project configuration in `dir`: `.tust.toml` and the names,
+/// permissions and content of the files in `.tust/hooks`
+fn fingerprint(dir: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let config_file = dir.join(config::FILE_NAME);
+    if config_file.is_file() {
+        hasher.update(config::FILE_NAME.as_bytes());
+        hasher.update(crate::hash_file(&config_file)?.as_bytes());
+    }
+    
+    let hooks = hooks_dir(dir);
+    if hooks.is_dir() {
+        let mut files = HashSet::new();
+        crate::collect_files(&hooks, Path::new(""), &mut files)?;
+        let mut files: Vec<PathBuf> = files.into_iter().collect();
+        files.sort();
+        for file in files {
+            let path = hooks.join(&file);
+            hasher.update(file.to_string_lossy().as_bytes());
+            hasher.update(&mode(&path)?.to_le_bytes());
+            hasher.update(crate::hash_file(&path)?.as_bytes());
+        }
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// The project's directory of hook executables
+pub fn hooks_dir(dir: &Path) -> PathBuf {
+    dir.join(crate::cache::DIR).join("hooks")
+}
+
+#[cfg(unix)]
+fn mode(path: &Path) -> std::io::Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    
+    Ok(fs::symlink_metadata(path)?.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn mode(_path: &Path) -> std::io::Result<u32> {
+    Ok(0)
+}
+
+/// Whether the user trusted the project configuration in `dir` as it is now
+pub fn is_trusted(dir: &Path) -> std::io::Result<bool> {
+    let dir = dir.canonicalize()?;
+    let Some(trusted) = read_trusted()?.remove(&dir) else {
+        debug!("{} was never trusted", dir.display());
+        return Ok(false);
+    };
+    Ok(trusted == fingerprint(&dir)?)
+}
+
+/// Trust the project configuration in `dir` as it is now
+pub fn trust(dir: &Path) -> std::io::Result<()> {
+    let dir = dir.canonicalize()?;
+    let mut trusted = read_trusted()?;
+    info!("Trusting the configuration of {}", dir.display());
+    trusted.insert(dir.clone(), fingerprint(&dir)?);
+    write_trusted(&trusted)
+}
+
+/// Stop trusting the project configuration in `dir`; whether it was trusted
+pub fn revoke(dir: &Path) -> std::io::Result<bool> {
+    let dir = dir.canonicalize()?;
+    let mut trusted = read_trusted()?;
+    if trusted.remove(&dir).is_none() {
+        return Ok(false);
+    }
+    info!("No longer trusting the configuration of {}", dir.display());
+    write_trusted(&trusted)?;
+    Ok(true)
+}
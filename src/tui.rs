@@ -0,0 +1,164 @@
+//! Full-screen review (`--tui`).
+//!
+//! The changes are listed on the left and the diff of the highlighted one is
+//! shown on the right. Every change starts out selected; toggling them and
+//! pressing Enter applies the selected subset, like answering per change.
+//! With `--difftool`, `d` opens the highlighted change in that tool.
+
+use std::path::Path;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::{Change, difftool, review};
+
+const HELP: &str = " ↑/↓ move  space toggle  a all  n none  PgUp/PgDn scroll diff  enter apply  q abort ";
+
+const DIFFTOOL_HELP: &str = " ↑/↓ move  space toggle  a all  n none  PgUp/PgDn scroll diff  d difftool  enter apply  q abort ";
+
+struct Review<'a> {
+    changes: &'a [Change],
+    original: &'a Path,
+    modified: &'a Path,
+    difftool: Option<&'a str>,
+    accepted: Vec<bool>,
+    list: ListState,
+    /// Diff of the highlighted change, computed when the highlight moves
+    diff: Vec<String>,
+    scroll: u16,
+    /// Shown instead of the help until the next key, e.g. a difftool failure
+    message: Option<String>,
+}
+
+/// Let the user pick changes on a full-screen review; returns which ones were
+/// accepted, or `None` if the user aborted
+pub fn select(changes: &[Change], original: &Path, modified: &Path, difftool: Option<&str>) -> std::io::Result<Option<Vec<bool>>> {
+    let mut review = Review {
+        changes,
+        original,
+        modified,
+        difftool,
+        accepted: vec![true; changes.len()],
+        list: ListState::default(),
+        diff: Vec::new(),
+        scroll: 0,
+        message: None,
+    };
+    review.highlight(0);
+    
+    let mut terminal = ratatui::try_init()?;
+    let result = review.run(&mut terminal);
+    ratatui::try_restore()?;
+    result
+}
+
+impl Review<'_> {
+    fn highlight(&mut self, index: usize) {
+        let index = index.min(self.changes.len().saturating_sub(1));
+        self.list.select(Some(index));
+        self.diff = review::diff_lines(&self.changes[index], self.original, self.modified);
+        self.scroll = 0;
+    }
+    
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> std::io::Result<Option<Vec<bool>>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            
+            self.message = None;
+            let current = self.list.selected().unwrap_or_default();
+            let page = terminal.size()?.height.saturating_sub(4);
+            match key.code {
+                KeyCode::Up | KeyCode::Char('k') => self.highlight(current.saturating_sub(1)),
+                KeyCode::Down | KeyCode::Char('j') => self.highlight(current + 1),
+                KeyCode::Char(' ') => self.accepted[current] = !self.accepted[current],
+                KeyCode::Char('a') => self.accepted.fill(true),
+                KeyCode::Char('n') => self.accepted.fill(false),
+                KeyCode::PageDown | KeyCode::Char('J') => {
+                    let last = self.diff.len().saturating_sub(1) as u16;
+                    self.scroll = self.scroll.saturating_add(page).min(last);
+                }
+                KeyCode::PageUp | KeyCode::Char('K') => self.scroll = self.scroll.saturating_sub(page),
+                KeyCode::Char('d') if let Some(tool) = self.difftool => {
+                    // The tool may need the terminal, so tust steps aside
+                    ratatui::try_restore()?;
+                    let opened = difftool::open(tool, &self.changes[current], self.original, self.modified);
+                    *terminal = ratatui::try_init()?;
+                    self.message = match opened {
+                        Ok(true) => None,
+                        Ok(false) => Some(" no file content to compare ".to_string()),
+                        Err(e) => Some(format!(" difftool failed: {} ", e)),
+                    };
+                }
+                KeyCode::Enter => return Ok(Some(self.accepted.clone())),
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+    
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, help] = Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)]).areas(main);
+        
+        let items: Vec<ListItem> = self
+            .changes
+            .iter()
+            .zip(&self.accepted)
+            .map(|(change, accepted)| {
+                let (marker, color, path) = match change {
+                    Change::Create(path) => ("+ ", Color::Green, path.display().to_string()),
+                    Change::Modify(path) => ("~ ", Color::Yellow, path.display().to_string()),
+                    Change::Delete(path) => ("- ", Color::Red, path.display().to_string()),
+                    Change::Chmod(path) => ("* ", Color::Magenta, path.display().to_string()),
+                    Change::Xattr(path) => ("@ ", Color::Cyan, path.display().to_string()),
+                    Change::Rename(from, to) => ("> ", Color::Blue, format!("{} -> {}", from.display(), to.display())),
+                };
+                let checkbox = if *accepted { "[x] " } else { "[ ] " };
+                ListItem::new(Line::from(vec![
+                    Span::raw(checkbox),
+                    Span::styled(marker, Style::new().fg(color)),
+                    Span::raw(path),
+                ]))
+            })
+            .collect();
+        let selected = self.accepted.iter().filter(|accepted| **accepted).count();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" Changes ({}/{} selected) ", selected, self.changes.len())))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, left, &mut self.list);
+        
+        let lines: Vec<Line> = self
+            .diff
+            .iter()
+            .map(|line| {
+                let color = match line.chars().next() {
+                    Some('@') => Color::Cyan,
+                    Some('+') => Color::Green,
+                    Some('-') => Color::Red,
+                    _ => Color::Reset,
+                };
+                Line::styled(line.as_str(), Style::new().fg(color))
+            })
+            .collect();
+        let diff = Paragraph::new(lines).block(Block::bordered().title(" Diff ")).scroll((self.scroll, 0));
+        frame.render_widget(diff, right);
+        
+        let text = match (&self.message, self.difftool) {
+            (Some(message), _) => message.as_str(),
+            (None, Some(_)) => DIFFTOOL_HELP,
+            (None, None) => HELP,
+        };
+        frame.render_widget(Line::styled(text, Style::new().add_modifier(Modifier::DIM)), help);
+    }
+}
@@ -0,0 +1,295 @@
+//! Undoing applied change sets (`tust undo`).
+//!
+//! Right before a change set is applied, the version of every original file
+//! it replaces, deletes or changes the metadata of is copied into a record
+//! under the user's data directory, together with the changes and the
+//! hashes of what they leave behind. `tust undo [id]` turns a record into
+//! the opposite change set and offers it for review like any other: created
+//! files are deleted, the saved versions are put back and renamed
+//! directories move back. Files that changed again since the apply are
+//! reported, and nothing is undone until they are back to what was applied.
+//! Only the latest records are kept, and each saves at most
+//! `--max-undo-size` of files; changes to the files that didn't fit are
+//! left out of the undo, with a warning on both occasions. `--no-undo` saves
+//! nothing. With `--encrypt` the saved files and the record are encrypted
+//! like a kept session (see `encryption`), and decrypted into a temporary
+//! directory to be undone.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::EntryChange;
+use crate::encryption::{self, Key};
+use crate::{Change, hash_file, redact, symlink};
+
+/// Records kept; older ones are deleted when a new one is stored
+const MAX_RECORDS: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Record {
+    pub directory: PathBuf,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    /// The tust invocation that applied the changes; a list of arguments in
+    /// older records
+    #[serde(deserialize_with = "crate::audit::command_line")]
+    pub command: String,
+    /// The applied changes, with the hashes of the files before and after
+    pub changes: Vec<EntryChange>,
+    /// Paths whose files were not saved, as they exceeded `--max-undo-size`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsaved: Vec<PathBuf>,
+}
+
+fn undo_dir() -> std::io::Result<PathBuf> {
+    Ok(crate::data_dir()?.join("undo"))
+}
+
+/// Save what applying `changes` (described as `described`) to `original`
+/// will replace, up to `max_size` bytes and encrypted with `key` when given,
+/// and return the record's id
+pub fn record(
+    original: &Path,
+    changes: &[Change],
+    described: &[EntryChange],
+    key: Option<&Key>,
+    max_size: u64,
+) -> std::io::Result<u64> {
+    let dir = undo_dir()?;
+    fs::create_dir_all(&dir)?;
+    
+    // Sequential ids, like sessions and snapshots
+    let mut id = record_ids(&dir)?.into_iter().max().unwrap_or(0);
+    let root = loop {
+        id += 1;
+        let root = dir.join(id.to_string());
+        match fs::create_dir(&root) {
+            Ok(()) => break root,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    
+    info!("Recording undo {} for {} changes", id, changes.len());
+    if let Err(e) = write(&root, original, changes, described, key, max_size) {
+        let _ = fs::remove_dir_all(&root);
+        return Err(e);
+    }
+    
+    for old in record_ids(&dir)?.iter().rev().skip(MAX_RECORDS) {
+        debug!("Deleting undo record {}", old);
+        fs::remove_dir_all(dir.join(old.to_string()))?;
+    }
+    Ok(id)
+}
+
+fn write(
+    root: &Path,
+    original: &Path,
+    changes: &[Change],
+    described: &[EntryChange],
+    key: Option<&Key>,
+    max_size: u64,
+) -> std::io::Result<()> {
+    let files = root.join("files");
+    let mut size = 0;
+    let mut unsaved = Vec::new();
+    for change in changes {
+        if let Change::Modify(path) | Change::Delete(path) | Change::Chmod(path) | Change::Xattr(path) = change {
+            let file_size = fs::symlink_metadata(original.join(path))?.len();
+            if size + file_size > max_size {
+                unsaved.push(path.clone());
+                continue;
+            }
+            size += file_size;
+            let saved = files.join(path);
+            if let Some(parent) = saved.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let original_path = original.join(path);
+            match key {
+                Some(key) if symlink::target(&original_path).is_none() => write_with_metadata(&original_path, &saved, || {
+                    encryption::write(&saved, &fs::read(&original_path)?, Some(key))
+                })?,
+                _ => {
+                    crate::reflink::copy(&original_path, &saved)?;
+                }
+            }
+        }
+    }
+    
+    if !unsaved.is_empty() {
+        warn!("Not saving {} files for undo, more than --max-undo-size", unsaved.len());
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: not saving {} for undo, as that would take more than --max-undo-size",
+                unsaved.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")
+            )
+            .yellow()
+        );
+    }
+    
+    let record = Record {
+        directory: original.to_path_buf(),
+        time: crate::session::now(),
+        command: redact::command_line(&std::env::args().collect::<Vec<_>>()),
+        changes: described.to_vec(),
+        unsaved,
+    };
+    let data = serde_json::to_vec_pretty(&record).map_err(std::io::Error::other)?;
+    encryption::write(&root.join("record.json"), &data, key)
+}
+
+/// Write `to` with `write`, then give it the permissions and other metadata
+/// of `from`
+fn write_with_metadata(from: &Path, to: &Path, write: impl FnOnce() -> std::io::Result<()>) -> std::io::Result<()> {
+    write()?;
+    fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+    crate::reflink::copy_metadata(from, to)
+}
+
+/// A copy of the saved `files` of a record with the encrypted ones
+/// decrypted, or `None` when none are
+pub fn decrypt(files: &Path) -> std::io::Result<Option<tempfile::TempDir>> {
+    let mut paths = std::collections::HashSet::new();
+    if files.is_dir() {
+        crate::collect_files(files, Path::new(""), &mut paths)?;
+    }
+    if !paths.iter().any(|path| encryption::is_encrypted(&files.join(path))) {
+        return Ok(None);
+    }
+    
+    let decrypted = tempfile::Builder::new().prefix("tust-").tempdir_in(crate::sandbox_parent(None))?;
+    debug!("Decrypting the saved files of {} into {}", files.display(), decrypted.path().display());
+    for path in paths {
+        let (saved, to) = (files.join(&path), decrypted.path().join(&path));
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if encryption::is_encrypted(&saved) {
+            write_with_metadata(&saved, &to, || fs::write(&to, encryption::read(&saved)?))?;
+        } else {
+            crate::reflink::copy(&saved, &to)?;
+        }
+    }
+    Ok(Some(decrypted))
+}
+
+fn record_ids(dir: &Path) -> std::io::Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// A stored record, by default the latest one
+pub fn open(id: Option<&str>) -> std::io::Result<(u64, Record, PathBuf)> {
+    let dir = undo_dir()?;
+    let id = match id {
+        Some(id) => id
+            .parse()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::NotFound, format!("no applied changes with id {}", id)))?,
+        None => {
+            let ids = if dir.is_dir() { record_ids(&dir)? } else { Vec::new() };
+            *ids.last().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no applied changes to undo"))?
+        }
+    };
+    let root = dir.join(id.to_string());
+    let data = match encryption::read(&root.join("record.json")) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no applied changes with id {}", id)));
+        }
+        Err(e) => return Err(e),
+    };
+    let record = serde_json::from_slice(&data).map_err(std::io::Error::other)?;
+    Ok((id, record, root.join("files")))
+}
+
+/// Changes that take `record.directory` back to where it was before the
+/// record's changes were applied, to be copied from the record's `files`
+pub fn changes(record: &Record) -> std::io::Result<Vec<Change>> {
+    let directory = &record.directory;
+    let mut changed = Vec::new();
+    let mut changes = Vec::new();
+    for change in &record.changes {
+        if record.unsaved.contains(&change.path) {
+            continue;
+        }
+        let path = directory.join(&change.path);
+        let (matches, undo) = match (change.kind.as_str(), &change.to) {
+            ("create", _) => (matches(&path, change.after.as_deref()), Change::Delete(change.path.clone())),
+            ("delete", _) => (!symlink::exists(&path), Change::Create(change.path.clone())),
+            ("rename", Some(to)) => (
+                !symlink::exists(&path) && directory.join(to).is_dir(),
+                Change::Rename(to.clone(), change.path.clone()),
+            ),
+            ("chmod", _) => (matches(&path, change.after.as_deref()), Change::Chmod(change.path.clone())),
+            ("xattr", _) => (matches(&path, change.after.as_deref()), Change::Xattr(change.path.clone())),
+            _ => (matches(&path, change.after.as_deref()), Change::Modify(change.path.clone())),
+        };
+        if !matches {
+            changed.push(change.path.display().to_string());
+        }
+        changes.push(undo);
+    }
+    
+    if !changed.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "changed again since the changes were applied: {}",
+            changed.join(", ")
+        )));
+    }
+    if !record.unsaved.is_empty() {
+        if changes.is_empty() {
+            return Err(std::io::Error::other("none of the replaced files were saved, as they exceeded --max-undo-size"));
+        }
+        warn!("Leaving {} unsaved files out of the undo", record.unsaved.len());
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: not undoing the changes to {}, which were not saved (--max-undo-size)",
+                record.unsaved.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")
+            )
+            .yellow()
+        );
+    }
+    Ok(changes)
+}
+
+/// Whether the file at `path` has the hash `expected`
+fn matches(path: &Path, expected: Option<&str>) -> bool {
+    symlink::exists(path)
+        && !symlink::is_dir(path)
+        && expected.is_none_or(|expected| hash_file(path).is_ok_and(|hash| hash.to_hex().as_str() == expected))
+}
+
+/// Print one line per stored record
+pub fn list() -> std::io::Result<()> {
+    let dir = undo_dir()?;
+    let ids = if dir.is_dir() { record_ids(&dir)? } else { Vec::new() };
+    if ids.is_empty() {
+        println!("{}", "No applied changes to undo".green());
+        return Ok(());
+    }
+    
+    let now = crate::session::now();
+    for id in ids {
+        let (_, record, _) = open(Some(&id.to_string()))?;
+        println!(
+            "  {:>4}  {:>10}  {:>8}  {}  {}",
+            id.to_string().bold(),
+            crate::snapshot::format_age(now.saturating_sub(record.time)),
+            format!("{} changes", record.changes.len()),
+            record.directory.display(),
+            record.command,
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! Checking whether a kept session or a patch still applies (`tust verify`).
+//!
+//! Nothing is changed. For a session, every recorded change is checked
+//! against its directory: files it creates must still be absent, files it
+//! modifies, deletes or touches the metadata of must still have the hash they
+//! had when the session's command ran, and the new content must still be in
+//! the sandbox. For a patch, every file patch is tried against the current
+//! directory the way `tust apply` would. Each entry that would fail is
+//! reported with the reason.
+
+use std::path::Path;
+
+use colored::Colorize;
+use log::info;
+
+use crate::session::{self, SessionChange};
+use crate::{hash_file, patch, symlink};
+
+/// Check every change of session `id` and report those that would fail
+pub fn session(id: &str) -> std::io::Result<bool> {
+    let session = session::open(id)?;
+    let Some(changes) = &session.changes else {
+        println!("{}", format!("Session {} recorded no changes, the command failed", id).yellow());
+        return Ok(false);
+    };
+    if !session.sandbox.is_dir() {
+        println!("{}", format!("Session {} cannot be applied: its sandbox {} was deleted", id, session.sandbox.display()).red().bold());
+        return Ok(false);
+    }
+    
+    info!("Verifying {} changes of session {} against {}", changes.len(), id, session.directory.display());
+    let results = changes
+        .iter()
+        .map(|change| {
+            check_change(change, &session.directory, &session.sandbox)
+                .map_err(|reason| format!("{}: {}", change.path.display(), reason))
+        })
+        .collect();
+    Ok(report(&format!("session {}", id), &session.directory, results))
+}
+
+/// Check every file patch of the patch at `path` and report those that would fail
+pub fn patch(path: &Path, strip: Option<usize>, directory: &Path) -> std::io::Result<bool> {
+    let text = String::from_utf8(crate::encryption::read(path)?).map_err(std::io::Error::other)?;
+    let patches = patch::parse(&text, strip)?;
+    
+    info!("Verifying {} file patches of {} against {}", patches.len(), path.display(), directory.display());
+    let results = patches
+        .iter()
+        .map(|file_patch| patch::check(file_patch, directory).map_err(|e| e.to_string()))
+        .collect();
+    Ok(report(&path.display().to_string(), directory, results))
+}
+
+/// Why `change` could no longer be applied to `directory` from `sandbox`
+fn check_change(change: &SessionChange, directory: &Path, sandbox: &Path) -> Result<(), String> {
+    let original = directory.join(&change.path);
+    match change.kind.as_str() {
+        "create" if symlink::exists(&original) => return Err("already exists".to_string()),
+        "create" => {}
+        "rename" => {
+            if !original.is_dir() {
+                return Err("directory to be renamed does not exist".to_string());
+            }
+            if let Some(to) = &change.to
+                && symlink::exists(&directory.join(to))
+            {
+                return Err(format!("{} already exists", to.display()));
+            }
+        }
+        _ => {
+            if !symlink::exists(&original) {
+                return Err("no longer exists".to_string());
+            }
+            if let Some(before) = &change.before {
+                let hash = hash_file(&original).map_err(|e| e.to_string())?;
+                if hash.to_hex().as_str() != before {
+                    return Err("changed since the session's command ran".to_string());
+                }
+            }
+        }
+    }
+    
+    if change.kind != "delete" && change.kind != "rename" && !symlink::exists(&sandbox.join(&change.path)) {
+        return Err("missing from the sandbox".to_string());
+    }
+    Ok(())
+}
+
+/// Print the entries that would fail, each starting with its path, or that
+/// all of them apply
+fn report(name: &str, directory: &Path, results: Vec<Result<(), String>>) -> bool {
+    let failed: Vec<&String> = results.iter().filter_map(|result| result.as_ref().err()).collect();
+    if failed.is_empty() {
+        println!("{}", format!("Every entry of {} applies to {}", name, directory.display()).green());
+        return true;
+    }
+    
+    println!(
+        "{}",
+        format!("{} of {} entries of {} would fail against {}:", failed.len(), results.len(), name, directory.display())
+            .red()
+            .bold()
+    );
+    for reason in failed {
+        println!("  {}{}", "! ".red(), reason);
+    }
+    false
+}
+
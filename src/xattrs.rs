@@ -0,0 +1,90 @@
+//! Extended attributes.
+//!
+//! Each file's extended attributes are copied into the sandbox with it and
+//! applied back with it, so metadata like `security.capability`, SELinux
+//! labels, POSIX ACLs (`system.posix_acl_*`) and macOS resource forks
+//! survives a run. A file whose content and permissions are unchanged but
+//! whose attributes differ is a `Change::Xattr`. Attributes tust may not read
+//! or write (`trusted.*` without root, say) and filesystems without extended
+//! attributes are skipped.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::Path;
+
+#[cfg(unix)]
+use log::debug;
+
+/// Attribute names and values of a file
+pub type Attributes = BTreeMap<OsString, Vec<u8>>;
+
+/// The extended attributes of `path`, of a symlink itself rather than its target
+#[cfg(unix)]
+pub fn read(path: &Path) -> std::io::Result<Attributes> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if is_skippable(&e) => return Ok(Attributes::new()),
+        Err(e) => return Err(e),
+    };
+    let mut attributes = Attributes::new();
+    for name in names {
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => {
+                attributes.insert(name, value);
+            }
+            // Removed since it was listed
+            Ok(None) => {}
+            Err(e) if is_skippable(&e) => debug!("Skipping attribute {:?} of {}: {}", name, path.display(), e),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(attributes)
+}
+
+#[cfg(not(unix))]
+pub fn read(_path: &Path) -> std::io::Result<Attributes> {
+    Ok(Attributes::new())
+}
+
+/// Give `to` exactly the extended attributes of `from`
+#[cfg(unix)]
+pub fn copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    let (wanted, current) = (read(from)?, read(to)?);
+    for name in current.keys().filter(|name| !wanted.contains_key(*name)) {
+        skip_unwritable(xattr::remove(to, name), name, to)?;
+    }
+    for (name, value) in wanted.iter().filter(|(name, value)| current.get(*name) != Some(*value)) {
+        skip_unwritable(xattr::set(to, name, value), name, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn copy(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Names of the attributes that differ between `old` and `new`, for display
+pub fn changed_names(old: &Path, new: &Path) -> std::io::Result<Vec<String>> {
+    let (old, new) = (read(old)?, read(new)?);
+    let names: BTreeSet<_> = old.keys().chain(new.keys()).filter(|name| old.get(*name) != new.get(*name)).collect();
+    Ok(names.into_iter().map(|name| name.to_string_lossy().into_owned()).collect())
+}
+
+#[cfg(unix)]
+fn skip_unwritable(result: std::io::Result<()>, name: &std::ffi::OsStr, path: &Path) -> std::io::Result<()> {
+    match result {
+        Err(e) if is_skippable(&e) => {
+            debug!("Could not set attribute {:?} of {}: {}", name, path.display(), e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Whether `e` means the attribute can't be handled here, rather than that
+/// the file itself is unreadable
+#[cfg(unix)]
+fn is_skippable(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::Unsupported | std::io::ErrorKind::PermissionDenied)
+}